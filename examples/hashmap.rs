@@ -151,7 +151,7 @@ const FIRST_ID: u64 = 1;
 
 impl Server {
     fn new(id: u64, info: &ServerInfo) -> Server {
-        trace!("Starting new server with state file {} and log file {}", &info.state_filename, &info.log_filename);
+        trace!("Starting new server with state file {} and log directory {}", &info.state_filename, &info.log_filename);
         Server { 
             handle: 
                 start_server(id, Box::new(RaftHashMap { map: HashMap::new() }), info.addr, id == FIRST_ID,
@@ -430,5 +430,19 @@ impl StateMachine for RaftHashMap {
                     .ok_or(key_error(&key))
             })
     }
+
+    fn snapshot (&self) -> Result<Vec<u8>, RaftError> {
+        json::encode(&self.map)
+            .map(|string| string.into_bytes())
+            .map_err(serialize_error)
+    }
+
+    fn restore_snapshot (&mut self, buffer: &[u8]) -> Result<(), RaftError> {
+        str::from_utf8(buffer)
+            .map_err(deserialize_error)
+            .and_then(|string| json::decode(&string)
+                                    .map_err(deserialize_error))
+            .map(|map: HashMap<String, String>| self.map = map)
+    }
 }
 
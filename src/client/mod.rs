@@ -3,19 +3,17 @@ use capnp::serialize::OwnedSegments;
 use capnp::message::Reader;
 use raft_capnp::{client_request};
 use rpc::client::Rpc;
-use common::{client_command, raft_query, raft_command, RaftError, SessionInfo};
+use rpc::transport::{Transport, TcpTransport};
+use common::{client_command, raft_query, raft_command, Config, RaftError, SessionInfo};
 use common::constants;
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::thread;
+use std::sync::Arc;
 use std::time::{Duration};
+use simulation;
+use simulation::thread;
 
-use rand;
-use rand::Rng;
-
-/// Base amount of time to backoff when a client request fails.
-const BACKOFF_TIME_MS:u64 = 50;
 const MIN_RETRY:u64 = 3;
 
 ///
@@ -49,19 +47,24 @@ pub struct RaftConnection {
     backoff_time: Duration,             // Base backoff time.
     client_id: Option<u64>,             // client id for current session
     sequence_number: u64,               // client id for current session
+    last_write_index: usize,            // Log index our latest command commited at
+    transport: Arc<Transport>,          // Carries our requests to the cluster
 }
 
 impl RaftConnection {
     ///
     /// New RaftConnection for testing.
     ///
-    fn new(cluster: &HashMap<u64, SocketAddr>, client_id: Option<u64>) -> RaftConnection {
+    fn new(cluster: &HashMap<u64, SocketAddr>, client_id: Option<u64>,
+           backoff_time: Duration, transport: Arc<Transport>) -> RaftConnection {
         RaftConnection { 
             cluster: cluster.clone(),
-            leader_guess: cluster.iter().next().map(|(_, b)| *b).unwrap(),
-            backoff_time: Duration::from_millis(BACKOFF_TIME_MS),
+            leader_guess: cluster.iter().min_by_key(|&(id, _)| id).map(|(_, b)| *b).unwrap(),
+            backoff_time: backoff_time,
             client_id: client_id,
             sequence_number: 0,
+            last_write_index: 0,
+            transport: transport,
         }
     }
 
@@ -81,7 +84,9 @@ impl RaftConnection {
     ///
     #[cfg(test)]
     fn new_mock(cluster: &HashMap<u64, SocketAddr>) -> RaftConnection {
-        RaftConnection::new(cluster, Some(0))
+        RaftConnection::new(cluster, Some(0),
+                            Duration::from_millis(constants::DEFAULT_CLIENT_BACKOFF_TIME),
+                            Arc::new(TcpTransport))
     }
 
     fn handle_register_client_reply(msg: Reader<OwnedSegments>) -> Result<(), RaftError> {
@@ -92,7 +97,7 @@ impl RaftConnection {
                 &mut result.get_as::<client_request::reply::Reader>().unwrap())
             })
             .and_then(|result| {
-                if let client_command::Reply::Command(reply) = result {
+                if let client_command::Reply::Command(reply, _) = result {
                   if raft_command::Reply::OpenSession == reply {
                     return Ok(())
                   }
@@ -106,7 +111,8 @@ impl RaftConnection {
     ///
     fn register_client(&mut self) -> Result<(), RaftError> {
 
-        let session_id = rand::thread_rng().next_u64();
+        let session_id = simulation::random_u64();
+        let transport = self.transport.clone();
         self.perform_leader_op(|leader_addr|  {
             let mut rpc = Rpc::new(constants::CLIENT_REQUEST_OPCODE);
             {
@@ -117,7 +123,7 @@ impl RaftConnection {
                         raft_command::Request::OpenSession(session_id)),
                     &mut params);
             }
-            rpc.send(leader_addr)
+            transport.send(leader_addr, &rpc)
                .map_err(|x| RaftError::IoError(format!("{:?}", x)))// jank
                .and_then(RaftConnection::handle_register_client_reply)
         }).map(|x| {
@@ -131,7 +137,29 @@ impl RaftConnection {
     ///
     pub fn new_with_session(cluster: &HashMap<u64, SocketAddr>)
         -> Option<RaftConnection> {
-        let mut conn = RaftConnection::new(cluster, None);
+        RaftConnection::new_with_transport(cluster, Arc::new(TcpTransport))
+    }
+
+    ///
+    /// Opens a new session with the Raft cluster specified, sending all requests
+    /// over |transport|.
+    ///
+    pub fn new_with_transport(cluster: &HashMap<u64, SocketAddr>, transport: Arc<Transport>)
+        -> Option<RaftConnection> {
+        let mut conn = RaftConnection::new(cluster, None,
+                                           Duration::from_millis(constants::DEFAULT_CLIENT_BACKOFF_TIME),
+                                           transport);
+        conn.register_client().ok().map(|_| conn)
+    }
+
+    ///
+    /// Opens a new session with the Raft cluster specified, backing off failed requests
+    /// by |config.client_backoff_time| and sending them over |config.transport|.
+    ///
+    pub fn new_with_config(cluster: &HashMap<u64, SocketAddr>, config: &Config)
+        -> Option<RaftConnection> {
+        let mut conn = RaftConnection::new(cluster, None, config.client_backoff_time,
+                                           config.transport.clone());
         conn.register_client().ok().map(|_| conn)
     }
 
@@ -167,11 +195,13 @@ impl RaftConnection {
     }
 
     ///
-    /// Helper to retrieve a random leader from our initial cluster
+    /// Helper to retrieve a random leader from our initial cluster.
+    /// In a simulation the choice comes from the simulation's seed.
     ///
     fn choose_random_leader(&self) -> u64 {
-        let keys: Vec<u64> = self.cluster.keys().cloned().collect();
-        *rand::thread_rng().choose(&keys).unwrap()
+        let mut keys: Vec<u64> = self.cluster.keys().cloned().collect();
+        keys.sort();
+        keys[(simulation::random_u64() % keys.len() as u64) as usize]
     }
 
     ///
@@ -189,7 +219,10 @@ impl RaftConnection {
             if let Err(ref err) = result {
                 match *err {
                     RaftError::NotLeader(leader) => {
-                        let leader_guess_id = leader.unwrap_or(self.choose_random_leader());
+                        // The hint may point at a server that has since been removed from the cluster
+                        let leader_guess_id = leader.into_iter()
+                            .find(|id| self.cluster.contains_key(id))
+                            .unwrap_or(self.choose_random_leader());
                         trace!("Will try to connect to {} as leader", leader_guess_id);
                         self.leader_guess = self.cluster.get(&leader_guess_id).unwrap().clone();
                         trace!("Got addr {} for {}", self.leader_guess, leader_guess_id);
@@ -218,14 +251,40 @@ impl RaftConnection {
     ///
     fn send_client_request(&mut self, op: client_command::Request)
         -> Result<client_command::Reply, RaftError> {
+        let transport = self.transport.clone();
         self.perform_leader_op(move |leader_addr|  {
-            RaftConnection::construct_client_request_rpc(op.clone())
-                .send(leader_addr)
+            transport.send(leader_addr, &RaftConnection::construct_client_request_rpc(op.clone()))
                 .map_err(|x| RaftError::IoError(format!("{:?}", x)))// jank
                 .and_then(RaftConnection::handle_client_reply)
         })
     }
 
+    ///
+    /// Sends a client request to a random server in the cluster, retrying elsewhere
+    /// if we can't reach it.
+    ///
+    fn send_any_server_request(&mut self, op: client_command::Request)
+        -> Result<client_command::Reply, RaftError> {
+        let mut backoff_multiplier = 0;
+        let mut num_retries = (MIN_RETRY + self.cluster.len() as u64) as i32;
+        while num_retries > 0 {
+            let addr = self.cluster.get(&self.choose_random_leader()).unwrap().clone();
+            let result = self.transport.send(addr, &RaftConnection::construct_client_request_rpc(op.clone()))
+                .map_err(|x| RaftError::IoError(format!("{:?}", x)))// jank
+                .and_then(RaftConnection::handle_client_reply);
+            match result {
+                // Maybe the server is down, or it doesn't know who the leader is right now
+                Err(RaftError::IoError(_)) | Err(RaftError::NotLeader(_)) => {
+                    num_retries -= 1;
+                    backoff_multiplier += 1;
+                    thread::sleep(self.backoff_time * backoff_multiplier);
+                },
+                _ => return result
+            }
+        }
+        return Err(RaftError::IoError(String::from("Couldn't reach any server in cluster.")));
+    }
+
     ///
     /// Sends this Raft cluster a command with data |buffer|.
     ///
@@ -234,33 +293,88 @@ impl RaftConnection {
     ///
     pub fn command(&mut self, buffer: &[u8]) -> Result<(), RaftError> {
         let session = self.get_session();
-        self.send_client_request(client_command::Request::Command(
+        let reply = self.send_client_request(client_command::Request::Command(
                 raft_command::Request::StateMachineCommand {
                 data: buffer.to_vec(),
-                session: session}))
-            .map(|_| {}) // Command to client should not return data...
+                session: session}))?;
+        // Command to client should not return data, but we remember where it was commited
+        // so our follower queries can wait for it
+        if let client_command::Reply::Command(_, index) = reply {
+            self.last_write_index = ::std::cmp::max(self.last_write_index, index);
+        }
+        Ok(())
     }
 
     ///
     /// Sends this Raft cluster a query with data |buffer|, and returns
     /// the queried data buffer from the state machine on success.
+    /// Queries are linearizable: they see every command that completed
+    /// before the query was sent.
     ///
     /// #Errors
     /// RaftError if Rpc or Client's state machine fails.
     ///
     pub fn query(&mut self, buffer: &[u8]) -> Result<Vec<u8>, RaftError> {
         self.send_client_request(client_command::Request::Query(
-                raft_query::Request::StateMachineQuery(buffer.to_vec())))
-            .and_then(|reply| {
-                if let client_command::Reply::Query(reply) = reply {
-                    match reply {
-                        raft_query::Reply::StateMachineQuery(data) => {
-                            return Ok(data);
-                        }
-                    }
+                raft_query::Request::StateMachineQuery(buffer.to_vec()), None))
+            .and_then(RaftConnection::query_reply_data)
+    }
+
+    ///
+    /// Sends a query with data |buffer| to any server in the cluster, and returns
+    /// the queried data buffer from the state machine on success.
+    /// Once this connection has sent a command, follower queries only promise read-your-writes:
+    /// they see every command sent through this connection, but they are NOT linearizable.
+    /// The server answers from whatever it has applied, without checking who the leader is,
+    /// so they may miss commands from other clients that completed recently.
+    /// Until then there's nothing of ours to wait for, so they're linearizable like
+    /// linearizable_follower_query.
+    ///
+    /// #Errors
+    /// RaftError if Rpc or Client's state machine fails.
+    ///
+    pub fn follower_query(&mut self, buffer: &[u8]) -> Result<Vec<u8>, RaftError> {
+        let request = self.follower_query_request(buffer);
+        self.send_any_server_request(request)
+            .and_then(RaftConnection::query_reply_data)
+    }
+
+    ///
+    /// Builds the request for follower_query. It only waits for our latest write if we've made one,
+    /// and goes through the leader's read index otherwise.
+    ///
+    fn follower_query_request(&self, buffer: &[u8]) -> client_command::Request {
+        let min_index = if self.last_write_index > 0 { Some(self.last_write_index) } else { None };
+        client_command::Request::Query(raft_query::Request::StateMachineQuery(buffer.to_vec()), min_index)
+    }
+
+    ///
+    /// Sends a query with data |buffer| to any server in the cluster, and returns
+    /// the queried data buffer from the state machine on success.
+    /// Followers ask the leader for a read index before answering, so like query,
+    /// these see every command that completed before the query was sent.
+    ///
+    /// #Errors
+    /// RaftError if Rpc or Client's state machine fails.
+    ///
+    pub fn linearizable_follower_query(&mut self, buffer: &[u8]) -> Result<Vec<u8>, RaftError> {
+        self.send_any_server_request(client_command::Request::Query(
+                raft_query::Request::StateMachineQuery(buffer.to_vec()), None))
+            .and_then(RaftConnection::query_reply_data)
+    }
+
+    ///
+    /// Helper to pull the state machine's data out of a query reply.
+    ///
+    fn query_reply_data(reply: client_command::Reply) -> Result<Vec<u8>, RaftError> {
+        if let client_command::Reply::Query(reply) = reply {
+            match reply {
+                raft_query::Reply::StateMachineQuery(data) => {
+                    return Ok(data);
                 }
-                Err(RaftError::Unknown)
-            })
+            }
+        }
+        Err(RaftError::Unknown)
     }
 
     pub fn add_server(&mut self, id: u64, addr: SocketAddr) -> Result<(), RaftError> {
@@ -271,14 +385,86 @@ impl RaftConnection {
         self.cluster.insert(id, addr);
         Ok(())
     }
+
+    pub fn remove_server(&mut self, id: u64, addr: SocketAddr) -> Result<(), RaftError> {
+        self.send_client_request(client_command::Request::RemoveServer((id, addr)))
+        // sucessful RemoveServer RPCs don't return anything
+        .map(|_| {})?;
+        // remove this server from our cached cluster map
+        self.cluster.remove(&id);
+        if self.leader_guess == addr {
+            // a removed leader steps down, so we'll need to find the new one
+            self.leader_guess = self.cluster.get(&self.choose_random_leader()).unwrap().clone();
+        }
+        Ok(())
+    }
+
+    ///
+    /// Replaces the cluster's membership with |servers| in a single change. The cluster moves
+    /// through a joint configuration, so any number of servers can be added or removed at once.
+    ///
+    pub fn set_config(&mut self, servers: &HashMap<u64, SocketAddr>) -> Result<(), RaftError> {
+        let config = servers.iter().map(|(&id, &addr)| (id, addr)).collect();
+        self.send_client_request(client_command::Request::SetConfig(config))
+        // sucessful SetConfig RPCs don't return anything
+        .map(|_| {})?;
+        self.cluster = servers.clone();
+        if !self.cluster.values().any(|&addr| addr == self.leader_guess) {
+            // the old leader steps down if it was removed
+            self.leader_guess = self.cluster.get(&self.choose_random_leader()).unwrap().clone();
+        }
+        Ok(())
+    }
+
+    ///
+    /// Adds a learner to the cluster. Learners get every entry in the log and can serve
+    /// follower queries, but they don't vote or count towards commits.
+    ///
+    pub fn add_learner(&mut self, id: u64, addr: SocketAddr) -> Result<(), RaftError> {
+        self.send_client_request(client_command::Request::AddLearner((id, addr)))
+        // sucessful AddLearner RPCs don't return anything
+        .map(|_| {})?;
+        self.cluster.insert(id, addr);
+        Ok(())
+    }
+
+    /// Turns the learner with |id| into a voting member of the cluster.
+    pub fn promote_learner(&mut self, id: u64) -> Result<(), RaftError> {
+        self.send_client_request(client_command::Request::PromoteLearner(id))
+        .map(|_| {})
+    }
+
+    /// Turns the voting server with |id| into a learner. A demoted leader steps down.
+    pub fn demote_to_learner(&mut self, id: u64) -> Result<(), RaftError> {
+        self.send_client_request(client_command::Request::DemoteToLearner(id))
+        .map(|_| {})
+    }
+
+    ///
+    /// Asks the leader to hand leadership off to the server with |id|, or to the most up to
+    /// date server if no id is given. The old leader stops accepting commands during the transfer.
+    ///
+    /// #Errors
+    /// RaftError::Timeout if the new leader wasn't elected within an election timeout.
+    ///
+    pub fn transfer_leadership(&mut self, id: Option<u64>) -> Result<(), RaftError> {
+        self.send_client_request(client_command::Request::TransferLeadership(id))
+        // sucessful TransferLeadership RPCs don't return anything
+        .map(|_| {})?;
+        let new_leader = id.and_then(|id| self.cluster.get(&id).cloned());
+        if let Some(addr) = new_leader {
+            self.leader_guess = addr;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{RaftConnection, BACKOFF_TIME_MS};
+    use super::RaftConnection;
     use super::super::rpc::server::{RpcObject, RpcServer};
     use super::super::rpc::RpcError;
-    use super::super::common::{client_command, RaftError };
+    use super::super::common::{client_command, constants, RaftError };
     use super::super::raft_capnp::{client_request as proto};
     use capnp;
     use std::collections::HashMap;
@@ -372,6 +558,7 @@ mod tests {
     ///
     fn client_request_redirects_to_leader<F>(chain_size: u64, db_op: F)
     where F: Fn(&mut RaftConnection) -> () {
+        const BACKOFF_TIME_MS: u64 = constants::DEFAULT_CLIENT_BACKOFF_TIME;
         // Create leader ...
         let (leader_port, _server) = start_leader_client_rpc_handler();
         let leader_socket = format!("{}:{}", LOCALHOST, leader_port);
@@ -435,6 +622,30 @@ mod tests {
             |db| { assert!((*db).query(&data).is_ok()); });
     }
 
+    #[test]
+    fn follower_query_sends() {
+        let data = vec![];
+        client_request_redirects_to_leader(0,
+            |db| { assert!((*db).follower_query(&data).is_ok()); });
+    }
+
+    #[test]
+    fn follower_query_is_linearizable_until_we_write() {
+        let mut cluster = HashMap::new();
+        cluster.insert(0, SocketAddr::from_str("127.0.0.1:8000").unwrap());
+        let mut db = RaftConnection::new_mock(&cluster);
+        match db.follower_query_request(b"query") {
+            client_command::Request::Query(_, min_index) => assert_eq!(min_index, None),
+            _ => panic!()
+        }
+
+        db.last_write_index = 7;
+        match db.follower_query_request(b"query") {
+            client_command::Request::Query(_, min_index) => assert_eq!(min_index, Some(7)),
+            _ => panic!()
+        }
+    }
+
     #[test]
     fn open_session_sends() {
         client_request_redirects_to_leader(0, 
@@ -1,6 +1,10 @@
 use std::collections::HashMap;
+use std::io::Cursor;
 use std::time::{Instant};
-use super::super::common::{RaftError, raft_command, raft_query, SessionInfo};
+use capnp::{message, serialize_packed};
+use capnp::message::ReaderOptions;
+use raft_capnp::{state_machine_snapshot};
+use super::super::common::{RaftError, raft_command, raft_query, client_command, SessionInfo};
 
 ///
 /// State machine trait for clients to implement. Client should define
@@ -25,6 +29,25 @@ pub trait StateMachine:  Send {
     /// successfully executes. Otherwise, results in RaftError::ClientError.
     ///
     fn query(&self, buffer: &[u8]) -> Result<Vec<u8>, RaftError>;
+
+    ///
+    /// Serializes the entire state of this state machine into a buffer that
+    /// can later be handed to |restore_snapshot|. Raft uses this to compact its log.
+    ///
+    /// # Returns
+    /// Returns the serialized state, or a RaftError::ClientError if the state
+    /// machine could not be serialized.
+    ///
+    fn snapshot(&self) -> Result<Vec<u8>, RaftError>;
+
+    ///
+    /// Replaces the state of this state machine with the state serialized
+    /// in |buffer|, which was produced by an earlier call to |snapshot|.
+    ///
+    /// # Returns
+    /// Results in Ok if the state was restored; otherwise a RaftError::ClientError.
+    ///
+    fn restore_snapshot(&mut self, buffer: &[u8]) -> Result<(), RaftError>;
 }
 
 
@@ -59,6 +82,8 @@ pub struct Session {
 /// # impl StateMachine for EmptyStateMachine {
 /// #     fn command(&mut self, _: &[u8]) -> Result<(), RaftError> {Ok(())}
 /// #     fn query(& self, _: &[u8]) -> Result<Vec<u8>, RaftError> {Ok(vec![])}
+/// #     fn snapshot(& self) -> Result<Vec<u8>, RaftError> {Ok(vec![])}
+/// #     fn restore_snapshot(&mut self, _: &[u8]) -> Result<(), RaftError> {Ok(())}
 /// # }
 /// # let mut state_machine = RaftStateMachine::new(Box::new(EmptyStateMachine{}));
 /// let session_id = 5; // normally you'd randomly generate this
@@ -160,6 +185,89 @@ impl RaftStateMachine {
                     .map(raft_query::Reply::StateMachineQuery)
         }
     }
+
+    ///
+    /// Serializes the client sessions, followed by the client state machine's own
+    /// snapshot, into a single buffer.
+    ///
+    /// # Errors
+    /// Returns a RaftError::IoError if the sessions could not be serialized, or
+    /// forwards any error from the client state machine as-is.
+    ///
+    pub fn snapshot (&self) -> Result<Vec<u8>, RaftError> {
+        let mut builder = message::Builder::new_default();
+        {
+            let snapshot = builder.init_root::<state_machine_snapshot::Builder>();
+            let mut sessions = snapshot.init_sessions(self.sessions.len() as u32);
+            for (i, (client_id, session)) in self.sessions.iter().enumerate() {
+                let mut session_builder = sessions.borrow().get(i as u32);
+                session_builder.set_client_id(*client_id);
+                let mut responses = session_builder.init_responses(session.responses.len() as u32);
+                for (j, (sequence_number, response)) in session.responses.iter().enumerate() {
+                    let mut response_builder = responses.borrow().get(j as u32);
+                    response_builder.set_sequence_number(*sequence_number);
+                    match *response {
+                        Ok(ref reply) => raft_command::reply_to_proto(
+                            reply.clone(), &mut response_builder.init_reply()),
+                        Err(ref err) => client_command::raft_error_to_proto(
+                            err.clone(), &mut response_builder.init_error())
+                    }
+                }
+            }
+        }
+
+        let mut buffer = Vec::new();
+        serialize_packed::write_message(&mut buffer, &builder)
+            .map_err(|e| RaftError::IoError(e.to_string()))?;
+        buffer.extend(self.client_state_machine.snapshot()?);
+        Ok(buffer)
+    }
+
+    ///
+    /// Replaces the client sessions and the client state machine's state with those
+    /// serialized in |buffer| by |snapshot|.
+    ///
+    /// # Errors
+    /// Returns a RaftError::IoError if the sessions could not be deserialized, or
+    /// forwards any error from the client state machine as-is.
+    ///
+    pub fn restore_snapshot (&mut self, buffer: &[u8]) -> Result<(), RaftError> {
+        let mut cursor = Cursor::new(buffer);
+        let mut sessions = HashMap::new();
+        {
+            let message = serialize_packed::read_message(&mut cursor, ReaderOptions::new())
+                .map_err(|e| RaftError::IoError(e.to_string()))?;
+            let snapshot = message.get_root::<state_machine_snapshot::Reader>()
+                .map_err(|e| RaftError::IoError(e.to_string()))?;
+            let session_list = snapshot.get_sessions()
+                .map_err(|e| RaftError::IoError(e.to_string()))?;
+            for session in session_list.iter() {
+                let mut responses = HashMap::new();
+                let response_list = session.get_responses()
+                    .map_err(|e| RaftError::IoError(e.to_string()))?;
+                for response in response_list.iter() {
+                    let result = match response.which() {
+                        Ok(state_machine_snapshot::response::Reply(reply)) => reply
+                            .map(|mut reply| Ok(raft_command::reply_from_proto(&mut reply))),
+                        Ok(state_machine_snapshot::response::Error(err)) => err
+                            .map(|mut err| Err(client_command::raft_error_from_proto(&mut err))),
+                        Err(_) => return Err(RaftError::IoError(
+                            String::from("Unknown response type in snapshot")))
+                    }.map_err(|e| RaftError::IoError(e.to_string()))?;
+                    responses.insert(response.get_sequence_number(), result);
+                }
+                sessions.insert(session.get_client_id(), Session {
+                    last_update: Instant::now(),
+                    responses: responses
+                });
+            }
+        }
+
+        let offset = cursor.position() as usize;
+        self.client_state_machine.restore_snapshot(&buffer[offset ..])?;
+        self.sessions = sessions;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -189,6 +297,14 @@ mod tests {
         fn query(&self, _: &[u8]) -> Result<Vec<u8>, RaftError> {
             Ok(Vec::new())
         }
+
+        fn snapshot(&self) -> Result<Vec<u8>, RaftError> {
+            Ok(Vec::new())
+        }
+
+        fn restore_snapshot(&mut self, _: &[u8]) -> Result<(), RaftError> {
+            Ok(())
+        }
     }
 
     /// Helper to send |state_machine| an open session request.
@@ -244,4 +360,43 @@ mod tests {
         assert!(rx.try_recv().is_ok());
         assert!(rx.try_recv().is_err());
     }
+
+    #[test]
+    fn it_restores_sessions_from_a_snapshot () {
+        let (tx, rx) = channel();
+        let client = Box::new(DumbStateMachine { commands: Some(tx.clone()) });
+        let mut state_machine = RaftStateMachine::new(client);
+        let client_id = open_session(&mut state_machine);
+        assert!(command_with_session(&mut state_machine,
+                    SessionInfo {client_id: client_id, sequence_number: 0 }).is_ok());
+        assert!(rx.try_recv().is_ok());
+        let snapshot = state_machine.snapshot().unwrap();
+
+        let restored_client = Box::new(DumbStateMachine { commands: Some(tx.clone()) });
+        let mut restored = RaftStateMachine::new(restored_client);
+        restored.restore_snapshot(&snapshot).unwrap();
+        // the restored session should remember the command it already executed
+        assert!(command_with_session(&mut restored,
+                    SessionInfo {client_id: client_id, sequence_number: 0 }).is_ok());
+        assert!(rx.try_recv().is_err());
+        assert!(command_with_session(&mut restored,
+                    SessionInfo {client_id: client_id, sequence_number: 1 }).is_ok());
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn it_only_restores_sessions_in_the_snapshot () {
+        let client = Box::new(DumbStateMachine { commands: None });
+        let mut state_machine = RaftStateMachine::new(client);
+        let client_id = open_session(&mut state_machine);
+        let snapshot = state_machine.snapshot().unwrap();
+
+        let mut restored = RaftStateMachine::new(Box::new(DumbStateMachine { commands: None }));
+        restored.restore_snapshot(&snapshot).unwrap();
+        let result = command_with_session(&mut restored,
+                         SessionInfo {client_id: client_id + 1, sequence_number: 0 });
+        assert!(matches!(result.unwrap_err(), RaftError::SessionError));
+        assert!(command_with_session(&mut restored,
+                    SessionInfo {client_id: client_id, sequence_number: 0 }).is_ok());
+    }
 }
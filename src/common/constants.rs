@@ -1,9 +1,34 @@
 // Constants
-// TODO: Many of these should be overwrittable by Config
-pub const ELECTION_TIMEOUT_MIN: u64 = 150; // min election timeout wait value in m.s.
-pub const ELECTION_TIMEOUT_MAX: u64 = 300; // min election timeout wait value in m.s.
+pub const DEFAULT_ELECTION_TIMEOUT_MIN: u64 = 150; // default min election timeout wait value in m.s.
+pub const DEFAULT_ELECTION_TIMEOUT_MAX: u64 = 300; // default max election timeout wait value in m.s.
+pub const DEFAULT_HEARTBEAT_TIMEOUT: u64 = 75; // default time between leader heartbeats in m.s.
 pub const APPEND_ENTRIES_OPCODE: i16 = 0;
 pub const REQUEST_VOTE_OPCODE: i16 = 1;
 pub const CLIENT_REQUEST_OPCODE: i16 = 2;
-/// maximum number of rounds to allow when adding a new server before giving up
-pub const MAX_ROUNDS_FOR_NEW_SERVER: u32 = 10;
+pub const INSTALL_SNAPSHOT_OPCODE: i16 = 3;
+pub const TIMEOUT_NOW_OPCODE: i16 = 4;
+pub const PRE_VOTE_OPCODE: i16 = 5;
+pub const READ_INDEX_OPCODE: i16 = 6;
+/// default number of rounds to allow when adding a new server before giving up
+pub const DEFAULT_MAX_ROUNDS_FOR_NEW_SERVER: u32 = 10;
+/// default base amount of time, in m.s., for clients to back off when a request fails
+pub const DEFAULT_CLIENT_BACKOFF_TIME: u64 = 50;
+/// default number of applied entries between state machine snapshots
+pub const DEFAULT_SNAPSHOT_THRESHOLD: usize = 10000;
+/// default bound on how far apart server clocks can drift over a read lease, in m.s.
+pub const DEFAULT_MAX_CLOCK_DRIFT: u64 = 50;
+/// default time, in m.s., a query waits for the state machine to apply the index it reads at
+pub const DEFAULT_QUERY_TIMEOUT: u64 = 1000;
+/// maximum number of bytes of a state machine image to send in one InstallSnapshot rpc
+pub const SNAPSHOT_CHUNK_SIZE: usize = 1 << 20;
+/// default number of AppendEntries rpcs a leader has in flight to each follower at once
+pub const DEFAULT_MAX_APPEND_ENTRIES_IN_FLIGHT: usize = 4;
+/// default most entries a leader sends in one AppendEntries rpc
+pub const DEFAULT_MAX_APPEND_ENTRIES_BATCH: usize = 1024;
+/// default most bytes of entries a leader sends in one AppendEntries rpc. This stays well
+/// under the size of message capnp will read by default
+pub const DEFAULT_MAX_APPEND_ENTRIES_BYTES: usize = 1 << 20;
+/// default size, in bytes, a log segment grows to before we start a new one
+pub const DEFAULT_LOG_SEGMENT_SIZE: u64 = 8 << 20;
+/// default number of the most recent log entries kept in memory
+pub const DEFAULT_ENTRY_CACHE_SIZE: usize = 4096;
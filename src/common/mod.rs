@@ -2,8 +2,12 @@ pub mod constants;
 use std::collections::HashMap;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::time::Duration;
-use std::io::{Error as IoError};
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
 use raft_capnp::{session_info};
+use rpc::transport::{Transport, TcpTransport};
+use server::clock::{Clock, SystemClock};
+use server::storage::Durability;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum RaftError { 
@@ -47,8 +51,13 @@ pub mod raft_command {
     pub enum Request {
         StateMachineCommand { data: Vec<u8>, session: SessionInfo },
         OpenSession (u64),
-        SetConfig (Vec<(u64, SocketAddr)>),
+        // The voting servers in the cluster, followed by its learners
+        SetConfig (Vec<(u64, SocketAddr)>, Vec<(u64, SocketAddr)>),
         Noop,
+        // Joint consensus config used to move from the first set of servers to the second.
+        // Until the second set is commited on its own, both sets need a majority.
+        // Learners come last, since they don't take part in either majority
+        SetJointConfig (Vec<(u64, SocketAddr)>, Vec<(u64, SocketAddr)>, Vec<(u64, SocketAddr)>),
     }
 
     #[derive(Clone, Debug, PartialEq)]
@@ -57,6 +66,7 @@ pub mod raft_command {
         OpenSession,
         SetConfig,
         Noop,
+        SetJointConfig,
     }
 
     #[cfg(test)]
@@ -83,8 +93,9 @@ pub mod raft_command {
             Request::StateMachineCommand{..} => 
                 Reply::StateMachineCommand,
             Request::OpenSession(_) => Reply::OpenSession,
-            Request::SetConfig(_) => Reply::SetConfig,
-            Request::Noop => Reply::Noop
+            Request::SetConfig(..) => Reply::SetConfig,
+            Request::Noop => Reply::Noop,
+            Request::SetJointConfig(..) => Reply::SetJointConfig
         }
     }
 
@@ -99,14 +110,18 @@ pub mod raft_command {
                 session.into_proto(&mut command.init_session());
             },
             Request::OpenSession(client_id) => builder.set_open_session(client_id),
-            Request::SetConfig(servers) => {
-                let mut proto_servers = builder.borrow().init_set_config(servers.len() as u32);
-                for (i, server) in servers.into_iter().enumerate() {
-                    let server_builder = proto_servers.borrow().get(i as u32);
-                    raft_server::to_proto(server, server_builder);
-                }
+            Request::SetConfig(servers, learners) => {
+                let len = (servers.len() + learners.len()) as u32;
+                let proto_servers = builder.borrow().init_set_config(len);
+                raft_server::list_with_roles_to_proto(servers, learners, proto_servers);
             }
             Request::Noop => builder.set_noop(()),
+            Request::SetJointConfig(old, new, learners) => {
+                let (old_len, new_len) = (old.len() as u32, (new.len() + learners.len()) as u32);
+                let mut config = builder.borrow().init_set_joint_config();
+                raft_server::list_to_proto(old, config.borrow().init_old(old_len));
+                raft_server::list_with_roles_to_proto(new, learners, config.init_new(new_len));
+            }
         }
     }
 
@@ -122,13 +137,16 @@ pub mod raft_command {
             },
             proto::OpenSession(client_id) => Request::OpenSession(client_id),
             proto::SetConfig(config) => {
-                let servers = config.unwrap().iter()
-                    .map(|s| raft_server::from_proto(s).unwrap())
-                    .collect::<Vec<(u64, SocketAddr)>>();
-
-                Request::SetConfig(servers)
+                let (servers, learners) = raft_server::list_with_roles_from_proto(config.unwrap()).unwrap();
+                Request::SetConfig(servers, learners)
             },
             proto::Noop(_) => Request::Noop,
+            proto::SetJointConfig(config) => {
+                let config = config.unwrap();
+                let (new, learners) = raft_server::list_with_roles_from_proto(config.get_new().unwrap()).unwrap();
+                Request::SetJointConfig(
+                    raft_server::list_from_proto(config.get_old().unwrap()).unwrap(), new, learners)
+            },
         }
     }
 
@@ -138,6 +156,7 @@ pub mod raft_command {
             Reply::OpenSession => builder.set_open_session(()),
             Reply::SetConfig => builder.set_set_config(()),
             Reply::Noop => builder.set_noop(()),
+            Reply::SetJointConfig => builder.set_set_joint_config(()),
         }
     }
 
@@ -147,16 +166,19 @@ pub mod raft_command {
             proto::reply::OpenSession(_) => Reply::OpenSession,
             proto::reply::SetConfig(_) => Reply::SetConfig,
             proto::reply::Noop(_) => Reply::Noop,
+            proto::reply::SetJointConfig(_) => Reply::SetJointConfig,
         }
     }
 
     #[cfg(test)]
     mod tests {
-        use super::{request_to_proto, request_from_proto,
+        use super::{Request, request_to_proto, request_from_proto,
                     reply_to_proto, reply_from_proto,
                     dummy_request, dummy_reply};
         use super::super::super::raft_capnp::{raft_command as proto};
         use super::super::super::rpc::client::Rpc;
+        use std::net::SocketAddr;
+        use std::str::FromStr;
 
         #[test]
         fn request_to_and_from_proto() {
@@ -172,6 +194,22 @@ pub mod raft_command {
             assert_eq!(request, request_from_proto(reader));
         }
 
+        #[test]
+        fn joint_config_to_and_from_proto() {
+            let addr = SocketAddr::from_str("127.0.0.1:8000").unwrap();
+            let request = Request::SetJointConfig(vec![(1, addr), (2, addr)], vec![(2, addr), (3, addr)],
+                                                  vec![(4, addr)]);
+            let mut rpc = Rpc::new(1);
+            {
+                let mut builder = rpc.get_param_builder()
+                                     .init_as::<proto::Builder>();
+                request_to_proto(request.clone(), &mut builder);
+            }
+            let reader = rpc.get_param_builder().as_reader()
+                            .get_as::<proto::Reader>().unwrap();
+            assert_eq!(request, request_from_proto(reader));
+        }
+
         #[test]
         fn reply_to_and_from_proto() {
             let mut rpc = Rpc::new(1);
@@ -284,9 +322,10 @@ pub mod raft_query {
 }
 
 /// Utility functions for serializing and deserializing a raft_server
-mod raft_server {
+pub mod raft_server {
     use super::super::raft_capnp::raft_server as proto;
     use capnp::{Result, Error, ErrorKind};
+    use capnp::struct_list;
     use std::net::{SocketAddr};
     use std::str::FromStr;
 
@@ -300,6 +339,46 @@ mod raft_server {
         proto.set_addr(&serialize_addr(server.1));
     }
 
+    /// Deserializes a list of raft servers
+    pub fn list_from_proto(proto: struct_list::Reader<proto::Owned>) -> Result<Vec<(u64, SocketAddr)>> {
+        proto.iter().map(from_proto).collect()
+    }
+
+    /// Serializes |servers| into |proto|, which must have been initialized with room for all of them
+    pub fn list_to_proto(servers: Vec<(u64, SocketAddr)>, mut proto: struct_list::Builder<proto::Owned>) {
+        for (i, server) in servers.into_iter().enumerate() {
+            to_proto(server, proto.reborrow().get(i as u32));
+        }
+    }
+
+    /// Deserializes a list of raft servers into its voting servers and its learners
+    pub fn list_with_roles_from_proto(proto: struct_list::Reader<proto::Owned>)
+        -> Result<(Vec<(u64, SocketAddr)>, Vec<(u64, SocketAddr)>)> {
+        let mut servers = Vec::new();
+        let mut learners = Vec::new();
+        for server in proto.iter() {
+            if server.get_learner() {
+                learners.push(from_proto(server)?);
+            } else {
+                servers.push(from_proto(server)?);
+            }
+        }
+        Ok((servers, learners))
+    }
+
+    /// Serializes |servers| followed by |learners| into |proto|, flagging the learners.
+    /// |proto| must have been initialized with room for all of them
+    pub fn list_with_roles_to_proto(servers: Vec<(u64, SocketAddr)>, learners: Vec<(u64, SocketAddr)>,
+                                    mut proto: struct_list::Builder<proto::Owned>) {
+        let num_servers = servers.len();
+        list_to_proto(servers, proto.reborrow());
+        for (i, learner) in learners.into_iter().enumerate() {
+            let mut builder = proto.reborrow().get((num_servers + i) as u32);
+            builder.set_learner(true);
+            to_proto(learner, builder);
+        }
+    }
+
 
     fn deserialize_addr(addr: &str) -> Result<SocketAddr> {
         SocketAddr::from_str(addr)
@@ -324,17 +403,33 @@ pub mod client_command {
     #[derive(Clone, Debug, PartialEq)]
     pub enum Request {
         Command(raft_command::Request),
-        Query(raft_query::Request),
+        // Queries are linearizable unless they give a minimum index. Any server that has
+        // applied that index can serve them, without checking in with the leader
+        Query(raft_query::Request, Option<usize>),
         AddServer((u64, SocketAddr)),
-        RemoveServer((u64, SocketAddr))
+        RemoveServer((u64, SocketAddr)),
+        // Transfers leadership to the given server, or to the most up to date one
+        TransferLeadership(Option<u64>),
+        // Replaces the servers in the cluster with the given ones in a single config change
+        SetConfig(Vec<(u64, SocketAddr)>),
+        // Adds a server that gets the log but doesn't vote
+        AddLearner((u64, SocketAddr)),
+        PromoteLearner(u64),
+        DemoteToLearner(u64)
     }
 
     #[derive(Clone, Debug, PartialEq)]
     pub enum Reply {
-        Command(raft_command::Reply),
+        // Commands reply with the index they were commited at
+        Command(raft_command::Reply, usize),
         Query(raft_query::Reply),
         AddServer,
-        RemoveServer
+        RemoveServer,
+        TransferLeadership,
+        SetConfig,
+        AddLearner,
+        PromoteLearner,
+        DemoteToLearner
     }
 
     pub fn request_from_proto(proto: proto::Reader) -> Request {
@@ -344,8 +439,12 @@ pub mod client_command {
                     raft_command::request_from_proto(raft_command.unwrap()))
             },
             proto::Query(raft_query) => {
+                let min_index = match proto.get_query_consistency().unwrap().which().unwrap() {
+                    proto::query_consistency::Linearizable(_) => None,
+                    proto::query_consistency::MinIndex(index) => Some(index as usize)
+                };
                 Request::Query(
-                    raft_query::request_from_proto(raft_query.unwrap()))
+                    raft_query::request_from_proto(raft_query.unwrap()), min_index)
             },
             proto::AddServer(raft_server) => {
                 Request::AddServer(
@@ -354,7 +453,24 @@ pub mod client_command {
             proto::RemoveServer(raft_server) => {
                 Request::RemoveServer(
                     raft_server::from_proto(raft_server.unwrap()).unwrap())
-            }
+            },
+            proto::TransferLeadership(transfer) => {
+                Request::TransferLeadership(
+                    match transfer.unwrap().which().unwrap() {
+                        proto::transfer_leadership::AnyServer(_) => None,
+                        proto::transfer_leadership::ServerId(id) => Some(id)
+                    })
+            },
+            proto::SetConfig(servers) => {
+                Request::SetConfig(
+                    raft_server::list_from_proto(servers.unwrap()).unwrap())
+            },
+            proto::AddLearner(raft_server) => {
+                Request::AddLearner(
+                    raft_server::from_proto(raft_server.unwrap()).unwrap())
+            },
+            proto::PromoteLearner(id) => Request::PromoteLearner(id),
+            proto::DemoteToLearner(id) => Request::DemoteToLearner(id)
         }
     }
 
@@ -363,14 +479,21 @@ pub mod client_command {
         match op {
             Ok(reply) => {
                 match reply {
-                    Reply::Command(command) =>
+                    Reply::Command(command, index) => {
                         raft_command::reply_to_proto(
-                            command, &mut builder.borrow().init_command_reply()),
+                            command, &mut builder.borrow().init_command_reply());
+                        builder.set_command_index(index as u64);
+                    },
                     Reply::Query(query) => 
                         raft_query::reply_to_proto(
                             query, &mut builder.borrow().init_query_reply()),
                     Reply::AddServer => builder.set_add_server_reply(()),
-                    Reply::RemoveServer => builder.set_remove_server_reply(())
+                    Reply::RemoveServer => builder.set_remove_server_reply(()),
+                    Reply::TransferLeadership => builder.set_transfer_leadership_reply(()),
+                    Reply::SetConfig => builder.set_set_config_reply(()),
+                    Reply::AddLearner => builder.set_add_learner_reply(()),
+                    Reply::PromoteLearner => builder.set_promote_learner_reply(()),
+                    Reply::DemoteToLearner => builder.set_demote_to_learner_reply(())
                 }
             }, 
             Err(err) => {
@@ -383,10 +506,10 @@ pub mod client_command {
     pub fn successful_reply_for(op: Request) -> Reply {
         match op {
             Request::Command(data) =>
-                Reply::Command(raft_command::successful_reply_for(data)),
-            Request::Query(data) =>
+                Reply::Command(raft_command::successful_reply_for(data), 0),
+            Request::Query(data, _) =>
                 Reply::Query(raft_query::successful_reply_for(data)),
-            _ => Reply::Command(raft_command::Reply::Noop),
+            _ => Reply::Command(raft_command::Reply::Noop, 0),
         }
     }
 
@@ -396,16 +519,37 @@ pub mod client_command {
                 raft_command::request_to_proto(raft_command,
                                       &mut builder.borrow().init_command());
             },
-            Request::Query(raft_query) => {
+            Request::Query(raft_query, min_index) => {
                 raft_query::request_to_proto(raft_query,
                                     &mut builder.borrow().init_query());
+                let mut consistency = builder.borrow().init_query_consistency();
+                match min_index {
+                    Some(index) => consistency.set_min_index(index as u64),
+                    None => consistency.set_linearizable(())
+                }
             },
             Request::AddServer(raft_server) => {
                 raft_server::to_proto(raft_server, builder.borrow().init_add_server());
             },
             Request::RemoveServer(raft_server) => {
                 raft_server::to_proto(raft_server, builder.borrow().init_remove_server());
-            }
+            },
+            Request::TransferLeadership(server_id) => {
+                let mut transfer = builder.borrow().init_transfer_leadership();
+                match server_id {
+                    Some(id) => transfer.set_server_id(id),
+                    None => transfer.set_any_server(())
+                }
+            },
+            Request::SetConfig(servers) => {
+                let proto_servers = builder.borrow().init_set_config(servers.len() as u32);
+                raft_server::list_to_proto(servers, proto_servers);
+            },
+            Request::AddLearner(raft_server) => {
+                raft_server::to_proto(raft_server, builder.borrow().init_add_learner());
+            },
+            Request::PromoteLearner(id) => builder.set_promote_learner(id),
+            Request::DemoteToLearner(id) => builder.set_demote_to_learner(id)
         }
     }
 
@@ -416,16 +560,22 @@ pub mod client_command {
                 Err(raft_error_from_proto(&mut err.unwrap())),
             proto::reply::CommandReply(command) =>
                 Ok(Reply::Command(
-                        raft_command::reply_from_proto(&mut command.unwrap()))),
+                        raft_command::reply_from_proto(&mut command.unwrap()),
+                        proto.get_command_index() as usize)),
             proto::reply::QueryReply(query) =>
                 Ok(Reply::Query(
                         raft_query::reply_from_proto(&mut query.unwrap()))),
             proto::reply::AddServerReply(_) => Ok(Reply::AddServer),
             proto::reply::RemoveServerReply(_) => Ok(Reply::RemoveServer),
+            proto::reply::TransferLeadershipReply(_) => Ok(Reply::TransferLeadership),
+            proto::reply::SetConfigReply(_) => Ok(Reply::SetConfig),
+            proto::reply::AddLearnerReply(_) => Ok(Reply::AddLearner),
+            proto::reply::PromoteLearnerReply(_) => Ok(Reply::PromoteLearner),
+            proto::reply::DemoteToLearnerReply(_) => Ok(Reply::DemoteToLearner),
         }
     }
 
-    fn raft_error_to_proto(err: RaftError, builder: &mut raft_error::Builder) {
+    pub fn raft_error_to_proto(err: RaftError, builder: &mut raft_error::Builder) {
         match err {
             RaftError::ClientError(err) => builder.set_client_error(err.as_str()),
             RaftError::IoError(err) => builder.set_io_error(err.as_str()),
@@ -445,7 +595,7 @@ pub mod client_command {
         }
     }
 
-    fn raft_error_from_proto(proto: &mut raft_error::Reader) -> RaftError {
+    pub fn raft_error_from_proto(proto: &mut raft_error::Reader) -> RaftError {
         match proto.which().unwrap() {
             raft_error::ClientError(err) => RaftError::ClientError(
                 err.unwrap().to_string()),
@@ -468,17 +618,19 @@ pub mod client_command {
         use super::{Request, Reply,
                     request_to_proto, request_from_proto,
                     reply_to_proto, reply_from_proto};
-        use super::super::{RaftError, raft_command};
+        use super::super::{RaftError, raft_command, raft_query};
         use super::super::super::raft_capnp::{client_request as proto};
         use super::super::super::rpc::client::Rpc;
         use std::string::String;
+        use std::net::SocketAddr;
+        use std::str::FromStr;
 
         fn dummy_request() -> Request {
             Request::Command(raft_command::dummy_request())
         }
 
         fn dummy_reply() -> Result<Reply, RaftError> {
-            Ok(Reply::Command(raft_command::dummy_reply()))
+            Ok(Reply::Command(raft_command::dummy_reply(), 17))
         }
 
         fn dummy_error_reply() -> Result<Reply, RaftError> {
@@ -528,6 +680,54 @@ pub mod client_command {
             assert_eq!(reply.unwrap_err(), reply_from_proto(&mut reader).unwrap_err());
         }
 
+        #[test]
+        fn query_consistency_to_and_from_proto() {
+            let query = raft_query::Request::StateMachineQuery(vec![4, 2]);
+            for request in vec![Request::Query(query.clone(), None), Request::Query(query, Some(12))] {
+                let mut rpc = Rpc::new(1);
+                {
+                    let mut builder = rpc.get_param_builder()
+                                         .init_as::<proto::Builder>();
+                    request_to_proto(request.clone(), &mut builder);
+                }
+                let reader = rpc.get_param_builder().as_reader()
+                                .get_as::<proto::Reader>().unwrap();
+                assert_eq!(request, request_from_proto(reader));
+            }
+        }
+
+        #[test]
+        fn transfer_leadership_to_and_from_proto() {
+            for request in vec![Request::TransferLeadership(None), Request::TransferLeadership(Some(7))] {
+                let mut rpc = Rpc::new(1);
+                {
+                    let mut builder = rpc.get_param_builder()
+                                         .init_as::<proto::Builder>();
+                    request_to_proto(request.clone(), &mut builder);
+                }
+                let reader = rpc.get_param_builder().as_reader()
+                                .get_as::<proto::Reader>().unwrap();
+                assert_eq!(request, request_from_proto(reader));
+            }
+        }
+
+        #[test]
+        fn learner_requests_to_and_from_proto() {
+            let addr = SocketAddr::from_str("127.0.0.1:8000").unwrap();
+            for request in vec![Request::AddLearner((3, addr)), Request::PromoteLearner(3),
+                                Request::DemoteToLearner(2)] {
+                let mut rpc = Rpc::new(1);
+                {
+                    let mut builder = rpc.get_param_builder()
+                                         .init_as::<proto::Builder>();
+                    request_to_proto(request.clone(), &mut builder);
+                }
+                let reader = rpc.get_param_builder().as_reader()
+                                .get_as::<proto::Reader>().unwrap();
+                assert_eq!(request, request_from_proto(reader));
+            }
+        }
+
     }
 }
 
@@ -539,9 +739,67 @@ pub struct Config<'a> {
     // Each server has a unique 64bit integer id that and a socket address
     // These mappings MUST be identical for each server in the cluster
     pub me: (u64, SocketAddr),
+    // How often a leader sends heartbeats. This has to be well below |election_timeout_min|,
+    // or followers will start elections while the leader is still healthy.
     pub heartbeat_timeout: Duration,
+    // Followers start an election after going a random time between these two without
+    // hearing from a leader. Clusters spread over slow links need longer timeouts.
+    pub election_timeout_min: Duration,
+    pub election_timeout_max: Duration,
+    // Where the server keeps its term and vote, and the directory it keeps its log in, unless
+    // it's started with some other Storage. Snapshots are saved next to |log_filename|.
     pub state_filename: &'a str,
-    pub log_filename: &'a str
+    pub log_filename: &'a str,
+    // Size in bytes a log segment grows to before we start a new one. Segments are only deleted
+    // once a snapshot covers every entry in them, so smaller segments free up disk sooner.
+    pub log_segment_size: u64,
+    // Number of the most recent log entries to keep in memory. Older entries are read back from
+    // storage when a slow follower or the state machine needs them.
+    pub entry_cache_size: usize,
+    // How hard the log works to make entries survive a crash before counting them as
+    // persisted. Anything but the default trades away some of Raft's guarantees for latency;
+    // see Durability for what each mode gives up.
+    pub durability: Durability,
+    // Number of entries to apply to the state machine before snapshotting it
+    // and compacting the log. A threshold of 0 disables snapshots.
+    pub snapshot_threshold: usize,
+    // Whether to run a PreVote round before starting an election. Servers only bump their
+    // term once a majority of the cluster would vote for them, so a partitioned server can't
+    // force a healthy leader to step down when it rejoins. Leave this off until every server
+    // in the cluster understands PreVote requests.
+    pub pre_vote: bool,
+    // Whether leaders may serve queries locally while they hold a lease, instead of confirming
+    // their leadership with a round of heartbeats on every query. A lease lasts for the minimum
+    // election timeout minus |max_clock_drift| after a majority of the cluster acknowledges a
+    // heartbeat. This is only safe if no server's clock runs faster than another's by more
+    // than |max_clock_drift| over that period.
+    pub lease_reads: bool,
+    pub max_clock_drift: Duration,
+    // How long a query waits for the state machine to apply the index it reads at. A follower
+    // that has fallen behind, or a client asking for an index that never commited, gets a
+    // Timeout error instead of waiting forever.
+    pub query_timeout: Duration,
+    // Number of AppendEntries requests a leader may have outstanding to each follower at once.
+    // Raising this keeps followers busy over links with long round trips; 1 sends one request
+    // per round trip.
+    pub max_append_entries_in_flight: usize,
+    // Most entries, and bytes of entries, a leader sends in one AppendEntries request. A
+    // follower that's far behind gets its missing entries over several requests.
+    pub max_append_entries_batch: usize,
+    pub max_append_entries_bytes: usize,
+    // Number of rounds of entries a new server gets to catch up with the leader before we give
+    // up on adding it to the cluster
+    pub max_rounds_for_new_server: u32,
+    // Base amount of time clients back off for when a request fails
+    pub client_backoff_time: Duration,
+    // How we talk to the rest of the cluster. Defaults to TCP
+    pub transport: Arc<Transport>,
+    // Where the server gets the time from. A simulation's servers all use its Scheduler
+    pub clock: Arc<Clock>,
+    // Seeds the random number generator behind our election timeouts, so that a simulation's
+    // servers pick the same timeouts on every run. Seeded from the OS if None, unless the server
+    // is started from a simulation, in which case it comes from the simulation's seed
+    pub seed: Option<u64>
 }
 
 impl<'a> Config<'a> {
@@ -550,9 +808,54 @@ impl<'a> Config<'a> {
         Config {
             me: (my_id, my_addr.to_socket_addrs().unwrap().next().unwrap()),
             heartbeat_timeout: heartbeat_timeout,
+            election_timeout_min: Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MIN),
+            election_timeout_max: Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MAX),
             state_filename: state_filename,
-            log_filename: log_filename
+            log_filename: log_filename,
+            log_segment_size: constants::DEFAULT_LOG_SEGMENT_SIZE,
+            entry_cache_size: constants::DEFAULT_ENTRY_CACHE_SIZE,
+            durability: Durability::default(),
+            snapshot_threshold: constants::DEFAULT_SNAPSHOT_THRESHOLD,
+            pre_vote: false,
+            lease_reads: false,
+            max_clock_drift: Duration::from_millis(constants::DEFAULT_MAX_CLOCK_DRIFT),
+            query_timeout: Duration::from_millis(constants::DEFAULT_QUERY_TIMEOUT),
+            max_append_entries_in_flight: constants::DEFAULT_MAX_APPEND_ENTRIES_IN_FLIGHT,
+            max_append_entries_batch: constants::DEFAULT_MAX_APPEND_ENTRIES_BATCH,
+            max_append_entries_bytes: constants::DEFAULT_MAX_APPEND_ENTRIES_BYTES,
+            max_rounds_for_new_server: constants::DEFAULT_MAX_ROUNDS_FOR_NEW_SERVER,
+            client_backoff_time: Duration::from_millis(constants::DEFAULT_CLIENT_BACKOFF_TIME),
+            transport: Arc::new(TcpTransport),
+            clock: Arc::new(SystemClock),
+            seed: None
+        }
+    }
+
+    ///
+    /// Checks that the timeouts and limits in this config make sense together.
+    ///
+    /// #Errors
+    /// Returns an InvalidInput error if the election timeouts don't form a range, if the
+    /// heartbeat timeout isn't well below the minimum election timeout, or if the leader
+    /// couldn't have any AppendEntries in flight to a peer
+    ///
+    pub fn validate(&self) -> Result<(), IoError> {
+        if self.election_timeout_min >= self.election_timeout_max {
+            return Err(IoError::new(ErrorKind::InvalidInput, format!(
+                "Minimum election timeout {:?} must be less than the maximum {:?}",
+                self.election_timeout_min, self.election_timeout_max)));
+        }
+        // A follower should be able to miss a heartbeat without starting an election
+        if self.heartbeat_timeout * 2 > self.election_timeout_min {
+            return Err(IoError::new(ErrorKind::InvalidInput, format!(
+                "Heartbeat timeout {:?} must be at most half the minimum election timeout {:?}",
+                self.heartbeat_timeout, self.election_timeout_min)));
         }
+        if self.max_append_entries_in_flight == 0 {
+            return Err(IoError::new(ErrorKind::InvalidInput,
+                                    "At least one AppendEntries must be allowed in flight to each peer"));
+        }
+        Ok(())
     }
 }
 
@@ -589,3 +892,46 @@ impl SessionInfo {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use std::time::Duration;
+
+    fn config_with_heartbeat(heartbeat_timeout: u64) -> Config<'static> {
+        Config::new(1, "127.0.0.1:0", Duration::from_millis(heartbeat_timeout), "state", "log")
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(config_with_heartbeat(75).validate().is_ok());
+    }
+
+    #[test]
+    fn config_rejects_heartbeat_close_to_election_timeout() {
+        let config = config_with_heartbeat(100);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn config_rejects_empty_election_timeout_range() {
+        let mut config = config_with_heartbeat(10);
+        config.election_timeout_max = config.election_timeout_min;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn config_accepts_longer_timeouts() {
+        let mut config = config_with_heartbeat(500);
+        config.election_timeout_min = Duration::from_secs(2);
+        config.election_timeout_max = Duration::from_secs(4);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn config_rejects_empty_append_entries_window() {
+        let mut config = config_with_heartbeat(75);
+        config.max_append_entries_in_flight = 0;
+        assert!(config.validate().is_err());
+    }
+}
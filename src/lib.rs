@@ -19,6 +19,12 @@ pub mod server;
 ///
 pub mod client;
 
+///
+/// Deterministic scheduling of the server's threads, so that simulations can be replayed
+/// from a seed. Its channels, locks and threads behave like the std ones outside of a simulation.
+///
+pub mod simulation;
+
 extern crate capnp;
 extern crate rand;
 
@@ -1,7 +1,7 @@
 extern crate capnp;
 
 use std::net::{TcpStream, ToSocketAddrs, Shutdown};
-use std::io::{BufWriter, BufReader, Write};
+use std::io::{BufWriter, BufReader, Write, Error as IoError};
 use rpc_capnp::{rpc_request, rpc_response};
 use capnp::{serialize_packed, message};
 use capnp::serialize::{OwnedSegments};
@@ -56,7 +56,7 @@ impl Rpc {
         TcpStream::connect(addr)
         .and_then(|s| {
             let mut writer = BufWriter::new(try!(s.try_clone()));
-            serialize_packed::write_message(&mut writer, &self.msg)
+            self.write_to(&mut writer)
             .and_then(move |_| {
                 writer.flush()
             })
@@ -75,6 +75,17 @@ impl Rpc {
         })
     }
 
+    ///
+    /// Serializes the Rpc into |writer| in the same format it's sent over the wire in.
+    /// This lets a Transport carry the Rpc over something other than a TcpStream.
+    ///
+    /// # Errors
+    /// Returns an IoError if the writer fails
+    ///
+    pub fn write_to<W: Write> (&self, writer: &mut W) -> Result<(), IoError> {
+        serialize_packed::write_message(writer, &self.msg)
+    }
+
     ///
     /// Associated method that exposes the result from the message reader returned by send.
     /// Returns a capnp::any_pointer:Reader which the user should cast to the correct type.
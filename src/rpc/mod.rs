@@ -65,6 +65,12 @@ pub mod client;
 /// # }
 /// ```
 pub mod server;
+/// Pluggable transports that carry Rpcs between servers.
+///
+/// `TcpTransport` sends each Rpc over its own TCP connection, while `ChannelNetwork` passes them
+/// between servers in the same process over channels. The in-process network can drop rpcs
+/// between any two addresses, which makes it handy for testing partitions.
+pub mod transport;
 #[cfg(test)]
 mod test;
 
@@ -53,7 +53,7 @@ pub trait RpcObject: Sync + Send {
     fn handle_rpc (&self, capnp::any_pointer::Reader, capnp::any_pointer::Builder) -> Result<(), RpcError>;
 }
 
-type ServicesMap = HashMap<i16, Box<RpcObject>>;
+pub type ServicesMap = HashMap<i16, Box<RpcObject>>;
 
 pub struct RpcServer {
     services: Arc<ServicesMap>,
@@ -66,12 +66,19 @@ impl RpcServer {
     // TODO: It'd be nice if this took a generic iterator over
     // (i16, RpcObject) tuples
     pub fn new_with_services (iter: Vec<(i16, Box<RpcObject>)>) -> RpcServer {
+        RpcServer {services: Arc::new(RpcServer::services_map(iter)), listener: None,
+                   repl_thread: None, shutdown_tx: None}
+    }
+
+    ///
+    /// Maps each opcode to the RpcObject that handles it.
+    ///
+    pub fn services_map (iter: Vec<(i16, Box<RpcObject>)>) -> ServicesMap {
         let mut map = HashMap::new();
         for (opcode, rpc_object) in iter {
             map.insert(opcode.clone(), rpc_object);
         }
-
-        RpcServer {services: Arc::new(map), listener: None, repl_thread: None, shutdown_tx: None}
+        map
     }
 
     ///
@@ -325,19 +332,40 @@ impl RpcServer {
     fn send_error<A: message::Allocator> (err: RpcError, stream: &mut TcpStream, msg: &mut message::Builder<A>, poll: &Poll)
             -> Result<(), IoError>
     {
+        RpcServer::set_error(err, msg);
+        RpcServer::send_message(stream, msg, poll)
+    }
+
+    ///
+    /// Places the given error into the response message.
+    ///
+    fn set_error<A: message::Allocator> (err: RpcError, msg: &mut message::Builder<A>) {
         // The message should already have the counter set, so we get the root and set the error flag
         // and error value
 
-        { // response scope
-            // We assume an rpc_response::Builder was passed in. If not panic
-            let mut response = msg.get_root::<rpc_response::Builder>().unwrap();
-            response.set_error(true);
+        // We assume an rpc_response::Builder was passed in. If not panic
+        let mut response = msg.get_root::<rpc_response::Builder>().unwrap();
+        response.set_error(true);
+
+        let mut result_builder = response.get_result().init_as::<rpc_error::Builder>();
+        // TODO #3: Errors shuold be more than just text
+        result_builder.set_msg(err.description());
+    }
 
-            let mut result_builder = response.get_result().init_as::<rpc_error::Builder>();
-            // TODO #3: Errors shuold be more than just text
-            result_builder.set_msg(err.description());
+    ///
+    /// Performs the rpc in |reader| and returns the response to send back to the caller.
+    /// Errors are placed in the response, just as they are for rpcs that arrive over TCP.
+    /// This lets a Transport serve rpcs without a TcpListener.
+    ///
+    pub fn respond (opcode_map: Arc<ServicesMap>, reader: message::Reader<OwnedSegments>)
+        -> message::Builder<message::HeapAllocator>
+    {
+        let mut response_msg = message::Builder::new_default();
+        let rpc_result = RpcServer::do_rpc(opcode_map, reader, &mut response_msg);
+        if let Err(e) = rpc_result {
+            RpcServer::set_error(e, &mut response_msg);
         }
-        RpcServer::send_message(stream, msg, poll)
+        response_msg
     }
 
     ///
@@ -0,0 +1,279 @@
+extern crate capnp;
+#[cfg(test)]
+mod test;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Cursor, Error as IoError, ErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use capnp::{serialize_packed, message};
+use capnp::serialize::OwnedSegments;
+use simulation::sync::Mutex;
+use simulation::sync::mpsc::{channel, Sender};
+use simulation::thread;
+use simulation::thread::JoinHandle;
+use super::RpcError;
+use super::client::Rpc;
+use super::server::{RpcObject, RpcServer};
+
+///
+/// Carries rpcs between raft servers, and between clients and the cluster.
+/// AppendEntries, InstallSnapshot, RequestVote, TimeoutNow and client requests all go out
+/// through a Transport, so swapping it out changes how the entire cluster talks to itself.
+///
+pub trait Transport: Send + Sync + fmt::Debug {
+    ///
+    /// Sends |rpc| to the server at |addr|, and blocks until a response is received (or it
+    /// errors out).
+    ///
+    /// # Errors
+    /// Returns an RpcError if the server can't be reached, or if its response isn't a valid
+    /// RpcResponse.
+    ///
+    fn send (&self, addr: SocketAddr, rpc: &Rpc) -> Result<message::Reader<OwnedSegments>, RpcError>;
+
+    ///
+    /// Starts handling rpcs sent to |addr| with |services|.
+    /// Rpcs are handled until the returned Listener is dropped.
+    ///
+    /// # Errors
+    /// Returns an IoError if we can't listen on |addr|.
+    /// This is typically because something else is already listening there.
+    ///
+    fn listen (&self, addr: SocketAddr, services: Vec<(i16, Box<RpcObject>)>)
+        -> Result<Box<Listener>, IoError>;
+}
+
+///
+/// A server started by Transport::listen.
+/// Dropping the Listener stops the server, blocking until the rpcs it's in the middle of are done.
+///
+pub trait Listener: Send {
+    ///
+    /// Returns the address that rpcs for this server should be sent to.
+    ///
+    fn local_addr (&self) -> SocketAddr;
+}
+
+///
+/// The default Transport. Sends each rpc over its own TCP connection.
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn send (&self, addr: SocketAddr, rpc: &Rpc) -> Result<message::Reader<OwnedSegments>, RpcError> {
+        rpc.send(addr)
+    }
+
+    fn listen (&self, addr: SocketAddr, services: Vec<(i16, Box<RpcObject>)>)
+        -> Result<Box<Listener>, IoError> {
+        let mut server = RpcServer::new_with_services(services);
+        try!(server.bind(addr).and_then(|_| server.repl()));
+        Ok(Box::new(server))
+    }
+}
+
+impl Listener for RpcServer {
+    fn local_addr (&self) -> SocketAddr {
+        // safe to unwrap since TcpTransport only hands out bound servers
+        self.get_local_addr().unwrap()
+    }
+}
+
+// A serialized rpc, and where to send the serialized response
+type LocalRequest = (Vec<u8>, Sender<Vec<u8>>);
+
+#[derive(Debug, Default)]
+struct NetworkState {
+    servers: HashMap<SocketAddr, Sender<LocalRequest>>,
+    // Ordered pairs of addresses that can't reach each other. We always insert both orders.
+    partitioned: HashSet<(SocketAddr, SocketAddr)>
+}
+
+///
+/// An in-process network of servers that pass rpcs to each other over channels.
+/// Nothing binds to a socket, so the addresses are just names and never conflict with
+/// anything else running on the machine.
+///
+/// Tests can cut any two addresses off from each other, which drops every rpc (and response)
+/// between them.
+///
+/// # Examples
+/// ```rust
+/// # use rusty_raft::rpc::transport::ChannelNetwork;
+/// # use std::net::SocketAddr;
+/// # use std::str::FromStr;
+/// let network = ChannelNetwork::new();
+/// let a = SocketAddr::from_str("127.0.0.1:1").unwrap();
+/// let b = SocketAddr::from_str("127.0.0.1:2").unwrap();
+/// // Give each server its own transport, and listen on it with Transport::listen
+/// let transport = network.transport(a);
+/// network.disconnect(a, b);
+/// // Rpcs between a and b are now dropped
+/// network.reconnect(a, b);
+/// ```
+///
+#[derive(Debug, Clone, Default)]
+pub struct ChannelNetwork {
+    state: Arc<Mutex<NetworkState>>
+}
+
+impl ChannelNetwork {
+    pub fn new () -> ChannelNetwork {
+        ChannelNetwork::default()
+    }
+
+    ///
+    /// Returns a transport that sends rpcs on this network from |me|.
+    /// |me| should be the address the owner of the transport listens on, so that
+    /// disconnecting that address cuts off the rpcs it sends as well as the ones it receives.
+    ///
+    pub fn transport (&self, me: SocketAddr) -> ChannelTransport {
+        ChannelTransport { network: self.clone(), me: me }
+    }
+
+    ///
+    /// Drops all rpcs between |a| and |b| until they're reconnected.
+    ///
+    pub fn disconnect (&self, a: SocketAddr, b: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.partitioned.insert((a, b));
+        state.partitioned.insert((b, a));
+    }
+
+    ///
+    /// Lets rpcs flow between |a| and |b| again.
+    ///
+    pub fn reconnect (&self, a: SocketAddr, b: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.partitioned.remove(&(a, b));
+        state.partitioned.remove(&(b, a));
+    }
+
+    fn is_connected (&self, a: SocketAddr, b: SocketAddr) -> bool {
+        !self.state.lock().unwrap().partitioned.contains(&(a, b))
+    }
+}
+
+///
+/// A Transport that sends rpcs over a ChannelNetwork.
+///
+#[derive(Debug, Clone)]
+pub struct ChannelTransport {
+    network: ChannelNetwork,
+    me: SocketAddr
+}
+
+impl ChannelTransport {
+    fn unreachable (addr: SocketAddr) -> RpcError {
+        RpcError::Io(IoError::new(ErrorKind::ConnectionRefused, format!("Unable to reach {}", addr)))
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send (&self, addr: SocketAddr, rpc: &Rpc) -> Result<message::Reader<OwnedSegments>, RpcError> {
+        let mut request = Vec::new();
+        try!(rpc.write_to(&mut request).map_err(RpcError::Io));
+
+        let (to_me, from_server) = channel();
+        { // to_server scope
+            // Only hold onto the server's sender for as long as we need it,
+            // so that the server can shut down once it stops listening
+            let to_server = {
+                let state = self.network.state.lock().unwrap();
+                if state.partitioned.contains(&(self.me, addr)) {
+                    return Err(ChannelTransport::unreachable(addr));
+                }
+                try!(state.servers.get(&addr).cloned().ok_or(ChannelTransport::unreachable(addr)))
+            };
+            try!(to_server.send((request, to_me)).map_err(|_| ChannelTransport::unreachable(addr)));
+        }
+
+        let response = try!(from_server.recv().map_err(|_| ChannelTransport::unreachable(addr)));
+        // we may have been cut off while the server was handling the rpc
+        if !self.network.is_connected(addr, self.me) {
+            return Err(ChannelTransport::unreachable(addr));
+        }
+        serialize_packed::read_message(&mut Cursor::new(response), message::ReaderOptions::new())
+            .map_err(RpcError::Capnp)
+    }
+
+    fn listen (&self, addr: SocketAddr, services: Vec<(i16, Box<RpcObject>)>)
+        -> Result<Box<Listener>, IoError> {
+        let (to_server, requests) = channel::<LocalRequest>();
+        {
+            let mut state = self.network.state.lock().unwrap();
+            if state.servers.contains_key(&addr) {
+                return Err(IoError::new(ErrorKind::AddrInUse,
+                                        format!("{} is already listening on this network", addr)));
+            }
+            state.servers.insert(addr, to_server);
+        }
+
+        let services = Arc::new(RpcServer::services_map(services));
+        let thread = thread::spawn(move || {
+            // TODO #1: Thread pool
+            // Each rpc gets its own thread, just like it would with the RpcServer
+            let mut background_threads: Vec<JoinHandle<()>> = vec![];
+            for (request, to_client) in requests {
+                let services = services.clone();
+                background_threads.push(thread::spawn(move || {
+                    let reader = match serialize_packed::read_message(&mut Cursor::new(request),
+                                                                      message::ReaderOptions::new()) {
+                        Ok(reader) => reader,
+                        Err(e) => {
+                            warn!("Dropping malformed rpc: {}", e);
+                            return;
+                        }
+                    };
+                    let response_msg = RpcServer::respond(services, reader);
+                    let mut response = Vec::new();
+                    match serialize_packed::write_message(&mut response, &response_msg) {
+                        // the client may have given up on us
+                        Ok(_) => { let _ = to_client.send(response); },
+                        Err(e) => warn!("Unable to serialize rpc response: {}", e)
+                    }
+                }));
+            }
+
+            for thread in background_threads {
+                if thread.join().is_err() {
+                    error!("Background RPC thread panicked");
+                }
+            }
+        });
+
+        Ok(Box::new(ChannelListener { network: self.network.clone(), addr: addr, thread: Some(thread) }))
+    }
+}
+
+struct ChannelListener {
+    network: ChannelNetwork,
+    addr: SocketAddr,
+    thread: Option<JoinHandle<()>>
+}
+
+impl Listener for ChannelListener {
+    fn local_addr (&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for ChannelListener {
+    /// Stops accepting rpcs, and blocks until the ones in progress have been handled
+    ///
+    /// # Panics
+    /// Panics if the background thread has panicked
+    fn drop (&mut self) {
+        // Once every sender is gone the background thread runs out of requests and exits
+        self.network.state.lock().unwrap().servers.remove(&self.addr);
+        match mem::replace(&mut self.thread, None) {
+            Some(t) => t.join().unwrap(),
+            None => {/* Nothing to shutdown */}
+        }
+    }
+}
@@ -0,0 +1,97 @@
+extern crate capnp;
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use super::{ChannelNetwork, Listener, TcpTransport, Transport};
+use super::super::RpcError;
+use super::super::client::Rpc;
+use super::super::server::RpcObject;
+use super::super::test::AdditionRpcHandler;
+use rpc_capnp::{math_result, math_params};
+
+/************************/
+/*   BEGIN UNIT TESTS   */
+/************************/
+
+#[test]
+fn it_sends_rpcs_over_tcp() {
+    let transport = TcpTransport;
+    let listener = listen_for_addition(&transport, "127.0.0.1:0");
+    assert_eq!(send_addition(&transport, listener.local_addr()).unwrap(), 3);
+}
+
+#[test]
+fn it_sends_rpcs_over_a_channel_network() {
+    let network = ChannelNetwork::new();
+    let (a, b) = (addr(1), addr(2));
+    let _listener = listen_for_addition(&network.transport(b), "127.0.0.1:2");
+    assert_eq!(send_addition(&network.transport(a), b).unwrap(), 3);
+}
+
+#[test]
+fn it_drops_rpcs_between_disconnected_addresses() {
+    let network = ChannelNetwork::new();
+    let (a, b, c) = (addr(1), addr(2), addr(3));
+    let _listener = listen_for_addition(&network.transport(b), "127.0.0.1:2");
+
+    network.disconnect(a, b);
+    assert!(send_addition(&network.transport(a), b).is_err());
+    // the rest of the network can still reach b
+    assert_eq!(send_addition(&network.transport(c), b).unwrap(), 3);
+
+    network.reconnect(b, a);
+    assert_eq!(send_addition(&network.transport(a), b).unwrap(), 3);
+}
+
+#[test]
+fn it_stops_serving_once_the_listener_is_dropped() {
+    let network = ChannelNetwork::new();
+    let (a, b) = (addr(1), addr(2));
+    {
+        let _listener = listen_for_addition(&network.transport(b), "127.0.0.1:2");
+        assert!(send_addition(&network.transport(a), b).is_ok());
+    }
+    assert!(send_addition(&network.transport(a), b).is_err());
+    // the address is free to listen on again
+    let _listener = listen_for_addition(&network.transport(b), "127.0.0.1:2");
+    assert!(send_addition(&network.transport(a), b).is_ok());
+}
+
+#[test]
+fn it_rejects_two_listeners_on_the_same_address() {
+    let network = ChannelNetwork::new();
+    let _listener = listen_for_addition(&network.transport(addr(1)), "127.0.0.1:1");
+    let services = vec![(0i16, Box::new(AdditionRpcHandler {}) as Box<RpcObject>)];
+    assert!(network.transport(addr(1)).listen(addr(1), services).is_err());
+}
+
+/**************************/
+/*   TEST HELPER METHODS  */
+/**************************/
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap()
+}
+
+/// Listens for addition rpcs on the given address
+fn listen_for_addition<T: Transport> (transport: &T, addr: &str) -> Box<Listener> {
+    let services = vec![(0i16, Box::new(AdditionRpcHandler {}) as Box<RpcObject>)];
+    transport.listen(SocketAddr::from_str(addr).unwrap(), services).unwrap()
+}
+
+/// Asks the server at addr to add 1 and 2
+fn send_addition<T: Transport> (transport: &T, addr: SocketAddr) -> Result<i32, RpcError> {
+    let mut rpc = Rpc::new(0i16);
+    {
+        let mut math_builder = rpc.get_param_builder().init_as::<math_params::Builder>();
+        math_builder.set_num1(1);
+        math_builder.set_num2(2);
+    }
+    transport.send(addr, &rpc).and_then(|response| {
+        Rpc::get_result_reader(&response)
+            .and_then(|result| {
+                result.get_as::<math_result::Reader>().map_err(RpcError::Capnp)
+            })
+            .map(|result| result.get_num())
+    })
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+///
+/// Where the server core gets the current time from.
+/// Everything that times out (elections, heartbeats, leases, leadership transfers) is measured
+/// against this clock, so a simulated clock can control all of it.
+/// Servers still block on their channels for the timeouts they measure, so they check now()
+/// again once they wake up, in case this clock hasn't gotten there yet.
+///
+/// A simulation::Scheduler is the Clock for every server in its simulation.
+///
+pub trait Clock: Send + Sync + fmt::Debug {
+    ///
+    /// Returns the current time according to this clock.
+    ///
+    fn now (&self) -> Instant;
+}
+
+///
+/// The default Clock. Just reads the system's monotonic clock.
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now (&self) -> Instant {
+        Instant::now()
+    }
+}
+
+///
+/// A Clock that only moves forward when it is told to.
+/// Lets tests that drive a server by hand decide when its timeouts expire.
+///
+#[derive(Debug)]
+pub struct SimulatedClock {
+    start: Instant,
+    elapsed: Mutex<Duration>
+}
+
+impl SimulatedClock {
+    pub fn new () -> SimulatedClock {
+        SimulatedClock { start: Instant::now(), elapsed: Mutex::new(Duration::from_millis(0)) }
+    }
+
+    ///
+    /// Moves the clock forward by |duration|.
+    ///
+    pub fn advance (&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    ///
+    /// Returns how far the clock has moved since it was created.
+    ///
+    pub fn elapsed (&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now (&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, SimulatedClock};
+    use std::time::Duration;
+
+    #[test]
+    fn simulated_clock_only_moves_when_advanced() {
+        let clock = SimulatedClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_millis(150));
+        assert_eq!(clock.now().duration_since(start), Duration::from_millis(150));
+        assert_eq!(clock.elapsed(), Duration::from_millis(150));
+    }
+}
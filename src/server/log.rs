@@ -1,19 +1,24 @@
 use std::fmt;
-use std::io::{Result, BufReader, BufWriter, Seek, SeekFrom, Error, ErrorKind};
-use std::fs::{File, OpenOptions};
+use std::io::{Result, Error, ErrorKind};
 use raft_capnp::{entry};
-use capnp::serialize_packed;
-use capnp::message;
-use capnp::message::ReaderOptions;
 use super::super::common::{raft_command};
 use super::MainThreadMessage;
-use std::thread;
-use std::thread::JoinHandle;
-use std::time::Duration;
-use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
-use std::sync::{Mutex, Arc};
+use super::clock::Clock;
+use super::snapshot::SnapshotMetadata;
+use super::storage::{SharedStorage, Durability};
+use simulation::thread;
+use simulation::thread::JoinHandle;
+use simulation::sync::mpsc::{channel, Receiver, Sender, TryRecvError, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use std::net::SocketAddr;
 use std::mem;
+use std::cmp::{min, max, Ordering};
+use std::collections::VecDeque;
+
+// Most entries we read from storage at once while opening a log
+const LOAD_BATCH_SIZE: usize = 1024;
 
 ///
 /// Abstraction for a single Entry for our log.
@@ -90,6 +95,24 @@ impl Entry {
                                 .init_op());
     }
 
+    ///
+    /// Roughly how many bytes this entry takes up in an rpc.
+    ///
+    pub fn approx_size(&self) -> usize {
+        // term, index and the op's tag
+        const HEADER_SIZE: usize = 24;
+        // an id and an address
+        const SERVER_SIZE: usize = 48;
+        HEADER_SIZE + match self.op {
+            raft_command::Request::StateMachineCommand{ref data, ..} => data.len() + 16,
+            raft_command::Request::SetConfig(ref servers, ref learners) =>
+                (servers.len() + learners.len()) * SERVER_SIZE,
+            raft_command::Request::SetJointConfig(ref old, ref new, ref learners) =>
+                (old.len() + new.len() + learners.len()) * SERVER_SIZE,
+            raft_command::Request::OpenSession(_) | raft_command::Request::Noop => 0
+        }
+    }
+
     ///
     /// Retrieves a copy of the data within an Entry, if there is any.
     ///
@@ -114,9 +137,42 @@ impl PartialEq for Entry {
     }
 }
 
-#[derive(Debug, PartialEq)]
+///
+/// Returns true if |op| changes the cluster config.
+///
+fn is_config(op: &raft_command::Request) -> bool {
+    matches!(*op, raft_command::Request::SetConfig(..) | raft_command::Request::SetJointConfig(..))
+}
+
+///
+/// Returns the sets of servers that each need a majority under the config set by |op|,
+/// or None if |op| doesn't change the config.
+///
+fn config_quorums(op: &raft_command::Request) -> Option<Vec<Vec<(u64, SocketAddr)>>> {
+    match *op {
+        raft_command::Request::SetConfig(ref servers, _) => Some(vec![servers.clone()]),
+        raft_command::Request::SetJointConfig(ref old, ref new, _) => Some(vec![old.clone(), new.clone()]),
+        _ => None
+    }
+}
+
+///
+/// Returns the non-voting members of the config set by |op|, or None if |op| doesn't change
+/// the config.
+///
+fn config_learners(op: &raft_command::Request) -> Option<Vec<(u64, SocketAddr)>> {
+    match *op {
+        raft_command::Request::SetConfig(_, ref learners) |
+        raft_command::Request::SetJointConfig(_, _, ref learners) => Some(learners.clone()),
+        _ => None
+    }
+}
+
+#[derive(Debug)]
 enum BackgroundThreadMessage {
     AppendEntry (Entry),
+    // The log thread appended entries itself, which need syncing once the interval is up
+    Written,
     Flush,
     Shutdown
 }
@@ -129,113 +185,259 @@ enum BackgroundThreadReply {
 ///
 /// Abstraction for a log of "commands" to apply to the client state machine,
 /// stored locally.
-/// Small wrapper over a list of Entries that handles pushing to persistent storage --
-/// eventually should also abstract away automatically snapshotting (TODO)
+/// Small wrapper over a list of Entries that handles pushing to persistent storage.
+/// Entries that have been compacted into a snapshot are dropped from both memory and storage,
+/// so the first entry in the log is at |start_index| rather than 1.
+/// Only the most recent entries are kept in memory. Older ones are read back from storage
+/// when they're needed, for instance to catch up a slow follower.
 pub struct Log {
-    file: File,
-    entries: Vec<Entry>,
+    storage: SharedStorage,
+    // The term of every entry in the log, starting at start_index
+    terms: Vec<u64>,
+    // Every config entry in the log, in order
+    configs: Vec<Entry>,
+    // The most recent entries in the log. Holds at least the last |cache_size| of them,
+    // and more if the background thread hasn't persisted the older ones yet
+    cache: VecDeque<Entry>,
+    cache_size: usize,
+    // Index of the last entry the background thread has written to storage. It may not be
+    // synced yet, depending on |durability|
+    persisted_index: Arc<AtomicUsize>,
+    durability: Durability,
     start_index: usize,
-    // Stores the byte offset of the first byte each log entry on disk
-    entry_offsets: Arc<Mutex<Vec<u64>>>,
+    // Describes the entries before start_index, if they've been compacted
+    snapshot: Option<SnapshotMetadata>,
     background_thread_tx: Sender<BackgroundThreadMessage>,
     background_thread_rx: Receiver<BackgroundThreadReply>,
     background_thread: Option<JoinHandle<()>>,
-    flushed: bool,
-    most_recent_cluster: Option<usize>
+    flushed: bool
 }
 
 impl Log {
-    pub fn new_from_filename(filename: &str, to_main_thread: Sender<MainThreadMessage>) -> Result<Log> {
-        let mut f = OpenOptions::new().write(true).read(true).create(true).open(filename)?;
-        let (entries, offsets, most_recent_cluster) = Log::read_entries(&mut f)?;
-        // start index is always 1 until we implement snapshotting
+    ///
+    /// Opens the log kept in |storage|, which picks up right after the storage's snapshot.
+    /// The last |cache_size| entries are kept in memory, and |durability| decides when entries
+    /// are synced to disk. Sync intervals are measured against |clock|.
+    ///
+    /// #Errors
+    /// * Returns an IO error if the log can't be read
+    /// * Returns a std::io::ErrorKind::InvalidData error if the log doesn't pick up where the
+    /// snapshot leaves off
+    ///
+    pub fn new(storage: SharedStorage, cache_size: usize, durability: Durability, clock: Arc<Clock>,
+               to_main_thread: Sender<MainThreadMessage>) -> Result<Log> {
+        let snapshot = storage.lock().unwrap().snapshot_metadata();
+        let start_index = snapshot.as_ref().map_or(1, |s| s.last_included_index + 1);
+        let mut terms = vec![];
+        let mut configs = vec![];
+        let mut cache = VecDeque::new();
+        {
+            // Read the log a batch at a time, so we never hold all of it in memory
+            let mut storage = storage.lock().unwrap();
+            let end = storage.last_index() + 1;
+            let mut index = start_index;
+            while index < end {
+                let entries = storage.entries_between(index, min(index + LOAD_BATCH_SIZE, end))?;
+                if entries.is_empty() {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Log is missing entry {}", index)));
+                }
+                for entry in entries {
+                    if entry.index != index {
+                        return Err(Error::new(ErrorKind::InvalidData, "Log does not continue from the snapshot"));
+                    }
+                    terms.push(entry.term);
+                    if is_config(&entry.op) {
+                        configs.push(entry.clone());
+                    }
+                    cache.push_back(entry);
+                    if cache.len() > cache_size {
+                        cache.pop_front();
+                    }
+                    index += 1;
+                }
+            }
+        }
+        let persisted_index = Arc::new(AtomicUsize::new(start_index + terms.len() - 1));
+
         let (to_background_thread, from_log_thread) = channel();
         let (to_log_thread, from_background_thread) = channel();
-        let f_clone = f.try_clone()?;
 
-        let entry_offsets = Arc::new(Mutex::new(offsets));
-        let offsets_clone = entry_offsets.clone();
-        let t = thread::spawn(move || Log::background_thread_repl(f_clone, to_main_thread, to_log_thread, from_log_thread, offsets_clone));
+        let storage_clone = storage.clone();
+        let persisted_index_clone = persisted_index.clone();
+        let t = thread::spawn(move || Log::background_thread_repl(storage_clone, persisted_index_clone, durability,
+                                                                  clock, to_main_thread, to_log_thread,
+                                                                  from_log_thread));
         Ok(Log {
-            file: f,
-            entries: entries,
-            start_index: 1,
-            entry_offsets: entry_offsets, 
+            storage: storage,
+            terms: terms,
+            configs: configs,
+            cache: cache,
+            cache_size: cache_size,
+            persisted_index: persisted_index,
+            durability: durability,
+            start_index: start_index,
+            snapshot: snapshot,
             background_thread_tx: to_background_thread,
             background_thread_rx: from_background_thread,
             background_thread: Some(t),
-            flushed: true,
-            most_recent_cluster: most_recent_cluster
+            flushed: true
         })
     }
 
     /// 
-    /// Retrieves read-only reference to an Entry in the log, specified by |index|.
+    /// Retrieves a copy of the Entry in the log at |index|, reading it from storage if it's no
+    /// longer in memory.
     /// Returns None if the index is out of bounds
     ///
-    pub fn get_entry(&self, index: usize) -> Option<&Entry> {
-        if index < self.start_index {
+    /// #Panics
+    /// Panics if the entry can't be read from storage
+    ///
+    pub fn get_entry(&self, index: usize) -> Option<Entry> {
+        if index < self.start_index || index > self.get_last_entry_index() {
             return None;
         }
+        let cache_start = self.cache_start();
+        if index >= cache_start {
+            return self.cache.get(index - cache_start).cloned();
+        }
 
-        self.entries.get(index - self.start_index)
+        match self.storage.lock().unwrap().entry(index) {
+            Ok(Some(entry)) => Some(entry),
+            Ok(None) => Log::storage_read_failed(index, "it isn't in storage"),
+            Err(e) => Log::storage_read_failed(index, e)
+        }
     }
 
     /// 
-    /// Retrieves read-only reference to a slice of all Entries in the log past |start_index|.
-    /// Slice does not include |start_index|
+    /// Retrieves a copy of every Entry in the log past |start_index|.
+    /// Does not include |start_index|
+    ///
+    /// Reads every entry that's no longer in memory from storage, so prefer
+    /// get_entries_batch for logs that may be long.
+    ///
+    /// #Panics
+    /// Panics if the entries can't be read from storage
+    ///
+    pub fn get_entries_from(&self, start_index: usize) -> Vec<Entry> {
+        let from = max(start_index + 1, self.start_index);
+        self.read_entries(from, self.get_last_entry_index() + 1)
+    }
+
     ///
-    pub fn get_entries_from(&self, mut start_index: usize) -> &[Entry] {
-        if start_index < self.start_index {
-            // We should return a slice containing the full memory log
-            start_index = self.start_index - 1;
+    /// Retrieves the entries past |start_index| that fit in a single AppendEntries rpc: at most
+    /// |max_entries| of them, taking up at most |max_bytes|. The first entry is always included
+    /// so that an entry larger than |max_bytes| can still be replicated.
+    ///
+    /// #Panics
+    /// Panics if the entries can't be read from storage
+    ///
+    pub fn get_entries_batch(&self, start_index: usize, max_entries: usize, max_bytes: usize) -> Vec<Entry> {
+        let from = max(start_index + 1, self.start_index);
+        let to = min(from.saturating_add(max_entries), self.get_last_entry_index() + 1);
+        let mut entries = self.read_entries(from, to);
+        let mut bytes = 0;
+        let len = entries.iter()
+            .take_while(|e| {
+                bytes += e.approx_size();
+                bytes <= max_bytes
+            })
+            .count();
+        let len = max(len, min(1, entries.len()));
+        entries.truncate(len);
+        entries
+    }
+
+    /// Index of the first entry in the cache
+    fn cache_start(&self) -> usize {
+        self.get_last_entry_index() + 1 - self.cache.len()
+    }
+
+    /// Reads the entries from |from| up to but not including |to|, which must all be in the
+    /// log, from the cache or from storage
+    fn read_entries(&self, from: usize, to: usize) -> Vec<Entry> {
+        if from >= to {
+            return vec![];
         }
+        let cache_start = self.cache_start();
+        let mut entries = if from < cache_start {
+            let to_read = min(to, cache_start);
+            match self.storage.lock().unwrap().entries_between(from, to_read) {
+                Ok(ref entries) if entries.len() != to_read - from =>
+                    Log::storage_read_failed(from, "some of them aren't in storage"),
+                Ok(entries) => entries,
+                Err(e) => Log::storage_read_failed(from, e)
+            }
+        } else {
+            vec![]
+        };
+        let first_cached = max(from, cache_start);
+        entries.extend(self.cache.iter().skip(first_cached - cache_start).take(to - first_cached).cloned());
+        entries
+    }
 
-        start_index -= self.start_index - 1;
-        if start_index > self.entries.len() {
-            return &[];
+    /// Entries only leave the cache once they're in storage, so we can't go on if we fail to
+    /// read them back.
+    fn storage_read_failed<E: fmt::Display>(index: usize, e: E) -> ! {
+        error!("Unable to read entries from {} out of log storage. This is unrecoverable, and the server will shut down. {}", index, e);
+        panic!("Unable to read from log storage.");
+    }
+
+    /// Drops the oldest entries from the cache until it's back down to |cache_size|, keeping
+    /// any that the background thread hasn't written to storage yet
+    fn trim_cache(&mut self) {
+        let persisted_index = self.persisted_index.load(AtomicOrdering::SeqCst);
+        while self.cache.len() > self.cache_size &&
+              self.cache.front().map_or(false, |e| e.index <= persisted_index) {
+            self.cache.pop_front();
         }
+    }
 
-        &self.entries[start_index ..]
+    /// Adds |entry|, whose index has already been set, to the end of the log in memory
+    fn push_entry(&mut self, entry: Entry) {
+        debug_assert_eq!(entry.index, self.get_last_entry_index() + 1);
+        self.terms.push(entry.term);
+        // keep track of the cluster config
+        if is_config(&entry.op) {
+            self.configs.push(entry.clone());
+        }
+        self.cache.push_back(entry);
     }
 
     ///
     /// Appends a copy of each entry in |entries| to log. Will correctly modify
     /// |index| field in each Entry to match its index in the log.
     /// Returns this log object.
-    /// Blocks until these entries are persisted to disk
+    /// Blocks until these entries are written to storage together. Under
+    /// Durability::SyncEveryWrite that includes a single sync to disk. Under
+    /// Durability::SyncInterval the background thread syncs them once the interval is up.
     ///
     pub fn append_entries_blocking(&mut self, entries: Vec<Entry>) -> Result<&Log> {
         debug_assert!(self.flushed, "Attempt to syncronously push entry into unflushed log");
 
-        let mut start_index = self.entries.len() + self.start_index;
-        let indexed_entries = entries.into_iter().map(|mut entry| {
+        let mut start_index = self.get_last_entry_index() + 1;
+        let indexed_entries: Vec<Entry> = entries.into_iter().map(|mut entry| {
             debug_assert!(entry.term > 0); // can't commit in term 0
             entry.index = start_index;
             start_index += 1;
             entry
-        });
-        
-        // write the entries to disk
-        let mut writer = BufWriter::new(&self.file);
-        let mut entry_offsets = self.entry_offsets.lock().unwrap();
-        for entry in indexed_entries {
-            let metadata = self.file.metadata()?;
-            let cursor = metadata.len();
-            let mut builder = message::Builder::new_default();
-            entry.into_proto(&mut builder.init_root::<entry::Builder>());
-            serialize_packed::write_message(&mut writer, &builder)?;
-
-            // keep track of the most recent cluster config
-            if matches!(entry.op, raft_command::Request::SetConfig(..)) {
-                self.most_recent_cluster = Some(entry.index);
-            }
+        }).collect();
 
-            self.entries.push(entry);
-            entry_offsets.push(cursor);
+        {
+            let mut storage = self.storage.lock().unwrap();
+            storage.append(&indexed_entries)?;
+            if self.durability == Durability::SyncEveryWrite {
+                storage.sync()?;
+            }
         }
-
-        self.file.sync_all()?;
+        if let Durability::SyncInterval(_) = self.durability {
+            self.background_thread_tx.send(BackgroundThreadMessage::Written).unwrap();
+        }
+        for entry in indexed_entries {
+            self.push_entry(entry);
+        }
+        // nothing else is being written while we're flushed
+        self.persisted_index.store(self.get_last_entry_index(), AtomicOrdering::SeqCst);
+        self.trim_cache();
         Ok(self)
     }
 
@@ -245,7 +447,9 @@ impl Log {
     /// |index| field in |entry| to match its index in the log.
     /// Returns this log object.
     ///
-    /// |entry| will be synced to disk in the background. You must
+    /// |entry| will be written to disk in the background, along with any other entries
+    /// that are waiting to be written by then, and synced as |durability| says. The main
+    /// thread hears about it with an EntryPersisted message once that's done. You must
     /// call flush_background_thread, before attempting to do anymore
     /// IO to the disk on this thread. Specifically you must flush
     /// before calling roll_back or append_entries_blocking
@@ -256,12 +460,10 @@ impl Log {
     ///
     pub fn append_entry(&mut self, mut entry: Entry) -> &Log {
         debug_assert!(entry.term > 0); // can't commit in term 0
-        entry.index = self.entries.len() + self.start_index;
+        entry.index = self.get_last_entry_index() + 1;
         // TODO(perf): We could wrap entry in an Arc and avoid having to make the copy here
-        self.entries.push(entry.clone());
-        if matches!(entry.op, raft_command::Request::SetConfig(..)) {
-            self.most_recent_cluster = Some(entry.index);
-        }
+        self.push_entry(entry.clone());
+        self.trim_cache();
 
         self.background_thread_tx.send(BackgroundThreadMessage::AppendEntry(entry)).unwrap();
 
@@ -273,98 +475,240 @@ impl Log {
     /// Retrieves the index of the last entry in our log.
     ///
     pub fn get_last_entry_index(&self) -> usize {
-        self.entries.len() - (self.start_index - 1)
+        self.terms.len() + self.start_index - 1
     }
 
     ///
     /// Retrieves the term of the last entry in our log. 
     ///
     pub fn get_last_entry_term(&self) -> u64 {
-        if self.entries.len() == 0 {
-            return 0;
+        self.get_term(self.get_last_entry_index()).unwrap_or(0)
+    }
+
+    ///
+    /// Retrieves the index of the first entry that is still stored in the log.
+    /// Every entry before it has been compacted into a snapshot.
+    ///
+    pub fn get_start_index(&self) -> usize {
+        self.start_index
+    }
+
+    ///
+    /// Retrieves the term of the entry at |index|. This includes the last entry covered
+    /// by our snapshot, even though that entry is no longer stored in the log.
+    /// Returns None if the index is out of bounds.
+    ///
+    pub fn get_term(&self, index: usize) -> Option<u64> {
+        if index == 0 {
+            return Some(0);
         }
 
-        self.get_entry(self.get_last_entry_index())
-            .map_or(0, |e| e.term)
+        match self.snapshot {
+            Some(ref snapshot) if snapshot.last_included_index == index => Some(snapshot.last_included_term),
+            _ if index < self.start_index => None,
+            _ => self.terms.get(index - self.start_index).cloned()
+        }
+    }
+
+    ///
+    /// Returns the index of the first entry in our log from |term|, or None if we don't have any
+    /// entries from that term. Entries that have been compacted into our snapshot don't count.
+    ///
+    pub fn first_index_of_term(&self, term: u64) -> Option<usize> {
+        // Terms never decrease along the log, so we can binary search for the first entry
+        // from |term| or later
+        let i = self.terms
+            .binary_search_by(|&t| if t < term { Ordering::Less } else { Ordering::Greater })
+            .unwrap_or_else(|i| i);
+        match self.terms.get(i) {
+            Some(&t) if t == term => Some(i + self.start_index),
+            _ => None
+        }
+    }
+
+    ///
+    /// Returns the index of the last entry in our log from |term|, including the last entry in
+    /// our snapshot, or None if we don't have any entries from that term.
+    ///
+    pub fn last_index_of_term(&self, term: u64) -> Option<usize> {
+        // the first entry from a later term comes right after it
+        let i = self.terms
+            .binary_search_by(|&t| if t <= term { Ordering::Less } else { Ordering::Greater })
+            .unwrap_or_else(|i| i);
+        if i > 0 {
+            if self.terms[i - 1] == term { Some(i - 1 + self.start_index) } else { None }
+        } else if self.get_term(self.start_index - 1) == Some(term) {
+            Some(self.start_index - 1)
+        } else {
+            None
+        }
     }
 
     ///
     /// Rolls back log so that the most recent entry is located at |index|.
     /// Returns this log object.
     ///
-    /// If this operation rolls back entries that are on disk
-    /// this operation will block until the on disk log is truncated
+    /// Blocks until the entries are removed from storage
     ///
     /// #Panics
     /// * Panics if you try to roll back entries that are < start_index
     /// because you should never try to roll back snapshotted entries
-    /// * Panics if the file on disk has been closed
     ///
     /// #Error
-    /// Returns an error if there was an issue rolling back the log on disk
+    /// Returns an error if there was an issue rolling back the log in storage
     ///
     pub fn roll_back(&mut self, index: usize) -> Result<&Log> {
         debug_assert!(self.flushed, "Attempt to roll back unflushed log");
         let start_index = index + 1 - self.start_index;
-        if start_index >= self.entries.len() {
+        if start_index >= self.terms.len() {
             return Ok(self); // nothing to remove
         }
 
-        self.entries.drain(start_index ..);
-
-        // we may be rolling back entries that only exist in memory
-        let mut entry_offsets = self.entry_offsets.lock().unwrap();
-        if start_index < entry_offsets.len() {
-            self.file.set_len(entry_offsets[start_index])?;
-            entry_offsets.drain(start_index ..);
+        self.storage.lock().unwrap().truncate(index)?;
+        self.terms.truncate(start_index);
+        while self.cache.back().map_or(false, |e| e.index > index) {
+            self.cache.pop_back();
         }
-
         // check if we rolled back our cluster
-        self.most_recent_cluster = self.most_recent_cluster
-            .and_then(|i| if i > index { None } else { Some(i) });
+        self.configs.retain(|e| e.index <= index);
+        self.persisted_index.store(index, AtomicOrdering::SeqCst);
 
         Ok(self)
     }
 
-    /// Gets (and caches) the most recent cluster config stored in the log if one exists
+    /// Gets the most recent cluster config stored in the log if one exists.
+    /// While a joint consensus change is in progress this holds every server in
+    /// either the old or the new config.
     ///
     /// #Panics
     /// * Panics if the log is corrupt
     pub fn get_cluster_config(&mut self) -> Option<Vec<(u64, SocketAddr)>> {
-        let option = self.most_recent_cluster
-            .map(|i| {
-                // We should never store an entry that is not in memory
-                self.get_entry(i).unwrap()
-            })
-            .or_else(|| {
-                // find the most recent config entry if it exists
-                self.entries.iter()
-                    .rev()
-                    .filter(|e| matches!(e.op, raft_command::Request::SetConfig(..)))
-                    .next()
-            })
-            .map(|e| {
-                match e.op {
-                    raft_command::Request::SetConfig(ref servers) => (servers.clone(), e.index),
-                    _ => {
-                        error!("Config log entry didn't have a server list. 
-                                The log is corrupt and there is no path forward. 
-                                There is some hope that if this server starts back
-                                up with an empty log the cluster will be able 
-                                to catch it up.");
-                        panic!("Corrupt config log entry.")
-                    }
+        self.get_cluster_quorums().map(|quorums| {
+            let mut servers: Vec<(u64, SocketAddr)> = Vec::new();
+            for server in quorums.into_iter().flat_map(|quorum| quorum.into_iter()) {
+                if !servers.iter().any(|&(id, _)| id == server.0) {
+                    servers.push(server);
                 }
-            });
+            }
+            servers
+        })
+    }
 
-        match option {
-            Some((servers, index)) => {
-                // cache the index for future lookups
-                self.most_recent_cluster = Some(index);
-                Some(servers)
+    /// Gets the sets of servers that each need a majority to commit an entry or elect a leader
+    /// under the most recent cluster config stored in the log. That's one set, unless we're
+    /// partway through a joint consensus change, which needs both the old and the new set to
+    /// agree.
+    ///
+    /// #Panics
+    /// * Panics if the log is corrupt
+    pub fn get_cluster_quorums(&mut self) -> Option<Vec<Vec<(u64, SocketAddr)>>> {
+        match self.configs.last() {
+            Some(e) => match config_quorums(&e.op) {
+                Some(quorums) => Some(quorums),
+                None => {
+                    error!("Config log entry didn't have a server list. 
+                            The log is corrupt and there is no path forward. 
+                            There is some hope that if this server starts back
+                            up with an empty log the cluster will be able 
+                            to catch it up.");
+                    panic!("Corrupt config log entry.")
+                }
             },
-            None => None
+            // the most recent config may have been compacted into our snapshot
+            None => self.snapshot.as_ref().and_then(|s| s.quorums())
+        }
+    }
+
+    /// Gets the learners in the most recent cluster config. They get every entry but don't vote
+    /// or count towards commits.
+    ///
+    /// #Panics
+    /// * Panics if the log is corrupt
+    pub fn get_cluster_learners(&mut self) -> Vec<(u64, SocketAddr)> {
+        if self.get_cluster_quorums().is_none() { return vec![]; }
+        match self.configs.last() {
+            Some(e) => config_learners(&e.op).unwrap(),
+            None => self.snapshot.as_ref().map_or(vec![], |s| s.learners.clone())
+        }
+    }
+
+    ///
+    /// Gets the quorums of the cluster config that was in effect as of |index|. That is the
+    /// most recent config entry at or before |index|, or the config stored in our snapshot if
+    /// there is no such entry in the log.
+    ///
+    pub fn get_cluster_quorums_at(&self, index: usize) -> Option<Vec<Vec<(u64, SocketAddr)>>> {
+        self.configs.iter()
+            .rev()
+            .skip_while(|e| e.index > index)
+            .filter_map(|e| config_quorums(&e.op))
+            .next()
+            .or_else(|| self.snapshot.as_ref().and_then(|s| s.quorums()))
+    }
+
+    ///
+    /// Gets the learners of the cluster config that was in effect as of |index|.
+    ///
+    pub fn get_cluster_learners_at(&self, index: usize) -> Vec<(u64, SocketAddr)> {
+        self.configs.iter()
+            .rev()
+            .skip_while(|e| e.index > index)
+            .filter_map(|e| config_learners(&e.op))
+            .next()
+            .or_else(|| self.snapshot.as_ref().map(|s| s.learners.clone()))
+            .unwrap_or(vec![])
+    }
+
+    ///
+    /// Discards every entry up to and including |snapshot.last_included_index|, since they are
+    /// now covered by |snapshot|, and moves |start_index| past them.
+    /// If our entry at that index doesn't match the snapshot's term the rest of the log can't
+    /// be trusted either, so the entire log is discarded.
+    /// Returns this log object.
+    ///
+    /// Blocks until the discarded entries have been removed from storage.
+    ///
+    /// #Errors
+    /// Returns an IO error if we're unable to compact the log in storage. The log is left
+    /// untouched in that case.
+    ///
+    pub fn compact(&mut self, snapshot: SnapshotMetadata) -> Result<&Log> {
+        if snapshot.last_included_index < self.start_index {
+            return Ok(self); // already compacted
         }
+
+        // the background thread may still be writing entries that we're about to move
+        self.flush_background_thread();
+
+        let conflicts = self.get_term(snapshot.last_included_index) != Some(snapshot.last_included_term);
+        let num_discarded = if conflicts {
+            self.terms.len()
+        } else {
+            snapshot.last_included_index + 1 - self.start_index
+        };
+
+        {
+            let mut storage = self.storage.lock().unwrap();
+            storage.compact(&snapshot)?;
+            if conflicts {
+                // the snapshot may not cover the rest of the log, which can't be trusted either
+                storage.truncate(snapshot.last_included_index)?;
+            }
+        }
+
+        self.terms.drain(.. num_discarded);
+        self.start_index = snapshot.last_included_index + 1;
+        if conflicts {
+            self.cache.clear();
+        }
+        let start_index = self.start_index;
+        while self.cache.front().map_or(false, |e| e.index < start_index) {
+            self.cache.pop_front();
+        }
+        self.configs.retain(|e| e.index >= start_index);
+        self.persisted_index.store(self.get_last_entry_index(), AtomicOrdering::SeqCst);
+        self.snapshot = Some(snapshot);
+        Ok(self)
     }
 
     ///
@@ -399,51 +743,136 @@ impl Log {
         }
     }
 
-    fn background_thread_repl(mut file: File, to_main_thread: Sender<MainThreadMessage>, to_log_thread: Sender<BackgroundThreadReply>,
-                              from_log_thread: Receiver<BackgroundThreadMessage>, entry_offsets: Arc<Mutex<Vec<u64>>>) {
+    fn background_thread_repl(storage: SharedStorage, persisted_index: Arc<AtomicUsize>, durability: Durability,
+                              clock: Arc<Clock>, to_main_thread: Sender<MainThreadMessage>, to_log_thread: Sender<BackgroundThreadReply>,
+                              from_log_thread: Receiver<BackgroundThreadMessage>) {
+        // a message we received while gathering up entries to write, which we still have to handle
+        let mut pending = None;
+        // Under Durability::SyncInterval, when we last synced, and when we have to sync the
+        // entries we've written since then, if there are any
+        let mut last_sync = clock.now();
+        let mut sync_deadline: Option<Instant> = None;
         loop {
-            match from_log_thread.recv().unwrap() {
+            if sync_deadline.map_or(false, |deadline| clock.now() >= deadline) {
+                let index = Log::background_sync(&storage);
+                to_main_thread.send(MainThreadMessage::EntryPersisted(index)).unwrap();
+                last_sync = clock.now();
+                sync_deadline = None;
+            }
+
+            let message = match (pending.take(), sync_deadline) {
+                (Some(message), _) => message,
+                (None, Some(deadline)) => {
+                    let now = clock.now();
+                    let timeout = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+                    match from_log_thread.recv_timeout(timeout) {
+                        // time to sync, unless a simulated clock hasn't gotten there yet
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        message => message.unwrap()
+                    }
+                },
+                (None, None) => from_log_thread.recv().unwrap()
+            };
+            match message {
                 BackgroundThreadMessage::Flush => {
                     // flush should not be followed by any messages until we reply
                     debug_assert_eq!(from_log_thread.try_recv().unwrap_err(), TryRecvError::Empty);
                     to_log_thread.send(BackgroundThreadReply::Flushed).unwrap();
                 },
                 BackgroundThreadMessage::AppendEntry(e) => {
-                    // Panic if we can't write the entry after trying for MAX_RETRIES
-                    let index = e.index;
-                    match Log::background_append_entry(&mut file, e, &entry_offsets) {
-                        Ok(_) => to_main_thread.send(MainThreadMessage::EntryPersisted(index)).unwrap(),
+                    // Write every entry that's queued up behind this one as well, so that they
+                    // all share one sync to disk
+                    let mut entries = vec![e];
+                    loop {
+                        match from_log_thread.try_recv() {
+                            Ok(BackgroundThreadMessage::AppendEntry(e)) => entries.push(e),
+                            Ok(message) => { pending = Some(message); break; },
+                            Err(_) => break
+                        }
+                    }
+
+                    // Panic if we can't write the entries after trying for MAX_RETRIES
+                    let index = entries.last().unwrap().index;
+                    match Log::background_append_entries(&storage, &entries) {
+                        // the entries can be read back from storage now, even if they aren't synced yet
+                        Ok(_) => persisted_index.store(index, AtomicOrdering::SeqCst),
                         Err(e) => {
-                            error!("Unable to write to log file. This is unrecoverable, and the server will shut down. {}", e);
-                            panic!("Unable to write to log file.");
+                            error!("Unable to write to log storage. This is unrecoverable, and the server will shut down. {}", e);
+                            panic!("Unable to write to log storage.");
                         }
                     };
+                    match durability {
+                        Durability::SyncEveryWrite => {
+                            let index = Log::background_sync(&storage);
+                            to_main_thread.send(MainThreadMessage::EntryPersisted(index)).unwrap();
+                        },
+                        Durability::SyncInterval(interval) => {
+                            sync_deadline = sync_deadline.or(Some(last_sync + interval));
+                        },
+                        Durability::OsBuffered => {
+                            to_main_thread.send(MainThreadMessage::EntryPersisted(index)).unwrap();
+                        }
+                    }
+                },
+                BackgroundThreadMessage::Written => {
+                    if let Durability::SyncInterval(interval) = durability {
+                        sync_deadline = sync_deadline.or(Some(last_sync + interval));
+                    }
                 },
-                BackgroundThreadMessage::Shutdown => break
+                BackgroundThreadMessage::Shutdown => {
+                    // nobody is waiting to hear about these, but they shouldn't wait on the OS
+                    if sync_deadline.is_some() {
+                        Log::background_sync(&storage);
+                    }
+                    break;
+                }
             }
         }
     }
 
-    /// Tries to write e to file. Puts its start offset into entry_offsets if sucessful.
+    /// Syncs every entry in storage to disk, and returns the index of the last one.
+    ///
+    /// #Panics
+    /// Panics if the sync fails. We can't retry, since the OS may have dropped the writes that
+    /// failed to sync, leaving holes in the log.
+    fn background_sync(storage: &SharedStorage) -> usize {
+        let mut storage = storage.lock().unwrap();
+        if let Err(e) = storage.sync() {
+            error!("Unable to sync log storage. This is unrecoverable, and the server will shut down. {}", e);
+            panic!("Unable to sync log storage.");
+        }
+        storage.last_index()
+    }
+
+    /// Tries to append |entries| to storage.
     ///
     /// #Error
-    /// Returns an IO error if it fails to write the entry after 5 retries or if
-    /// it fails to flush the entry to disk after writing it
+    /// Returns an IO error if it fails to append the entries after 5 retries
     ///
     /// #Panics
-    /// Panics if the offsets vec lock is posioned
-    fn background_append_entry(file: &mut File, e: Entry, entry_offsets: &Arc<Mutex<Vec<u64>>>) -> Result<()> {
-        const MAX_RETRIES: u32 = 5; // maximum times to try writing an entry before giving up
+    /// Panics if the storage lock is posioned
+    fn background_append_entries(storage: &SharedStorage, entries: &[Entry]) -> Result<()> {
+        const MAX_RETRIES: u32 = 5; // maximum times to try writing entries before giving up
         let retry_wait_time = Duration::from_millis(50);
+        let first_index = entries[0].index;
 
         for i in 0..MAX_RETRIES {
-            match Log::write_entry_to_disk(file, &e, &entry_offsets) {
+            let result = {
+                let mut storage = storage.lock().unwrap();
+                // a failed attempt may have written some of the entries without syncing them
+                if i > 0 && storage.last_index() >= first_index {
+                    storage.truncate(first_index - 1).and_then(|_| storage.append(entries))
+                } else {
+                    storage.append(entries)
+                }
+            };
+            match result {
                 Ok(_) => {
                     break;
                 },
                 Err(e) => {
                     if i != MAX_RETRIES - 1 {
-                        warn!("Unable to write to log file. Will retry.");
+                        warn!("Unable to write to log storage. Will retry.");
                         thread::sleep(retry_wait_time * (i + 1));
                     } else {
                         return Err(e)
@@ -452,72 +881,8 @@ impl Log {
             }
         }
 
-        file.sync_all()
-    }
-
-    /// Takes in a file (assumed to be pointed at EOF) and writes the given entry to the file.
-    ///
-    /// #Panics
-    /// Panics if the offsets vec lock is poisioned
-    ///
-    fn write_entry_to_disk(file: &mut File, e: &Entry, entry_offsets: &Arc<Mutex<Vec<u64>>>) -> Result<()> {
-        let metadata = file.metadata()?;
-        let mut writer = BufWriter::new(file);
-        let cursor = metadata.len();
-
-        let mut builder = message::Builder::new_default();
-        e.into_proto(&mut builder.init_root::<entry::Builder>());
-        serialize_packed::write_message(&mut writer, &builder)?;
-
-        entry_offsets.lock().unwrap().push(cursor);
         Ok(())
     }
-
-    /// Reads all entries from the file into the returned entry vector and
-    /// stores their byte offets in the offsets vector.
-    /// After this function returns the file's cursor will point to EOF
-    ///
-    /// #Errors
-    /// * Returns an IO error if there are errors reading the from the file.
-    /// * Returns a std::io::ErrorKind::InvalidData error if the data in the file is corrupt
-    /// * Returns an IO error if there are gaps in the log
-    ///
-    /// Does not currently check for corrupted entries that are valid
-    /// capnproto entries. We could add checksums to do this.
-    fn read_entries(f: &mut File) -> Result<(Vec<Entry>, Vec<u64>, Option<usize>)> {
-        let mut cursor: u64 = 0;
-        let mut offsets = vec![];
-        let mut entries = vec![];
-
-        let metadata = f.metadata()?;
-        let len = metadata.len();
-        let mut buf_reader = BufReader::new(f);
-        let mut most_recent_cluster = None;
-        while cursor < len {
-            let entry_offset = cursor;
-            let entry = serialize_packed::read_message(&mut buf_reader, ReaderOptions::new())
-            .map_err(|_| Error::new(ErrorKind::InvalidData, "Corrupt captain proto entry while reading"))
-            .and_then(|buf| {
-                buf_reader.seek(SeekFrom::Current(0))
-                .map(|offset| {
-                    cursor = offset;
-                })?;
-
-                buf.get_root::<entry::Reader>()
-                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Corrupt captain proto entry while converting"))
-                    .map(|e| Entry::from_proto(e))
-            })?;
-            if let raft_command::Request::SetConfig(_) = entry.op {
-                most_recent_cluster = Some(entry.index);
-            }
-            // TODO Ensure that there are no holes in the log
-            // and that it is in order
-            entries.push(entry);
-            offsets.push(entry_offset);
-        }
-
-        Ok((entries, offsets, most_recent_cluster))
-    }
 }
 
 impl Drop for Log {
@@ -542,26 +907,66 @@ pub mod mocks {
     use super::*;
     use std::fs;
     use rand::{thread_rng, Rng};
-    use std::sync::mpsc::{Receiver};
+    use simulation::sync::Mutex;
+    use simulation::sync::mpsc::{Receiver};
+    use std::sync::Arc;
     use super::super::MainThreadMessage;
+    use super::super::clock::{Clock, SystemClock};
+    use super::super::snapshot::snapshot_filename;
+    use super::super::storage::{Storage, FileStorage, SharedStorage};
+    use super::super::super::common::constants;
 
 
-    /// Wrapper around a test log file that cleans up the file when dropped..
+    /// Wrapper around a test log directory that cleans up its files when dropped..
     pub struct MockLogFileHandle{
         pub name: String,
+        pub state_name: String,
+        // The storage behind the log, which also holds our term and vote
+        pub storage: SharedStorage,
         pub main_thread_rx: Receiver<MainThreadMessage>
     }
 
-    /// Returns a new Log and a handle to the file backing that log on disk.
-    /// The file will be automatically deleted when the MockLogFileHandle goes out of scope,
+    /// Returns a new Log and a handle to the files backing that log on disk.
+    /// The files will be automatically deleted when the MockLogFileHandle goes out of scope,
     /// so take care to keep it in scope as long as the Log 
     pub fn new_mock_log() -> (Log, MockLogFileHandle) {
+        open_mock_log(constants::DEFAULT_ENTRY_CACHE_SIZE, Durability::default(), Arc::new(SystemClock))
+    }
+
+    /// Returns a new Log that keeps only the last |cache_size| entries in memory
+    pub fn new_mock_log_with_cache_size(cache_size: usize) -> (Log, MockLogFileHandle) {
+        open_mock_log(cache_size, Durability::default(), Arc::new(SystemClock))
+    }
+
+    /// Returns a new Log that syncs entries to disk as |durability| says
+    pub fn new_mock_log_with_durability(durability: Durability) -> (Log, MockLogFileHandle) {
+        open_mock_log(constants::DEFAULT_ENTRY_CACHE_SIZE, durability, Arc::new(SystemClock))
+    }
+
+    /// Returns a new Log that syncs entries to disk as |durability| says, timed against |clock|
+    pub fn new_mock_log_with_clock(durability: Durability, clock: Arc<Clock>) -> (Log, MockLogFileHandle) {
+        open_mock_log(constants::DEFAULT_ENTRY_CACHE_SIZE, durability, clock)
+    }
+
+    fn open_mock_log(cache_size: usize, durability: Durability, clock: Arc<Clock>) -> (Log, MockLogFileHandle) {
         const LOG_FILENAME_LEN: usize = 20;
         let mut log_filename: String = thread_rng().gen_ascii_chars().take(LOG_FILENAME_LEN).collect();
         log_filename = String::from("/tmp/") + &log_filename;
+        let state_filename = log_filename.clone() + ".state";
 
+        let storage = FileStorage::new(&state_filename, &log_filename,
+                                       constants::DEFAULT_LOG_SEGMENT_SIZE).unwrap();
+        let storage: SharedStorage = Arc::new(Mutex::new(Box::new(storage) as Box<Storage>));
         let (tx, rx) = channel();
-        (Log::new_from_filename(&log_filename, tx).unwrap(), MockLogFileHandle {name: log_filename, main_thread_rx: rx})
+        (Log::new(storage.clone(), cache_size, durability, clock, tx).unwrap(),
+         MockLogFileHandle {name: log_filename, state_name: state_filename, storage: storage, main_thread_rx: rx})
+    }
+
+    /// Opens the files behind |handle| as a second Log, to check what made it to disk.
+    pub fn reopen_mock_log(handle: &MockLogFileHandle, to_main_thread: Sender<MainThreadMessage>) -> Result<Log> {
+        let storage = FileStorage::new(&handle.state_name, &handle.name, constants::DEFAULT_LOG_SEGMENT_SIZE)?;
+        Log::new(Arc::new(Mutex::new(Box::new(storage) as Box<Storage>)), constants::DEFAULT_ENTRY_CACHE_SIZE,
+                 Durability::default(), Arc::new(SystemClock), to_main_thread)
     }
 
     pub fn new_random_with_term(size: usize, term: u64) -> (Log, MockLogFileHandle) {
@@ -573,7 +978,10 @@ pub mod mocks {
 
     impl Drop for MockLogFileHandle {
         fn drop (&mut self) {
-            fs::remove_file(&self.name).unwrap();
+            fs::remove_dir_all(&self.name).unwrap();
+            fs::remove_file(&self.state_name).unwrap();
+            // only there if the log was snapshotted
+            let _ = fs::remove_file(snapshot_filename(&self.name));
         }
     }
 }
@@ -586,11 +994,16 @@ mod tests {
     use super::super::super::raft_capnp::entry;
     use super::{Log, random_entry, random_entry_with_term, random_entries_with_term};
     use super::Entry;
-    use super::mocks::{new_mock_log, MockLogFileHandle};
+    use super::mocks::{new_mock_log, new_mock_log_with_cache_size, new_mock_log_with_durability,
+                       new_mock_log_with_clock, reopen_mock_log, MockLogFileHandle};
     use super::super::super::common::{raft_command};
     use super::super::MainThreadMessage;
-    use std::sync::mpsc::channel;
+    use simulation::Scheduler;
+    use super::super::snapshot::{SnapshotFile, SnapshotMetadata, snapshot_filename};
+    use super::super::storage::Durability;
+    use simulation::sync::mpsc::channel;
     use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+    use std::time::Duration;
     use rand::{thread_rng, Rng};
     
     fn create_filled_log (length: usize) -> (Log, MockLogFileHandle) {
@@ -710,6 +1123,40 @@ mod tests {
         log.roll_back(1).unwrap();
     }
 
+    #[test]
+    fn finds_first_and_last_index_of_term() {
+        let (mut log, _file_handle) = new_mock_log();
+        log.append_entries_blocking(random_entries_with_term(3, 1)).unwrap();
+        log.append_entries_blocking(random_entries_with_term(4, 3)).unwrap();
+        log.append_entries_blocking(random_entries_with_term(2, 4)).unwrap();
+        assert_eq!(log.first_index_of_term(1), Some(1));
+        assert_eq!(log.last_index_of_term(1), Some(3));
+        assert_eq!(log.first_index_of_term(3), Some(4));
+        assert_eq!(log.last_index_of_term(3), Some(7));
+        assert_eq!(log.first_index_of_term(4), Some(8));
+        assert_eq!(log.last_index_of_term(4), Some(9));
+        assert_eq!(log.first_index_of_term(2), None);
+        assert_eq!(log.last_index_of_term(2), None);
+        assert_eq!(log.last_index_of_term(5), None);
+    }
+
+    #[test]
+    fn last_index_of_term_includes_snapshot() {
+        let (mut log, _file_handle) = new_mock_log();
+        log.append_entries_blocking(random_entries_with_term(3, 1)).unwrap();
+        log.append_entries_blocking(random_entries_with_term(2, 2)).unwrap();
+        log.compact(SnapshotMetadata {
+            last_included_index: 3,
+            last_included_term: 1,
+            config: None,
+            joint_config: None,
+            learners: vec![]
+        }).unwrap();
+        assert_eq!(log.last_index_of_term(1), Some(3));
+        assert_eq!(log.first_index_of_term(1), None);
+        assert_eq!(log.first_index_of_term(2), Some(4));
+    }
+
     #[test]
     fn is_other_log_valid_rejects_previous_terms() {
         let (l, _file_handle) = new_mock_log();
@@ -771,9 +1218,9 @@ mod tests {
         log.flush_background_thread();
 
         let (tx, _rx) = channel();
-        let log_from_disk = Log::new_from_filename(&file_handle.name, tx).unwrap();
+        let log_from_disk = reopen_mock_log(&file_handle, tx).unwrap();
         assert_eq!(log_from_disk.get_last_entry_index(), 1);
-        assert_eq!(*log_from_disk.get_entry(1).unwrap(), entry);
+        assert_eq!(log_from_disk.get_entry(1).unwrap(), entry);
     }
 
     #[test]
@@ -785,9 +1232,90 @@ mod tests {
         assert!(matches!(msg, MainThreadMessage::EntryPersisted(1)));
 
         let (tx, _rx) = channel();
-        let log_from_disk = Log::new_from_filename(&file_handle.name, tx).unwrap();
+        let log_from_disk = reopen_mock_log(&file_handle, tx).unwrap();
         assert_eq!(log_from_disk.get_last_entry_index(), 1);
-        assert_eq!(*log_from_disk.get_entry(1).unwrap(), entry);
+        assert_eq!(log_from_disk.get_entry(1).unwrap(), entry);
+    }
+
+    #[test]
+    fn background_thread_group_commits_queued_entries() {
+        const LENGTH: usize = 100;
+        let (mut log, file_handle) = new_mock_log();
+        {
+            // The background thread can't write anything while we hold the storage lock, so by
+            // the time we let go every entry after the first one it picked up is queued behind it
+            let _storage = file_handle.storage.lock().unwrap();
+            for entry in random_entries_with_term(LENGTH, 1) {
+                log.append_entry(entry);
+            }
+        }
+        log.flush_background_thread();
+
+        // the queued entries share one sync, which is reported once
+        let mut reported = vec![];
+        while let Ok(MainThreadMessage::EntryPersisted(index)) = file_handle.main_thread_rx.try_recv() {
+            reported.push(index);
+        }
+        assert!(reported.len() <= 2, "Reported {:?}", reported);
+        assert!(reported.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(reported.last(), Some(&LENGTH));
+
+        let (tx, _rx) = channel();
+        assert_eq!(reopen_mock_log(&file_handle, tx).unwrap().get_last_entry_index(), LENGTH);
+    }
+
+    /// Checks that the main thread hears about |length| entries being persisted, however
+    /// they're synced
+    fn check_entries_persisted_with_durability(durability: Durability, length: usize) {
+        let (mut log, file_handle) = new_mock_log_with_durability(durability);
+        for entry in random_entries_with_term(length, 1) {
+            log.append_entry(entry);
+        }
+
+        let mut last_persisted = 0;
+        while last_persisted < length {
+            match file_handle.main_thread_rx.recv_timeout(Duration::from_secs(5)) {
+                Ok(MainThreadMessage::EntryPersisted(index)) => last_persisted = index,
+                _ => panic!("Only heard about {} of {} entries being persisted", last_persisted, length)
+            }
+        }
+        assert_eq!(last_persisted, length);
+    }
+
+    #[test]
+    fn sync_interval_reports_entries_once_synced() {
+        check_entries_persisted_with_durability(Durability::SyncInterval(Duration::from_millis(50)), 20);
+    }
+
+    #[test]
+    fn os_buffered_reports_entries_once_written() {
+        check_entries_persisted_with_durability(Durability::OsBuffered, 20);
+    }
+
+    #[test]
+    fn sync_interval_syncs_blocking_appends() {
+        let (mut log, file_handle) = new_mock_log_with_durability(Durability::SyncInterval(Duration::from_millis(50)));
+        log.append_entries_blocking(random_entries_with_term(3, 1)).unwrap();
+        let msg = file_handle.main_thread_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(msg, MainThreadMessage::EntryPersisted(3)));
+    }
+
+    #[test]
+    fn sync_interval_waits_on_the_log_clock() {
+        let scheduler = Scheduler::new(0);
+        Scheduler::enter(&scheduler);
+        {
+            let (mut log, file_handle) = new_mock_log_with_clock(
+                Durability::SyncInterval(Duration::from_millis(50)), scheduler.clone());
+            log.append_entries_blocking(random_entries_with_term(3, 1)).unwrap();
+            assert!(file_handle.main_thread_rx.recv_timeout(Duration::from_millis(49)).is_err());
+
+            let msg = file_handle.main_thread_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert!(matches!(msg, MainThreadMessage::EntryPersisted(3)));
+            // the log synced on the simulation's clock, without any real time going by
+            assert_eq!(scheduler.elapsed(), Duration::from_millis(50));
+        }
+        Scheduler::exit();
     }
 
     #[test]
@@ -797,12 +1325,46 @@ mod tests {
         log.append_entries_blocking(entries.clone()).unwrap();
 
         let (tx, _rx) = channel();
-        let log_from_disk = Log::new_from_filename(&file_handle.name, tx).unwrap();
+        let log_from_disk = reopen_mock_log(&file_handle, tx).unwrap();
         assert_eq!(log_from_disk.get_last_entry_index(), 8);
         assert_eq!(log_from_disk.get_entries_from(0).len(), entries.len());
         assert_eq!(log_from_disk.get_entries_from(0), &entries[..]);
     }
 
+    #[test]
+    fn reads_entries_that_left_the_cache_from_disk() {
+        let (mut log, _file_handle) = new_mock_log_with_cache_size(2);
+        let entries = random_entries_with_term(8, 1);
+        log.append_entries_blocking(entries.clone()).unwrap();
+        for &i in &[1, 4, 8] {
+            assert_eq!(log.get_entry(i).unwrap(), entries[i - 1]);
+        }
+        assert_eq!(log.get_entries_from(0), entries);
+        // a batch that starts on disk and ends in the cache
+        assert_eq!(log.get_entries_batch(4, 10, usize::max_value()), &entries[4 ..]);
+        assert_eq!(log.get_entries_batch(1, 2, usize::max_value()), &entries[1 .. 3]);
+
+        log.roll_back(5).unwrap();
+        log.append_entry(random_entry_with_term(2));
+        log.flush_background_thread();
+        assert_eq!(log.get_entries_from(0)[.. 5], entries[.. 5]);
+        assert_eq!(log.get_last_entry_term(), 2);
+        assert_eq!(log.last_index_of_term(1), Some(5));
+    }
+
+    #[test]
+    fn roll_back_then_append_writes_to_disk() {
+        let (mut log, file_handle) = new_mock_log();
+        log.append_entries_blocking(random_entries_with_term(8, 1)).unwrap();
+        log.roll_back(4).unwrap();
+        log.append_entries_blocking(random_entries_with_term(3, 2)).unwrap();
+
+        let (tx, _rx) = channel();
+        let log_from_disk = reopen_mock_log(&file_handle, tx).unwrap();
+        assert_eq!(log_from_disk.get_last_entry_index(), 7);
+        assert_eq!(log_from_disk.get_entries_from(0), log.get_entries_from(0));
+    }
+
     fn cluster_config_with_servers(num_servers: usize) -> (Entry, Vec<(u64, SocketAddr)>) {
         let mut rng = thread_rng();
         let servers: Vec<(u64, SocketAddr)> = (0..num_servers)
@@ -818,7 +1380,7 @@ mod tests {
         (Entry {
             index: 0,
             term: 1,
-            op: raft_command::Request::SetConfig(servers.clone())
+            op: raft_command::Request::SetConfig(servers.clone(), vec![])
        }, servers)
     }
 
@@ -879,4 +1441,148 @@ mod tests {
             assert_eq!(scoped_log.get_cluster_config(), None);
         }
     }
+
+    #[test]
+    fn tracks_joint_cluster_config() {
+        let (mut log, _file_handle) = new_mock_log();
+        let (_, old_servers) = cluster_config_with_servers(3);
+        let (_, mut new_servers) = cluster_config_with_servers(2);
+        // servers can be in both configs
+        new_servers.push(old_servers[0]);
+        let mut entries = random_entries_with_term(3, 1);
+        entries.insert(1, Entry {
+            index: 0,
+            term: 1,
+            op: raft_command::Request::SetJointConfig(old_servers.clone(), new_servers.clone(), vec![])
+        });
+        log.append_entries_blocking(entries).unwrap();
+        assert_eq!(log.get_cluster_quorums(), Some(vec![old_servers.clone(), new_servers.clone()]));
+        let mut all_servers = old_servers.clone();
+        all_servers.extend_from_slice(&new_servers[.. 2]);
+        assert_eq!(log.get_cluster_config(), Some(all_servers));
+
+        let metadata = snapshot_of(&log, 3);
+        assert_eq!(metadata.joint_config.as_ref(), Some(&new_servers));
+        log.compact(metadata).unwrap();
+        assert_eq!(log.get_cluster_quorums(), Some(vec![old_servers, new_servers]));
+    }
+
+    /// Describes a snapshot of |log| up to and including |index|
+    fn snapshot_of(log: &Log, index: usize) -> SnapshotMetadata {
+        let mut quorums = log.get_cluster_quorums_at(index).unwrap_or(vec![]).into_iter();
+        SnapshotMetadata {
+            last_included_index: index,
+            last_included_term: log.get_term(index).unwrap(),
+            config: quorums.next(),
+            joint_config: quorums.next(),
+            learners: log.get_cluster_learners_at(index)
+        }
+    }
+
+    #[test]
+    fn tracks_cluster_learners() {
+        let (mut log, _file_handle) = new_mock_log();
+        let (_, servers) = cluster_config_with_servers(3);
+        let (_, learners) = cluster_config_with_servers(2);
+        assert_eq!(log.get_cluster_learners(), vec![]);
+        let mut entries = random_entries_with_term(3, 1);
+        entries.insert(1, Entry {
+            index: 0,
+            term: 1,
+            op: raft_command::Request::SetConfig(servers.clone(), learners.clone())
+        });
+        log.append_entries_blocking(entries).unwrap();
+        // learners aren't part of any quorum
+        assert_eq!(log.get_cluster_config(), Some(servers));
+        assert_eq!(log.get_cluster_learners(), learners);
+
+        let metadata = snapshot_of(&log, 3);
+        assert_eq!(metadata.learners, learners);
+        log.compact(metadata).unwrap();
+        assert_eq!(log.get_cluster_learners(), learners);
+    }
+
+    #[test]
+    fn compact_discards_prefix() {
+        const LENGTH: usize = 10;
+        const SNAPSHOT_INDEX: usize = 4;
+        let (mut log, _file_handle) = create_filled_log(LENGTH);
+        let metadata = snapshot_of(&log, SNAPSHOT_INDEX);
+        log.compact(metadata).unwrap();
+
+        assert_eq!(log.get_start_index(), SNAPSHOT_INDEX + 1);
+        assert!(log.get_entry(SNAPSHOT_INDEX).is_none());
+        assert_eq!(log.get_entry(SNAPSHOT_INDEX + 1).unwrap().index, SNAPSHOT_INDEX + 1);
+        assert_eq!(log.get_term(SNAPSHOT_INDEX), Some(1));
+        assert_eq!(log.get_last_entry_index(), LENGTH);
+        assert_eq!(log.get_entries_from(0).len(), LENGTH - SNAPSHOT_INDEX);
+    }
+
+    #[test]
+    fn compacted_log_appends_after_last_entry() {
+        const LENGTH: usize = 5;
+        let (mut log, _file_handle) = create_filled_log(LENGTH);
+        let metadata = snapshot_of(&log, LENGTH);
+        log.compact(metadata).unwrap();
+        assert_eq!(log.get_last_entry_index(), LENGTH);
+        assert_eq!(log.get_last_entry_term(), 1);
+
+        log.append_entries_blocking(random_entries_with_term(2, 2)).unwrap();
+        assert_eq!(log.get_entry(LENGTH + 1).unwrap().index, LENGTH + 1);
+        assert_eq!(log.get_last_entry_index(), LENGTH + 2);
+        assert_eq!(log.get_last_entry_term(), 2);
+    }
+
+    #[test]
+    fn compact_discards_log_that_conflicts_with_snapshot() {
+        let (mut log, _file_handle) = create_filled_log(10);
+        log.compact(SnapshotMetadata {
+            last_included_index: 5,
+            last_included_term: 3,
+            config: None,
+            joint_config: None,
+            learners: vec![]
+        }).unwrap();
+
+        assert_eq!(log.get_start_index(), 6);
+        assert!(log.get_entry(6).is_none());
+        assert_eq!(log.get_last_entry_index(), 5);
+        assert_eq!(log.get_last_entry_term(), 3);
+    }
+
+    #[test]
+    fn compacted_log_reloads_from_disk() {
+        const LENGTH: usize = 10;
+        const SNAPSHOT_INDEX: usize = 6;
+        let (mut log, file_handle) = new_mock_log();
+        log.append_entries_blocking(random_entries_with_term(LENGTH, 1)).unwrap();
+        let metadata = snapshot_of(&log, SNAPSHOT_INDEX);
+        log.compact(metadata.clone()).unwrap();
+        // make sure the background thread writes to the compacted file
+        log.append_entry(random_entry_with_term(2));
+        log.flush_background_thread();
+
+        // the server saves the snapshot before compacting the log
+        SnapshotFile::new_from_filename(&snapshot_filename(&file_handle.name)).save(&metadata, &[]).unwrap();
+        let (tx, _rx) = channel();
+        let log_from_disk = reopen_mock_log(&file_handle, tx).unwrap();
+        assert_eq!(log_from_disk.get_start_index(), SNAPSHOT_INDEX + 1);
+        assert_eq!(log_from_disk.get_last_entry_index(), LENGTH + 1);
+        assert_eq!(log_from_disk.get_entries_from(0), log.get_entries_from(0));
+    }
+
+    #[test]
+    fn compact_keeps_cluster_config() {
+        const NUM_SERVERS: usize = 3;
+        let (mut log, _file_handle) = new_mock_log();
+        let (config_entry, servers) = cluster_config_with_servers(NUM_SERVERS);
+        let mut entries = random_entries_with_term(5, 1);
+        entries.insert(1, config_entry);
+        log.append_entries_blocking(entries).unwrap();
+
+        let metadata = snapshot_of(&log, 4);
+        assert_eq!(metadata.config.as_ref(), Some(&servers));
+        log.compact(metadata).unwrap();
+        assert_eq!(log.get_cluster_config(), Some(servers));
+    }
 }
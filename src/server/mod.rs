@@ -1,16 +1,24 @@
+/// Where the server gets the time from
+pub mod clock;
+mod checksum;
 mod log;
 mod peer;
 mod state_machine;
 mod state_file;
+mod snapshot;
+/// Where the server keeps its log and the state it needs across restarts
+pub mod storage;
 use capnp;
 use rand;
 use raft_capnp::{append_entries, append_entries_reply,
+                 install_snapshot, install_snapshot_reply,
                  request_vote, request_vote_reply,
-                 client_request, entry};
-use capnp::message;
-use capnp::serialize_packed;
+                 timeout_now, timeout_now_reply,
+                 read_index_reply, client_request};
 use rpc::{RpcError};
-use rpc::server::{RpcObject, RpcServer};
+use rpc::client::Rpc;
+use rpc::server::RpcObject;
+use rpc::transport::{Transport, Listener};
 use client::state_machine::{RaftStateMachine, StateMachine};
 use common::{Config, RaftError,
              raft_command,
@@ -19,23 +27,28 @@ use common::{Config, RaftError,
 use common::constants;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
-use std::thread;
-use std::thread::JoinHandle;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::sync::Arc;
 use std::mem;
-use std::cmp::min;
-use std::collections::{HashMap, VecDeque};
-use std::io::{Error as IoError, BufWriter};
-use std::fs::{File, OpenOptions};
+use std::cmp::{min, max};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::{Error as IoError, ErrorKind, Read};
 use rand::distributions::{IndependentSample, Range};
-use rand::{thread_rng, Rng};
+use rand::{SeedableRng, StdRng};
+use self::clock::Clock;
 use self::log::{Log, Entry};
 use self::state_machine::{StateMachineMessage, state_machine_thread, StateMachineHandle};
-use self::peer::{Peer, PeerHandle, PeerThreadMessage, RequestVoteMessage, PeerState, NonVotingPeerState, PeerInfo};
-use self::state_file::StateFile;
+use self::peer::{Peer, PeerHandle, PeerThreadMessage, RequestVoteMessage, TimeoutNowMessage,
+                 PeerState, NonVotingPeerState, PeerInfo, FlowControl};
+use self::snapshot::{SnapshotMetadata, IncomingSnapshot};
+use self::storage::{Storage, FileStorage, HardState, SharedStorage};
+use simulation;
+use simulation::thread;
+use simulation::thread::JoinHandle;
+use simulation::sync::Mutex;
+use simulation::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
 
 pub type RpcHandlerPipe = Sender<Result<(), RaftError>>;
+pub type ReadIndexPipe = Sender<Result<usize, RaftError>>;
 
 /// Messages that can be sent to the main thread.
 /// *Reply messages encapsulate replies from peer machines, and
@@ -45,12 +58,27 @@ pub struct AppendEntriesReply {
     commit_index: usize,
     peer: PeerInfo,
     success: bool,
+    // seq of the AppendEntries this replies to
+    seq: u64,
+    // when we sent the AppendEntries this replies to
+    sent_at: Instant,
+    // (term, first index of that term) of the entry where the peer's log stopped matching
+    // ours, if it told us. The term is 0 if the peer's log ended before our entries started
+    conflict: Option<(u64, usize)>,
+    // set if this replies to an InstallSnapshot, which doesn't count towards the peer's
+    // window of in flight AppendEntries
+    snapshot: bool,
+    // set if we couldn't reach the peer, rather than it rejecting our entries. We don't
+    // retry until our next heartbeat, so a peer that's down doesn't keep us busy
+    unreachable: bool
 }
 
 #[derive(PartialEq, Clone, Copy)]
 pub struct RequestVoteReply {
     term: u64,
     vote_granted: bool,
+    pre_vote: bool,
+    peer: PeerInfo,
 }
 
 pub enum MainThreadMessage {
@@ -60,11 +88,20 @@ pub enum MainThreadMessage {
     Shutdown,
     EntryPersisted (usize),
     AddServer(PeerInfo, RpcHandlerPipe),
-    RemoveServer(PeerInfo, RpcHandlerPipe)
+    RemoveServer(PeerInfo, RpcHandlerPipe),
+    TransferLeadership(Option<u64>, RpcHandlerPipe),
+    SetConfig(Vec<PeerInfo>, RpcHandlerPipe),
+    AddLearner(PeerInfo, RpcHandlerPipe),
+    PromoteLearner(u64, RpcHandlerPipe),
+    DemoteToLearner(u64, RpcHandlerPipe),
+    // The leader of the given term asked us to start an election
+    TimeoutNow(u64),
+    ReadIndex(ReadIndexPipe)
 }
 
 // TODO: RW locks?
-// NB: State lock should never be acquired while holding the log lock
+// NB: State lock should never be acquired while holding the log lock, and nothing should be
+// acquired while holding the storage lock
 struct Server {
     // TODO: Rename properties?
     state: Arc<Mutex<ServerState>>,
@@ -76,17 +113,69 @@ struct ServerInfo {
     state_machine: StateMachineHandle,
     me: (u64, SocketAddr),
     heartbeat_timeout: Duration,
-    to_me: Sender<MainThreadMessage>
+    to_me: Sender<MainThreadMessage>,
+    // Same storage as the ServerState's, so peers can read our snapshot from it
+    storage: SharedStorage,
+    pre_vote: bool,
+    flow_control: FlowControl,
+    max_rounds_for_new_server: u32,
+    transport: Arc<Transport>,
+    // Same clock as the ServerState's, so peers can be started without the state lock
+    clock: Arc<Clock>
+}
+
+// Changes to the cluster config. The leader only has one of them in the log
+// but not yet commited at a time
+#[derive(Debug, Clone)]
+enum ClusterChange {
+    AddServer (PeerInfo),
+    RemoveServer (PeerInfo),
+    // Moves the cluster to the given servers, going through a joint config that needs
+    // a majority of both the old and the new servers first
+    SetConfig (Vec<PeerInfo>),
+    // Moves from a commited joint config to the given servers
+    LeaveJointConfig (Vec<PeerInfo>),
+    AddLearner (PeerInfo),
+    // Learners become voting servers, and voting servers become learners, as soon as the
+    // change is in the log
+    PromoteLearner (u64),
+    DemoteToLearner (u64)
+}
+
+// A leader handing leadership off to one of its peers
+#[derive(Debug, Clone)]
+struct LeadershipTransfer {
+    target: u64,
+    start_time: Instant,
+    // whether we've told the target to start its election yet
+    timeout_now_sent: bool,
+    pipe: RpcHandlerPipe
+}
+
+// A read that is waiting for a majority of the cluster to confirm we're still leader.
+// Once they have, it can be served as soon as the state machine applies |read_index|
+#[derive(Debug, Clone)]
+struct PendingRead {
+    read_index: usize,
+    start_time: Instant,
+    pipe: ReadIndexPipe
 }
 
 // States that each machine can be in!
 #[derive(Debug, Clone)]
 enum State {
-    Candidate { num_votes: usize, start_time: Instant },
+    // PreVote candidates haven't moved into the term they're campaigning for yet.
+    // |votes| holds the ids of every server that voted for us, including ourselves
+    Candidate { votes: HashSet<u64>, start_time: Instant, pre_vote: bool },
     Leader { 
         last_heartbeat: Instant,
-        pending_cluster_changes: VecDeque<(PeerInfo, RpcHandlerPipe)>,
-        uncommited_cluster_change: Option<(usize, RpcHandlerPipe)>
+        pending_cluster_changes: VecDeque<(ClusterChange, RpcHandlerPipe)>,
+        uncommited_cluster_change: Option<(usize, ClusterChange, RpcHandlerPipe)>,
+        leadership_transfer: Option<LeadershipTransfer>,
+        pending_reads: Vec<PendingRead>,
+        // Set once we've told a peer to start an election in this term. Servers vote for it
+        // without waiting out an election timeout, so our read lease can't be trusted anymore
+        lease_revoked: bool
     },
     Follower,
 }
@@ -102,8 +191,16 @@ pub struct ServerState {
     last_leader_contact: (Instant, Option<u64>),
     voted_for: Option<u64>,
     election_timeout: Duration,
-    state_file: StateFile,
-    peers: HashMap<u64, PeerHandle>
+    // (min, max) from our config. Every election timeout we pick falls between the two
+    election_timeout_range: (Duration, Duration),
+    // Where we persist our term and vote. Shared with the log
+    storage: SharedStorage,
+    // In id order, so that a simulation replays our rpcs to them in the same order
+    peers: BTreeMap<u64, PeerHandle>,
+    // Every timeout is measured against this clock
+    clock: Arc<Clock>,
+    // Picks our election timeouts. Seeded from our config, if it has a seed
+    rng: StdRng
 }
 
 /// 
@@ -120,32 +217,63 @@ impl ServerState {
     fn transition_to_candidate(&mut self, info: &mut ServerInfo, log: Arc<Mutex<Log>>) -> Result<(), IoError> {
         debug_assert!(matches!(self.current_state, State::Follower) ||
                       matches!(self.current_state, State::Candidate { .. }));
-        self.current_state = State::Candidate { start_time: Instant::now(), num_votes: 1 }; // we always start with 1 vote from ourselves
+        self.current_state = State::Candidate { start_time: self.clock.now(), votes: vote_for_self(info), pre_vote: false };
         self.current_term += 1;
         self.voted_for = Some(info.me.0); // vote for ourselves
-        self.election_timeout = generate_election_timeout();
-        self.state_file.save_state(state_file::State {term: self.current_term, voted_for: self.voted_for})?;
+        self.election_timeout = generate_election_timeout(self.election_timeout_range, &mut self.rng);
+        self.storage.lock().unwrap().save_hard_state(HardState {term: self.current_term, voted_for: self.voted_for})?;
+
+        if self.start_peers(info, &log) && self.peers.len() == 0 {
+            // We win an election if we're the only server in the cluster and have
+            // a valid config
+            self.transition_to_leader(info, log);
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Transitions into the PreVote phase of an election. We ask our peers if they would vote
+    /// for us in the next term, but don't move into that term or persist anything until a
+    /// majority of them say they would.
+    /// This should only be called if we're in the follower state or already in the candidate state
+    ///
+    fn transition_to_pre_candidate(&mut self, info: &mut ServerInfo, log: Arc<Mutex<Log>>) {
+        debug_assert!(matches!(self.current_state, State::Follower) ||
+                      matches!(self.current_state, State::Candidate { .. }));
+        self.current_state = State::Candidate { start_time: self.clock.now(), votes: vote_for_self(info), pre_vote: true };
+        self.election_timeout = generate_election_timeout(self.election_timeout_range, &mut self.rng);
+        self.start_peers(info, &log);
+    }
 
-        // start up peer threads
-        let cluster_config = log.lock().unwrap().get_cluster_config();
+    ///
+    /// Starts up peer threads for every other server and learner in the latest config in our log.
+    /// Returns false and leaves our peers alone if our log doesn't have a config yet.
+    ///
+    fn start_peers(&mut self, info: &ServerInfo, log: &Arc<Mutex<Log>>) -> bool {
+        let (cluster_config, learners) = {
+            let mut log = log.lock().unwrap();
+            (log.get_cluster_config(), log.get_cluster_learners())
+        };
         match cluster_config {
             Some(config) => {
                 self.peers = config.into_iter()
                 .filter(|&(id, addr)| id != info.me.0)
                 .map(|(id, addr)| {
-                    (id, Peer::start((id, addr), info.to_me.clone(), None))
+                    (id, Peer::start((id, addr), info.to_me.clone(), None, info.storage.clone(),
+                                     info.flow_control, info.transport.clone(), info.clock.clone()))
                 })
                 .collect();
-                if self.peers.len() == 0 {
-                    // We win an election if we're the only server in the cluster and have
-                    // a valid config
-                    self.transition_to_leader(info, log);
+                for learner in learners.into_iter().filter(|&(id, _)| id != info.me.0) {
+                    let mut peer = Peer::start(learner, info.to_me.clone(), None, info.storage.clone(),
+                                               info.flow_control, info.transport.clone(), info.clock.clone());
+                    peer.state = PeerState::Learner;
+                    self.peers.insert(learner.0, peer);
                 }
+                true
             },
-            None => {/*No config. There's hope that if we wait we'll hear from a leader about a cluster config*/}
-        };
-
-        Ok(())
+            None => false /*No config. There's hope that if we wait we'll hear from a leader about a cluster config*/
+        }
     }
 
     ///
@@ -159,14 +287,24 @@ impl ServerState {
                 .get_last_entry_index()
         };
         self.current_state = State::Leader { 
-            last_heartbeat: Instant::now(),
+            last_heartbeat: self.clock.now(),
             pending_cluster_changes: VecDeque::new(),
-            uncommited_cluster_change: None
+            uncommited_cluster_change: None,
+            leadership_transfer: None,
+            pending_reads: Vec::new(),
+            lease_revoked: false
         };
+        let now = self.clock.now();
         for (_, peer) in &mut self.peers {
             peer.next_index = self.commit_index + 1;
+            // we don't know how much of our log the peer has, and replies to anything we sent
+            // it in an earlier term won't count towards our window
+            peer.match_index = 0;
+            peer.in_flight.clear();
+            peer.probing = true;
+            // give every peer a full election timeout to reach us before we count it as lost
+            peer.last_contact = now;
         }
-        self.commit_index = self.commit_index + 1;
         broadcast_append_entries(info, self, log.clone());
 
         trace!("Server {}: Became leader for term {}", info.me.0, self.current_term)
@@ -181,22 +319,21 @@ impl ServerState {
                               to_state_machine: &Sender<StateMachineMessage>,
                               voted_for: Option<u64>, log: Arc<Mutex<Log>>) -> Result<(), IoError> {
         debug_assert!(new_term >= self.current_term);
-        if let State::Leader {ref pending_cluster_changes, ref uncommited_cluster_change, ..} = self.current_state {
+        if let State::Leader {ref pending_cluster_changes, ref uncommited_cluster_change,
+                              ref leadership_transfer, ref pending_reads, ..} = self.current_state {
             to_state_machine.send(StateMachineMessage::Flush).unwrap();
             log.lock().unwrap().flush_background_thread();
 
             // Send NOT_LEADER errors to any clients waiting on pending cluster changes
             // they can retry with the new leader
-            let pipes = pending_cluster_changes
-                .iter()
-                .map(|&(_, ref pipe)| {
-                    pipe
-                })
-                .chain(pending_cluster_changes.iter().map(|&(_, ref pipe)| pipe));
-            for pipe in pipes {
-                warn!("Sending not leader!");
-                // TODO: Send leader guess
-                pipe.send(Err(RaftError::NotLeader(None)));
+            reject_cluster_changes(pending_cluster_changes, uncommited_cluster_change);
+            if let Some(ref transfer) = *leadership_transfer {
+                // Leadership has moved on, which is all the transfer was waiting for
+                let _ = transfer.pipe.send(Ok(()));
+            }
+            // We can't confirm these reads anymore
+            for read in pending_reads {
+                let _ = read.pipe.send(Err(RaftError::NotLeader(None)));
             }
         }
         // Drop peers
@@ -205,71 +342,247 @@ impl ServerState {
         self.voted_for = voted_for;
         self.current_term = new_term;
         self.current_state = State::Follower;
-        self.election_timeout = generate_election_timeout();
+        self.election_timeout = generate_election_timeout(self.election_timeout_range, &mut self.rng);
         
-        self.state_file.save_state(state_file::State {term: self.current_term, voted_for: self.voted_for})?;
+        self.storage.lock().unwrap().save_hard_state(HardState {term: self.current_term, voted_for: self.voted_for})?;
         Ok(())
     }
 }
 
 ///
-/// Updates the server's commit index to the median of our peers' indices.
+/// Sends NOT_LEADER errors to the clients waiting on any of the given cluster changes,
+/// so they can retry with the new leader.
+///
+fn reject_cluster_changes(pending_cluster_changes: &VecDeque<(ClusterChange, RpcHandlerPipe)>,
+                          uncommited_cluster_change: &Option<(usize, ClusterChange, RpcHandlerPipe)>) {
+    let pipes = pending_cluster_changes
+        .iter()
+        .map(|&(_, ref pipe)| pipe)
+        .chain(uncommited_cluster_change.iter().map(|&(_, _, ref pipe)| pipe));
+    for pipe in pipes {
+        warn!("Sending not leader!");
+        // TODO: Send leader guess
+        // the client may have given up on this change, so it's fine if the send fails
+        let _ = pipe.send(Err(RaftError::NotLeader(None)));
+    }
+}
+
+///
+/// Returns true if the server with |id| is a member of |config|.
+/// If we don't know of any config yet, we assume every server is a member.
+///
+fn is_in_config(id: u64, config: &Option<Vec<PeerInfo>>) -> bool {
+    config.as_ref().map_or(true, |servers| servers.iter().any(|&(server_id, _)| server_id == id))
+}
+
+///
+/// Returns the initial votes of an election, which only has our own vote.
+///
+fn vote_for_self(info: &ServerInfo) -> HashSet<u64> {
+    let mut votes = HashSet::new();
+    votes.insert(info.me.0);
+    votes
+}
+
+///
+/// Returns the ids of the servers in each of |quorums|. Committing an entry or winning an
+/// election takes a majority of every one of them.
+/// If we don't know of any config yet, we assume our voting peers and ourselves make up the cluster.
+///
+fn voting_quorums(me: u64, peers: &BTreeMap<u64, PeerHandle>,
+                  quorums: Option<Vec<Vec<PeerInfo>>>) -> Vec<Vec<u64>> {
+    match quorums {
+        Some(quorums) => quorums.into_iter()
+            .map(|quorum| quorum.into_iter().map(|(id, _)| id).collect())
+            .collect(),
+        None => {
+            let mut servers: Vec<u64> = peers.values()
+                .filter(|peer| matches!(peer.state, PeerState::Voting))
+                .map(|peer| peer.id)
+                .collect();
+            servers.push(me);
+            vec![servers]
+        }
+    }
+}
+
+///
+/// Returns true if |agrees| holds for a majority of the servers in every one of |quorums|.
+///
+fn has_majority<F>(quorums: &[Vec<u64>], agrees: F) -> bool where F: Fn(u64) -> bool {
+    quorums.iter().all(|quorum| quorum.iter().filter(|&&id| agrees(id)).count() > quorum.len() / 2)
+}
+
+///
+/// Returns our peer for the server with |id|, if it's allowed to vote.
+///
+fn voting_peer(peers: &BTreeMap<u64, PeerHandle>, id: u64) -> Option<&PeerHandle> {
+    peers.get(&id).and_then(|peer| if matches!(peer.state, PeerState::Voting) { Some(peer) } else { None })
+}
+
+///
+/// Updates the server's commit index to the highest index replicated on a majority of the
+/// servers in our current config. During a joint consensus change that index has to be
+/// replicated on a majority of both the old and the new servers.
+/// We only count ourselves if we're part of the config.
 ///
 /// #Panics
 /// Panics if called while not leader
 ///
 fn update_commit_index(server_info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) {
-    // Find median of all (voting) peer commit indices.
-    let mut indices: Vec<usize> = state.peers.iter()
-        .filter(|&(_,  peer)| matches!(peer.state, PeerState::Voting))
-        .map(|(_, peer)| peer.match_index).collect();
-    indices.sort();
-    let new_index;
-    if indices.len() == 0 {
-        // if there are no other servers in the cluster
-        // then our commit index should be in lockstep with our
-        // persisted entries
-        new_index = state.last_persisted_index;
-    } else {
-        new_index = *indices.get( indices.len() / 2 ).unwrap();
-    }
+    let quorums = voting_quorums(server_info.me.0, &state.peers, log.lock().unwrap().get_cluster_quorums());
+    let new_index = {
+        let match_index = |id: u64| {
+            if id == server_info.me.0 {
+                // our own entries count towards the majority once they've been persisted
+                state.last_persisted_index
+            } else {
+                voting_peer(&state.peers, id).map_or(0, |peer| peer.match_index)
+            }
+        };
+        quorums.iter()
+            .map(|quorum| {
+                // Find median of the quorum's indices.
+                let mut indices: Vec<usize> = quorum.iter().map(|&id| match_index(id)).collect();
+                if indices.len() == 0 { return 0; }
+                indices.sort();
+                indices[(indices.len() - 1) / 2]
+            })
+            .min()
+            .unwrap_or(0)
+    };
     // Set new commit index if it's higher and it has been persisted to disk!
     if new_index <= state.commit_index || new_index > state.last_persisted_index { return; }
+    // We only count replicas of entries from our own term. Entries from earlier terms are
+    // commited along with the first entry from our term after them. Otherwise a server that
+    // never got them could still win an election and overwrite them (Figure 8 in the Raft paper)
+    if log.lock().unwrap().get_term(new_index) != Some(state.current_term) { return; }
     state.commit_index = new_index;
     server_info.state_machine.tx.send(
         StateMachineMessage::Commit(state.commit_index)).unwrap();
 
-    let mut next_cluster_change;
-    match state.current_state {
-        State::Leader{ref mut pending_cluster_changes, ref mut uncommited_cluster_change, ..} => {
-            next_cluster_change = match *uncommited_cluster_change {
-                None => pending_cluster_changes.pop_front(),
-                Some((index, ref pipe)) => {
-                    if new_index >= index {
-                        // cluster change commited
-                        // notify rpc handler
-                        pipe.send(Ok(())).unwrap();
-                        pending_cluster_changes.pop_front()
-                    } else {
-                        None
-                    }
-                }
+    let commited_cluster_change = match state.current_state {
+        State::Leader{ref mut uncommited_cluster_change, ..} => {
+            let commited = match *uncommited_cluster_change {
+                Some((index, _, _)) => new_index >= index,
+                None => false
+            };
+            if commited {
+                uncommited_cluster_change.take().map(|(_, change, pipe)| (change, pipe))
+            } else {
+                None
             }
         },
         State::Follower | State::Candidate{..} => {
             panic!("Call to update_commit_index while not leader.");
         }
+    };
+
+    let commited_cluster_change = match commited_cluster_change {
+        Some((ClusterChange::SetConfig(servers), pipe)) => {
+            // Both the old and the new servers know about the joint config now, so we can
+            // move on to the new config. The client hears back once that commits
+            append_cluster_change(ClusterChange::LeaveJointConfig(servers), pipe, server_info, state, log);
+            return;
+        },
+        Some((change, pipe)) => {
+            // notify rpc handler. The client may have given up on the change
+            let _ = pipe.send(Ok(()));
+            Some(change)
+        },
+        None => None
+    };
+
+    match commited_cluster_change {
+        Some(ClusterChange::RemoveServer((id, _))) if id == server_info.me.0 => {
+            leave_cluster(server_info, state, log);
+            return;
+        },
+        Some(ClusterChange::DemoteToLearner(id)) if id == server_info.me.0 => {
+            // learners can't lead
+            leave_cluster(server_info, state, log);
+            return;
+        },
+        Some(ClusterChange::RemoveServer((id, _))) => {
+            if let Some(mut peer) = state.peers.remove(&id) {
+                // Let the server know its removal committed before we shutdown its peer thread.
+                // This is the last thing we send it, so don't wait on earlier requests
+                peer.in_flight.clear();
+                peer.append_entries_nonblocking(server_info.me.0, state.commit_index,
+                                                state.current_term, log.clone());
+            }
+        },
+        Some(ClusterChange::LeaveJointConfig(ref servers)) => {
+            if !servers.iter().any(|&(id, _)| id == server_info.me.0) {
+                leave_cluster(server_info, state, log);
+                return;
+            }
+            // Shutdown the peers of every server that didn't make it into the new config,
+            // once they know the config committed
+            let removed: Vec<u64> = state.peers.values()
+                .filter(|peer| matches!(peer.state, PeerState::Voting) &&
+                               !servers.iter().any(|&(id, _)| id == peer.id))
+                .map(|peer| peer.id)
+                .collect();
+            for id in removed {
+                let mut peer = state.peers.remove(&id).unwrap();
+                peer.in_flight.clear();
+                peer.append_entries_nonblocking(server_info.me.0, state.commit_index,
+                                                state.current_term, log.clone());
+            }
+        },
+        Some(ClusterChange::AddServer(_)) | Some(ClusterChange::SetConfig(_)) |
+        Some(ClusterChange::AddLearner(_)) | Some(ClusterChange::PromoteLearner(_)) |
+        Some(ClusterChange::DemoteToLearner(_)) | None => {}
+    };
+
+    let no_uncommited_change = match state.current_state {
+        State::Leader{ref uncommited_cluster_change, ..} => uncommited_cluster_change.is_none(),
+        State::Follower | State::Candidate{..} => false
+    };
+    // The last leader may have gone down partway through a joint consensus change. We have to
+    // finish it, otherwise we'd need both configs to agree from now on. We haven't appended a
+    // config in this term, so the joint config came before the entry we just commited under
+    // both majorities. Only now is it safe to move on to the new config
+    let joint_config = if no_uncommited_change {
+        log.lock().unwrap().get_cluster_quorums()
+            .and_then(|mut quorums| if quorums.len() > 1 { quorums.pop() } else { None })
+    } else {
+        None
+    };
+    if let Some(servers) = joint_config {
+        // Nobody is waiting to hear about this change
+        let (pipe, _) = channel();
+        append_cluster_change(ClusterChange::LeaveJointConfig(servers), pipe, server_info, state, log);
+        return;
     }
 
-    match next_cluster_change {
-        Some((peer, pipe)) => add_server_to_log(peer, pipe, server_info, state, log),
-        None => {
-            // we're not waiting on a cluster change
-            if let State::Leader{ref mut uncommited_cluster_change, ..} = state.current_state {
-                // always executes because the state is locked and we made it this far
-                *uncommited_cluster_change = None;
+    let next_cluster_change = match state.current_state {
+        State::Leader{ref mut pending_cluster_changes, ref uncommited_cluster_change, ..} => {
+            if uncommited_cluster_change.is_none() {
+                pending_cluster_changes.pop_front()
+            } else {
+                None
             }
-        }
+        },
+        State::Follower | State::Candidate{..} => None
+    };
+    if let Some((change, pipe)) = next_cluster_change {
+        append_cluster_change(change, pipe, server_info, state, log);
+    }
+}
+
+///
+/// Steps down once a config without us has commited. We make sure our peers hear that
+/// the config committed first, so the remaining servers can elect a new leader.
+///
+fn leave_cluster(server_info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) {
+    broadcast_append_entries(server_info, state, log.clone());
+    let (current_term, voted_for) = (state.current_term, state.voted_for);
+    match state.transition_to_follower(current_term, &server_info.state_machine.tx,
+                                       voted_for, log) {
+        Ok(_) => {},
+        Err(e) => error!("Unable to write to state file after leaving the cluster: {}", e)
     };
 }
 
@@ -277,7 +590,7 @@ pub struct ServerHandle {
     tx: Sender<MainThreadMessage>,
     thread: Option<JoinHandle<()>>,
     addr: SocketAddr,
-    rpc_server: Option<RpcServer>
+    rpc_server: Option<Box<Listener>>
 }
 
 impl ServerHandle {
@@ -297,24 +610,33 @@ impl ServerHandle {
 impl Drop for ServerHandle {
     /// Gracefully shuts down the raft server. Blocking until all incoming connections
     /// have been dealt with.
+    /// If this server is the leader it first hands leadership off to another server, so the
+    /// cluster doesn't have to wait out an election timeout.
     ///
     /// This should not be called from a thread that would deal with an incoming conncetion or else
     /// it could deadlock
     ///
-    /// #Panics
-    /// Panics if the main server thread has panicked
     fn drop (&mut self) {
         let thread = mem::replace(&mut self.thread, None);
         match thread {
             Some(t) => {
+                {
+                    // Transfer leadership while we can still serve client requests.
+                    // If we aren't leader, or the transfer fails, we shutdown anyways.
+                    // The main thread may have exited already, in which case there's nobody to ask
+                    let (to_me, from_main) = channel();
+                    let _ = self.tx.send(MainThreadMessage::TransferLeadership(None, to_me));
+                    let _ = from_main.recv();
+                }
                 {
                     // drop the rpc server (if it exists)
                     mem::replace(&mut self.rpc_server, None);
                 }
-                // send the shutdown message to the main thread
-                self.tx.send(MainThreadMessage::Shutdown).unwrap();
-                // join the main thread
-                t.join().unwrap();
+                // send the shutdown message to the main thread, unless it has already exited
+                let _ = self.tx.send(MainThreadMessage::Shutdown);
+                // join the main thread. If it panicked, it already reported why, and panicking
+                // again here would abort us if we're being dropped while unwinding
+                let _ = t.join();
             },
             None => {/* Nothing to shutdown*/}
         };
@@ -335,54 +657,94 @@ pub fn start_test_server<F> (id: u64, state_machine: F,
                          state_filename: &str, log_filename: &str,
                          relay_addr: Option<SocketAddr>) -> Result<ServerHandle, IoError> 
     where F: FnOnce() -> Box<RaftStateMachine> {
-    const HEARTBEAT_TIMEOUT: u64 = 75;
-
+    let config = Config::new (id,
+        "127.0.0.1:0",
+        Duration::from_millis(constants::DEFAULT_HEARTBEAT_TIMEOUT),
+        &state_filename,
+        &log_filename
+    );
+    let mut storage = FileStorage::new(state_filename, log_filename, config.log_segment_size)?;
     if let Some(addr) = relay_addr {
-        write_initial_config_to_log(&log_filename, id, relay_addr.unwrap())?;
+        write_initial_config(&mut storage, id, addr)?;
     }
+    start_server_with_storage(config, Box::new(storage), state_machine)
+}
 
-    let config = Config::new (id,
-        "127.0.0.1:0",
-        Duration::from_millis(HEARTBEAT_TIMEOUT),
+/// Starts a new test server that listens on |addr| and talks to the rest of the cluster over
+/// |transport|. Pass |first| for the first server in the cluster, so it starts out with a
+/// config that contains just itself.
+///
+/// This is mostly useful with a `ChannelNetwork`, which runs an entire cluster in-process.
+pub fn start_test_server_with_transport<F> (id: u64, state_machine: F, addr: SocketAddr, first: bool,
+                                            state_filename: &str, log_filename: &str,
+                                            transport: Arc<Transport>) -> Result<ServerHandle, IoError>
+    where F: FnOnce() -> Box<RaftStateMachine> {
+    let mut config = Config::new (id,
+        addr,
+        Duration::from_millis(constants::DEFAULT_HEARTBEAT_TIMEOUT),
         &state_filename,
         &log_filename
     );
-    start_server_with_config(config, state_machine)
+    config.transport = transport;
+    start_test_server_with_config(config, first, state_machine)
+}
+
+/// Starts a new test server from |config|. Pass |first| for the first server in the cluster, so
+/// it starts out with a config that contains just itself.
+///
+/// Simulations use this to give each server the simulation's Scheduler as its clock, and its own seed.
+pub fn start_test_server_with_config<F> (config: Config, first: bool, state_machine: F)
+    -> Result<ServerHandle, IoError>
+    where F: FnOnce() -> Box<RaftStateMachine> {
+    let mut storage = FileStorage::new(config.state_filename, config.log_filename, config.log_segment_size)?;
+    if first { write_initial_config(&mut storage, config.me.0, config.me.1)?; }
+    start_server_with_storage(config, Box::new(storage), state_machine)
 }
 
 /// Starts a new server running the raft consensus algorithim.
 ///
 /// TODO(jason): Take in filenames instead of using random ones, and only
 pub fn start_server(id: u64, state_machine: Box<StateMachine>, my_addr: SocketAddr, first: bool, state_filename: String, log_filename: String) -> Result<ServerHandle, IoError> {
-    const HEARTBEAT_TIMEOUT: u64 = 75;
     const STATE_FILENAME_LEN: usize = 20;
 
-    if first { write_initial_config_to_log(&log_filename, id, my_addr)?; }
-
     let state_machine = RaftStateMachine::new(state_machine);
     let config = Config::new (id,
         my_addr,
-        Duration::from_millis(HEARTBEAT_TIMEOUT),
+        Duration::from_millis(constants::DEFAULT_HEARTBEAT_TIMEOUT),
         &state_filename,
         &log_filename
     );
-    start_server_with_config(config, move || Box::new(state_machine))
+    let mut storage = FileStorage::new(&state_filename, &log_filename, config.log_segment_size)?;
+    if first { write_initial_config(&mut storage, id, my_addr)?; }
+    start_server_with_storage(config, Box::new(storage), move || Box::new(state_machine))
 }
 
-fn write_initial_config_to_log(log_filename: &str, id: u64, addr: SocketAddr) -> Result<(), IoError> {
-    // write the initial config to the log file
+/// Starts the empty log in |storage| with a config that contains just the server |id| at |addr|
+///
+/// #Errors
+/// Returns an InvalidInput error if |storage| already holds a snapshot or any entries
+fn write_initial_config(storage: &mut Storage, id: u64, addr: SocketAddr) -> Result<(), IoError> {
+    if storage.snapshot_metadata().is_some() || storage.last_index() != 0 {
+        return Err(IoError::new(ErrorKind::InvalidInput,
+                                "Only a server with an empty log can start a new cluster"));
+    }
     let entry = Entry {
         index: 1,
         term: 1, 
-        op: raft_command::Request::SetConfig(vec![(id, addr)])
+        op: raft_command::Request::SetConfig(vec![(id, addr)], vec![])
     };
-    let mut file = OpenOptions::new().write(true).read(false)
-                  .create(true).truncate(true).open(log_filename)?;
+    storage.append(&[entry])?;
+    storage.sync()
+}
 
-    let mut writer = BufWriter::new(&mut file);
-    let mut builder = message::Builder::new_default();
-    entry.into_proto(&mut builder.init_root::<entry::Builder>());
-    serialize_packed::write_message(&mut writer, &builder)
+///
+/// Returns how long leaders may serve reads locally after a majority acknowledges one of their
+/// heartbeats, or None if lease reads are disabled. Followers won't vote for a new leader for
+/// an election timeout after hearing from us, less whatever their clocks may drift by.
+///
+fn read_lease_duration(config: &Config) -> Option<Duration> {
+    if !config.lease_reads { return None; }
+    config.election_timeout_min.checked_sub(config.max_clock_drift)
 }
 
 ///
@@ -390,11 +752,22 @@ fn write_initial_config_to_log(log_filename: &str, id: u64, addr: SocketAddr) ->
 /// This is mostly just a bootstrapper for now. It probably won't end up in the public API
 ///
 pub fn start_server_with_config<F> (config: Config, load_state_machine: F) -> Result<ServerHandle, IoError>
+    where F: FnOnce() -> Box<RaftStateMachine> {
+    let storage = FileStorage::new(config.state_filename, config.log_filename, config.log_segment_size)?;
+    start_server_with_storage(config, Box::new(storage), load_state_machine)
+}
+
+///
+/// Starts up a new raft server with the given config, which keeps its log, term, vote and
+/// snapshots in |storage| instead of the files named in |config|.
+///
+pub fn start_server_with_storage<F> (config: Config, storage: Box<Storage>, load_state_machine: F)
+    -> Result<ServerHandle, IoError>
     where F: FnOnce() -> Box<RaftStateMachine> {
     let (tx, rx) = channel();
     let tx_clone = tx.clone();
-    let (server, rpc_server) = try!(Server::new(config, tx, load_state_machine()));
-    let addr = try!(rpc_server.get_local_addr());
+    let (server, rpc_server) = try!(Server::new(config, storage, tx, load_state_machine()));
+    let addr = rpc_server.local_addr();
 
     // start the server
     let join_handle = server.repl(rx);
@@ -429,80 +802,305 @@ fn handle_append_entries_reply(m: AppendEntriesReply, server_info: &mut ServerIn
                               it will likely be one on the next election if the
                               issue isn't fixed: {}", e)
         };
-    } else if m.success {
+        return;
+    } else if m.term < state.current_term {
+        // We sent this before our current term began, to a peer that has since been replaced
+        return;
+    }
+
+    if let Some(peer) = state.peers.get_mut(&m.peer.0) {
+        // This reply makes room for another AppendEntries in the peer's window
+        if !m.snapshot {
+            peer.in_flight.remove(&m.seq);
+        }
+        // A peer that rejects our entries while it catches up still follows us
+        if !m.unreachable && m.sent_at > peer.last_contact {
+            peer.last_contact = m.sent_at;
+        }
+    }
+    if m.success {
         // TODO: Handle non voting members here
-        // On success, advance peer's index.
+        // On success, advance peer's index. Replies to pipelined requests can arrive out of
+        // order, so we never move backwards
+        let max_rounds = server_info.max_rounds_for_new_server;
+        let max_round_time = state.election_timeout_range.0;
         state.peers.get_mut(&m.peer.0).map(|peer| {
-            debug_assert!(m.commit_index >= peer.next_index - 1);
-            peer.next_index = m.commit_index + 1;
-            peer.match_index = m.commit_index;
-            peer.advance_non_voting_peer_round(log.lock().unwrap().get_last_entry_index())
+            peer.next_index = max(peer.next_index, m.commit_index + 1);
+            peer.match_index = max(peer.match_index, m.commit_index);
+            peer.probing = false;
+            peer.advance_non_voting_peer_round(log.lock().unwrap().get_last_entry_index(),
+                                               max_rounds, max_round_time)
         })
         .map(|peer_state| {
             match peer_state {
                 NonVotingPeerState::TimedOut(pipe) => timeout_add_server(m.peer.0, pipe, state),
-                NonVotingPeerState::CaughtUp(pipe) => add_caught_up_server(m.peer, pipe, server_info, state, log),
+                NonVotingPeerState::CaughtUp(pipe) => add_caught_up_server(m.peer, pipe, server_info, state, log.clone()),
                 NonVotingPeerState::CatchingUp => {/* need to wait for another round */},
-                NonVotingPeerState::VotingPeer => update_commit_index(server_info, state, log)
+                NonVotingPeerState::VotingPeer => update_commit_index(server_info, state, log.clone())
             }
         });
+        advance_leadership_transfer(m.peer.0, server_info, state, log.clone());
+        confirm_pending_reads(server_info, state, log.clone());
+
+        // Keep the pipeline full while the peer is still missing entries
+        if !matches!(state.current_state, State::Leader{..}) { return; }
+        let last_log_index = log.lock().unwrap().get_last_entry_index();
+        let (leader_id, commit_index, current_term) = (server_info.me.0, state.commit_index, state.current_term);
+        if let Some(peer) = state.peers.get_mut(&m.peer.0) {
+            while peer.next_index <= last_log_index &&
+                  peer.append_entries_nonblocking(leader_id, commit_index, current_term, log.clone()) {}
+        }
     } else {
         let leader_id = server_info.me.0;
-        // If we failed, roll back peer index by 1 (if we can) and retry
-        // the append entries call.
+        // If we failed, the peer doesn't have the entry before the ones we sent, so we retry
+        // from that entry. Anything we pipelined after this request is rejected for the same
+        // reason, so we probe with one request at a time until the peer accepts one.
+        // If we couldn't reach the peer at all, that request waits for our next heartbeat.
+        // The exception is when another request we're still waiting on starts at or before
+        // this one. The peer may have gotten this one first, or we may have already resent
+        // these entries. Either way that request's reply decides whether we need to probe.
         let (commit_index, current_term) = (state.commit_index, state.current_term);
+        let retry_index = match m.conflict {
+            // the peer's log is too short, so pick up where it ends
+            Some((0, index)) => min(index, m.commit_index),
+            // If we have entries from the peer's conflicting term, our logs match up to the
+            // last of them. Otherwise none of the peer's entries from that term are in our log
+            Some((term, index)) => min(m.commit_index, log.lock().unwrap().last_index_of_term(term)
+                                                          .map_or(index, |i| i + 1)),
+            None => m.commit_index
+        };
         state.peers.get_mut(&m.peer.0).map(|peer| {
-            if peer.next_index > 1 {
-                // can we make sure match_index never exceeds peer_index?
-                peer.next_index = peer.next_index - 1;
+            let pending = peer.in_flight.values().any(|&prev_log_index| prev_log_index <= m.commit_index);
+            if pending && !m.unreachable {
+                // if that request succeeds we pipeline these entries again from here
+                peer.next_index = max(min(peer.next_index, m.commit_index + 1), peer.match_index + 1);
+                return;
+            }
+            // the peer has everything up to |match_index| though
+            peer.next_index = max(min(peer.next_index, retry_index), peer.match_index + 1);
+            peer.probing = true;
+            if !m.unreachable {
+                peer.append_entries_nonblocking(leader_id,
+                                                commit_index,
+                                                current_term, log);
             }
-            peer.append_entries_nonblocking(leader_id,
-                                            commit_index,
-                                            current_term, log);
         });
     }
 }
 
+///
+/// Moves an ongoing leadership transfer along after |peer_id| has replicated more of our log.
+/// Once the transfer's target has our entire log we tell it to start an election,
+/// otherwise we keep sending it entries.
+/// Noop if we aren't transferring leadership to |peer_id|.
+///
+fn advance_leadership_transfer(peer_id: u64, info: &mut ServerInfo, state: &mut ServerState,
+                               log: Arc<Mutex<Log>>) {
+    let (commit_index, current_term) = (state.commit_index, state.current_term);
+    if let State::Leader{leadership_transfer: Some(ref mut transfer), ref mut lease_revoked, ..} = state.current_state {
+        if transfer.target != peer_id || transfer.timeout_now_sent { return; }
+        let last_log_index = log.lock().unwrap().get_last_entry_index();
+        if let Some(peer) = state.peers.get_mut(&peer_id) {
+            if peer.match_index == last_log_index {
+                peer.to_peer.send(PeerThreadMessage::TimeoutNow(TimeoutNowMessage {
+                    term: current_term,
+                    leader_id: info.me.0
+                })).unwrap(); // panics if the peer thread has panicked
+                transfer.timeout_now_sent = true;
+                *lease_revoked = true;
+            } else {
+                peer.append_entries_nonblocking(info.me.0, commit_index, current_term, log);
+            }
+        }
+    }
+}
+
+///
+/// Gives up on a leadership transfer that has taken longer than an election timeout,
+/// so we can start accepting proposals again.
+///
+fn check_leadership_transfer_timeout(state: &mut ServerState) {
+    let timed_out = match state.current_state {
+        State::Leader{leadership_transfer: Some(ref transfer), ..} => {
+            state.clock.now().duration_since(transfer.start_time) > state.election_timeout_range.1
+        },
+        _ => false
+    };
+    if timed_out {
+        if let State::Leader{ref mut leadership_transfer, ..} = state.current_state {
+            // always executes since we just checked the transfer
+            let transfer = leadership_transfer.take().unwrap();
+            warn!("Unable to transfer leadership to {}", transfer.target);
+            let _ = transfer.pipe.send(Err(RaftError::Timeout));
+        }
+    }
+}
+
+///
+/// Steps down if we haven't heard from a majority of the servers in our config within an
+/// election timeout, since the rest of the cluster may have elected a new leader without us.
+/// Stepping down flushes any commands waiting to commit so their clients get a NotLeader error.
+/// Returns true if we stepped down.
+///
+fn check_quorum(info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) -> bool {
+    let quorums = voting_quorums(info.me.0, &state.peers, log.lock().unwrap().get_cluster_quorums());
+    let now = state.clock.now();
+    let since = now - state.election_timeout_range.1;
+    if heard_from_majority_since(since, info.me.0, &state.peers, &quorums) { return false; }
+
+    warn!("Server {}: Lost contact with a majority of the cluster in term {}. Stepping down",
+          info.me.0, state.current_term);
+    // we don't know who the new leader is
+    state.last_leader_contact = (now, None);
+    let (current_term, voted_for) = (state.current_term, state.voted_for);
+    match state.transition_to_follower(current_term, &info.state_machine.tx, voted_for, log) {
+        Ok(_) => {},
+        Err(e) => error!("Unable to write to state file after stepping down: {}", e)
+    };
+    true
+}
+
+///
+/// Returns true if a majority of the servers in each of |quorums| have acknowledged an
+/// AppendEntries that we sent after |since|. We always count ourselves if we're part of a quorum.
+///
+fn heard_from_majority_since(since: Instant, me: u64, peers: &BTreeMap<u64, PeerHandle>,
+                             quorums: &[Vec<u64>]) -> bool {
+    has_majority(quorums, |id| {
+        id == me || voting_peer(peers, id).map_or(false, |peer| peer.last_contact > since)
+    })
+}
+
+///
+/// Replies to every pending read that a majority of the cluster has confirmed our leadership
+/// for since the read came in. Reads are confirmed in the order they arrived.
+///
+fn confirm_pending_reads(info: &ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) {
+    let has_pending_reads = match state.current_state {
+        State::Leader{ref pending_reads, ..} => !pending_reads.is_empty(),
+        State::Follower | State::Candidate{..} => false
+    };
+    if !has_pending_reads { return; }
+
+    let quorums = voting_quorums(info.me.0, &state.peers, log.lock().unwrap().get_cluster_quorums());
+    let peers = &state.peers;
+    if let State::Leader{ref mut pending_reads, ..} = state.current_state {
+        let num_confirmed = pending_reads.iter()
+            .take_while(|read| heard_from_majority_since(read.start_time, info.me.0, peers, &quorums))
+            .count();
+        for read in pending_reads.drain(..num_confirmed) {
+            let _ = read.pipe.send(Ok(read.read_index));
+        }
+    }
+}
+
 /// Either adds a caught up server directly to the cluster
 /// or queues it to be added once all pending config changes
 /// have commited
-/// Noop if we're not leader
 ///
 /// #Panics
-/// Panics if the log lock is poisoned
+/// Panics if the log lock is poisoned or we aren't leader
 fn add_caught_up_server(peer: PeerInfo, pipe: RpcHandlerPipe, info: &mut ServerInfo,
                         state: &mut ServerState, log_lock: Arc<Mutex<Log>>) {
-    let log_lock_clone = log_lock.clone();
+    queue_cluster_change(ClusterChange::AddServer(peer), pipe, info, state, log_lock);
+}
+
+/// Either applies the cluster change directly to the cluster config
+/// or queues it to be applied once all pending config changes
+/// have commited
+///
+/// #Panics
+/// Panics if the log lock is poisoned or we aren't leader
+fn queue_cluster_change(change: ClusterChange, pipe: RpcHandlerPipe, info: &mut ServerInfo,
+                        state: &mut ServerState, log_lock: Arc<Mutex<Log>>) {
     match state.current_state {
         State::Leader{ref mut pending_cluster_changes, ref uncommited_cluster_change, ..} => {
             if uncommited_cluster_change.is_some() || log_lock.lock().unwrap().get_last_entry_term() != state.current_term {
                 // It's not safe to introduce a config right now. Queue it for later
-                pending_cluster_changes.push_back((peer, pipe));
+                pending_cluster_changes.push_back((change, pipe));
                 None
             } else {
-                Some((peer, pipe))
+                Some((change, pipe))
             }
         },
-        _ => panic!("Attempt to add a server when we aren't leader. This should be impossible.")
+        _ => panic!("Attempt to change the cluster when we aren't leader. This should be impossible.")
     }
-    .map(|(peer, pipe)| add_server_to_log(peer, pipe, info, state, log_lock));
+    .map(|(change, pipe)| append_cluster_change(change, pipe, info, state, log_lock));
 }
 
-/// Adds the caught up server to the cluster
+/// Appends a new config with the cluster change applied to the log
 /// And updates our current state to indicate
 /// that we are waiting on this cluster change
 ///
 /// #Panics
 /// Panics if we are not leader
-fn add_server_to_log (peer: PeerInfo, pipe: RpcHandlerPipe, info: &mut ServerInfo,
-                      state: &mut ServerState, log_lock: Arc<Mutex<Log>>) {
-    let mut config = log_lock.lock().unwrap().get_cluster_config().unwrap();
-    config.push(peer);
-    let op = raft_command::Request::SetConfig(config);
+fn append_cluster_change (change: ClusterChange, pipe: RpcHandlerPipe, info: &mut ServerInfo,
+                          state: &mut ServerState, log_lock: Arc<Mutex<Log>>) {
+    let (mut config, mut learners) = {
+        let mut log = log_lock.lock().unwrap();
+        (log.get_cluster_config().unwrap(), log.get_cluster_learners())
+    };
+    let op = match change {
+        ClusterChange::AddServer(peer) => {
+            config.push(peer);
+            raft_command::Request::SetConfig(config, learners)
+        },
+        ClusterChange::RemoveServer((id, _)) => {
+            config.retain(|&(server_id, _)| server_id != id);
+            learners.retain(|&(server_id, _)| server_id != id);
+            raft_command::Request::SetConfig(config, learners)
+        },
+        ClusterChange::SetConfig(ref servers) => {
+            // The new servers need to hear about the joint config before it can commit
+            for &server in servers {
+                if server.0 == info.me.0 { continue; }
+                let peer = state.peers.entry(server.0).or_insert_with(|| {
+                    Peer::start(server, info.to_me.clone(), None, info.storage.clone(),
+                                info.flow_control, info.transport.clone(), info.clock.clone())
+                });
+                if matches!(peer.state, PeerState::Learner) {
+                    // learners in the new config get promoted
+                    peer.state = PeerState::Voting;
+                }
+            }
+            learners.retain(|&(id, _)| !servers.iter().any(|&(server_id, _)| server_id == id));
+            raft_command::Request::SetJointConfig(config, servers.clone(), learners)
+        },
+        ClusterChange::LeaveJointConfig(ref servers) => raft_command::Request::SetConfig(servers.clone(), learners),
+        ClusterChange::AddLearner(learner) => {
+            if learner.0 != info.me.0 && !state.peers.contains_key(&learner.0) {
+                let mut peer = Peer::start(learner, info.to_me.clone(), None, info.storage.clone(),
+                                           info.flow_control, info.transport.clone(), info.clock.clone());
+                peer.state = PeerState::Learner;
+                state.peers.insert(learner.0, peer);
+            }
+            learners.push(learner);
+            raft_command::Request::SetConfig(config, learners)
+        },
+        ClusterChange::PromoteLearner(id) => {
+            // the learner may have been removed while this change was queued
+            if let Some(position) = learners.iter().position(|&(learner_id, _)| learner_id == id) {
+                config.push(learners.remove(position));
+                if let Some(peer) = state.peers.get_mut(&id) {
+                    peer.state = PeerState::Voting;
+                }
+            }
+            raft_command::Request::SetConfig(config, learners)
+        },
+        ClusterChange::DemoteToLearner(id) => {
+            if let Some(position) = config.iter().position(|&(server_id, _)| server_id == id) {
+                learners.push(config.remove(position));
+                if let Some(peer) = state.peers.get_mut(&id) {
+                    peer.state = PeerState::Learner;
+                }
+            }
+            raft_command::Request::SetConfig(config, learners)
+        }
+    };
     let index = append_to_log(log_lock.clone(), op, state.current_term, &state.current_state).unwrap();
     if let State::Leader{ref mut uncommited_cluster_change, ..} = state.current_state {
         // always executes because the state is locked and we made it this far
-        *uncommited_cluster_change = Some((index, pipe));
+        *uncommited_cluster_change = Some((index, change, pipe));
     }
     broadcast_append_entries(info, state, log_lock);
 }
@@ -541,61 +1139,107 @@ fn append_to_log(log: Arc<Mutex<Log>>, op: raft_command::Request, current_term:
 fn broadcast_append_entries(info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) {
     // only broadcast append entries if we're leader
     if let State::Leader {ref mut last_heartbeat, ..} = state.current_state {
-        for (_, peer) in &state.peers {
+        for (_, peer) in &mut state.peers {
             // Perf: each call copies the needed |entries| from |log| to send along to the peer.
-            peer.append_entries_nonblocking(info.me.0, state.commit_index,
-                                            state.current_term, log.clone());
+            peer.heartbeat_nonblocking(info.me.0, state.commit_index,
+                                       state.current_term, log.clone());
         }
-        *last_heartbeat = Instant::now();
+        *last_heartbeat = state.clock.now();
     }
 }
 
 ///
 /// Handles replies to RequestVote Rpc
 ///
+/// PreVote replies are only counted during the PreVote phase, and once a majority would vote
+/// for us we start the real election.
+///
 fn handle_request_vote_reply(reply: RequestVoteReply, info: &mut ServerInfo,
                              state: &mut ServerState, log: Arc<Mutex<Log>>) {
     // Since we can't have two mutable borrows on |state| at once we gotta
     // update votes & do the transition check in separate scopes.
-    if reply.term == state.current_term && reply.vote_granted {
-        if let State::Candidate{ref mut num_votes, ..} = state.current_state {
-            *num_votes += 1;
+    let current_term = state.current_term;
+    if let State::Candidate{ref mut votes, pre_vote, ..} = state.current_state {
+        // PreVotes are for the term after ours
+        let election_term = if pre_vote { current_term + 1 } else { current_term };
+        if reply.pre_vote == pre_vote && reply.term == election_term && reply.vote_granted {
+            votes.insert(reply.peer.0);
         }
     }
-    if let State::Candidate{num_votes, ..} = state.current_state {
-        let num_peers = state.peers.iter().filter(|&(_, p)| matches!(p.state, PeerState::Voting)).count();
-        if num_votes > (num_peers + 1) / 2{
-            // Woo! We won the election
-            state.transition_to_leader(info, log);
+    let won = match state.current_state {
+        State::Candidate{ref votes, ..} => {
+            // During a joint consensus change we need votes from a majority of both configs
+            let quorums = voting_quorums(info.me.0, &state.peers, log.lock().unwrap().get_cluster_quorums());
+            has_majority(&quorums, |id| votes.contains(&id))
+        },
+        State::Leader{..} | State::Follower => false
+    };
+    if let State::Candidate{pre_vote, ..} = state.current_state {
+        if won {
+            if pre_vote {
+                // A majority would vote for us, so it's safe to disrupt the cluster with a new term
+                Server::start_election(info, state, log, false);
+            } else {
+                // Woo! We won the election
+                state.transition_to_leader(info, log);
+            }
         }
     }
 }
 
 impl Server {
-    fn new (config: Config, tx: Sender<MainThreadMessage>, state_machine: Box<RaftStateMachine>)
-        -> Result<(Server, RpcServer), IoError> {
+    fn new (config: Config, storage: Box<Storage>, tx: Sender<MainThreadMessage>,
+            mut state_machine: Box<RaftStateMachine>) -> Result<(Server, Box<Listener>), IoError> {
+        config.validate()?;
         let me = config.me;
-        let mut state_file = StateFile::new_from_filename(&config.state_filename)?;
-        let persisted_state = state_file.get_state()?;
+        let storage: SharedStorage = Arc::new(Mutex::new(storage));
+        let (persisted_state, snapshot) = {
+            let mut storage = storage.lock().unwrap();
+            (storage.hard_state()?, storage.snapshot_metadata())
+        };
+
+        // Restore the state machine from the snapshot our log was compacted into
+        if snapshot.is_some() {
+            let (_, mut reader) = storage.lock().unwrap().snapshot()?
+                .ok_or(IoError::new(ErrorKind::InvalidData, "Our log was compacted into a missing snapshot"))?;
+            let mut data = vec![];
+            reader.read_to_end(&mut data)?;
+            state_machine.restore_snapshot(&data)
+                .map_err(|e| IoError::new(ErrorKind::InvalidData,
+                                          format!("Unable to restore snapshot: {:?}", e)))?;
+        }
+        // Everything in the snapshot has already been committed and applied
+        let snapshot_index = snapshot.as_ref().map_or(0, |s| s.last_included_index);
+
         let (last_persisted_index, log) = {
-            let l = Log::new_from_filename(config.log_filename, tx.clone())?;
+            let l = Log::new(storage.clone(), config.entry_cache_size, config.durability, config.clock.clone(),
+                             tx.clone())?;
             (l.get_last_entry_index(), Arc::new(Mutex::new(l)))
         };
+        let election_timeout_range = (config.election_timeout_min, config.election_timeout_max);
+        let mut rng = match config.seed {
+            Some(seed) => StdRng::from_seed(&[seed as usize][..]),
+            None => StdRng::from_seed(&[simulation::random_u64() as usize][..])
+        };
         let state = Arc::new(Mutex::new(ServerState {
             current_state: State::Follower,
             current_term: persisted_state.term,
-            commit_index: 0,
+            commit_index: snapshot_index,
             voted_for: persisted_state.voted_for,
-            last_leader_contact: (Instant::now(), None),
-            election_timeout: generate_election_timeout(),
-            state_file: state_file,
-            peers: HashMap::new(),
-            last_persisted_index: last_persisted_index
+            last_leader_contact: (config.clock.now(), None),
+            election_timeout: generate_election_timeout(election_timeout_range, &mut rng),
+            election_timeout_range: election_timeout_range,
+            storage: storage.clone(),
+            peers: BTreeMap::new(),
+            last_persisted_index: last_persisted_index,
+            clock: config.clock.clone(),
+            rng: rng
         }));
 
         // 1a. Start state machine thread.
         let state_machine_handle = state_machine_thread(
-            log.clone(), 0, state_machine, state.clone(), tx.clone());
+            log.clone(), snapshot_index, state_machine, state.clone(), tx.clone(),
+            storage.clone(), config.snapshot_threshold);
         let to_state_machine_locked = 
             Arc::new(Mutex::new(state_machine_handle.tx.clone()));
 
@@ -604,37 +1248,62 @@ impl Server {
             AppendEntriesHandler {state: state.clone(), log: log.clone(),
                                   to_state_machine: to_state_machine_locked.clone() }
         );
+        let install_snapshot_handler: Box<RpcObject> = Box::new(
+            InstallSnapshotHandler {state: state.clone(), log: log.clone(),
+                                    to_state_machine: to_state_machine_locked.clone(),
+                                    incoming: Mutex::new(None) }
+        );
+        let timeout_now_handler: Box<RpcObject> = Box::new(
+            TimeoutNowHandler {state: state.clone(), log: log.clone(),
+                               to_state_machine: to_state_machine_locked.clone(),
+                               to_main_thread: Arc::new(Mutex::new(tx.clone()))}
+        );
         let request_vote_handler: Box<RpcObject> = Box::new(
             RequestVoteHandler {state: state.clone(), log: log.clone(),
                                 to_state_machine: to_state_machine_locked.clone() }
         );
+        let pre_vote_handler: Box<RpcObject> = Box::new(
+            PreVoteHandler {state: state.clone(), log: log.clone()}
+        );
         let client_request_handler: Box<RpcObject> = Box::new(
-            ClientRequestHandler {state: state.clone(),
+            ClientRequestHandler {me: me.0, state: state.clone(), log: log.clone(),
                                   to_state_machine: to_state_machine_locked.clone(),
-                                  to_main_thread: Arc::new(Mutex::new(tx.clone()))}
+                                  to_main_thread: Arc::new(Mutex::new(tx.clone())),
+                                  read_lease: read_lease_duration(&config),
+                                  query_timeout: config.query_timeout,
+                                  transport: config.transport.clone()}
+        );
+        let read_index_handler: Box<RpcObject> = Box::new(
+            ReadIndexHandler {to_main_thread: Arc::new(Mutex::new(tx.clone()))}
         );
         let services = vec![
             (constants::APPEND_ENTRIES_OPCODE, append_entries_handler),
             (constants::REQUEST_VOTE_OPCODE, request_vote_handler),
-            (constants::CLIENT_REQUEST_OPCODE, client_request_handler)
+            (constants::CLIENT_REQUEST_OPCODE, client_request_handler),
+            (constants::INSTALL_SNAPSHOT_OPCODE, install_snapshot_handler),
+            (constants::TIMEOUT_NOW_OPCODE, timeout_now_handler),
+            (constants::PRE_VOTE_OPCODE, pre_vote_handler),
+            (constants::READ_INDEX_OPCODE, read_index_handler)
         ];
-        let mut rpc_server = RpcServer::new_with_services(services);
-        try!(
-            rpc_server.bind(me.1)
-            .and_then(|_| {
-                rpc_server.repl()
-            })
-        );
+        let rpc_server = try!(config.transport.listen(me.1, services));
 
         // 3. Construct server state object.
-        // safe to unwrap here because we should only get this far
-        // if the server is bound.
-        let bound_address = rpc_server.get_local_addr().unwrap();
+        let bound_address = rpc_server.local_addr();
         let info = ServerInfo {
             me: (me.0, bound_address),
             heartbeat_timeout: config.heartbeat_timeout,
             state_machine: state_machine_handle,
-            to_me: tx.clone()
+            to_me: tx.clone(),
+            storage: storage,
+            pre_vote: config.pre_vote,
+            flow_control: FlowControl {
+                max_in_flight: config.max_append_entries_in_flight,
+                max_batch_entries: config.max_append_entries_batch,
+                max_batch_bytes: config.max_append_entries_bytes
+            },
+            max_rounds_for_new_server: config.max_rounds_for_new_server,
+            transport: config.transport.clone(),
+            clock: config.clock.clone()
         };
 
         Ok((Server {
@@ -655,29 +1324,29 @@ impl Server {
             // NB: This thread handles all state changes EXCEPT for those that move us back into the
             // follower state from either the candidate or leader state. Those are both handled in the
             // AppendEntriesHandler
+            let clock = self.state.lock().unwrap().clock.clone();
 
             loop {
                 let current_timeout;
+                let now = clock.now();
                 { // state lock scope
                     // TODO(jason): Decompose and test
                     let state = self.state.lock().unwrap();
 
                     match state.current_state {
                         State::Follower => {
-                            let now = Instant::now();
                             current_timeout = state.election_timeout.checked_sub(
                                 now.duration_since(state.last_leader_contact.0));
                         }
                         State::Candidate{start_time, ..} => {
-                            let time_since_election = Instant::now() - start_time;
+                            let time_since_election = now.duration_since(start_time);
 
                             current_timeout = state.election_timeout.checked_sub(time_since_election);
                         },
                         State::Leader{last_heartbeat, ..} => {
                             // TODO: Should last_heartbeat be in the leader state?
                             let heartbeat_timeout = self.info.heartbeat_timeout;
-                            let since_last_heartbeat = Instant::now()
-                                                           .duration_since(last_heartbeat);
+                            let since_last_heartbeat = now.duration_since(last_heartbeat);
                             current_timeout = heartbeat_timeout.checked_sub(since_last_heartbeat);
                         },
                     } // end match server.state
@@ -720,8 +1389,45 @@ impl Server {
                                 // Once commited return success
                                 Server::add_peer(server_info, to_background, &mut self.info, state, self.log.clone())
                             },
-                            MainThreadMessage::RemoveServer(index, addr) => {
-                                unimplemented!();
+                            MainThreadMessage::RemoveServer(server_info, to_background) => {
+                                // Once every earlier cluster change has commited, append a
+                                // config without this server. Once that commits we shutdown
+                                // its peer (or step down if we removed ourselves) and return success
+                                Server::remove_server(server_info, to_background, &mut self.info, state, self.log.clone())
+                            },
+                            MainThreadMessage::SetConfig(servers, to_background) => {
+                                // Once every earlier cluster change has commited, append a joint
+                                // config with both the current and the new servers. Once that
+                                // commits we append a config with just the new servers, and
+                                // return success when it commits
+                                Server::set_config(servers, to_background, &mut self.info, state, self.log.clone())
+                            },
+                            MainThreadMessage::AddLearner(server_info, to_background) => {
+                                // Learners don't affect the quorum, so we start replicating to
+                                // them and append the new config right away
+                                Server::add_learner(server_info, to_background, &mut self.info, state, self.log.clone())
+                            },
+                            MainThreadMessage::PromoteLearner(id, to_background) => {
+                                Server::promote_learner(id, to_background, &mut self.info, state, self.log.clone())
+                            },
+                            MainThreadMessage::DemoteToLearner(id, to_background) => {
+                                // If we demote ourselves we step down once the change commits
+                                Server::demote_to_learner(id, to_background, &mut self.info, state, self.log.clone())
+                            },
+                            MainThreadMessage::TransferLeadership(target, to_background) => {
+                                // Stop accepting proposals and catch the target up. Once it has
+                                // our whole log we tell it to start an election, which it should
+                                // win since nobody else has a more up to date log
+                                Server::transfer_leadership(target, to_background, &mut self.info, state, self.log.clone())
+                            },
+                            MainThreadMessage::TimeoutNow(term) => {
+                                Server::handle_timeout_now(term, &mut self.info, state, self.log.clone())
+                            },
+                            MainThreadMessage::ReadIndex(to_background) => {
+                                // Record our commit index and send out a round of heartbeats.
+                                // Once a majority acknowledges one we know we were still leader
+                                // when the read came in, so it's safe to serve
+                                Server::read_index(to_background, &mut self.info, state, self.log.clone())
                             },
                             MainThreadMessage::Shutdown => {
                                 break;
@@ -729,6 +1435,10 @@ impl Server {
                         };
                     },
                     Err(_) => {
+                        // A simulated clock may not have reached the end of the timeout yet
+                        if current_timeout.map_or(false, |timeout| clock.now() < now + timeout) {
+                            continue;
+                        }
                         self.handle_timeout();
                         continue;
                     }
@@ -739,19 +1449,16 @@ impl Server {
             // clean up based on state
             let mut state = self.state.lock().unwrap();
             match state.current_state {
-                State::Leader {ref pending_cluster_changes, ..} => {
+                State::Leader {ref pending_cluster_changes, ref uncommited_cluster_change,
+                               ref leadership_transfer, ref pending_reads, ..} => {
                     self.info.state_machine.tx.send(StateMachineMessage::Flush).unwrap();
                     self.log.lock().unwrap().flush_background_thread();
-                    let pipes = pending_cluster_changes
-                        .iter()
-                        .map(|&(_, ref pipe)| {
-                            pipe
-                        })
-                        .chain(pending_cluster_changes.iter().map(|&(_, ref pipe)| pipe));
-                    for pipe in pipes {
-                        warn!("Sending not leader!");
-                        // TODO: Send leader guess
-                        pipe.send(Err(RaftError::NotLeader(None)));
+                    reject_cluster_changes(pending_cluster_changes, uncommited_cluster_change);
+                    if let Some(ref transfer) = *leadership_transfer {
+                        let _ = transfer.pipe.send(Err(RaftError::NotLeader(None)));
+                    }
+                    for read in pending_reads {
+                        let _ = read.pipe.send(Err(RaftError::NotLeader(None)));
                     }
                 },
                 State::Follower | State::Candidate {..} => {/*No cleanup work*/}
@@ -765,52 +1472,305 @@ impl Server {
     /// catching up
     fn add_peer(server_info: PeerInfo, to_background: RpcHandlerPipe, info: &mut ServerInfo, state: &mut ServerState,
                 log: Arc<Mutex<Log>>) {
-        let peer = Peer::start(server_info, info.to_me.clone(), Some(to_background));
+        let mut peer = Peer::start(server_info, info.to_me.clone(), Some(to_background), info.storage.clone(),
+                                   info.flow_control, info.transport.clone(), info.clock.clone());
         peer.append_entries_nonblocking(info.me.0, state.commit_index, state.current_term, log);
         state.peers.insert(peer.id, peer);
     }
 
-    /// Updates commit index if appropiate after an entry has been commited to disk
-    fn handle_entry_persisted(index: usize, info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) {
-        state.last_persisted_index = index;
-        // commit index may need to be marched forward if it was waiting on a background disk write
-        // but only if we are still leader
-        match state.current_state {
-            State::Leader{..} => update_commit_index(info, state, log),
-            State::Candidate{..} | State::Follower => {}
+    /// Queues up a config change that removes the given server from the cluster.
+    /// Rejects the request if we aren't leader or if the server is the last one in the cluster.
+    fn remove_server(server_info: PeerInfo, to_background: RpcHandlerPipe, info: &mut ServerInfo,
+                     state: &mut ServerState, log: Arc<Mutex<Log>>) {
+        if !matches!(state.current_state, State::Leader{..}) {
+            to_background.send(Err(RaftError::NotLeader(state.last_leader_contact.1))).unwrap();
+            return;
+        }
+        let config = log.lock().unwrap().get_cluster_config().unwrap_or(vec![]);
+        if config.iter().all(|&(id, _)| id == server_info.0) {
+            to_background.send(Err(RaftError::ClientError(
+                String::from("Unable to remove the last server in the cluster")))).unwrap();
+            return;
         }
+        queue_cluster_change(ClusterChange::RemoveServer(server_info), to_background, info, state, log);
     }
 
-    ///
-    /// Handles timeouts on the main thread.
-    /// Timeouts are handled differently based on our current state.
-    /// If we're a follower or candidate, we check if we've recieved 
-    /// anything from the leader since we went to sleep. 
-    /// If not then we start a new election
-    /// If we're the leader we send a hearbeat
-    ///
-    /// # Panics
-    /// Panics if any of the peer threads have panicked.
-    ///
-    fn handle_timeout(&mut self) {
-        let ref mut state = self.state.lock().unwrap();
-        match state.current_state {
-            State::Follower | State::Candidate{ .. } => {
-                let now = Instant::now();
-                if now.duration_since(state.last_leader_contact.0)
-                    > state.election_timeout {
-                    Server::start_election(&mut self.info, state, self.log.clone());
-                }
-            },
-            State::Leader{ .. } => {
-                broadcast_append_entries(&mut self.info, state, self.log.clone());
-            }
+    /// Queues up a joint consensus change that replaces the servers in the cluster with |servers|.
+    /// Rejects the request if we aren't leader or if |servers| is empty.
+    fn set_config(servers: Vec<PeerInfo>, to_background: RpcHandlerPipe, info: &mut ServerInfo,
+                  state: &mut ServerState, log: Arc<Mutex<Log>>) {
+        if !matches!(state.current_state, State::Leader{..}) {
+            to_background.send(Err(RaftError::NotLeader(state.last_leader_contact.1))).unwrap();
+            return;
         }
+        if servers.is_empty() {
+            to_background.send(Err(RaftError::ClientError(
+                String::from("Unable to remove every server from the cluster")))).unwrap();
+            return;
+        }
+        queue_cluster_change(ClusterChange::SetConfig(servers), to_background, info, state, log);
     }
 
-
+    /// Queues up a config change that adds the given server as a learner.
+    /// Rejects the request if we aren't leader or if the server is already in the cluster.
+    fn add_learner(server_info: PeerInfo, to_background: RpcHandlerPipe, info: &mut ServerInfo,
+                   state: &mut ServerState, log: Arc<Mutex<Log>>) {
+        if !matches!(state.current_state, State::Leader{..}) {
+            to_background.send(Err(RaftError::NotLeader(state.last_leader_contact.1))).unwrap();
+            return;
+        }
+        let is_member = {
+            let mut log = log.lock().unwrap();
+            let mut members = log.get_cluster_config().unwrap_or(vec![]);
+            members.extend(log.get_cluster_learners());
+            members.iter().any(|&(id, _)| id == server_info.0)
+        };
+        if is_member {
+            to_background.send(Err(RaftError::ClientError(
+                String::from("Server is already a member of the cluster")))).unwrap();
+            return;
+        }
+        queue_cluster_change(ClusterChange::AddLearner(server_info), to_background, info, state, log);
+    }
+
+    /// Queues up a config change that turns the learner with |id| into a voting server.
+    /// Rejects the request if we aren't leader or if the server isn't a learner.
+    fn promote_learner(id: u64, to_background: RpcHandlerPipe, info: &mut ServerInfo,
+                       state: &mut ServerState, log: Arc<Mutex<Log>>) {
+        if !matches!(state.current_state, State::Leader{..}) {
+            to_background.send(Err(RaftError::NotLeader(state.last_leader_contact.1))).unwrap();
+            return;
+        }
+        let is_learner = log.lock().unwrap().get_cluster_learners().iter().any(|&(learner, _)| learner == id);
+        if !is_learner {
+            to_background.send(Err(RaftError::ClientError(
+                String::from("Server is not a learner")))).unwrap();
+            return;
+        }
+        queue_cluster_change(ClusterChange::PromoteLearner(id), to_background, info, state, log);
+    }
+
+    /// Queues up a config change that turns the voting server with |id| into a learner.
+    /// Rejects the request if we aren't leader, the server doesn't vote or it's the last
+    /// voting server in the cluster.
+    fn demote_to_learner(id: u64, to_background: RpcHandlerPipe, info: &mut ServerInfo,
+                         state: &mut ServerState, log: Arc<Mutex<Log>>) {
+        if !matches!(state.current_state, State::Leader{..}) {
+            to_background.send(Err(RaftError::NotLeader(state.last_leader_contact.1))).unwrap();
+            return;
+        }
+        let config = log.lock().unwrap().get_cluster_config().unwrap_or(vec![]);
+        let error = if !config.iter().any(|&(server_id, _)| server_id == id) {
+            Some("Server is not a voting member of the cluster")
+        } else if config.len() == 1 {
+            Some("Unable to demote the last voting server in the cluster")
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            to_background.send(Err(RaftError::ClientError(String::from(error)))).unwrap();
+            return;
+        }
+        queue_cluster_change(ClusterChange::DemoteToLearner(id), to_background, info, state, log);
+    }
+
+    /// Starts handing leadership off to |target|, or to our most up to date peer if no target is
+    /// given. Rejects the request if we aren't leader, are already transferring leadership,
+    /// or there's no suitable server to take over.
+    fn transfer_leadership(target: Option<u64>, to_background: RpcHandlerPipe, info: &mut ServerInfo,
+                           state: &mut ServerState, log: Arc<Mutex<Log>>) {
+        match state.current_state {
+            State::Leader{leadership_transfer: None, ..} => {},
+            State::Leader{leadership_transfer: Some(_), ..} => {
+                to_background.send(Err(RaftError::ClientError(
+                    String::from("Already transferring leadership")))).unwrap();
+                return;
+            },
+            State::Follower | State::Candidate{..} => {
+                to_background.send(Err(RaftError::NotLeader(state.last_leader_contact.1))).unwrap();
+                return;
+            }
+        }
+
+        let config = log.lock().unwrap().get_cluster_config();
+        let target = {
+            let mut candidates = state.peers.values()
+                .filter(|peer| matches!(peer.state, PeerState::Voting) && is_in_config(peer.id, &config));
+            let peer = match target {
+                Some(id) if id == info.me.0 => {
+                    // we're already leader
+                    to_background.send(Ok(())).unwrap();
+                    return;
+                },
+                Some(id) => candidates.find(|peer| peer.id == id),
+                None => candidates.max_by_key(|peer| peer.match_index)
+            };
+            peer.map(|peer| peer.id)
+        };
+        let target = match target {
+            Some(id) => id,
+            None => {
+                to_background.send(Err(RaftError::ClientError(
+                    String::from("No server in the cluster can take over leadership")))).unwrap();
+                return;
+            }
+        };
+
+        if let State::Leader{ref mut leadership_transfer, ..} = state.current_state {
+            // always executes because we checked that we're leader above
+            *leadership_transfer = Some(LeadershipTransfer {
+                target: target,
+                start_time: state.clock.now(),
+                timeout_now_sent: false,
+                pipe: to_background
+            });
+        }
+        advance_leadership_transfer(target, info, state, log);
+    }
+
+    /// Starts an election right away if the leader of our current term asked us to take over
+    fn handle_timeout_now(term: u64, info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) {
+        if term != state.current_term || !matches!(state.current_state, State::Follower) { return; }
+        let config = log.lock().unwrap().get_cluster_config();
+        if is_in_config(info.me.0, &config) {
+            Server::start_election(info, state, log, true);
+        }
+    }
+
+    ///
+    /// Starts a ReadIndex read by recording the index the state machine must apply before the
+    /// read can be served, and confirming we're still leader with a round of heartbeats.
+    /// Replies once a majority acknowledges one of them, or with NotLeader if we aren't leader.
+    ///
+    fn read_index(to_background: ReadIndexPipe, info: &mut ServerInfo, state: &mut ServerState,
+                  log: Arc<Mutex<Log>>) {
+        if !matches!(state.current_state, State::Leader{..}) {
+            to_background.send(Err(RaftError::NotLeader(state.last_leader_contact.1))).unwrap();
+            return;
+        }
+        let read_index = {
+            let log = log.lock().unwrap();
+            // Until an entry from our own term commits we may not know about everything the
+            // last leader commited, so wait on our entire log instead
+            if log.get_term(state.commit_index) == Some(state.current_term) {
+                state.commit_index
+            } else {
+                log.get_last_entry_index()
+            }
+        };
+        if let State::Leader{ref mut pending_reads, ..} = state.current_state {
+            pending_reads.push(PendingRead {
+                read_index: read_index,
+                start_time: state.clock.now(),
+                pipe: to_background
+            });
+        }
+        broadcast_append_entries(info, state, log.clone());
+        // we may be the only server in the cluster
+        confirm_pending_reads(info, state, log);
+    }
+
+    /// Updates commit index if appropiate after every entry up to |index| has been commited to disk
+    fn handle_entry_persisted(index: usize, info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) {
+        state.last_persisted_index = index;
+        // commit index may need to be marched forward if it was waiting on a background disk write
+        // but only if we are still leader
+        match state.current_state {
+            State::Leader{..} => update_commit_index(info, state, log),
+            State::Candidate{..} | State::Follower => {}
+        }
+    }
+
+    ///
+    /// Handles timeouts on the main thread.
+    /// Timeouts are handled differently based on our current state.
+    /// If we're a follower or candidate, we check if we've recieved 
+    /// anything from the leader since we went to sleep. 
+    /// If not then we start a new election
+    /// If we're the leader we send a hearbeat, unless we've lost contact with
+    /// a majority of the cluster, in which case we step down
+    ///
+    /// # Panics
+    /// Panics if any of the peer threads have panicked.
+    ///
+    fn handle_timeout(&mut self) {
+        let ref mut state = self.state.lock().unwrap();
+        match state.current_state {
+            State::Follower | State::Candidate{ .. } => {
+                let now = state.clock.now();
+                if now.duration_since(state.last_leader_contact.0)
+                    > state.election_timeout {
+                    let config = self.log.lock().unwrap().get_cluster_config();
+                    if !is_in_config(self.info.me.0, &config) {
+                        // We're a learner or we've been removed from the cluster, so we shouldn't
+                        // disrupt it with elections. Just wait to hear from a leader in case
+                        // we're added back.
+                        state.last_leader_contact.0 = now;
+                    } else if self.info.pre_vote {
+                        Server::start_pre_vote(&mut self.info, state, self.log.clone());
+                    } else {
+                        Server::start_election(&mut self.info, state, self.log.clone(), false);
+                    }
+                }
+            },
+            State::Leader{ .. } => {
+                check_leadership_transfer_timeout(state);
+                if !check_quorum(&mut self.info, state, self.log.clone()) {
+                    broadcast_append_entries(&mut self.info, state, self.log.clone());
+                }
+            }
+        }
+    }
+
+    ///
+    /// Starts the PreVote phase of an election by asking all peers if they would vote
+    /// for us in the next term. If a majority would, handle_request_vote_reply starts the
+    /// real election.
+    /// Like start_election, this returns immediatly after asking each peer.
+    ///
+    /// # Panics
+    /// Panics if any other thread has panicked while holding the log lock
+    ///
+    fn start_pre_vote(info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) {
+        trace!("Server {}: Starting PreVote for term {}", info.me.0, state.current_term + 1);
+        state.transition_to_pre_candidate(info, log.clone());
+        let num_voting_peers = state.peers.values()
+            .filter(|peer| matches!(peer.state, PeerState::Voting))
+            .count();
+        if num_voting_peers == 0 {
+            // Nobody else gets a say
+            Server::start_election(info, state, log, false);
+            return;
+        }
+
+        let (last_log_index, last_log_term) = {
+            let log = log.lock().unwrap();
+            (log.get_last_entry_index(), log.get_last_entry_term())
+        };
+        Server::request_votes(state, RequestVoteMessage {
+            term: state.current_term + 1,
+            candidate_id: info.me.0,
+            last_log_index: last_log_index,
+            last_log_term: last_log_term,
+            leader_transfer: false,
+            pre_vote: true
+        });
+    }
+
+    /// Sends the given vote request to all of our voting peers
+    fn request_votes(state: &ServerState, request_vote_message: RequestVoteMessage) {
+        // only request votes from voting members
+        for (_, peer) in state.peers.iter().filter(|&(_, peer)| matches!(peer.state, PeerState::Voting)) {
+            peer.to_peer.send(PeerThreadMessage::RequestVote(request_vote_message))
+                        .unwrap(); // panic if the peer thread is down
+        }
+    }
+
+
     ///
     /// Starts a new election by requesting votes from all peers.
+    /// |leader_transfer| should be set if the leader asked us to start this election,
+    /// so our peers vote for us even though they've heard from the leader recently.
     /// Returns the amount of time to wait before the election should time out.
     /// This function does not block to wait for the election to finish.
     /// It returns immediatly after asking each peer for a vote.
@@ -820,7 +1780,8 @@ impl Server {
     /// # Panics
     /// Panics if any other thread has panicked while holding the log lock
     ///
-    fn start_election(info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>) -> Duration {
+    fn start_election(info: &mut ServerInfo, state: &mut ServerState, log: Arc<Mutex<Log>>,
+                      leader_transfer: bool) -> Duration {
         trace!("Server {}: Starting election for term {}. Previously voted for {:?}", info.me.0,
                  state.current_term + 1, state.voted_for);
         // transition to the candidate state
@@ -847,14 +1808,11 @@ impl Server {
             term: state.current_term,
             candidate_id: info.me.0,
             last_log_index: last_log_index,
-            last_log_term: last_log_term
+            last_log_term: last_log_term,
+            leader_transfer: leader_transfer,
+            pre_vote: false
         };
-
-        // only request votes from voting members
-        for (_, peer) in state.peers.iter().filter(|&(_, peer)| matches!(peer.state, PeerState::Voting)) {
-            peer.to_peer.send(PeerThreadMessage::RequestVote(request_vote_message))
-                        .unwrap(); // panic if the peer thread is down
-        }
+        Server::request_votes(state, request_vote_message);
         state.election_timeout
     }
 }
@@ -870,13 +1828,14 @@ impl RpcObject for RequestVoteHandler {
     fn handle_rpc (&self, params: capnp::any_pointer::Reader, result: capnp::any_pointer::Builder) 
         ->Result<(), RpcError>
     {
-        let (candidate_id, term, last_log_index, last_log_term) = try!(
+        let (candidate_id, term, last_log_index, last_log_term, leader_transfer) = try!(
             params.get_as::<request_vote::Reader>()
             .map_err(RpcError::Capnp)
             .map(|params| {(
                  params.get_candidate_id(), params.get_term(),
                  params.get_last_log_index() as usize,
-                 params.get_last_log_term())
+                 params.get_last_log_term(),
+                 params.get_leader_transfer())
             }));
         let mut vote_granted = false;
         let current_term;
@@ -895,12 +1854,12 @@ impl RpcObject for RequestVoteHandler {
                 new_term = state.current_term;
                 voted_for = state.voted_for;
             }
-            let now = Instant::now();
-            // only grant our vote if we've noticed a timeout
-            let timed_out = now.duration_since(state.last_leader_contact.0) >= 
-                            Duration::from_millis(constants::ELECTION_TIMEOUT_MIN);
+            let now = state.clock.now();
+            // only grant our vote if we've noticed a timeout, or the leader is handing off
+            // leadership to this candidate
+            let timed_out = now.duration_since(state.last_leader_contact.0) >= state.election_timeout_range.0;
 
-            if timed_out && (voted_for == None || voted_for == Some(candidate_id)) {
+            if (timed_out || leader_transfer) && (voted_for == None || voted_for == Some(candidate_id)) {
                 let log_is_valid = {
                     let log = self.log.lock().unwrap(); // panics if mutex is poisoned
                     log.is_other_log_valid(last_log_index, last_log_term)
@@ -910,7 +1869,7 @@ impl RpcObject for RequestVoteHandler {
                     match state.transition_to_follower(new_term, &self.to_state_machine.lock().unwrap(), Some(candidate_id), self.log.clone()) {
                         Ok(_) => {
                             vote_granted = true;
-                            state.last_leader_contact = (Instant::now(), Some(candidate_id));
+                            state.last_leader_contact = (state.clock.now(), Some(candidate_id));
                         },
                         Err(e) => {
                             vote_granted = false;
@@ -938,6 +1897,56 @@ impl RpcObject for RequestVoteHandler {
     }
 }
 
+///
+/// Decides whether we would vote for a candidate in |term| if it started an election.
+/// We only grant PreVotes for terms after our own, when we haven't heard from a leader
+/// recently, and when the candidate's log is at least as up to date as ours.
+/// Never changes any of our state.
+///
+fn grant_pre_vote(term: u64, last_log_index: usize, last_log_term: u64, state: &ServerState,
+                  log: &Arc<Mutex<Log>>) -> bool {
+    if term <= state.current_term || matches!(state.current_state, State::Leader{..}) {
+        return false;
+    }
+    let timed_out = state.clock.now().duration_since(state.last_leader_contact.0) >=
+                    state.election_timeout_range.0;
+    timed_out && log.lock().unwrap().is_other_log_valid(last_log_index, last_log_term)
+}
+
+///
+/// Answers PreVote requests. They share the RequestVote messages, but we don't
+/// persist a vote or move into the candidate's term.
+///
+struct PreVoteHandler {
+    state: Arc<Mutex<ServerState>>,
+    log: Arc<Mutex<Log>>,
+}
+
+impl RpcObject for PreVoteHandler {
+    fn handle_rpc (&self, params: capnp::any_pointer::Reader, result: capnp::any_pointer::Builder)
+        ->Result<(), RpcError>
+    {
+        let (term, last_log_index, last_log_term) = try!(
+            params.get_as::<request_vote::Reader>()
+            .map_err(RpcError::Capnp)
+            .map(|params| {(
+                 params.get_term(),
+                 params.get_last_log_index() as usize,
+                 params.get_last_log_term())
+            }));
+        let (current_term, vote_granted) = {
+            let state = self.state.lock().unwrap(); // panics if mutex is poisoned
+            (state.current_term, grant_pre_vote(term, last_log_index, last_log_term, &state, &self.log))
+        };
+        let mut result_builder = result.init_as::<request_vote_reply::Builder>();
+        // Candidates only count PreVotes that come back with the term they asked about,
+        // which is always ahead of ours when we grant one
+        result_builder.set_term(if vote_granted { term } else { current_term });
+        result_builder.set_vote_granted(vote_granted);
+        Ok(())
+    }
+}
+
 struct AppendEntriesHandler {
     state: Arc<Mutex<ServerState>>,
     log: Arc<Mutex<Log>>,
@@ -961,30 +1970,58 @@ impl AppendEntriesHandler {
             // Become follower for the higher term
             state.transition_to_follower(
                 message.get_term(), &self.to_state_machine.lock().unwrap(), None, self.log.clone()).unwrap();
-            reply.set_term(current_term);
+            reply.set_term(state.current_term);
         }
 
         debug_assert!(message.get_term() == state.current_term);
         // Reset election timer for this term.
-        state.last_leader_contact = (Instant::now(), Some(message.get_leader_id()));
-        // Check: (prev_log_term, prev_log_index) exists in our log
-        let prev_log_index = message.get_prev_log_index() as usize;
-        let (last_log_index, prev_log_entry_term) = {
-            let log = self.log.lock().unwrap();
-            (log.get_last_entry_index(),
-             log.get_entry(prev_log_index).map(|x| x.term).unwrap_or(0))
-        };
-        // Does (term, index) exist in our log?
-        if prev_log_index > last_log_index ||
-           prev_log_entry_term != message.get_prev_log_term() { return; }
-        // Append all the entries to our log.
-        let entries: Vec<Entry> = message.get_entries().unwrap().iter()
+        state.last_leader_contact = (state.clock.now(), Some(message.get_leader_id()));
+        let mut prev_log_index = message.get_prev_log_index() as usize;
+        let mut prev_log_term = message.get_prev_log_term();
+        let mut entries: Vec<Entry> = message.get_entries().unwrap().iter()
             .map(Entry::from_proto).collect();
         let commit_index = { // Append entries to log.
+            // Hold the log lock throughout so the state machine thread can't compact
+            // the log out from under us
             let mut log = self.log.lock().unwrap();
-            log.roll_back(prev_log_index).unwrap();
-            log.append_entries_blocking(entries).unwrap();
-            min(log.get_last_entry_index(), message.get_leader_commit() as usize)
+            let snapshot_index = log.get_start_index() - 1;
+            if prev_log_index < snapshot_index {
+                // The leader is sending us entries that we've already compacted into a snapshot.
+                // Those entries are committed, so they must match the leader's log.
+                entries.retain(|e| e.index > snapshot_index);
+                prev_log_index = snapshot_index;
+                prev_log_term = log.get_term(snapshot_index).unwrap();
+            }
+
+            // Check: (prev_log_term, prev_log_index) exists in our log
+            if prev_log_index > log.get_last_entry_index() {
+                // Tell the leader where our log ends, so it can start from there
+                reply.set_conflict_index(log.get_last_entry_index() as u64 + 1);
+                return;
+            }
+            // safe to unwrap since prev_log_index is either in the log or is the last
+            // entry in our snapshot
+            let term = log.get_term(prev_log_index).unwrap();
+            if term != prev_log_term {
+                // Tell the leader about the whole term that conflicts, so it can skip past all
+                // of it instead of backing up one entry at a time
+                reply.set_conflict_term(term);
+                reply.set_conflict_index(log.first_index_of_term(term).unwrap_or(prev_log_index) as u64);
+                return;
+            }
+            // The leader pipelines requests, so this one may arrive after a later one that we've
+            // already appended. Skip the entries we already have, and only truncate our log if
+            // one of them conflicts with the leader's.
+            let last_new_index = prev_log_index + entries.len();
+            let num_matching = entries.iter()
+                .take_while(|e| log.get_term(e.index) == Some(e.term))
+                .count();
+            if num_matching < entries.len() {
+                log.roll_back(prev_log_index + num_matching).unwrap();
+                log.append_entries_blocking(entries.split_off(num_matching)).unwrap();
+            }
+            // Entries past this request may not match the leader's log yet
+            min(last_new_index, message.get_leader_commit() as usize)
         };
         debug_assert!(matches!(state.current_state, State::Follower));
         // March forward the commit index.
@@ -1009,26 +2046,180 @@ impl RpcObject for AppendEntriesHandler {
     }
 }
 
+struct InstallSnapshotHandler {
+    state: Arc<Mutex<ServerState>>,
+    log: Arc<Mutex<Log>>,
+    to_state_machine: Arc<Mutex<Sender<StateMachineMessage>>>,
+    // the snapshot the leader is still sending us
+    incoming: Mutex<Option<IncomingSnapshot>>
+}
+
+impl InstallSnapshotHandler {
+    ///
+    /// Adds the chunk of the leader's snapshot in |message| to the snapshot we're receiving, and
+    /// hands the snapshot to the state machine thread once we've received all of it.
+    ///
+    /// # Panics
+    /// * Panics if the main thread or the state machine thread have panicked
+    ///
+    fn handle_message(&self, message: install_snapshot::Reader, reply: &mut install_snapshot_reply::Builder)
+        -> Result<(), capnp::Error> {
+        reply.set_success(false);
+        { // Check: If our term doesn't match the message's term...
+            let ref mut state = self.state.lock().unwrap();
+            reply.set_term(state.current_term);
+            if message.get_term() < state.current_term { return Ok(()); }
+            if message.get_term() > state.current_term ||
+                matches!(state.current_state, State::Candidate{..}) {
+                // Become follower for the higher term
+                state.transition_to_follower(
+                    message.get_term(), &self.to_state_machine.lock().unwrap(), None, self.log.clone()).unwrap();
+                reply.set_term(state.current_term);
+            }
+            debug_assert!(message.get_term() == state.current_term);
+            // Reset election timer for this term.
+            state.last_leader_contact = (state.clock.now(), Some(message.get_leader_id()));
+        }
+
+        let metadata = SnapshotMetadata::from_proto(message.get_metadata()?)?;
+        let mut incoming = self.incoming.lock().unwrap();
+        if message.get_offset() == 0 {
+            // The leader is (re)starting the transfer, possibly with a newer snapshot
+            *incoming = Some(IncomingSnapshot::new(metadata.clone()));
+        }
+
+        let chunk_written = match *incoming {
+            Some(ref mut snapshot) => {
+                // A chunk from some other snapshot means the leader will need to restart the transfer
+                snapshot.metadata == metadata &&
+                    snapshot.write_chunk(message.get_offset(), message.get_data()?)
+            },
+            // We missed the start of this snapshot, so the leader will need to resend it
+            None => false
+        };
+        if !chunk_written { return Ok(()); }
+        if !message.get_done() {
+            reply.set_success(true);
+            return Ok(());
+        }
+
+        // safe to unwrap since we just wrote a chunk to this snapshot
+        let (metadata, data) = incoming.take().unwrap().finish();
+        let (to_me, from_sm) = channel();
+        self.to_state_machine.lock().unwrap().send(
+            StateMachineMessage::InstallSnapshot {
+                metadata: metadata,
+                data: data,
+                response_channel: to_me
+            }).unwrap();
+        // Don't hold the state lock while we wait on the state machine thread, since it
+        // may need the lock to finish what it's working on.
+        match from_sm.recv().unwrap() {
+            Ok(last_applied) => {
+                let mut state = self.state.lock().unwrap();
+                // Everything in the snapshot has been committed and written to disk
+                if last_applied > state.commit_index {
+                    state.commit_index = last_applied;
+                }
+                if last_applied > state.last_persisted_index {
+                    state.last_persisted_index = last_applied;
+                }
+                reply.set_success(true);
+            },
+            Err(e) => warn!("Unable to install snapshot from leader: {:?}", e)
+        }
+        Ok(())
+    }
+}
+
+impl RpcObject for InstallSnapshotHandler {
+    fn handle_rpc (&self, params: capnp::any_pointer::Reader, result: capnp::any_pointer::Builder)
+        -> Result<(), RpcError>
+    {
+        let mut reply = result.init_as::<install_snapshot_reply::Builder>();
+        params.get_as::<install_snapshot::Reader>()
+            .and_then(|install_snapshot| self.handle_message(install_snapshot, &mut reply))
+            .map_err(RpcError::Capnp)
+    }
+}
+
+struct TimeoutNowHandler {
+    state: Arc<Mutex<ServerState>>,
+    log: Arc<Mutex<Log>>,
+    to_state_machine: Arc<Mutex<Sender<StateMachineMessage>>>,
+    to_main_thread: Arc<Mutex<Sender<MainThreadMessage>>>
+}
+
+impl TimeoutNowHandler {
+    ///
+    /// Asks the main thread to start an election if the message came from the leader
+    /// of our current term.
+    ///
+    /// # Panics
+    /// * Panics if the main thread or the state machine thread have panicked
+    ///
+    fn handle_message(&self, message: timeout_now::Reader, reply: &mut timeout_now_reply::Builder) {
+        let ref mut state = self.state.lock().unwrap();
+        if message.get_term() > state.current_term ||
+            (message.get_term() == state.current_term && matches!(state.current_state, State::Candidate{..})) {
+            // Become follower for the higher term
+            state.transition_to_follower(
+                message.get_term(), &self.to_state_machine.lock().unwrap(), None, self.log.clone()).unwrap();
+        }
+        reply.set_term(state.current_term);
+
+        if message.get_term() == state.current_term && matches!(state.current_state, State::Follower) {
+            state.last_leader_contact = (state.clock.now(), Some(message.get_leader_id()));
+            self.to_main_thread.lock().unwrap()
+                .send(MainThreadMessage::TimeoutNow(state.current_term)).unwrap();
+        }
+    }
+}
+
+impl RpcObject for TimeoutNowHandler {
+    fn handle_rpc (&self, params: capnp::any_pointer::Reader, result: capnp::any_pointer::Builder)
+        -> Result<(), RpcError>
+    {
+        let mut reply = result.init_as::<timeout_now_reply::Builder>();
+        params.get_as::<timeout_now::Reader>()
+            .map(|timeout_now| self.handle_message(timeout_now, &mut reply))
+            .map_err(RpcError::Capnp)
+    }
+}
+
 ///
-/// Returns a new random election timeout.
+/// Returns a new random election timeout, picked by |rng|.
 /// The election timeout should be reset whenever we transition into the follower state or the
 /// candidate state
 ///
-fn generate_election_timeout() -> Duration {
-    let btwn = Range::new(constants::ELECTION_TIMEOUT_MIN, constants::ELECTION_TIMEOUT_MAX);
-    let mut range = rand::thread_rng();
-    Duration::from_millis(btwn.ind_sample(&mut range))
+fn generate_election_timeout(range: (Duration, Duration), rng: &mut StdRng) -> Duration {
+    let to_millis = |d: Duration| d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64;
+    let btwn = Range::new(to_millis(range.0), to_millis(range.1));
+    Duration::from_millis(btwn.ind_sample(rng))
 }
 
 struct ClientRequestHandler {
+    me: u64,
     state: Arc<Mutex<ServerState>>,
+    log: Arc<Mutex<Log>>,
     to_state_machine: Arc<Mutex<Sender<StateMachineMessage>>>,
-    to_main_thread: Arc<Mutex<Sender<MainThreadMessage>>>
+    to_main_thread: Arc<Mutex<Sender<MainThreadMessage>>>,
+    // How long a majority acknowledging one of our heartbeats lets us serve reads
+    // without contacting our peers. None if lease reads are disabled
+    read_lease: Option<Duration>,
+    // How long a query waits for the state machine to catch up to its read index
+    query_timeout: Duration,
+    // How followers reach the leader for a read index
+    transport: Arc<Transport>
 }
 
 impl ClientRequestHandler {
+    ///
+    /// Client write. Blocks until the command is commited and applied, then returns the
+    /// state machine's reply along with the index the command was commited at.
+    ///
     fn client_write_blocking(&self, op: raft_command::Request)
-        -> Result<raft_command::Reply, RaftError>
+        -> Result<(raft_command::Reply, usize), RaftError>
     {
         let (to_me, from_sm) = channel();
         self.to_state_machine.lock().unwrap().send(
@@ -1040,19 +2231,110 @@ impl ClientRequestHandler {
     }
 
     ///
-    /// Client read. Blocks until most recent write is properly committed to the log,
-    /// then returns result from client state machine query.
+    /// Client read. Blocks until the state machine has applied |min_index|, or, if the client
+    /// didn't give one, every write that commited before the read came in, then returns result
+    /// from client state machine query.
+    /// The leader finds that index by confirming with a majority of the cluster that it's still
+    /// leader. Followers ask the leader for it.
+    /// Returns RaftError::Timeout if the state machine doesn't apply the read index within our
+    /// query timeout, which a client can cause by asking for an index that never commited.
     ///
-    fn client_read_blocking(&self, op: raft_query::Request)
+    fn client_read_blocking(&self, op: raft_query::Request, min_index: Option<usize>, is_leader: bool)
         -> Result<raft_query::Reply, RaftError>
     {
+        let read_index = match min_index {
+            Some(min_index) => min_index,
+            None if is_leader => match self.lease_read_index() {
+                Some(read_index) => read_index,
+                None => self.read_index_blocking()?
+            },
+            None => self.leader_read_index_blocking()?
+        };
         let (to_me, from_sm) = channel();
         self.to_state_machine.lock().unwrap().send(
             StateMachineMessage::Query {
                 query: op,
+                read_index: read_index,
                 response_channel: to_me,
             }).unwrap();
-        from_sm.recv().unwrap()
+        match from_sm.recv_timeout(self.query_timeout) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Timeout) => Err(RaftError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => panic!("State machine dropped a query")
+        }
+    }
+
+    ///
+    /// Returns the index a read can be served at without contacting our peers, if we hold
+    /// a read lease. We hold one while a majority of the cluster has acknowledged a heartbeat
+    /// we sent within the lease duration. Those servers won't vote for anyone else until an
+    /// election timeout after they received it, so as long as clocks don't drift apart by more
+    /// than the configured bound no other leader can have been elected yet.
+    /// Leadership transfers skip that wait, so leases are off while one is in progress and for
+    /// the rest of the term once we've told the target to start its election.
+    ///
+    fn lease_read_index(&self) -> Option<usize> {
+        let lease = match self.read_lease {
+            Some(lease) => lease,
+            None => return None
+        };
+        let state = self.state.lock().unwrap();
+        match state.current_state {
+            State::Leader{leadership_transfer: None, lease_revoked: false, ..} => {},
+            _ => return None
+        }
+        let mut log = self.log.lock().unwrap();
+        // Like ReadIndex, we only know our commit index is up to date once we've
+        // commited an entry from our own term
+        if log.get_term(state.commit_index) != Some(state.current_term) {
+            return None;
+        }
+        let quorums = voting_quorums(self.me, &state.peers, log.get_cluster_quorums());
+        let lease_start = state.clock.now() - lease;
+        if heard_from_majority_since(lease_start, self.me, &state.peers, &quorums) {
+            Some(state.commit_index)
+        } else {
+            None
+        }
+    }
+
+    fn read_index_blocking(&self) -> Result<usize, RaftError> {
+        let (to_me, from_main) = channel();
+        self.to_main_thread.lock().unwrap().send(
+            MainThreadMessage::ReadIndex(to_me)
+        ).unwrap();
+        from_main.recv().unwrap()
+    }
+
+    ///
+    /// Asks the leader we last heard from for a read index.
+    ///
+    /// # Errors
+    /// Returns RaftError::NotLeader if we don't know who the leader is or it isn't leader
+    /// anymore, and RaftError::IoError if we couldn't reach it.
+    ///
+    fn leader_read_index_blocking(&self) -> Result<usize, RaftError> {
+        let leader = self.state.lock().unwrap().last_leader_contact.1;
+        let leader_addr = leader.and_then(|leader| {
+            self.log.lock().unwrap().get_cluster_config().and_then(|config| {
+                config.into_iter().find(|&(id, _)| id == leader).map(|(_, addr)| addr)
+            })
+        });
+        let leader_addr = match leader_addr {
+            Some(addr) => addr,
+            None => return Err(RaftError::NotLeader(leader))
+        };
+        let msg = self.transport.send(leader_addr, &Rpc::new(constants::READ_INDEX_OPCODE))
+            .map_err(|e| RaftError::IoError(format!("{:?}", e)))?;
+        let result = Rpc::get_result_reader(&msg)
+            .map_err(|e| RaftError::IoError(format!("{:?}", e)))?;
+        let reply = result.get_as::<read_index_reply::Reader>()
+            .map_err(|e| RaftError::IoError(e.to_string()))?;
+        match reply.which() {
+            Ok(read_index_reply::ReadIndex(read_index)) => Ok(read_index as usize),
+            Ok(read_index_reply::Error(Ok(mut err))) => Err(client_command::raft_error_from_proto(&mut err)),
+            _ => Err(RaftError::Unknown)
+        }
     }
 
     fn add_server_blocking(&self, server_info: PeerInfo) -> Result<(), RaftError> {
@@ -1062,34 +2344,112 @@ impl ClientRequestHandler {
         ).unwrap();
         from_main.recv().unwrap()
     }
-}
 
-impl RpcObject for ClientRequestHandler {
-    fn handle_rpc (&self, params: capnp::any_pointer::Reader,
-                   result: capnp::any_pointer::Builder) -> Result<(), RpcError> {
-        params.get_as::<client_request::Reader>().map(|client_request| {
-            let (is_leader, mut reply) = {
-                let state = self.state.lock().unwrap();
-                (matches!(state.current_state, State::Leader { .. }),
-                 Err(RaftError::NotLeader(state.last_leader_contact.1)))
-            };
+    fn remove_server_blocking(&self, server_info: PeerInfo) -> Result<(), RaftError> {
+        let (to_me, from_main) = channel();
+        self.to_main_thread.lock().unwrap().send(
+            MainThreadMessage::RemoveServer(server_info, to_me)
+        ).unwrap();
+        from_main.recv().unwrap()
+    }
 
-            if is_leader { 
-                let op = client_command::request_from_proto(client_request);
-                reply = match op {
-                    client_command::Request::Command(op) =>
-                        self.client_write_blocking(op).map(client_command::Reply::Command),
-                    client_command::Request::Query(op) =>
-                        self.client_read_blocking(op).map(client_command::Reply::Query),
-                    client_command::Request::AddServer(server_info) => {
-                        self.add_server_blocking(server_info)
-                            .map(|_| client_command::Reply::AddServer)
-                    }
-                    client_command::Request::RemoveServer(server_info) => {
-                        unimplemented!()
-                    }
-                };
-            }
+    fn set_config_blocking(&self, servers: Vec<PeerInfo>) -> Result<(), RaftError> {
+        let (to_me, from_main) = channel();
+        self.to_main_thread.lock().unwrap().send(
+            MainThreadMessage::SetConfig(servers, to_me)
+        ).unwrap();
+        from_main.recv().unwrap()
+    }
+
+    fn add_learner_blocking(&self, server: PeerInfo) -> Result<(), RaftError> {
+        let (to_me, from_main) = channel();
+        self.to_main_thread.lock().unwrap().send(
+            MainThreadMessage::AddLearner(server, to_me)
+        ).unwrap();
+        from_main.recv().unwrap()
+    }
+
+    fn promote_learner_blocking(&self, id: u64) -> Result<(), RaftError> {
+        let (to_me, from_main) = channel();
+        self.to_main_thread.lock().unwrap().send(
+            MainThreadMessage::PromoteLearner(id, to_me)
+        ).unwrap();
+        from_main.recv().unwrap()
+    }
+
+    fn demote_to_learner_blocking(&self, id: u64) -> Result<(), RaftError> {
+        let (to_me, from_main) = channel();
+        self.to_main_thread.lock().unwrap().send(
+            MainThreadMessage::DemoteToLearner(id, to_me)
+        ).unwrap();
+        from_main.recv().unwrap()
+    }
+
+    fn transfer_leadership_blocking(&self, target: Option<u64>) -> Result<(), RaftError> {
+        let (to_me, from_main) = channel();
+        self.to_main_thread.lock().unwrap().send(
+            MainThreadMessage::TransferLeadership(target, to_me)
+        ).unwrap();
+        from_main.recv().unwrap()
+    }
+}
+
+impl RpcObject for ClientRequestHandler {
+    fn handle_rpc (&self, params: capnp::any_pointer::Reader,
+                   result: capnp::any_pointer::Builder) -> Result<(), RpcError> {
+        params.get_as::<client_request::Reader>().map(|client_request| {
+            let (is_leader, transfer_target, not_leader) = {
+                let state = self.state.lock().unwrap();
+                let transfer_target = match state.current_state {
+                    State::Leader { leadership_transfer: Some(ref transfer), .. } => Some(transfer.target),
+                    _ => None
+                };
+                (matches!(state.current_state, State::Leader { .. }),
+                 transfer_target,
+                 Err(RaftError::NotLeader(state.last_leader_contact.1)))
+            };
+
+            let op = client_command::request_from_proto(client_request);
+            let reply = match op {
+                // Any server can serve queries once its state machine has caught up
+                client_command::Request::Query(op, min_index) =>
+                    self.client_read_blocking(op, min_index, is_leader).map(client_command::Reply::Query),
+                _ if !is_leader => not_leader,
+                // We don't take new proposals while handing off leadership. Point the client
+                // at the server that should be leader soon
+                _ if transfer_target.is_some() => Err(RaftError::NotLeader(transfer_target)),
+                client_command::Request::Command(op) =>
+                    self.client_write_blocking(op)
+                        .map(|(reply, index)| client_command::Reply::Command(reply, index)),
+                client_command::Request::AddServer(server_info) => {
+                    self.add_server_blocking(server_info)
+                        .map(|_| client_command::Reply::AddServer)
+                }
+                client_command::Request::RemoveServer(server_info) => {
+                    self.remove_server_blocking(server_info)
+                        .map(|_| client_command::Reply::RemoveServer)
+                }
+                client_command::Request::TransferLeadership(target) => {
+                    self.transfer_leadership_blocking(target)
+                        .map(|_| client_command::Reply::TransferLeadership)
+                },
+                client_command::Request::SetConfig(servers) => {
+                    self.set_config_blocking(servers)
+                        .map(|_| client_command::Reply::SetConfig)
+                },
+                client_command::Request::AddLearner(server) => {
+                    self.add_learner_blocking(server)
+                        .map(|_| client_command::Reply::AddLearner)
+                },
+                client_command::Request::PromoteLearner(id) => {
+                    self.promote_learner_blocking(id)
+                        .map(|_| client_command::Reply::PromoteLearner)
+                },
+                client_command::Request::DemoteToLearner(id) => {
+                    self.demote_to_learner_blocking(id)
+                        .map(|_| client_command::Reply::DemoteToLearner)
+                }
+            };
             let mut reply_proto = result.init_as::<client_request::reply::Builder>();
             client_command::reply_to_proto(reply, &mut reply_proto);
         })
@@ -1097,23 +2457,51 @@ impl RpcObject for ClientRequestHandler {
     }
 }
 
+///
+/// Serves ReadIndex requests from followers, which need to know how far to catch up
+/// before answering a linearizable query.
+///
+struct ReadIndexHandler {
+    to_main_thread: Arc<Mutex<Sender<MainThreadMessage>>>
+}
+
+impl RpcObject for ReadIndexHandler {
+    fn handle_rpc (&self, _: capnp::any_pointer::Reader, result: capnp::any_pointer::Builder)
+        -> Result<(), RpcError>
+    {
+        let (to_me, from_main) = channel();
+        self.to_main_thread.lock().unwrap().send(
+            MainThreadMessage::ReadIndex(to_me)
+        ).unwrap();
+        let mut reply = result.init_as::<read_index_reply::Builder>();
+        match from_main.recv().unwrap() {
+            Ok(read_index) => reply.set_read_index(read_index as u64),
+            Err(err) => client_command::raft_error_to_proto(err, &mut reply.init_error())
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use super::*;
-    use super::rand::{thread_rng, Rng};
-    use super::peer::{PeerThreadMessage, PeerHandle, PeerState};
+    use super::rand::{SeedableRng, StdRng};
+    use super::peer::{PeerThreadMessage, PeerHandle, PeerState, FlowControl};
     use super::{ServerInfo, broadcast_append_entries, ServerState,
                 State, generate_election_timeout, update_commit_index,
                 StateMachineMessage,
                 handle_request_vote_reply};
-    use super::log::{Log, random_entries_with_term};
+    use super::log::{Log, random_entries_with_term, random_entry_with_term};
     use super::log::mocks::{new_mock_log, MockLogFileHandle};
+    use super::storage::MemoryStorage;
+    use super::super::rpc::transport::TcpTransport;
+    use super::clock::{Clock, SimulatedClock, SystemClock};
     use std::time::{Duration, Instant};
-    use std::sync::mpsc::{channel, Receiver};
-    use std::sync::{Arc, Mutex};
-    use std::fs;
-    use std::collections::VecDeque;
+    use simulation::sync::Mutex;
+    use simulation::sync::mpsc::{channel, Receiver};
+    use std::sync::Arc;
+    use std::collections::{BTreeMap, VecDeque};
 
     const DEFAULT_HEARTBEAT_TIMEOUT_MS: u64 = 150;
 
@@ -1121,30 +2509,40 @@ mod tests {
         peer_rx: Receiver<PeerThreadMessage>,
         state_machine_rx: Receiver<StateMachineMessage>,
         server: Server,
-        state_filename: String,
-        _log_file: MockLogFileHandle
+        // Must outlive the server, since it deletes the server's files
+        log_file: MockLogFileHandle
+    }
+
+    fn mock_election_timeout_range() -> (Duration, Duration) {
+        (Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MIN),
+         Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MAX))
     }
-    
-    impl Drop for MockServer {
-        /// Deletes state file
-        fn drop (&mut self) {
-            fs::remove_file(self.state_filename.clone()).unwrap();
+
+    fn mock_flow_control() -> FlowControl {
+        FlowControl {
+            max_in_flight: constants::DEFAULT_MAX_APPEND_ENTRIES_IN_FLIGHT,
+            max_batch_entries: constants::DEFAULT_MAX_APPEND_ENTRIES_BATCH,
+            max_batch_bytes: constants::DEFAULT_MAX_APPEND_ENTRIES_BYTES
         }
     }
 
-    fn mock_server(num_peers: u64) -> MockServer {
-        let state_filename_len = 20;
-        let mut state_filename: String = thread_rng().gen_ascii_chars().take(state_filename_len).collect();
-        state_filename = String::from("/tmp/") + &state_filename;
+    // Our peers are numbered from 0, so the mock server needs an id none of them use.
+    // Votes and replicas are counted by id
+    const MOCK_SERVER_ID: u64 = 100;
 
+    fn mock_server(num_peers: u64) -> MockServer {
         let (mock_log, log_file_handle) = new_mock_log();
         let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
         let (tx, rx) = channel();
         let (tx1, rx1) = channel();
         let peers = (0 .. num_peers)
             .map(|n| (n, PeerHandle {id: n, to_peer: tx.clone(),
-                                 next_index: 1, match_index: 0, thread: None, state: PeerState::Voting}))
-            .collect::<HashMap<u64, PeerHandle>>();
+                                 next_index: 1, match_index: 0, thread: None, state: PeerState::Voting,
+                                 last_contact: Instant::now(), in_flight: BTreeMap::new(), next_seq: 0,
+                                 probing: false,
+                                 flow_control: mock_flow_control(), clock: Arc::new(SystemClock)}))
+            .collect::<BTreeMap<u64, PeerHandle>>();
+        let mut rng = StdRng::from_seed(&[0][..]);
         let state = Arc::new(Mutex::new(ServerState {
             current_state: State::Follower,
             current_term: 0,
@@ -1152,23 +2550,31 @@ mod tests {
             voted_for: None,
             peers: peers,
             last_leader_contact: (Instant::now(), None),
-            election_timeout: generate_election_timeout(),
-            state_file: StateFile::new_from_filename(&state_filename).unwrap(),
-            last_persisted_index: 0
+            election_timeout: generate_election_timeout(mock_election_timeout_range(), &mut rng),
+            election_timeout_range: mock_election_timeout_range(),
+            storage: log_file_handle.storage.clone(),
+            last_persisted_index: 0,
+            clock: Arc::new(SystemClock),
+            rng: rng
         }));
         let server = Server {
             state: state,
             log: log,
             info: ServerInfo {
-                me: (0, SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080)),
+                me: (MOCK_SERVER_ID, SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080)),
                 heartbeat_timeout: Duration::from_millis(DEFAULT_HEARTBEAT_TIMEOUT_MS),
                 state_machine: StateMachineHandle {tx: tx1, thread: None},
                 to_me: channel().0,
+                storage: log_file_handle.storage.clone(),
+                pre_vote: false,
+                flow_control: mock_flow_control(),
+                max_rounds_for_new_server: constants::DEFAULT_MAX_ROUNDS_FOR_NEW_SERVER,
+                transport: Arc::new(TcpTransport),
+                clock: Arc::new(SystemClock)
             }
         };
         MockServer {peer_rx: rx, state_machine_rx: rx1, server: server,
-                    state_filename: state_filename,
-                    _log_file: log_file_handle}
+                    log_file: log_file_handle}
     }
 
     /// Makes sure peer threads receive appendEntries msesages
@@ -1191,7 +2597,10 @@ mod tests {
         state.current_state = State::Leader {
             last_heartbeat: Instant::now(),
             pending_cluster_changes: VecDeque::new(),
-            uncommited_cluster_change: None
+            uncommited_cluster_change: None,
+            leadership_transfer: None,
+            pending_reads: Vec::new(),
+            lease_revoked: false
         };
         broadcast_append_entries(&mut mock_server.server.info, state, mock_server.server.log.clone());
 
@@ -1214,7 +2623,7 @@ mod tests {
         const NUM_PEERS: u64 = 4;
         let mut mock_server = mock_server(NUM_PEERS);
         let mut state = mock_server.server.state.lock().unwrap();
-        Server::start_election(&mut mock_server.server.info, &mut state, mock_server.server.log.clone());
+        Server::start_election(&mut mock_server.server.info, &mut state, mock_server.server.log.clone(), false);
         assert!(matches!(state.current_state, State::Candidate{ .. }));
         // Each peer should have received a RequestVote message.
         for _ in 0..state.peers.len() {
@@ -1231,17 +2640,18 @@ mod tests {
     fn mock_replicate_two_entries() -> MockServer {
         const NUM_PEERS: u64 = 4;
         let mut mock_server = mock_server(NUM_PEERS);
-        {
-            let mut state = mock_server.server.state.lock().unwrap();
-            state.peers.get_mut(&0).unwrap().match_index = 2;
-            state.peers.get_mut(&1).unwrap().match_index = 1;
-            state.peers.get_mut(&2).unwrap().match_index = 2;
-            state.peers.get_mut(&3).unwrap().match_index = 3;
-        }
-
+        replicate_two_entries(&mut mock_server.server.state.lock().unwrap());
         mock_server
     }
 
+    // Sets our peers' match indices so that a majority of the cluster has the first two entries
+    fn replicate_two_entries(state: &mut ServerState) {
+        state.peers.get_mut(&0).unwrap().match_index = 2;
+        state.peers.get_mut(&1).unwrap().match_index = 1;
+        state.peers.get_mut(&2).unwrap().match_index = 2;
+        state.peers.get_mut(&3).unwrap().match_index = 3;
+    }
+
     #[test]
     fn leader_waits_on_persisted_index() {
         let mut mock_server = mock_replicate_two_entries();
@@ -1260,6 +2670,10 @@ mod tests {
         // we must be leader before commiting anything
         state.transition_to_candidate(&mut mock_server.server.info, mock_server.server.log.clone()).unwrap();
         state.transition_to_leader(&mut mock_server.server.info, mock_server.server.log.clone());
+        // Becoming leader appended the first entry and reset what we know about our peers.
+        // Only entries from our own term count towards the commit index
+        mock_server.server.log.lock().unwrap().append_entry(random_entry_with_term(state.current_term));
+        replicate_two_entries(&mut state);
         update_commit_index(&mut mock_server.server.info, &mut state, mock_server.server.log.clone());
         assert_eq!(state.commit_index, 2);
 
@@ -1277,29 +2691,134 @@ mod tests {
         // trigger an election
         {
             let mut state = mock_server.server.state.lock().unwrap();
-            Server::start_election(&mut mock_server.server.info, &mut state, mock_server.server.log.clone());
-            assert!(matches!(state.current_state, State::Candidate{ num_votes: 1, .. }));
+            Server::start_election(&mut mock_server.server.info, &mut state, mock_server.server.log.clone(), false);
+            assert_eq!(get_votes(&state), Some((1, false)));
         }
 
         let request_vote_reply = RequestVoteReply {
             term: 1,
-            vote_granted: true
+            vote_granted: true,
+            pre_vote: false,
+            peer: (1, mock_server.server.info.me.1)
         };
 
         let mut state = mock_server.server.state.lock().unwrap();
         handle_request_vote_reply(request_vote_reply, &mut mock_server.server.info, &mut state,
                                   mock_server.server.log.clone());
+        assert_eq!(get_votes(&state), Some((2, false)));
+
+        // the same server can't vote for us twice
+        handle_request_vote_reply(request_vote_reply, &mut mock_server.server.info, &mut state,
+                                  mock_server.server.log.clone());
+        assert_eq!(get_votes(&state), Some((2, false)));
+    }
+
+    #[test]
+    fn pre_vote_does_not_change_term() {
+        const NUM_PEERS: u64 = 4;
+        let mut mock_server = mock_server(NUM_PEERS);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        Server::start_pre_vote(&mut s.info, &mut state, s.log.clone());
+        assert_eq!(get_votes(&state), Some((1, true)));
+        assert_eq!(state.current_term, 0);
+        assert_eq!(state.voted_for, None);
+        for _ in 0..NUM_PEERS {
+            match mock_server.peer_rx.recv().unwrap() {
+                PeerThreadMessage::RequestVote(vote) => {
+                    assert!(vote.pre_vote);
+                    assert_eq!(vote.term, 1);
+                },
+                _ => panic!()
+            }
+        }
+    }
+
+    #[test]
+    fn pre_vote_majority_starts_election() {
+        const NUM_PEERS: u64 = 4;
+        let mut mock_server = mock_server(NUM_PEERS);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        Server::start_pre_vote(&mut s.info, &mut state, s.log.clone());
+        let pre_vote_reply = RequestVoteReply {
+            term: 1,
+            vote_granted: true,
+            pre_vote: true,
+            peer: (1, s.info.me.1)
+        };
+        // real votes don't count towards the PreVote
+        handle_request_vote_reply(RequestVoteReply {pre_vote: false, ..pre_vote_reply},
+                                  &mut s.info, &mut state, s.log.clone());
+        assert_eq!(get_votes(&state), Some((1, true)));
+
+        handle_request_vote_reply(pre_vote_reply, &mut s.info, &mut state, s.log.clone());
+        assert_eq!(get_votes(&state), Some((2, true)));
+        assert_eq!(state.current_term, 0);
+
+        handle_request_vote_reply(RequestVoteReply {peer: (2, s.info.me.1), ..pre_vote_reply},
+                                  &mut s.info, &mut state, s.log.clone());
+        assert_eq!(get_votes(&state), Some((1, false)));
+        assert_eq!(state.current_term, 1);
+        assert_eq!(state.voted_for, Some(s.info.me.0));
+
+        // late PreVote replies don't count towards the real election
+        handle_request_vote_reply(RequestVoteReply {peer: (3, s.info.me.1), ..pre_vote_reply},
+                                  &mut s.info, &mut state, s.log.clone());
+        assert_eq!(get_votes(&state), Some((1, false)));
+    }
+
+    #[test]
+    fn grants_pre_vote_only_after_election_timeout() {
+        let mock_server = mock_server(0);
+        let s = &mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        assert!(!grant_pre_vote(1, 0, 0, &state, &s.log));
+
+        state.last_leader_contact.0 = Instant::now() - Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MIN);
+        assert!(grant_pre_vote(1, 0, 0, &state, &s.log));
+        // the candidate has to be campaigning for a term after ours
+        assert!(!grant_pre_vote(0, 0, 0, &state, &s.log));
+        assert_eq!(state.current_term, 0);
+        assert_eq!(state.voted_for, None);
+    }
 
-        assert!(matches!(state.current_state, State::Candidate{ num_votes: 2, .. }));
+    #[test]
+    fn denies_pre_vote_for_stale_log() {
+        let mock_server = mock_server(0);
+        let s = &mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        s.log.lock().unwrap().append_entries_blocking(random_entries_with_term(2, 1)).unwrap();
+        state.current_term = 1;
+        state.last_leader_contact.0 = Instant::now() - Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MIN);
+        assert!(!grant_pre_vote(2, 1, 1, &state, &s.log));
+        assert!(grant_pre_vote(2, 2, 1, &state, &s.log));
     }
 
-    // Mocks casting a vote for this server
+    // Returns how many votes we have and whether they're PreVotes, if we're a candidate
+    fn get_votes(state: &ServerState) -> Option<(usize, bool)> {
+        match state.current_state {
+            State::Candidate{ref votes, pre_vote, ..} => Some((votes.len(), pre_vote)),
+            State::Leader{..} | State::Follower => None
+        }
+    }
+
+    // Mocks casting a vote for this server from a peer that hasn't voted for us yet
     fn cast_vote (s: &mut Server) {
+        let mut state = s.state.lock().unwrap();
+        let voter = {
+            let voted = |id: &u64| match state.current_state {
+                State::Candidate{ref votes, ..} => votes.contains(id),
+                State::Leader{..} | State::Follower => false
+            };
+            *state.peers.keys().filter(|&&id| !voted(&id)).min().unwrap()
+        };
         let request_vote_reply = RequestVoteReply {
             term: 1,
-            vote_granted: true
+            vote_granted: true,
+            pre_vote: false,
+            peer: (voter, s.info.me.1)
         };
-        let mut state = s.state.lock().unwrap();
         // we've already voted for ourselves
         handle_request_vote_reply(request_vote_reply.clone(), &mut s.info, &mut state, s.log.clone());
     }
@@ -1313,8 +2832,8 @@ mod tests {
             // trigger an election
             {
                 let mut state = s.state.lock().unwrap();
-                Server::start_election(&mut s.info, &mut state, s.log.clone());
-                assert!(matches!(state.current_state, State::Candidate{ num_votes: 1, .. }));
+                Server::start_election(&mut s.info, &mut state, s.log.clone(), false);
+                assert_eq!(get_votes(&state), Some((1, false)));
             }
 
             // there are peers.size() + 1 (ourself) servers in the cluster.
@@ -1340,8 +2859,8 @@ mod tests {
         let s = &mut mock_server.server;
 
         let state = s.state.lock().unwrap();
-        let _correct_vote_count = NUM_PEERS + 1;
-        assert!(matches!(state.current_state, State::Candidate{ num_votes: _correct_vote_count, .. }));
+        let correct_vote_count = (NUM_PEERS as usize + 1) / 2;
+        assert_eq!(get_votes(&state), Some((correct_vote_count, false)));
     }
 
     #[test]
@@ -1353,8 +2872,8 @@ mod tests {
         let s = &mut mock_server.server;
 
         let state = s.state.lock().unwrap();
-        let _correct_vote_count = NUM_PEERS + 1;
-        assert!(matches!(state.current_state, State::Candidate{ num_votes: _correct_vote_count, .. }));
+        let correct_vote_count = (NUM_PEERS as usize + 1) / 2;
+        assert_eq!(get_votes(&state), Some((correct_vote_count, false)));
     }
 
     // Returns a server that has mocked out being elected leader
@@ -1432,13 +2951,999 @@ mod tests {
         assert_eq!(state.commit_index, prev_commit_index);
     }
 
+    // Returns a mock leader whose log holds a config with ourself and |peer_ids|,
+    // and whose peers have all replicated the entire log.
+    fn mock_leader_with_config(peer_ids: &[u64]) -> MockServer {
+        const TERM: u64 = 1;
+        let mut mock_server = mock_server(peer_ids.len() as u64);
+        {
+            let s = &mut mock_server.server;
+            let mut config = vec![s.info.me];
+            config.extend(peer_ids.iter().map(|&id| (id, s.info.me.1)));
+            let last_index = {
+                let mut log = s.log.lock().unwrap();
+                log.append_entries_blocking(vec![Entry {
+                    index: 0,
+                    term: TERM,
+                    op: raft_command::Request::SetConfig(config, vec![])
+                }]).unwrap();
+                log.get_last_entry_index()
+            };
+
+            let mut state = s.state.lock().unwrap();
+            let handles: Vec<PeerHandle> = mem::replace(&mut state.peers, BTreeMap::new())
+                .into_iter().map(|(_, peer)| peer).collect();
+            for (mut peer, &id) in handles.into_iter().zip(peer_ids.iter()) {
+                peer.id = id;
+                peer.next_index = last_index + 1;
+                peer.match_index = last_index;
+                state.peers.insert(id, peer);
+            }
+            state.current_term = TERM;
+            state.commit_index = last_index;
+            state.last_persisted_index = last_index;
+            state.current_state = State::Leader {
+                last_heartbeat: Instant::now(),
+                pending_cluster_changes: VecDeque::new(),
+                uncommited_cluster_change: None,
+                leadership_transfer: None,
+                pending_reads: Vec::new(),
+                lease_revoked: false
+            };
+        }
+        mock_server
+    }
+
+    #[test]
+    fn leader_steps_down_without_quorum() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let term = state.current_term;
+        state.voted_for = Some(s.info.me.0);
+        let lost_contact = Instant::now() - Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MAX * 2);
+        for (_, peer) in state.peers.iter_mut() {
+            peer.last_contact = lost_contact;
+        }
+
+        assert!(check_quorum(&mut s.info, &mut state, s.log.clone()));
+        assert!(matches!(state.current_state, State::Follower));
+        assert_eq!(state.current_term, term);
+        assert_eq!(state.voted_for, Some(s.info.me.0));
+        assert_eq!(state.last_leader_contact.1, None);
+        // pending client commands get flushed
+        assert!(matches!(mock_server.state_machine_rx.recv().unwrap(), StateMachineMessage::Flush));
+    }
+
+    #[test]
+    fn leader_stays_leader_with_quorum() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        // we still hear from 1, so 2 out of 3 servers are in contact
+        state.peers.get_mut(&2).unwrap().last_contact =
+            Instant::now() - Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MAX * 2);
+
+        assert!(!check_quorum(&mut s.info, &mut state, s.log.clone()));
+        assert!(matches!(state.current_state, State::Leader{..}));
+    }
+
+    #[test]
+    fn append_entries_reply_refreshes_peer_contact() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let lost_contact = Instant::now() - Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MAX * 2);
+        for (_, peer) in state.peers.iter_mut() {
+            peer.last_contact = lost_contact;
+        }
+        let reply = AppendEntriesReply {
+            term: state.current_term,
+            commit_index: state.peers[&1].match_index,
+            peer: (1, s.info.me.1),
+            success: true,
+            seq: 0,
+            sent_at: Instant::now(),
+            conflict: None,
+            snapshot: false,
+            unreachable: false
+        };
+        handle_append_entries_reply(reply, &mut s.info, &mut state, s.log.clone());
+        assert!(state.peers[&1].last_contact > lost_contact);
+        assert!(!check_quorum(&mut s.info, &mut state, s.log.clone()));
+    }
+
+    #[test]
+    fn rejected_append_entries_reply_refreshes_peer_contact() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let lost_contact = Instant::now() - Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MAX * 2);
+        for (_, peer) in state.peers.iter_mut() {
+            peer.last_contact = lost_contact;
+        }
+        let (term, match_index, addr) = (state.current_term, state.peers[&1].match_index, s.info.me.1);
+        let rejection = move |unreachable| AppendEntriesReply {
+            term: term,
+            commit_index: match_index,
+            peer: (1, addr),
+            success: false,
+            seq: 0,
+            sent_at: Instant::now(),
+            conflict: None,
+            snapshot: false,
+            unreachable: unreachable
+        };
+        // we never heard back from a peer we couldn't reach
+        handle_append_entries_reply(rejection(true), &mut s.info, &mut state, s.log.clone());
+        assert_eq!(state.peers[&1].last_contact, lost_contact);
+
+        // but a peer that's still catching up follows us
+        handle_append_entries_reply(rejection(false), &mut s.info, &mut state, s.log.clone());
+        assert!(state.peers[&1].last_contact > lost_contact);
+        assert!(!check_quorum(&mut s.info, &mut state, s.log.clone()));
+    }
+
+    // Has peer 1 reject AppendEntries after index 8 with |conflict|, when our log has entries
+    // from term 1 up to index 4 and term 3 after that. Returns the prev_log_index we retry from.
+    fn retry_after_conflict(conflict: Option<(u64, usize)>) -> usize {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        {
+            let mut log = s.log.lock().unwrap();
+            log.append_entries_blocking(random_entries_with_term(3, 1)).unwrap();
+            log.append_entries_blocking(random_entries_with_term(4, 3)).unwrap();
+            assert_eq!(log.get_last_entry_index(), 8);
+        }
+        state.current_term = 3;
+        {
+            let peer = state.peers.get_mut(&1).unwrap();
+            peer.next_index = 9;
+            peer.match_index = 1;
+            peer.in_flight.insert(0, 8);
+        }
+        let reply = AppendEntriesReply {
+            term: state.current_term,
+            commit_index: 8,
+            peer: (1, s.info.me.1),
+            success: false,
+            seq: 0,
+            sent_at: Instant::now(),
+            conflict: conflict,
+            snapshot: false,
+            unreachable: false
+        };
+        handle_append_entries_reply(reply, &mut s.info, &mut state, s.log.clone());
+        assert!(state.peers[&1].probing);
+        match mock_server.peer_rx.recv().unwrap() {
+            PeerThreadMessage::AppendEntries(message) => message.prev_log_index,
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn unreachable_peer_waits_for_next_heartbeat() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let match_index = {
+            let peer = state.peers.get_mut(&1).unwrap();
+            peer.in_flight.insert(0, peer.match_index);
+            peer.match_index
+        };
+        let reply = AppendEntriesReply {
+            term: state.current_term,
+            commit_index: match_index,
+            peer: (1, s.info.me.1),
+            success: false,
+            seq: 0,
+            sent_at: Instant::now(),
+            conflict: None,
+            snapshot: false,
+            unreachable: true
+        };
+        handle_append_entries_reply(reply, &mut s.info, &mut state, s.log.clone());
+        assert!(mock_server.peer_rx.try_recv().is_err());
+        assert!(state.peers[&1].in_flight.is_empty());
+        assert!(state.peers[&1].probing);
+
+        broadcast_append_entries(&mut s.info, &mut state, s.log.clone());
+        assert!(matches!(mock_server.peer_rx.recv().unwrap(), PeerThreadMessage::AppendEntries(_)));
+    }
+
+    #[test]
+    fn reordered_append_entries_keep_pipelining() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        s.log.lock().unwrap().append_entries_blocking(random_entries_with_term(7, 1)).unwrap();
+        {
+            // we pipelined entries 3-4, 5-6 and 7-8 to a peer that has up to 2
+            let peer = state.peers.get_mut(&1).unwrap();
+            peer.next_index = 9;
+            peer.match_index = 2;
+            peer.in_flight = vec![(0, 2), (1, 4), (2, 6)].into_iter().collect();
+            peer.next_seq = 3;
+        }
+        let (term, addr) = (state.current_term, s.info.me.1);
+        let reply = move |seq: u64, success: bool, commit_index: usize| AppendEntriesReply {
+            term: term,
+            commit_index: commit_index,
+            peer: (1, addr),
+            success: success,
+            seq: seq,
+            sent_at: Instant::now(),
+            // the peer's log ends at 2
+            conflict: if success { None } else { Some((0, 3)) },
+            snapshot: false,
+            unreachable: false
+        };
+
+        // 5-6 got to the peer before 3-4, so we wait to hear about 3-4
+        handle_append_entries_reply(reply(1, false, 4), &mut s.info, &mut state, s.log.clone());
+        assert!(!state.peers[&1].probing);
+        assert_eq!(state.peers[&1].next_index, 5);
+        assert!(mock_server.peer_rx.try_recv().is_err());
+
+        // 3-4 went through, so we send the rest again
+        handle_append_entries_reply(reply(0, true, 4), &mut s.info, &mut state, s.log.clone());
+        match mock_server.peer_rx.try_recv().unwrap() {
+            PeerThreadMessage::AppendEntries(message) => {
+                assert_eq!(message.prev_log_index, 4);
+                assert_eq!(message.entries.len(), 4);
+            },
+            _ => panic!()
+        }
+        assert!(!state.peers[&1].probing);
+        assert_eq!(state.peers[&1].in_flight.len(), 2);
+
+        // 7-8 was rejected for the same reason, and we've already sent it again
+        handle_append_entries_reply(reply(2, false, 6), &mut s.info, &mut state, s.log.clone());
+        assert!(!state.peers[&1].probing);
+        assert!(mock_server.peer_rx.try_recv().is_err());
+
+        handle_append_entries_reply(reply(3, true, 8), &mut s.info, &mut state, s.log.clone());
+        assert_eq!(state.peers[&1].match_index, 8);
+        assert_eq!(state.peers[&1].next_index, 9);
+        assert!(state.peers[&1].in_flight.is_empty());
+    }
+
+    #[test]
+    fn append_entries_reply_without_conflict_backs_up_one_entry() {
+        assert_eq!(retry_after_conflict(None), 7);
+    }
+
+    #[test]
+    fn append_entries_reply_skips_to_end_of_short_log() {
+        assert_eq!(retry_after_conflict(Some((0, 4))), 3);
+    }
+
+    #[test]
+    fn append_entries_reply_skips_conflicting_term_we_dont_have() {
+        // the peer has entries from term 2 starting at index 3
+        assert_eq!(retry_after_conflict(Some((2, 3))), 2);
+    }
+
+    #[test]
+    fn append_entries_reply_skips_to_end_of_conflicting_term_we_have() {
+        // the peer has entries from term 1 after index 4
+        assert_eq!(retry_after_conflict(Some((1, 2))), 4);
+    }
+
+    #[test]
+    fn read_index_waits_for_majority_heartbeat() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let commit_index = state.commit_index;
+        let (tx, rx) = channel();
+        Server::read_index(tx, &mut s.info, &mut state, s.log.clone());
+        // we haven't heard back from any heartbeats sent after the read came in
+        assert!(rx.try_recv().is_err());
+
+        let heartbeat = match mock_server.peer_rx.recv().unwrap() {
+            PeerThreadMessage::AppendEntries(entry) => entry,
+            _ => panic!()
+        };
+        let reply = AppendEntriesReply {
+            term: state.current_term,
+            commit_index: heartbeat.prev_log_index + heartbeat.entries.len(),
+            peer: (1, s.info.me.1),
+            success: true,
+            seq: heartbeat.seq,
+            sent_at: heartbeat.sent_at,
+            conflict: None,
+            snapshot: false,
+            unreachable: false
+        };
+        handle_append_entries_reply(reply, &mut s.info, &mut state, s.log.clone());
+        assert_eq!(rx.recv().unwrap().unwrap(), commit_index);
+    }
+
+    #[test]
+    fn read_index_waits_on_entries_from_current_term() {
+        let mut mock_server = mock_leader_with_config(&[]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        state.current_term += 1;
+        let last_index = {
+            let mut log = s.log.lock().unwrap();
+            log.append_entry(Entry::noop(state.current_term));
+            log.get_last_entry_index()
+        };
+        assert!(state.commit_index < last_index);
+
+        let (tx, rx) = channel();
+        // we're the only server, so our leadership is confirmed right away
+        Server::read_index(tx, &mut s.info, &mut state, s.log.clone());
+        assert_eq!(rx.recv().unwrap().unwrap(), last_index);
+    }
+
+    #[test]
+    fn read_index_rejected_when_not_leader() {
+        let mut mock_server = mock_server(2);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        Server::read_index(tx, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(rx.recv().unwrap(), Err(RaftError::NotLeader(_))));
+    }
+
+    #[test]
+    fn stepping_down_rejects_pending_reads() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        Server::read_index(tx, &mut s.info, &mut state, s.log.clone());
+        let new_term = state.current_term + 1;
+        state.transition_to_follower(new_term, &s.info.state_machine.tx, None, s.log.clone()).unwrap();
+        assert!(matches!(rx.recv().unwrap(), Err(RaftError::NotLeader(_))));
+    }
+
+    fn mock_client_request_handler(s: &Server, read_lease: Option<Duration>) -> ClientRequestHandler {
+        ClientRequestHandler {
+            me: s.info.me.0,
+            state: s.state.clone(),
+            log: s.log.clone(),
+            to_state_machine: Arc::new(Mutex::new(s.info.state_machine.tx.clone())),
+            to_main_thread: Arc::new(Mutex::new(s.info.to_me.clone())),
+            read_lease: read_lease,
+            query_timeout: Duration::from_millis(constants::DEFAULT_QUERY_TIMEOUT),
+            transport: s.info.transport.clone()
+        }
+    }
+
+    #[test]
+    fn lease_reads_served_locally_after_recent_heartbeat() {
+        let mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mock_server.server;
+        let commit_index = s.state.lock().unwrap().commit_index;
+        let handler = mock_client_request_handler(s, Some(Duration::from_millis(100)));
+        assert_eq!(handler.lease_read_index(), Some(commit_index));
+
+        // without a lease we always go through ReadIndex
+        let handler = mock_client_request_handler(s, None);
+        assert_eq!(handler.lease_read_index(), None);
+    }
+
+    #[test]
+    fn lease_expires_without_majority_heartbeat() {
+        let mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mock_server.server;
+        {
+            let mut state = s.state.lock().unwrap();
+            let expired = Instant::now() - Duration::from_millis(200);
+            state.peers.get_mut(&1).unwrap().last_contact = expired;
+            state.peers.get_mut(&2).unwrap().last_contact = expired;
+        }
+        let handler = mock_client_request_handler(s, Some(Duration::from_millis(100)));
+        assert_eq!(handler.lease_read_index(), None);
+    }
+
+    #[test]
+    fn lease_disabled_by_leadership_transfer() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let (tx, _rx) = channel();
+        {
+            let s = &mut mock_server.server;
+            let mut state = s.state.lock().unwrap();
+            Server::transfer_leadership(Some(1), tx, &mut s.info, &mut state, s.log.clone());
+        }
+        let handler = mock_client_request_handler(&mock_server.server, Some(Duration::from_millis(100)));
+        assert_eq!(handler.lease_read_index(), None);
+
+        // 1 may still win the election we asked it to start even if the transfer is aborted
+        {
+            let mut state = mock_server.server.state.lock().unwrap();
+            if let State::Leader{ref mut leadership_transfer, ..} = state.current_state {
+                *leadership_transfer = None;
+            }
+        }
+        assert_eq!(handler.lease_read_index(), None);
+    }
+
+    #[test]
+    fn follower_read_waits_for_client_min_index() {
+        let mock_server = mock_server(2);
+        let handler = mock_client_request_handler(&mock_server.server, None);
+        let query = raft_query::Request::StateMachineQuery(vec![1, 2]);
+        let read = thread::spawn(move || handler.client_read_blocking(query, Some(7), false));
+        match mock_server.state_machine_rx.recv().unwrap() {
+            StateMachineMessage::Query { read_index, response_channel, .. } => {
+                assert_eq!(read_index, 7);
+                response_channel.send(Ok(raft_query::Reply::StateMachineQuery(vec![3]))).unwrap();
+            },
+            _ => panic!("Expected the query to be sent to the state machine")
+        }
+        assert_eq!(read.join().unwrap(), Ok(raft_query::Reply::StateMachineQuery(vec![3])));
+    }
+
+    #[test]
+    fn follower_read_times_out_waiting_for_min_index() {
+        let mock_server = mock_server(2);
+        let handler = ClientRequestHandler {
+            query_timeout: Duration::from_millis(50),
+            ..mock_client_request_handler(&mock_server.server, None)
+        };
+        let query = raft_query::Request::StateMachineQuery(vec![1, 2]);
+        // nothing ever gets applied, so the state machine never answers
+        assert_eq!(handler.client_read_blocking(query, Some(7), false), Err(RaftError::Timeout));
+        assert!(matches!(mock_server.state_machine_rx.recv().unwrap(),
+                         StateMachineMessage::Query { read_index: 7, .. }));
+    }
+
+    #[test]
+    fn follower_read_without_leader_is_rejected() {
+        let mock_server = mock_server(2);
+        let handler = mock_client_request_handler(&mock_server.server, None);
+        let query = raft_query::Request::StateMachineQuery(vec![1, 2]);
+        assert_eq!(handler.client_read_blocking(query, None, false), Err(RaftError::NotLeader(None)));
+        assert!(mock_server.state_machine_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn leader_only_commits_entries_from_its_own_term() {
+        // Figure 8 from the Raft paper: we replicated an entry to one peer while we led term 2,
+        // then server 4 led term 3 without getting it. Now we lead term 4.
+        let mut mock_server = mock_leader_with_config(&[1, 2, 3, 4]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let old_index = {
+            let mut log = s.log.lock().unwrap();
+            log.append_entries_blocking(random_entries_with_term(1, 2)).unwrap();
+            log.get_last_entry_index()
+        };
+        state.current_term = 4;
+        state.last_persisted_index = old_index;
+        state.peers.get_mut(&1).unwrap().match_index = old_index;
+        state.peers.get_mut(&2).unwrap().match_index = old_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        // server 4 could still win an election with the votes of 2 and 3, and overwrite the entry
+        assert!(state.commit_index < old_index);
+
+        // once an entry from our own term is on a majority, everything before it is too
+        let new_index = s.log.lock().unwrap().append_entry(Entry::noop(4)).get_last_entry_index();
+        state.last_persisted_index = new_index;
+        state.peers.get_mut(&1).unwrap().match_index = new_index;
+        state.peers.get_mut(&2).unwrap().match_index = new_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert_eq!(state.commit_index, new_index);
+    }
+
+    // Returns whether the state machine was told to commit |index|
+    fn commited(state_machine_rx: &Receiver<StateMachineMessage>, index: usize) -> bool {
+        state_machine_rx.try_iter().any(|message| match message {
+            StateMachineMessage::Commit(commit_index) => commit_index >= index,
+            _ => false
+        })
+    }
+
+    #[test]
+    fn figure_8_entry_from_earlier_term_is_never_commited_on_its_own() {
+        // Figure 8 from the Raft paper, as seen by server 1. Our peers 1 through 4 are
+        // servers 2 through 5
+        let mut mock_server = mock_leader_with_config(&[1, 2, 3, 4]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let me = s.info.me;
+        let reply = |term: u64, peer: u64, index: usize| AppendEntriesReply {
+            term: term,
+            commit_index: index,
+            peer: (peer, me.1),
+            success: true,
+            seq: 0,
+            sent_at: Instant::now(),
+            conflict: None,
+            snapshot: false,
+            unreachable: false
+        };
+
+        // (a) We lead term 2, and get an entry to server 2 before we crash
+        state.current_term = 2;
+        let old_index = s.log.lock().unwrap().append_entry(Entry::noop(2)).get_last_entry_index();
+        state.last_persisted_index = old_index;
+        handle_append_entries_reply(reply(2, 1, old_index), &mut s.info, &mut state, s.log.clone());
+        assert!(state.commit_index < old_index);
+
+        // (b) Server 5 leads term 3 with the votes of servers 3 and 4, while we're down.
+        // It has its own entry at |old_index|, that only it has
+        state.transition_to_follower(3, &s.info.state_machine.tx, Some(4), s.log.clone()).unwrap();
+
+        // (c) We come back and win term 4 with the votes of servers 2 and 3
+        Server::start_election(&mut s.info, &mut state, s.log.clone(), false);
+        assert_eq!(state.current_term, 4);
+        let vote = RequestVoteReply { term: 4, vote_granted: true, pre_vote: false, peer: (1, me.1) };
+        handle_request_vote_reply(vote, &mut s.info, &mut state, s.log.clone());
+        handle_request_vote_reply(RequestVoteReply {peer: (2, me.1), ..vote}, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(state.current_state, State::Leader{ .. }));
+        let noop_index = s.log.lock().unwrap().get_last_entry_index();
+        assert_eq!(noop_index, old_index + 1);
+        state.last_persisted_index = noop_index;
+        // Our entry from term 2 reaches server 3, so a majority has it. We crash before
+        // our noop reaches anyone
+        handle_append_entries_reply(reply(4, 1, old_index), &mut s.info, &mut state, s.log.clone());
+        handle_append_entries_reply(reply(4, 2, old_index), &mut s.info, &mut state, s.log.clone());
+        assert!(state.commit_index < old_index);
+
+        // (d) Servers 2, 3 and 4 would vote for server 5, whose last entry is from term 3.
+        // It replaces our entry everywhere, so it must never have been commited
+        state.transition_to_follower(5, &s.info.state_machine.tx, Some(4), s.log.clone()).unwrap();
+        {
+            let mut log = s.log.lock().unwrap();
+            log.roll_back(old_index - 1).unwrap();
+            log.append_entries_blocking(vec![Entry {index: old_index, ..Entry::noop(3)}]).unwrap();
+        }
+        assert!(!commited(&mock_server.state_machine_rx, old_index));
+    }
+
+    #[test]
+    fn removing_follower_drops_its_peer_once_config_commits() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        Server::remove_server((2, s.info.me.1), tx, &mut s.info, &mut state, s.log.clone());
+        let config_index = {
+            let mut log = s.log.lock().unwrap();
+            assert_eq!(log.get_cluster_config().unwrap(), vec![s.info.me, (1, s.info.me.1)]);
+            log.get_last_entry_index()
+        };
+        state.last_persisted_index = config_index;
+
+        // the removed server doesn't count towards commiting the new config
+        state.peers.get_mut(&2).unwrap().match_index = config_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert!(state.commit_index < config_index);
+        assert!(rx.try_recv().is_err());
+        assert!(state.peers.contains_key(&2));
+
+        state.peers.get_mut(&1).unwrap().match_index = config_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert_eq!(state.commit_index, config_index);
+        assert!(rx.recv().unwrap().is_ok());
+        assert!(!state.peers.contains_key(&2));
+        assert!(matches!(state.current_state, State::Leader{ .. }));
+    }
+
+    #[test]
+    fn removed_leader_steps_down_once_config_commits() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        let me = s.info.me;
+        Server::remove_server(me, tx, &mut s.info, &mut state, s.log.clone());
+        let config_index = {
+            let mut log = s.log.lock().unwrap();
+            assert_eq!(log.get_cluster_config().unwrap(), vec![(1, me.1), (2, me.1)]);
+            log.get_last_entry_index()
+        };
+
+        // we no longer count towards a majority ourselves
+        state.last_persisted_index = config_index;
+        state.peers.get_mut(&1).unwrap().match_index = config_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert!(state.commit_index < config_index);
+        assert!(matches!(state.current_state, State::Leader{ .. }));
+
+        state.peers.get_mut(&2).unwrap().match_index = config_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert_eq!(state.commit_index, config_index);
+        assert!(rx.recv().unwrap().is_ok());
+        assert!(matches!(state.current_state, State::Follower));
+        assert_eq!(state.current_term, 1);
+    }
+
+    #[test]
+    fn joint_config_needs_majority_of_both_configs() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        let me = s.info.me;
+        Server::set_config(vec![me, (1, me.1)], tx, &mut s.info, &mut state, s.log.clone());
+        let joint_index = {
+            let mut log = s.log.lock().unwrap();
+            assert_eq!(log.get_cluster_quorums().unwrap(),
+                       vec![vec![me, (1, me.1), (2, me.1)], vec![me, (1, me.1)]]);
+            log.get_last_entry_index()
+        };
+        state.last_persisted_index = joint_index;
+
+        // a majority of the old config isn't enough
+        state.peers.get_mut(&2).unwrap().match_index = joint_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert!(state.commit_index < joint_index);
+
+        // once the joint config commits we move on to the new config
+        state.peers.get_mut(&1).unwrap().match_index = joint_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert_eq!(state.commit_index, joint_index);
+        assert!(rx.try_recv().is_err());
+        let final_index = {
+            let mut log = s.log.lock().unwrap();
+            assert_eq!(log.get_cluster_quorums().unwrap(), vec![vec![me, (1, me.1)]]);
+            log.get_last_entry_index()
+        };
+        assert_eq!(final_index, joint_index + 1);
+        assert!(state.peers.contains_key(&2));
+
+        state.last_persisted_index = final_index;
+        state.peers.get_mut(&1).unwrap().match_index = final_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert_eq!(state.commit_index, final_index);
+        assert!(rx.recv().unwrap().is_ok());
+        assert!(!state.peers.contains_key(&2));
+        assert!(matches!(state.current_state, State::Leader{ .. }));
+    }
+
+    #[test]
+    fn candidate_needs_votes_from_both_configs() {
+        let mut mock_server = mock_server(2);
+        let s = &mut mock_server.server;
+        let me = s.info.me;
+        s.log.lock().unwrap().append_entries_blocking(vec![Entry {
+            index: 0,
+            term: 0,
+            op: raft_command::Request::SetJointConfig(vec![me, (1, me.1), (2, me.1)], vec![(1, me.1), (2, me.1)], vec![])
+        }]).unwrap();
+        let mut state = s.state.lock().unwrap();
+        Server::start_election(&mut s.info, &mut state, s.log.clone(), false);
+
+        // we're a majority of the old config but not of the new one
+        let vote = RequestVoteReply { term: 1, vote_granted: true, pre_vote: false, peer: (1, me.1) };
+        handle_request_vote_reply(vote, &mut s.info, &mut state, s.log.clone());
+        assert_eq!(get_votes(&state), Some((2, false)));
+
+        handle_request_vote_reply(RequestVoteReply {peer: (2, me.1), ..vote}, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(state.current_state, State::Leader{ .. }));
+    }
+
+    #[test]
+    fn new_leader_leaves_joint_config_once_it_commits() {
+        let mut mock_server = mock_server(2);
+        let s = &mut mock_server.server;
+        let me = s.info.me;
+        let new_config = vec![(0, me.1), (1, me.1)];
+        s.log.lock().unwrap().append_entries_blocking(vec![Entry {
+            index: 0,
+            term: 0,
+            op: raft_command::Request::SetJointConfig(vec![me, (0, me.1), (1, me.1)], new_config.clone(), vec![])
+        }]).unwrap();
+        let mut state = s.state.lock().unwrap();
+        Server::start_election(&mut s.info, &mut state, s.log.clone(), false);
+        let vote = RequestVoteReply { term: 1, vote_granted: true, pre_vote: false, peer: (0, me.1) };
+        handle_request_vote_reply(vote, &mut s.info, &mut state, s.log.clone());
+        handle_request_vote_reply(RequestVoteReply {peer: (1, me.1), ..vote}, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(state.current_state, State::Leader{ .. }));
+
+        // the joint config may not have commited yet, so we can't leave it until our noop does
+        let noop_index = {
+            let mut log = s.log.lock().unwrap();
+            assert_eq!(log.get_cluster_quorums().unwrap().len(), 2);
+            log.get_last_entry_index()
+        };
+        state.last_persisted_index = noop_index;
+        state.peers.get_mut(&0).unwrap().match_index = noop_index;
+        state.peers.get_mut(&1).unwrap().match_index = noop_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert_eq!(state.commit_index, noop_index);
+        let mut log = s.log.lock().unwrap();
+        assert_eq!(log.get_last_entry_index(), noop_index + 1);
+        assert_eq!(log.get_cluster_quorums().unwrap(), vec![new_config]);
+    }
+
+    #[test]
+    fn learners_dont_count_towards_commits() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        let me = s.info.me;
+        Server::demote_to_learner(2, tx, &mut s.info, &mut state, s.log.clone());
+        let config_index = {
+            let mut log = s.log.lock().unwrap();
+            assert_eq!(log.get_cluster_config().unwrap(), vec![me, (1, me.1)]);
+            assert_eq!(log.get_cluster_learners(), vec![(2, me.1)]);
+            log.get_last_entry_index()
+        };
+        assert!(matches!(state.peers[&2].state, PeerState::Learner));
+        state.last_persisted_index = config_index;
+
+        state.peers.get_mut(&2).unwrap().match_index = config_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert!(state.commit_index < config_index);
+
+        state.peers.get_mut(&1).unwrap().match_index = config_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert_eq!(state.commit_index, config_index);
+        assert!(rx.recv().unwrap().is_ok());
+        // learners keep getting the log
+        assert!(state.peers.contains_key(&2));
+
+        let (tx, rx) = channel();
+        Server::promote_learner(2, tx, &mut s.info, &mut state, s.log.clone());
+        {
+            let mut log = s.log.lock().unwrap();
+            assert_eq!(log.get_cluster_config().unwrap(), vec![me, (1, me.1), (2, me.1)]);
+            assert_eq!(log.get_cluster_learners(), vec![]);
+        }
+        assert!(matches!(state.peers[&2].state, PeerState::Voting));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_learner_changes() {
+        let mut mock_server = mock_leader_with_config(&[1]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let me = s.info.me;
+
+        let (tx, rx) = channel();
+        Server::add_learner((1, me.1), tx, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(rx.recv().unwrap(), Err(RaftError::ClientError(_))));
+        let (tx, rx) = channel();
+        Server::promote_learner(1, tx, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(rx.recv().unwrap(), Err(RaftError::ClientError(_))));
+        let (tx, rx) = channel();
+        Server::demote_to_learner(7, tx, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(rx.recv().unwrap(), Err(RaftError::ClientError(_))));
+
+        // we can't demote the last voting server
+        let (tx, rx) = channel();
+        Server::demote_to_learner(1, tx, &mut s.info, &mut state, s.log.clone());
+        state.last_persisted_index = s.log.lock().unwrap().get_last_entry_index();
+        state.peers.get_mut(&1).unwrap().match_index = state.last_persisted_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert!(rx.recv().unwrap().is_ok());
+        let (tx, rx) = channel();
+        Server::demote_to_learner(me.0, tx, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(rx.recv().unwrap(), Err(RaftError::ClientError(_))));
+    }
+
+    #[test]
+    fn demoted_leader_steps_down_once_config_commits() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        let me = s.info.me;
+        Server::demote_to_learner(me.0, tx, &mut s.info, &mut state, s.log.clone());
+        let config_index = s.log.lock().unwrap().get_last_entry_index();
+        state.last_persisted_index = config_index;
+        state.peers.get_mut(&1).unwrap().match_index = config_index;
+        state.peers.get_mut(&2).unwrap().match_index = config_index;
+        update_commit_index(&mut s.info, &mut state, s.log.clone());
+        assert!(rx.recv().unwrap().is_ok());
+        assert!(matches!(state.current_state, State::Follower));
+        assert_eq!(s.log.lock().unwrap().get_cluster_learners(), vec![me]);
+    }
+
+    #[test]
+    fn refuses_to_remove_last_server() {
+        let mut mock_server = mock_leader_with_config(&[]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        let me = s.info.me;
+        Server::remove_server(me, tx, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(rx.recv().unwrap(), Err(RaftError::ClientError(_))));
+        assert_eq!(s.log.lock().unwrap().get_cluster_config().unwrap(), vec![me]);
+    }
+
+    #[test]
+    fn removed_server_does_not_start_elections() {
+        let mut mock_server = mock_server(0);
+        let other_server = (1, mock_server.server.info.me.1);
+        mock_server.server.log.lock().unwrap().append_entries_blocking(vec![Entry {
+            index: 0,
+            term: 1,
+            op: raft_command::Request::SetConfig(vec![other_server], vec![])
+        }]).unwrap();
+        mock_server.server.state.lock().unwrap().last_leader_contact =
+            (Instant::now() - Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MAX * 2), None);
+
+        mock_server.server.handle_timeout();
+        let state = mock_server.server.state.lock().unwrap();
+        assert!(matches!(state.current_state, State::Follower));
+        assert_eq!(state.current_term, 0);
+    }
+
+    #[test]
+    fn follower_times_out_on_its_clock() {
+        let mut mock_server = mock_server(0);
+        let clock = Arc::new(SimulatedClock::new());
+        {
+            let mut state = mock_server.server.state.lock().unwrap();
+            state.clock = clock.clone();
+            state.last_leader_contact = (clock.now(), None);
+        }
+
+        // No real amount of time passing should start an election
+        mock_server.server.handle_timeout();
+        assert!(matches!(mock_server.server.state.lock().unwrap().current_state, State::Follower));
+
+        clock.advance(Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MAX * 2));
+        mock_server.server.handle_timeout();
+        let state = mock_server.server.state.lock().unwrap();
+        assert!(!matches!(state.current_state, State::Follower));
+        assert_eq!(state.current_term, 1);
+    }
+
+    #[test]
+    fn seeded_election_timeouts_repeat() {
+        let range = mock_election_timeout_range();
+        let (mut rng1, mut rng2) = (StdRng::from_seed(&[42][..]), StdRng::from_seed(&[42][..]));
+        for _ in 0..10 {
+            let timeout = generate_election_timeout(range, &mut rng1);
+            assert_eq!(timeout, generate_election_timeout(range, &mut rng2));
+            assert!(timeout >= range.0 && timeout < range.1);
+        }
+    }
+
+    // Returns the target of the leader's ongoing leadership transfer and whether we've
+    // sent it a TimeoutNow yet
+    fn get_leadership_transfer(state: &ServerState) -> Option<(u64, bool)> {
+        match state.current_state {
+            State::Leader{leadership_transfer: Some(ref transfer), ..} =>
+                Some((transfer.target, transfer.timeout_now_sent)),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn transfer_leadership_sends_timeout_now_to_caught_up_peer() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, _rx) = channel();
+        Server::transfer_leadership(Some(2), tx, &mut s.info, &mut state, s.log.clone());
+        match mock_server.peer_rx.recv().unwrap() {
+            PeerThreadMessage::TimeoutNow(message) => {
+                assert_eq!(message.term, state.current_term);
+                assert_eq!(message.leader_id, s.info.me.0);
+            },
+            _ => panic!()
+        }
+        assert_eq!(get_leadership_transfer(&state), Some((2, true)));
+    }
+
+    #[test]
+    fn transfer_leadership_catches_up_target_first() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let last_index = s.log.lock().unwrap().get_last_entry_index();
+        {
+            let peer = state.peers.get_mut(&2).unwrap();
+            peer.next_index = last_index;
+            peer.match_index = last_index - 1;
+        }
+        let (tx, _rx) = channel();
+        Server::transfer_leadership(Some(2), tx, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(mock_server.peer_rx.recv().unwrap(), PeerThreadMessage::AppendEntries(_)));
+        assert_eq!(get_leadership_transfer(&state), Some((2, false)));
+
+        let reply = AppendEntriesReply {
+            term: state.current_term,
+            commit_index: last_index,
+            peer: (2, s.info.me.1),
+            success: true,
+            seq: 0,
+            sent_at: Instant::now(),
+            conflict: None,
+            snapshot: false,
+            unreachable: false
+        };
+        handle_append_entries_reply(reply, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(mock_server.peer_rx.recv().unwrap(), PeerThreadMessage::TimeoutNow(_)));
+        assert_eq!(get_leadership_transfer(&state), Some((2, true)));
+    }
+
+    #[test]
+    fn transfer_leadership_picks_most_up_to_date_peer() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        {
+            let peer = state.peers.get_mut(&1).unwrap();
+            peer.next_index = 1;
+            peer.match_index = 0;
+        }
+        let (tx, _rx) = channel();
+        Server::transfer_leadership(None, tx, &mut s.info, &mut state, s.log.clone());
+        assert_eq!(get_leadership_transfer(&state), Some((2, true)));
+    }
+
+    #[test]
+    fn transfer_leadership_rejects_unknown_server() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        Server::transfer_leadership(Some(3), tx, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(rx.recv().unwrap(), Err(RaftError::ClientError(_))));
+        assert_eq!(get_leadership_transfer(&state), None);
+    }
+
+    #[test]
+    fn leadership_transfer_times_out() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        Server::transfer_leadership(Some(1), tx, &mut s.info, &mut state, s.log.clone());
+
+        check_leadership_transfer_timeout(&mut state);
+        assert!(rx.try_recv().is_err());
+        if let State::Leader{leadership_transfer: Some(ref mut transfer), ..} = state.current_state {
+            transfer.start_time = Instant::now() - Duration::from_millis(constants::DEFAULT_ELECTION_TIMEOUT_MAX * 2);
+        }
+        check_leadership_transfer_timeout(&mut state);
+        assert!(matches!(rx.recv().unwrap(), Err(RaftError::Timeout)));
+        assert_eq!(get_leadership_transfer(&state), None);
+    }
+
+    #[test]
+    fn stepping_down_completes_leadership_transfer() {
+        let mut mock_server = mock_leader_with_config(&[1, 2]);
+        let s = &mut mock_server.server;
+        let mut state = s.state.lock().unwrap();
+        let (tx, rx) = channel();
+        Server::transfer_leadership(Some(1), tx, &mut s.info, &mut state, s.log.clone());
+        let new_term = state.current_term + 1;
+        state.transition_to_follower(new_term, &s.info.state_machine.tx, Some(1), s.log.clone()).unwrap();
+        assert!(rx.recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn timeout_now_starts_election_immediately() {
+        let mut mock_leader = mock_leader_with_config(&[]);
+        let s = &mut mock_leader.server;
+        let mut state = s.state.lock().unwrap();
+        let term = state.current_term;
+        state.current_state = State::Follower;
+        state.last_leader_contact = (Instant::now(), Some(1));
+
+        // stale requests are ignored
+        Server::handle_timeout_now(term - 1, &mut s.info, &mut state, s.log.clone());
+        assert!(matches!(state.current_state, State::Follower));
+
+        Server::handle_timeout_now(term, &mut s.info, &mut state, s.log.clone());
+        // we're the only server in our config, so we win right away
+        assert!(matches!(state.current_state, State::Leader{ .. }));
+        assert_eq!(state.current_term, term + 1);
+    }
+
     // TODO: Come up with a consistent way to structure modules and unit tests 
     mod server_state {
         use super::mock_server;
         use super::super::{State};
         use super::super::peer::{PeerThreadMessage};
         use super::super::super::common::{raft_command};
-        use super::super::StateFile;
+        use super::super::state_file::StateFile;
 
         #[test]
         fn transition_to_candidate_normal() {
@@ -1452,10 +3957,10 @@ mod tests {
 
             state.transition_to_candidate(&mut s.info, s.log.clone()).unwrap();
             match state.current_state {
-                State::Candidate{num_votes, ..} => {
+                State::Candidate{ref votes, ..} => {
                     assert_eq!(state.current_term, 1);
                     assert_eq!(state.voted_for, Some(s.info.me.0));
-                    assert_eq!(num_votes, 1);
+                    assert_eq!(votes.len(), 1);
                 },
                 _ => panic!("Transition to candidate did not enter the candidate state.")
             }
@@ -1486,7 +3991,7 @@ mod tests {
                         // our commit index when the leader changes, the first broadcast
                         // should actually just be a heartbeat.
                         assert_eq!(msg.entries.len(), 1);
-                        assert_eq!(*entry, msg.entries[0]);
+                        assert_eq!(entry, msg.entries[0]);
                     },
                     _=> panic!("Incorrect message type sent in response to transition to leader")
                 }
@@ -1534,7 +4039,7 @@ mod tests {
             let mut state = s.state.lock().unwrap();
             state.transition_to_follower(1, &s.info.state_machine.tx, None, s.log.clone()).unwrap();
 
-            let mut state_file = StateFile::new_from_filename(&mock_server.state_filename).unwrap();
+            let mut state_file = StateFile::new_from_filename(&mock_server.log_file.state_name).unwrap();
             let state = state_file.get_state().unwrap();
             assert_eq!(state.term, 1);
             assert_eq!(state.voted_for, None);
@@ -1549,7 +4054,7 @@ mod tests {
             let mut state = s.state.lock().unwrap();
             state.transition_to_follower(1, &s.info.state_machine.tx, Some(VOTE_ID), s.log.clone()).unwrap();
 
-            let mut state_file = StateFile::new_from_filename(&mock_server.state_filename).unwrap();
+            let mut state_file = StateFile::new_from_filename(&mock_server.log_file.state_name).unwrap();
             let state = state_file.get_state().unwrap();
             assert_eq!(state.term, 1);
             assert_eq!(state.voted_for, Some(VOTE_ID));
@@ -1564,10 +4069,44 @@ mod tests {
             let mut state = s.state.lock().unwrap();
             state.transition_to_candidate(&mut s.info, s.log.clone()).unwrap();
 
-            let mut state_file = StateFile::new_from_filename(&mock_server.state_filename).unwrap();
+            let mut state_file = StateFile::new_from_filename(&mock_server.log_file.state_name).unwrap();
             let state = state_file.get_state().unwrap();
             assert_eq!(state.term, 1);
             assert_eq!(state.voted_for.unwrap(), s.info.me.0);
         }
     }
+
+    #[test]
+    fn server_handle_drops_after_main_thread_exits() {
+        let (tx, rx) = channel();
+        drop(rx);
+        let main_thread = thread::spawn(|| panic!("The main thread crashed"));
+        let handle = ServerHandle {
+            tx: tx,
+            thread: Some(main_thread),
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            rpc_server: None
+        };
+        drop(handle);
+    }
+
+    #[test]
+    fn initial_config_is_only_written_to_an_empty_log() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut storage = MemoryStorage::new();
+        write_initial_config(&mut storage, 1, addr).unwrap();
+        assert_eq!(storage.last_index(), 1);
+        assert!(write_initial_config(&mut storage, 1, addr).is_err());
+
+        let mut storage = MemoryStorage::new();
+        storage.compact(&SnapshotMetadata {
+            last_included_index: 5,
+            last_included_term: 2,
+            config: None,
+            joint_config: None,
+            learners: vec![]
+        }).unwrap();
+        assert!(write_initial_config(&mut storage, 1, addr).is_err());
+        assert_eq!(storage.last_index(), 5);
+    }
 }
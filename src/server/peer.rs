@@ -1,18 +1,27 @@
 use capnp::serialize::OwnedSegments;
 use capnp::message::Reader;
 use raft_capnp::{append_entries, append_entries_reply,
-                 request_vote, request_vote_reply};
+                 request_vote, request_vote_reply,
+                 install_snapshot, install_snapshot_reply,
+                 timeout_now, timeout_now_reply};
 use rpc::{RpcError};
 use rpc::client::Rpc;
+use rpc::transport::Transport;
+use super::clock::Clock;
 use std::net::SocketAddr;
-use std::thread;
-use std::thread::JoinHandle;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Sender, Receiver};
+use std::io::Read;
+use simulation::thread;
+use simulation::thread::JoinHandle;
+use simulation::sync::Mutex;
+use simulation::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::Arc;
 use std::mem;
 use std::time::{Instant, Duration};
+use std::collections::BTreeMap;
 
 use super::log::{Log, Entry};
+use super::snapshot::SnapshotMetadata;
+use super::storage::SharedStorage;
 use super::super::common::{constants, RaftError};
 use super::{MainThreadMessage, AppendEntriesReply, RequestVoteReply, RpcHandlerPipe};
 
@@ -28,6 +37,19 @@ pub struct AppendEntriesMessage {
     pub prev_log_term: u64,
     pub entries: Vec<Entry>,
     pub leader_commit: usize,
+    // Numbers the AppendEntries the leader sends this peer, in the order it sent them.
+    // Echoed back in the reply, since the peer may get them out of order
+    pub seq: u64,
+    // When the leader asked for this AppendEntries. Echoed back to the leader in the reply
+    // so it knows this peer still followed it at that time
+    pub sent_at: Instant,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InstallSnapshotMessage {
+    pub term: u64,
+    pub leader_id: u64,
+    pub sent_at: Instant,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -36,6 +58,16 @@ pub struct RequestVoteMessage {
     pub candidate_id: u64,
     pub last_log_index: usize,
     pub last_log_term: u64,
+    pub leader_transfer: bool,
+    // PreVotes ask whether the peer would vote for us in |term| without
+    // the peer (or us) moving into that term
+    pub pre_vote: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TimeoutNowMessage {
+    pub term: u64,
+    pub leader_id: u64,
 }
 
 ///
@@ -44,7 +76,9 @@ pub struct RequestVoteMessage {
 #[derive(Debug)]
 pub enum PeerThreadMessage {
     AppendEntries (AppendEntriesMessage),
+    InstallSnapshot (InstallSnapshotMessage),
     RequestVote (RequestVoteMessage),
+    TimeoutNow (TimeoutNowMessage),
     Shutdown
 }
 
@@ -53,7 +87,23 @@ pub enum PeerState {
     Voting,
     // non voting members have a current round and a time that round started at as well as an
     // rpc handler thread that is waiting to hear if this succeeds or not
-    NonVoting(u32, Instant, RpcHandlerPipe)
+    NonVoting(u32, Instant, RpcHandlerPipe),
+    // learners are permanent non voting members. They get the log but never vote
+    Learner
+}
+
+///
+/// Limits on how much a leader sends to each peer at once, so that a peer that's far behind
+/// gets its entries in pieces instead of one huge message.
+///
+#[derive(Copy, Clone, Debug)]
+pub struct FlowControl {
+    // most AppendEntries we'll have in flight to a peer at once
+    pub max_in_flight: usize,
+    // most entries we'll send in one AppendEntries
+    pub max_batch_entries: usize,
+    // most bytes of entries we'll send in one AppendEntries, unless a single entry is larger
+    pub max_batch_bytes: usize
 }
 
 ///
@@ -66,7 +116,20 @@ pub struct PeerHandle {
     pub next_index: usize,
     pub match_index: usize,
     pub thread: Option<JoinHandle<()>>,
-    pub state: PeerState
+    pub state: PeerState,
+    // when we sent the latest AppendEntries that this peer successfully replied to
+    pub last_contact: Instant,
+    // seq of each AppendEntries sent to this peer that it hasn't replied to yet, and the
+    // prev_log_index it was sent with
+    pub in_flight: BTreeMap<u64, usize>,
+    // seq of the next AppendEntries we send this peer. Never reset, so a reply to a request
+    // from an earlier term can't be mistaken for one that's in flight
+    pub next_seq: u64,
+    // whether we're still looking for where this peer's log matches ours. Until we find it
+    // we only have one AppendEntries in flight, since the rest would likely be rejected
+    pub probing: bool,
+    pub flow_control: FlowControl,
+    pub clock: Arc<Clock>
 }
 
 pub enum NonVotingPeerState {
@@ -78,50 +141,129 @@ pub enum NonVotingPeerState {
 
 impl PeerHandle {
     ///
-    /// Pushes a non-blocking append-entries request to this peer.
+    /// Pushes a non-blocking append-entries request to this peer, and optimistically advances
+    /// |next_index| past the entries we sent so the next request can go out before this one
+    /// is acknowledged. Each request carries at most one batch of entries, as bounded by
+    /// |flow_control|. Does nothing if our window of requests in flight to this peer is full.
+    /// If the entries this peer needs have been compacted we send it our snapshot instead.
+    /// Returns whether we sent an AppendEntries request.
     ///
     /// #Panics
     /// Panics if the peer thread has panicked.
     ///
-    pub fn append_entries_nonblocking (&self, leader_id: u64,
+    pub fn append_entries_nonblocking (&mut self, leader_id: u64,
                                        commit_index: usize, current_term: u64,
-                                       log: Arc<Mutex<Log>>) {
+                                       log: Arc<Mutex<Log>>) -> bool {
+        if self.in_flight.len() >= self.window() { return false; }
         let prev_log_index = self.next_index - 1;
-        let (last_entry, entries) = {
+        let (prev_log_term, entries) = {
             let log = log.lock().unwrap();
             debug_assert!(self.next_index <= log.get_last_entry_index() + 1, "{} <= {}", self.next_index, log.get_last_entry_index());
-            (log.get_entry(prev_log_index).cloned(),
-             log.get_entries_from(prev_log_index).to_vec())
+            if self.next_index < log.get_start_index() {
+                let message = PeerThreadMessage::InstallSnapshot(InstallSnapshotMessage {
+                    term: current_term,
+                    leader_id: leader_id,
+                    sent_at: self.clock.now()
+                });
+                self.to_peer.send(message).unwrap(); //panics if the peer thread has panicked
+                return false;
+            }
+            // safe to unwrap since prev_log_index is either in the log or is the last
+            // entry in our snapshot
+            (log.get_term(prev_log_index).unwrap(),
+             log.get_entries_batch(prev_log_index, self.flow_control.max_batch_entries,
+                                   self.flow_control.max_batch_bytes))
         }; 
 
-        // We should never be out of bounds.
-        debug_assert!(commit_index - prev_log_index <= entries.len());
+        self.next_index = prev_log_index + entries.len() + 1;
+        let seq = self.start_request(prev_log_index);
 
         let message = PeerThreadMessage::AppendEntries(AppendEntriesMessage {
             term: current_term,
             leader_id: leader_id,
             prev_log_index: prev_log_index,
-            prev_log_term: last_entry.map(|entry| entry.term).unwrap_or(0),
+            prev_log_term: prev_log_term,
             entries: entries.to_vec(),
             leader_commit: commit_index,
+            seq: seq,
+            sent_at: self.clock.now()
+        });
+        self.to_peer.send(message).unwrap(); //panics if the peer thread has panicked
+        true
+    }
+
+    ///
+    /// Sends this peer its share of our heartbeat. That's the same request
+    /// append_entries_nonblocking would send, unless our window of requests in flight to the
+    /// peer is full. Then we send an AppendEntries with no entries that starts at |match_index|,
+    /// so the peer hears from us and learns |commit_index| even while its window is stuck.
+    /// At most one such heartbeat is in flight past the window at a time.
+    ///
+    /// #Panics
+    /// Panics if the peer thread has panicked.
+    ///
+    pub fn heartbeat_nonblocking (&mut self, leader_id: u64,
+                                  commit_index: usize, current_term: u64,
+                                  log: Arc<Mutex<Log>>) {
+        if self.in_flight.len() < self.window() {
+            self.append_entries_nonblocking(leader_id, commit_index, current_term, log);
+            return;
+        }
+        if self.in_flight.len() > self.window() { return; }
+        // the peer has everything up to |match_index|, so it'll accept this no matter
+        // what else we have in flight to it
+        let prev_log_term = match log.lock().unwrap().get_term(self.match_index) {
+            Some(term) => term,
+            // it was compacted, so the peer needs our snapshot, which is already on its way
+            None => return
+        };
+        let seq = self.start_request(self.match_index);
+
+        let message = PeerThreadMessage::AppendEntries(AppendEntriesMessage {
+            term: current_term,
+            leader_id: leader_id,
+            prev_log_index: self.match_index,
+            prev_log_term: prev_log_term,
+            entries: vec![],
+            leader_commit: commit_index,
+            seq: seq,
+            sent_at: self.clock.now()
         });
         self.to_peer.send(message).unwrap(); //panics if the peer thread has panicked
     }
 
-    /// Advances the current round of this non-voting peer. Is a noop for voting peers.
-    pub fn advance_non_voting_peer_round(&mut self, latest_log_index: usize) -> NonVotingPeerState {
+    /// Numbers a new AppendEntries to this peer that starts after |prev_log_index|,
+    /// and counts it as in flight
+    fn start_request (&mut self, prev_log_index: usize) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.in_flight.insert(seq, prev_log_index);
+        seq
+    }
+
+    /// How many AppendEntries we let this peer have in flight at once
+    fn window (&self) -> usize {
+        if self.probing { 1 } else { self.flow_control.max_in_flight }
+    }
+
+    /// Advances the current round of this non-voting peer. Is a noop for voting peers and learners.
+    /// The peer gets |max_rounds| rounds to catch up, and its last round must take less than
+    /// |max_round_time|.
+    pub fn advance_non_voting_peer_round(&mut self, latest_log_index: usize, max_rounds: u32,
+                                         max_round_time: Duration) -> NonVotingPeerState {
         let mut ret_state = NonVotingPeerState::VotingPeer;
         if let PeerState::NonVoting(ref mut round, ref mut start_time, ref mut pipe) = self.state {
             *round += 1;
-            if self.next_index == latest_log_index + 1 {
+            // |next_index| runs ahead of what the peer has actually acknowledged
+            if self.match_index == latest_log_index {
                 // woohoo they're all caught up
                 // need to do a replace here since pipe isn't optional...
                 let p = mem::replace(pipe, channel().0);
                 ret_state = NonVotingPeerState::CaughtUp(p);
             } else {
-                let now = Instant::now();
-                if *round == constants::MAX_ROUNDS_FOR_NEW_SERVER {
-                    if now.duration_since(*start_time) > Duration::from_millis(constants::ELECTION_TIMEOUT_MIN) {
+                let now = self.clock.now();
+                if *round == max_rounds {
+                    if now.duration_since(*start_time) > max_round_time {
                         // need to do a replace here since pipe isn't optional...
                         let p = mem::replace(pipe, channel().0);
                         ret_state = NonVotingPeerState::TimedOut(p);
@@ -173,7 +315,14 @@ pub struct Peer {
     id: u64,
     addr: SocketAddr,
     to_main: Sender<MainThreadMessage>,
-    from_main: Receiver<PeerThreadMessage>
+    from_main: Receiver<PeerThreadMessage>,
+    // where we read the snapshot we send if the peer falls too far behind
+    storage: SharedStorage,
+    // (term, index) of the last snapshot this peer successfully installed
+    last_snapshot_sent: Option<(u64, usize)>,
+    transport: Arc<Transport>,
+    // AppendEntries waiting for one of our append entries workers to send them
+    to_workers: Sender<AppendEntriesMessage>
 }
 
 // TODO(jason): Use mio to ensure that peers shutdown without blocking the main thread
@@ -181,25 +330,43 @@ pub struct Peer {
 impl Peer {
     ///
     /// Spawns a new Peer in a background thread to communicate with the server at id.
+    /// The peer sends the snapshot saved in |storage| if the server falls too far behind,
+    /// and sends it entries within the limits of |flow_control|. All rpcs go out over |transport|,
+    /// and the peer's timestamps come from |clock|.
+    /// AppendEntries are sent by a fixed pool of worker threads, one for each request the
+    /// leader may have in flight, plus one for the heartbeat it may send while that window is full.
     ///
     /// # Panics
     /// Panics if the OS fails to create a new background thread.
     ///
-    pub fn start (id: PeerInfo, to_main: Sender<MainThreadMessage>, non_voting: Option<RpcHandlerPipe>) -> PeerHandle {
+    pub fn start (id: PeerInfo, to_main: Sender<MainThreadMessage>, non_voting: Option<RpcHandlerPipe>,
+                  storage: SharedStorage, flow_control: FlowControl, transport: Arc<Transport>,
+                  clock: Arc<Clock>) -> PeerHandle {
         let (to_peer, from_main) = channel();
+
+        let (to_workers, from_peer) = channel();
+        let from_peer = Arc::new(Mutex::new(from_peer));
+        for _ in 0 .. flow_control.max_in_flight + 1 {
+            let (from_peer, to_main, transport) = (from_peer.clone(), to_main.clone(), transport.clone());
+            thread::spawn(move || Peer::append_entries_worker(id, to_main, transport, from_peer));
+        }
         
         let t = thread::spawn(move || {
             let peer = Peer {
                 id: id.0,
                 addr: id.1,
                 to_main: to_main,
-                from_main: from_main
+                from_main: from_main,
+                storage: storage,
+                last_snapshot_sent: None,
+                transport: transport,
+                to_workers: to_workers
             };
             peer.main();
         });
 
         let state = match non_voting {
-            Some(pipe) => PeerState::NonVoting(0, Instant::now(), pipe),
+            Some(pipe) => PeerState::NonVoting(0, clock.now(), pipe),
             None => PeerState::Voting
         };
 
@@ -209,7 +376,13 @@ impl Peer {
             next_index: 1,
             match_index: 0,
             thread: Some(t),
-            state: state
+            state: state,
+            last_contact: clock.now(),
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            probing: true,
+            flow_control: flow_control,
+            clock: clock
         }
     }
 
@@ -233,14 +406,15 @@ impl Peer {
 
     ///
     /// Processes a append_entries_reply for the current term.
-    /// Returns a tuple containing the reply's term and whether the peer
-    /// successfully appended the entry.
+    /// Returns a tuple containing the reply's term, whether the peer
+    /// successfully appended the entry, and the (term, index) of the conflict
+    /// the peer found in its log, if it sent one.
     ///
     /// # Errors
     /// Returns an RpcError if the msg is not a well formed append_entries_reply
     ///
     fn handle_append_entries_reply (entry_term: u64, msg: Reader<OwnedSegments>)
-        -> Result<(u64, bool), RpcError> {
+        -> Result<(u64, bool, Option<(u64, usize)>), RpcError> {
         Rpc::get_result_reader(&msg).and_then(|result| {
             result.get_as::<append_entries_reply::Reader>()
                   .map_err(RpcError::Capnp)
@@ -248,51 +422,204 @@ impl Peer {
             .map(|reply_reader| {
                 let term = reply_reader.get_term();
                 let success = reply_reader.get_success();
-                (term, term == entry_term && success)
+                let conflict = match reply_reader.get_conflict_index() {
+                    0 => None,
+                    index => Some((reply_reader.get_conflict_term(), index as usize))
+                };
+                (term, term == entry_term && success, conflict)
             })
     }
 
     ///
-    /// Sends the appropriate append entries RPC to this peer.
+    /// Hands the append entries RPC to one of this peer's workers, so we don't wait a round
+    /// trip before sending the next one. The main thread bounds how many of them are in flight
+    /// at once.
+    /// Requests may reach the peer out of order, in which case it rejects the ones it isn't
+    /// ready for. Each reply carries its request's seq, so the main thread can tell those
+    /// rejections apart and resend them without falling back to probing.
     ///
     /// # Panics
-    /// Panics if proto fails to initialize, or main thread has panicked or
-    /// is deallocated.
+    /// Panics if every worker has panicked.
     ///
-    fn append_entries_blocking (&mut self, entry: AppendEntriesMessage) {
-        let mut rpc = Rpc::new(constants::APPEND_ENTRIES_OPCODE);
-        Peer::construct_append_entries(&mut rpc, &entry);
-        let (term, success) = rpc.send(self.addr)
-            .and_then(|msg| Peer::handle_append_entries_reply(entry.term, msg))
-            .unwrap_or((entry.term, false));
-        let new_commit_index = entry.prev_log_index + entry.entries.len();
+    fn send_append_entries (&mut self, entry: AppendEntriesMessage) {
+        // the peer may need our snapshot again if it falls behind after this
+        self.last_snapshot_sent = None;
+        self.to_workers.send(entry).unwrap();
+    }
+
+    ///
+    /// Sends the AppendEntries queued on |from_peer| to the peer at |id| one at a time,
+    /// and reports each reply to the main thread. Exits once the peer shuts down and
+    /// the queue is empty.
+    ///
+    fn append_entries_worker (id: PeerInfo, to_main: Sender<MainThreadMessage>, transport: Arc<Transport>,
+                              from_peer: Arc<Mutex<Receiver<AppendEntriesMessage>>>) {
+        loop {
+            // the lock is only held while we wait, so the other workers can send meanwhile
+            let entry = match from_peer.lock().unwrap().recv() {
+                Ok(entry) => entry,
+                Err(_) => return
+            };
+            let mut rpc = Rpc::new(constants::APPEND_ENTRIES_OPCODE);
+            Peer::construct_append_entries(&mut rpc, &entry);
+            let result = transport.send(id.1, &rpc)
+                .and_then(|msg| Peer::handle_append_entries_reply(entry.term, msg));
+            let unreachable = result.is_err();
+            let (term, success, conflict) = result.unwrap_or((entry.term, false, None));
+            let new_commit_index = entry.prev_log_index + entry.entries.len();
+            let reply = AppendEntriesReply {
+                term: term,
+                commit_index: if success { new_commit_index } else { entry.prev_log_index },
+                peer: id,
+                success: success,
+                seq: entry.seq,
+                sent_at: entry.sent_at,
+                conflict: conflict,
+                snapshot: false,
+                unreachable: unreachable
+            };
+            // The server may have shut down while this was in flight
+            let _ = to_main.send(MainThreadMessage::AppendEntriesReply(reply));
+        }
+    }
+
+    ///
+    /// Streams our snapshot to this peer one chunk at a time.
+    /// Reports to the main thread once the peer has installed the entire snapshot, or if the
+    /// peer knows of a newer term. Otherwise we'll try again on the leader's next heartbeat.
+    ///
+    /// # Panics
+    /// Panics if the main thread has panicked or been deallocated.
+    ///
+    fn install_snapshot_blocking (&mut self, message: InstallSnapshotMessage) {
+        let snapshot = self.storage.lock().unwrap().snapshot();
+        let (metadata, mut reader) = match snapshot {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) => {
+                error!("Peer {} needs our snapshot but we don't have one", self.id);
+                return;
+            },
+            Err(e) => {
+                warn!("Unable to open our snapshot for peer {}: {}", self.id, e);
+                return;
+            }
+        };
+
+        // Heartbeats that were sent while we were busy installing this snapshot
+        // don't know that the peer already has it
+        if self.last_snapshot_sent == Some((message.term, metadata.last_included_index)) {
+            return;
+        }
+
+        let (term, success) = self.send_snapshot_chunks(&message, &metadata, &mut reader)
+            .unwrap_or((message.term, false));
+        if !success && term <= message.term {
+            return;
+        }
+
+        if success {
+            self.last_snapshot_sent = Some((message.term, metadata.last_included_index));
+        }
         let reply = AppendEntriesReply {
             term: term,
-            commit_index: if success { new_commit_index } else { entry.prev_log_index },
+            commit_index: metadata.last_included_index,
             peer: (self.id, self.addr),
             success: success,
+            // snapshots aren't numbered, since they don't count as in flight
+            seq: 0,
+            sent_at: message.sent_at,
+            conflict: None,
+            snapshot: true,
+            unreachable: false
         };
         // Panics if main thread has panicked or been otherwise deallocated.
         self.to_main.send(MainThreadMessage::AppendEntriesReply(reply)).unwrap();
     }
 
+    ///
+    /// Sends the state machine image in |reader| to this peer, one InstallSnapshot rpc per chunk.
+    /// Returns the term the peer replied with and whether it accepted every chunk.
+    ///
+    /// # Errors
+    /// Returns an RpcError if we can't read the snapshot or can't reach the peer.
+    ///
+    fn send_snapshot_chunks (&self, message: &InstallSnapshotMessage, metadata: &SnapshotMetadata,
+                             reader: &mut Read) -> Result<(u64, bool), RpcError> {
+        let mut offset = 0;
+        loop {
+            let mut data = Vec::with_capacity(constants::SNAPSHOT_CHUNK_SIZE);
+            Read::take(&mut *reader, constants::SNAPSHOT_CHUNK_SIZE as u64).read_to_end(&mut data)
+                .map_err(RpcError::Io)?;
+            let done = data.len() < constants::SNAPSHOT_CHUNK_SIZE;
+
+            let mut rpc = Rpc::new(constants::INSTALL_SNAPSHOT_OPCODE);
+            Peer::construct_install_snapshot(&mut rpc, message, metadata, offset, &data, done);
+            let (term, success) = self.transport.send(self.addr, &rpc)
+                .and_then(|msg| Peer::handle_install_snapshot_reply(message.term, msg))?;
+            if !success || done {
+                return Ok((term, success));
+            }
+            offset += data.len() as u64;
+        }
+    }
+
+    ///
+    /// Sets the fields of an InstallSnapshot rpc carrying the chunk |data|, which starts
+    /// |offset| bytes into the snapshot described by |metadata|.
+    ///
+    fn construct_install_snapshot (rpc: &mut Rpc, message: &InstallSnapshotMessage,
+                                   metadata: &SnapshotMetadata, offset: u64, data: &[u8], done: bool) {
+        let mut params = rpc.get_param_builder().init_as::<install_snapshot::Builder>();
+        params.set_term(message.term);
+        params.set_leader_id(message.leader_id);
+        metadata.into_proto(&mut params.borrow().init_metadata());
+        params.set_offset(offset);
+        params.set_data(data);
+        params.set_done(done);
+    }
+
+    ///
+    /// Processes an install_snapshot_reply for the current term.
+    /// Returns a tuple containing the reply's term and whether the peer
+    /// accepted the chunk.
+    ///
+    /// # Errors
+    /// Returns an RpcError if the msg is not a well formed install_snapshot_reply
+    ///
+    fn handle_install_snapshot_reply (term: u64, msg: Reader<OwnedSegments>)
+        -> Result<(u64, bool), RpcError> {
+        Rpc::get_result_reader(&msg).and_then(|result| {
+            result.get_as::<install_snapshot_reply::Reader>()
+                  .map_err(RpcError::Capnp)
+            })
+            .map(|reply_reader| {
+                let reply_term = reply_reader.get_term();
+                (reply_term, reply_term == term && reply_reader.get_success())
+            })
+    }
+
     ///
     /// Requests a vote in the new term from this peer.
+    /// PreVotes are sent as their own rpc so that servers that don't support them
+    /// simply deny them.
     ///
     /// # Panics
     /// Panics if the main thread has panicked or been deallocated
     ///
     fn send_request_vote (&self, vote: RequestVoteMessage) {
-        let mut rpc = Rpc::new(constants::REQUEST_VOTE_OPCODE);
+        let opcode = if vote.pre_vote { constants::PRE_VOTE_OPCODE } else { constants::REQUEST_VOTE_OPCODE };
+        let mut rpc = Rpc::new(opcode);
         Peer::construct_request_vote(&mut rpc, &vote);
 
-        let vote_granted = rpc.send(self.addr)
+        let vote_granted = self.transport.send(self.addr, &rpc)
             .and_then(|msg| Peer::handle_request_vote_reply(vote.term, msg))
             .unwrap_or(false);
 
         let reply = RequestVoteReply {
             term: vote.term,
-            vote_granted: vote_granted
+            vote_granted: vote_granted,
+            pre_vote: vote.pre_vote,
+            peer: (self.id, self.addr)
         };
         // Panics if the main thread has panicked or been deallocated
         self.to_main.send(MainThreadMessage::RequestVoteReply(reply)).unwrap();
@@ -308,6 +635,47 @@ impl Peer {
         params.set_candidate_id(vote.candidate_id);
         params.set_last_log_index(vote.last_log_index as u64);
         params.set_last_log_term(vote.last_log_term);
+        params.set_leader_transfer(vote.leader_transfer);
+    }
+
+    ///
+    /// Tells this peer to start an election right away so it can take over as leader.
+    /// The peer will send us a RequestVote with a higher term if it does, so we don't need
+    /// to report anything back to the main thread.
+    ///
+    fn send_timeout_now (&self, message: TimeoutNowMessage) {
+        let mut rpc = Rpc::new(constants::TIMEOUT_NOW_OPCODE);
+        Peer::construct_timeout_now(&mut rpc, &message);
+        match self.transport.send(self.addr, &rpc).and_then(|msg| Peer::handle_timeout_now_reply(msg)) {
+            Ok(term) => {
+                if term > message.term {
+                    trace!("Peer {} ignored our TimeoutNow for term {} since it's in term {}",
+                           self.id, message.term, term);
+                }
+            },
+            Err(e) => warn!("Unable to send TimeoutNow to peer {}: {:?}", self.id, e)
+        }
+    }
+
+    fn construct_timeout_now (rpc: &mut Rpc, message: &TimeoutNowMessage) {
+        let mut params = rpc.get_param_builder().init_as::<timeout_now::Builder>();
+        params.set_term(message.term);
+        params.set_leader_id(message.leader_id);
+    }
+
+    ///
+    /// Returns the term in a timeout_now_reply.
+    ///
+    /// # Errors
+    /// Returns an RpcError if the msg is not a well formed timeout_now_reply
+    ///
+    fn handle_timeout_now_reply (msg: Reader<OwnedSegments>) -> Result<u64, RpcError> {
+        Rpc::get_result_reader(&msg)
+            .and_then(|result| {
+                result.get_as::<timeout_now_reply::Reader>()
+                      .map_err(RpcError::Capnp)
+            })
+            .map(|reply_reader| reply_reader.get_term())
     }
 
     ///
@@ -340,8 +708,10 @@ impl Peer {
     fn main (mut self) {
         loop {
             match self.from_main.recv().unwrap() {
-                PeerThreadMessage::AppendEntries(entry) => self.append_entries_blocking(entry),
+                PeerThreadMessage::AppendEntries(entry) => self.send_append_entries(entry),
+                PeerThreadMessage::InstallSnapshot(message) => self.install_snapshot_blocking(message),
                 PeerThreadMessage::RequestVote(vote) => self.send_request_vote(vote),
+                PeerThreadMessage::TimeoutNow(message) => self.send_timeout_now(message),
                 PeerThreadMessage::Shutdown => break
             }
         }
@@ -354,16 +724,29 @@ mod tests {
     use capnp::{message, serialize_packed};
     use capnp::serialize::OwnedSegments;
     use std::io::BufReader;
-    use std::sync::mpsc::{channel};
-    use std::sync::{Arc, Mutex};
+    use simulation::sync::Mutex;
+    use simulation::sync::mpsc::{channel, Sender, Receiver};
+    use std::sync::Arc;
     use super::*;
     use super::super::constants;
     use super::super::log::{Entry, random_entry_with_term, random_entries_with_term, Log};
     use super::super::log::mocks::{new_mock_log, new_random_with_term};
+    use super::super::snapshot::SnapshotMetadata;
+    use super::super::clock::SystemClock;
     use super::super::super::raft_capnp::{request_vote, request_vote_reply,
-                                          append_entries, append_entries_reply};
+                                          append_entries, append_entries_reply,
+                                          install_snapshot, install_snapshot_reply,
+                                          timeout_now};
     use super::super::super::rpc_capnp::rpc_response;
 
+    fn flow_control(max_in_flight: usize) -> FlowControl {
+        FlowControl {
+            max_in_flight: max_in_flight,
+            max_batch_entries: constants::DEFAULT_MAX_APPEND_ENTRIES_BATCH,
+            max_batch_bytes: constants::DEFAULT_MAX_APPEND_ENTRIES_BYTES
+        }
+    }
+
     #[test]
     fn constructs_valid_append_entries() {
         const TERM: u64 = 13;
@@ -380,6 +763,8 @@ mod tests {
             prev_log_term: PREV_LOG_TERM,
             leader_commit: LEADER_COMMIT as usize,
             entries: entries.clone(),
+            seq: 0,
+            sent_at: Instant::now(),
         };
         Peer::construct_append_entries(&mut rpc, &entry);
         let param_reader = rpc.get_param_builder().as_reader()
@@ -402,7 +787,7 @@ mod tests {
         let mut builder = message::Builder::new_default();
         construct_append_entries_reply(&mut builder, TERM, true);
         let reader = get_message_reader(&builder);
-        let (term, success) = Peer::handle_append_entries_reply(TERM, reader)
+        let (term, success, _) = Peer::handle_append_entries_reply(TERM, reader)
                                     .unwrap();
         assert_eq!(term, TERM);
         assert!(success);
@@ -414,19 +799,37 @@ mod tests {
         let mut builder = message::Builder::new_default();
         construct_append_entries_reply(&mut builder, TERM + 1, true);
         let reader = get_message_reader(&builder);
-        let (term, success) = Peer::handle_append_entries_reply(TERM, reader)
+        let (term, success, _) = Peer::handle_append_entries_reply(TERM, reader)
                                     .unwrap();
         assert!(term != TERM);
         assert!(!success);
     }
 
+    #[test]
+    fn append_entries_reply_carries_conflict() {
+        const TERM: u64 = 54;
+        let mut builder = message::Builder::new_default();
+        {
+            let response_builder = builder.init_root::<rpc_response::Builder>();
+            let mut reply_builder = response_builder.get_result().init_as::<append_entries_reply::Builder>();
+            reply_builder.set_term(TERM);
+            reply_builder.set_success(false);
+            reply_builder.set_conflict_term(TERM - 1);
+            reply_builder.set_conflict_index(12);
+        }
+        let reader = get_message_reader(&builder);
+        let (_, success, conflict) = Peer::handle_append_entries_reply(TERM, reader).unwrap();
+        assert!(!success);
+        assert_eq!(conflict, Some((TERM - 1, 12)));
+    }
+
     #[test]
     fn append_entries_handles_failed_commit() {
         const TERM: u64 = 54;
         let mut builder = message::Builder::new_default();
         construct_append_entries_reply(&mut builder, TERM, false);
         let reader = get_message_reader(&builder);
-        let (term, success) = Peer::handle_append_entries_reply(TERM, reader)
+        let (term, success, _) = Peer::handle_append_entries_reply(TERM, reader)
                                     .unwrap();
         assert!(term == TERM);
         assert!(!success);
@@ -440,13 +843,19 @@ mod tests {
         const COMMIT_INDEX: usize = 8; // COMMIT_INDEX < LOG_SIZE
         const LOG_SIZE: usize = 9;
         const LEADER_ID: u64 = 0; // LEADER_ID != PEER_ID
-        let handle = PeerHandle {
+        let mut handle = PeerHandle {
             id: 1,
             to_peer: tx.clone(),
             next_index: PEER_NEXT_INDEX,
             match_index: PEER_NEXT_INDEX - 1,
             thread: None,
-            state: PeerState::Voting
+            state: PeerState::Voting,
+            last_contact: Instant::now(),
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            probing: false,
+            flow_control: flow_control(1),
+            clock: Arc::new(SystemClock)
         };
         let (mock_log, _log_file_handle) = new_random_with_term(LOG_SIZE, TERM);
         let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
@@ -460,7 +869,7 @@ mod tests {
                 assert_eq!(message.prev_log_index, PEER_NEXT_INDEX - 1);
                 assert_eq!(message.prev_log_term, TERM);
                 assert_eq!(message.entries.len(), COMMIT_INDEX - PEER_NEXT_INDEX + 1);
-                let log_entries = log.lock().unwrap().get_entries_from(PEER_NEXT_INDEX - 1).to_vec();
+                let log_entries = log.lock().unwrap().get_entries_from(PEER_NEXT_INDEX - 1);
                 assert_eq!(message.entries.len(), log_entries.len());
                 let entries_same = message.entries.iter().zip(log_entries.iter())
                     .fold(true, |and, (e1, e2)| and && *e1 == *e2);
@@ -478,13 +887,19 @@ mod tests {
         const COMMIT_INDEX: usize = 8; // COMMIT_INDEX < LOG_SIZE
         const LOG_SIZE: usize = 9;
         const LEADER_ID: u64 = 0; // LEADER_ID != PEER_ID
-        let handle = PeerHandle {
+        let mut handle = PeerHandle {
             id: 1,
             to_peer: tx.clone(),
             next_index: PEER_NEXT_INDEX,
             match_index: PEER_NEXT_INDEX - 1,
             thread: None,
-            state: PeerState::Voting
+            state: PeerState::Voting,
+            last_contact: Instant::now(),
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            probing: false,
+            flow_control: flow_control(1),
+            clock: Arc::new(SystemClock)
         };
         let (mock_log, _log_file_handle) = new_mock_log();
         let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
@@ -507,6 +922,257 @@ mod tests {
         };
     }
 
+    fn mock_peer_handle(to_peer: Sender<PeerThreadMessage>, next_index: usize,
+                        flow_control: FlowControl) -> PeerHandle {
+        PeerHandle {
+            id: 1,
+            to_peer: to_peer,
+            next_index: next_index,
+            match_index: next_index - 1,
+            thread: None,
+            state: PeerState::Voting,
+            last_contact: Instant::now(),
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            probing: false,
+            flow_control: flow_control,
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Returns the (prev_log_index, number of entries) of each AppendEntries sent to a peer
+    fn sent_batches(rx: &Receiver<PeerThreadMessage>) -> Vec<(usize, usize)> {
+        rx.try_iter().map(|message| match message {
+            PeerThreadMessage::AppendEntries(message) => (message.prev_log_index, message.entries.len()),
+            _ => panic!()
+        }).collect()
+    }
+
+    #[test]
+    fn peerhandle_pipelines_up_to_max_in_flight() {
+        let (tx, rx) = channel();
+        const TERM: u64 = 5;
+        const LOG_SIZE: usize = 9;
+        const LEADER_ID: u64 = 0;
+        let mut handle = mock_peer_handle(tx, 3, flow_control(2));
+        let (mock_log, _log_file_handle) = new_random_with_term(LOG_SIZE, TERM);
+        let last_index = mock_log.get_last_entry_index();
+        let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
+        assert!(handle.append_entries_nonblocking(LEADER_ID, last_index, TERM, log.clone()));
+        // we assume the peer will accept the entries we just sent
+        assert_eq!(handle.next_index, last_index + 1);
+        assert_eq!(handle.match_index, 2);
+
+        log.lock().unwrap().append_entries_blocking(random_entries_with_term(2, TERM)).unwrap();
+        assert!(handle.append_entries_nonblocking(LEADER_ID, last_index, TERM, log.clone()));
+        assert_eq!(handle.next_index, last_index + 3);
+        assert_eq!(handle.in_flight, vec![(0, 2), (1, last_index)].into_iter().collect());
+        // the window is full
+        assert!(!handle.append_entries_nonblocking(LEADER_ID, last_index, TERM, log.clone()));
+
+        assert_eq!(sent_batches(&rx), vec![(2, last_index - 2), (last_index, 2)]);
+    }
+
+    #[test]
+    fn peerhandle_only_has_one_request_in_flight_while_probing() {
+        let (tx, rx) = channel();
+        const TERM: u64 = 5;
+        const LEADER_ID: u64 = 0;
+        let mut handle = mock_peer_handle(tx, 3, flow_control(4));
+        handle.probing = true;
+        let (mock_log, _log_file_handle) = new_random_with_term(9, TERM);
+        let last_index = mock_log.get_last_entry_index();
+        let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
+        assert!(handle.append_entries_nonblocking(LEADER_ID, last_index, TERM, log.clone()));
+        assert!(!handle.append_entries_nonblocking(LEADER_ID, last_index, TERM, log.clone()));
+        assert_eq!(sent_batches(&rx), vec![(2, last_index - 2)]);
+    }
+
+    #[test]
+    fn peerhandle_heartbeats_past_a_full_window() {
+        let (tx, rx) = channel();
+        const TERM: u64 = 5;
+        const LEADER_ID: u64 = 0;
+        let mut handle = mock_peer_handle(tx, 3, flow_control(1));
+        let (mock_log, _log_file_handle) = new_random_with_term(9, TERM);
+        let last_index = mock_log.get_last_entry_index();
+        let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
+        handle.heartbeat_nonblocking(LEADER_ID, 1, TERM, log.clone());
+        assert_eq!(sent_batches(&rx), vec![(2, last_index - 2)]);
+
+        // the window is full, so the heartbeat carries no entries and starts where we
+        // know the peer's log matches ours
+        handle.heartbeat_nonblocking(LEADER_ID, 2, TERM, log.clone());
+        match rx.try_recv().unwrap() {
+            PeerThreadMessage::AppendEntries(message) => {
+                assert_eq!(message.prev_log_index, 2);
+                assert_eq!(message.prev_log_term, TERM);
+                assert_eq!(message.leader_commit, 2);
+                assert!(message.entries.is_empty());
+            },
+            _ => panic!()
+        }
+        assert_eq!(handle.in_flight.len(), 2);
+        assert_eq!(handle.next_index, last_index + 1);
+
+        // only one heartbeat goes past the window
+        handle.heartbeat_nonblocking(LEADER_ID, 2, TERM, log.clone());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn peerhandle_splits_entries_into_batches() {
+        let (tx, rx) = channel();
+        const TERM: u64 = 5;
+        const LEADER_ID: u64 = 0;
+        const MAX_BATCH_ENTRIES: usize = 3;
+        let mut handle = mock_peer_handle(tx, 1, FlowControl {
+            max_in_flight: 10,
+            max_batch_entries: MAX_BATCH_ENTRIES,
+            max_batch_bytes: constants::DEFAULT_MAX_APPEND_ENTRIES_BYTES
+        });
+        let (mock_log, _log_file_handle) = new_random_with_term(9, TERM);
+        let last_index = mock_log.get_last_entry_index();
+        let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
+        while handle.next_index <= last_index {
+            assert!(handle.append_entries_nonblocking(LEADER_ID, last_index, TERM, log.clone()));
+        }
+        assert_eq!(sent_batches(&rx), vec![(0, 3), (3, 3), (6, 2)]);
+    }
+
+    #[test]
+    fn peerhandle_bounds_bytes_in_a_batch() {
+        let (tx, rx) = channel();
+        const TERM: u64 = 5;
+        const LEADER_ID: u64 = 0;
+        let (mock_log, _log_file_handle) = new_random_with_term(9, TERM);
+        let last_index = mock_log.get_last_entry_index();
+        // every mock entry is the same size
+        let entry_size = mock_log.get_entry(1).unwrap().approx_size();
+        let mut handle = mock_peer_handle(tx, 1, FlowControl {
+            max_in_flight: 10,
+            max_batch_entries: constants::DEFAULT_MAX_APPEND_ENTRIES_BATCH,
+            max_batch_bytes: entry_size * 5 - 1
+        });
+        let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
+        while handle.next_index <= last_index {
+            assert!(handle.append_entries_nonblocking(LEADER_ID, last_index, TERM, log.clone()));
+        }
+        assert_eq!(sent_batches(&rx), vec![(0, 4), (4, 4)]);
+    }
+
+    #[test]
+    fn peerhandle_sends_entries_larger_than_a_batch() {
+        let (tx, rx) = channel();
+        const TERM: u64 = 5;
+        const LEADER_ID: u64 = 0;
+        let mut handle = mock_peer_handle(tx, 1, FlowControl {
+            max_in_flight: 10,
+            max_batch_entries: constants::DEFAULT_MAX_APPEND_ENTRIES_BATCH,
+            max_batch_bytes: 1
+        });
+        let (mock_log, _log_file_handle) = new_random_with_term(3, TERM);
+        let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
+        while handle.next_index <= 2 {
+            assert!(handle.append_entries_nonblocking(LEADER_ID, 2, TERM, log.clone()));
+        }
+        assert_eq!(sent_batches(&rx), vec![(0, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn peerhandle_sends_snapshot_when_entries_are_compacted() {
+        let (tx, rx) = channel();
+        const TERM: u64 = 5;
+        const PEER_NEXT_INDEX: usize = 3;
+        const SNAPSHOT_INDEX: usize = 6; // PEER_NEXT_INDEX < SNAPSHOT_INDEX
+        const COMMIT_INDEX: usize = 8;
+        const LOG_SIZE: usize = 9;
+        const LEADER_ID: u64 = 0;
+        let mut handle = PeerHandle {
+            id: 1,
+            to_peer: tx.clone(),
+            next_index: PEER_NEXT_INDEX,
+            match_index: PEER_NEXT_INDEX - 1,
+            thread: None,
+            state: PeerState::Voting,
+            last_contact: Instant::now(),
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            probing: false,
+            flow_control: flow_control(1),
+            clock: Arc::new(SystemClock)
+        };
+        let (mut mock_log, _log_file_handle) = new_random_with_term(LOG_SIZE, TERM);
+        mock_log.compact(SnapshotMetadata {
+            last_included_index: SNAPSHOT_INDEX,
+            last_included_term: TERM,
+            config: None,
+            joint_config: None,
+            learners: vec![]
+        }).unwrap();
+        let log: Arc<Mutex<Log>> = Arc::new(Mutex::new(mock_log));
+        handle.append_entries_nonblocking(LEADER_ID, COMMIT_INDEX, TERM, log.clone());
+        match rx.recv().unwrap() {
+            PeerThreadMessage::InstallSnapshot(message) => {
+                assert_eq!(message.term, TERM);
+                assert_eq!(message.leader_id, LEADER_ID);
+            },
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn constructs_valid_install_snapshot() {
+        const TERM: u64 = 13;
+        const LEADER_ID: u64 = 6;
+        const OFFSET: u64 = 1024;
+        let data = vec![7; 32];
+        let metadata = SnapshotMetadata {
+            last_included_index: 78,
+            last_included_term: 5,
+            config: None,
+            joint_config: None,
+            learners: vec![]
+        };
+        let message = InstallSnapshotMessage {
+            term: TERM,
+            leader_id: LEADER_ID,
+            sent_at: Instant::now()
+        };
+        let mut rpc = Rpc::new(constants::INSTALL_SNAPSHOT_OPCODE);
+        Peer::construct_install_snapshot(&mut rpc, &message, &metadata, OFFSET, &data, true);
+        let param_reader = rpc.get_param_builder().as_reader()
+                              .get_as::<install_snapshot::Reader>().unwrap();
+        assert_eq!(param_reader.get_term(), TERM);
+        assert_eq!(param_reader.get_leader_id(), LEADER_ID);
+        assert_eq!(SnapshotMetadata::from_proto(param_reader.get_metadata().unwrap()).unwrap(), metadata);
+        assert_eq!(param_reader.get_offset(), OFFSET);
+        assert_eq!(param_reader.get_data().unwrap(), &data[..]);
+        assert!(param_reader.get_done());
+    }
+
+    #[test]
+    fn install_snapshot_reply_handles_success() {
+        const TERM: u64 = 54;
+        let mut builder = message::Builder::new_default();
+        construct_install_snapshot_reply(&mut builder, TERM, true);
+        let reader = get_message_reader(&builder);
+        let (term, success) = Peer::handle_install_snapshot_reply(TERM, reader).unwrap();
+        assert_eq!(term, TERM);
+        assert!(success);
+    }
+
+    #[test]
+    fn install_snapshot_reply_handles_incorrect_term() {
+        const TERM: u64 = 54;
+        let mut builder = message::Builder::new_default();
+        construct_install_snapshot_reply(&mut builder, TERM + 1, true);
+        let reader = get_message_reader(&builder);
+        let (term, success) = Peer::handle_install_snapshot_reply(TERM, reader).unwrap();
+        assert_eq!(term, TERM + 1);
+        assert!(!success);
+    }
+
     #[test]
     fn constructs_valid_request_vote() {
         const TERM: u64 = 13;
@@ -518,7 +1184,9 @@ mod tests {
             term: TERM,
             candidate_id: CANDIDATE_ID,
             last_log_index: LAST_LOG_INDEX as usize,
-            last_log_term: LAST_LOG_TERM
+            last_log_term: LAST_LOG_TERM,
+            leader_transfer: true,
+            pre_vote: false
         };
 
         Peer::construct_request_vote(&mut rpc, &vote);
@@ -527,6 +1195,23 @@ mod tests {
         assert_eq!(param_reader.get_candidate_id(), CANDIDATE_ID);
         assert_eq!(param_reader.get_last_log_index(), LAST_LOG_INDEX);
         assert_eq!(param_reader.get_last_log_term(), LAST_LOG_TERM);
+        assert!(param_reader.get_leader_transfer());
+    }
+
+    #[test]
+    fn constructs_valid_timeout_now() {
+        const TERM: u64 = 21;
+        const LEADER_ID: u64 = 3;
+        let mut rpc = Rpc::new(constants::TIMEOUT_NOW_OPCODE);
+        let message = TimeoutNowMessage {
+            term: TERM,
+            leader_id: LEADER_ID
+        };
+
+        Peer::construct_timeout_now(&mut rpc, &message);
+        let param_reader = rpc.get_param_builder().as_reader().get_as::<timeout_now::Reader>().unwrap();
+        assert_eq!(param_reader.get_term(), TERM);
+        assert_eq!(param_reader.get_leader_id(), LEADER_ID);
     }
 
     fn get_message_reader<A> (msg: &message::Builder<A>) -> message::Reader<OwnedSegments> 
@@ -604,6 +1289,17 @@ mod tests {
         reply_builder.set_success(success);
     }
 
+    /// Constructs a valid rpc_response with the given information contained in an
+    /// install_snapshot_reply inside the provided msg buffer.
+    fn construct_install_snapshot_reply<A> (msg: &mut message::Builder<A>, term: u64, success: bool)
+        where A: message::Allocator
+    {
+        let response_builder = msg.init_root::<rpc_response::Builder>();
+        let mut reply_builder = response_builder.get_result().init_as::<install_snapshot_reply::Builder>();
+        reply_builder.set_term(term);
+        reply_builder.set_success(success);
+    }
+
     /// Constructs a valid rpc_response with the given information contained in a request_vote_reply
     /// inside the provided msg buffer.
     fn construct_request_vote_reply<A> (msg: &mut message::Builder<A>, term: u64, vote_granted: bool) 
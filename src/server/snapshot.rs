@@ -0,0 +1,339 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Error, ErrorKind, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use capnp::{message, serialize_packed};
+use capnp::message::ReaderOptions;
+use raft_capnp::{snapshot_metadata};
+use super::super::common::raft_server;
+
+///
+/// Describes the prefix of the log that a snapshot replaces.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotMetadata {
+    pub last_included_index: usize,  // index of the last entry applied to the snapshot
+    pub last_included_term: u64,     // term of that entry
+    pub config: Option<Vec<(u64, SocketAddr)>>,  // cluster config as of that entry
+    // servers we were moving to if that config was a joint consensus config
+    pub joint_config: Option<Vec<(u64, SocketAddr)>>,
+    // non-voting members of that config
+    pub learners: Vec<(u64, SocketAddr)>
+}
+
+impl SnapshotMetadata {
+    ///
+    /// Deserializes snapshot metadata from its protobuf representation.
+    ///
+    /// # Errors
+    /// Returns a capnp error if the proto is malformed.
+    ///
+    pub fn from_proto(proto: snapshot_metadata::Reader) -> ::capnp::Result<SnapshotMetadata> {
+        let servers = raft_server::list_from_proto(proto.get_config()?)?;
+        let joint_servers = raft_server::list_from_proto(proto.get_joint_config()?)?;
+        let learners = raft_server::list_from_proto(proto.get_learners()?)?;
+
+        Ok(SnapshotMetadata {
+            last_included_index: proto.get_last_included_index() as usize,
+            last_included_term: proto.get_last_included_term(),
+            config: if servers.is_empty() { None } else { Some(servers) },
+            joint_config: if joint_servers.is_empty() { None } else { Some(joint_servers) },
+            learners: learners
+        })
+    }
+
+    ///
+    /// Returns the sets of servers that each need a majority under the config as of the end of
+    /// the snapshot, or None if there was no config yet.
+    ///
+    pub fn quorums(&self) -> Option<Vec<Vec<(u64, SocketAddr)>>> {
+        self.config.clone().map(|servers| {
+            let mut quorums = vec![servers];
+            quorums.extend(self.joint_config.clone());
+            quorums
+        })
+    }
+
+    ///
+    /// Populates the proto |builder| with this metadata.
+    ///
+    pub fn into_proto(&self, builder: &mut snapshot_metadata::Builder) {
+        builder.set_last_included_index(self.last_included_index as u64);
+        builder.set_last_included_term(self.last_included_term);
+        if let Some(ref servers) = self.config {
+            raft_server::list_to_proto(servers.clone(), builder.borrow().init_config(servers.len() as u32));
+        }
+        if let Some(ref servers) = self.joint_config {
+            raft_server::list_to_proto(servers.clone(), builder.borrow().init_joint_config(servers.len() as u32));
+        }
+        raft_server::list_to_proto(self.learners.clone(), builder.borrow().init_learners(self.learners.len() as u32));
+    }
+}
+
+///
+/// Returns the name of the snapshot file that accompanies the log stored at |log_filename|.
+///
+pub fn snapshot_filename(log_filename: &str) -> String {
+    format!("{}.snapshot", log_filename)
+}
+
+///
+/// A snapshot of the state machine stored on disk.
+/// The file holds a packed SnapshotMetadata message followed by the raw state machine image.
+///
+#[derive(Debug)]
+pub struct SnapshotFile {
+    filename: String
+}
+
+impl SnapshotFile {
+    pub fn new_from_filename(filename: &str) -> SnapshotFile {
+        SnapshotFile {
+            filename: String::from(filename)
+        }
+    }
+
+    ///
+    /// Reads the metadata and state machine image stored in this snapshot.
+    /// Returns None if no snapshot has been saved yet.
+    ///
+    /// # Errors
+    /// * Returns an IO error if the file exists but can't be read.
+    /// * Returns a std::io::ErrorKind::InvalidData error if the metadata is corrupt.
+    ///
+    pub fn read(&self) -> Result<Option<(SnapshotMetadata, Vec<u8>)>> {
+        match self.open()? {
+            Some((metadata, mut reader)) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                Ok(Some((metadata, data)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    ///
+    /// Opens this snapshot for streaming. Returns its metadata along with a reader
+    /// pointed at the first byte of the state machine image.
+    /// The reader keeps seeing this snapshot even if it is replaced while being read.
+    /// Returns None if no snapshot has been saved yet.
+    ///
+    /// # Errors
+    /// Same as |read|
+    ///
+    pub fn open(&self) -> Result<Option<(SnapshotMetadata, BufReader<File>)>> {
+        let file = match File::open(&self.filename) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+
+        let mut reader = BufReader::new(file);
+        let metadata = SnapshotFile::read_metadata(&mut reader)?;
+        Ok(Some((metadata, reader)))
+    }
+
+    ///
+    /// Reads just the metadata stored in this snapshot.
+    /// Returns None if no snapshot has been saved yet.
+    ///
+    /// # Errors
+    /// Same as |read|
+    ///
+    pub fn get_metadata(&self) -> Result<Option<SnapshotMetadata>> {
+        let file = match File::open(&self.filename) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+
+        SnapshotFile::read_metadata(&mut BufReader::new(file)).map(Some)
+    }
+
+    ///
+    /// Atomically replaces this snapshot with |metadata| and |data|.
+    /// The snapshot is written to a temporary file and synced to disk before being
+    /// renamed over the old one, so a crash leaves either the old or the new snapshot intact.
+    ///
+    /// # Errors
+    /// Returns an IO error if the snapshot could not be written.
+    ///
+    pub fn save(&self, metadata: &SnapshotMetadata, data: &[u8]) -> Result<()> {
+        let tmp_filename = format!("{}.tmp", self.filename);
+        {
+            let file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_filename)?;
+            let mut writer = BufWriter::new(&file);
+            let mut builder = message::Builder::new_default();
+            metadata.into_proto(&mut builder.init_root::<snapshot_metadata::Builder>());
+            serialize_packed::write_message(&mut writer, &builder)?;
+            writer.write_all(data)?;
+            writer.flush()?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_filename, &self.filename)
+    }
+
+    ///
+    /// Reads the snapshot metadata from the front of |reader|, leaving |reader| pointed at
+    /// the first byte of the state machine image.
+    ///
+    fn read_metadata<R: Read + Seek>(reader: &mut BufReader<R>) -> Result<SnapshotMetadata> {
+        reader.seek(SeekFrom::Start(0))?;
+        let message = serialize_packed::read_message(reader, ReaderOptions::new())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Corrupt snapshot metadata"))?;
+        message.get_root::<snapshot_metadata::Reader>()
+            .and_then(SnapshotMetadata::from_proto)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Corrupt snapshot metadata"))
+    }
+}
+
+///
+/// A snapshot that is being received from the leader one chunk at a time.
+/// It's kept in memory until it is complete, since the state machine needs all of it
+/// in memory to restore from it anyway.
+///
+pub struct IncomingSnapshot {
+    pub metadata: SnapshotMetadata,
+    data: Vec<u8>
+}
+
+impl IncomingSnapshot {
+    ///
+    /// Starts receiving the snapshot described by |metadata|.
+    ///
+    pub fn new(metadata: SnapshotMetadata) -> IncomingSnapshot {
+        IncomingSnapshot {
+            metadata: metadata,
+            data: Vec::new()
+        }
+    }
+
+    ///
+    /// Appends |data|, which starts at |offset| within the state machine image, to this snapshot.
+    /// Returns false without writing anything if |offset| is not where the previous chunk ended.
+    ///
+    pub fn write_chunk(&mut self, offset: u64, data: &[u8]) -> bool {
+        if offset != self.data.len() as u64 {
+            return false;
+        }
+
+        self.data.extend_from_slice(data);
+        true
+    }
+
+    ///
+    /// Returns the metadata and state machine image of the complete snapshot.
+    ///
+    pub fn finish(self) -> (SnapshotMetadata, Vec<u8>) {
+        (self.metadata, self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SnapshotFile, SnapshotMetadata, IncomingSnapshot};
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use rand::{thread_rng, Rng};
+
+    fn random_filename() -> String {
+        const FILENAME_LEN: usize = 20;
+        let filename: String = thread_rng().gen_ascii_chars().take(FILENAME_LEN).collect();
+        String::from("/tmp/") + &filename
+    }
+
+    fn metadata_with_config() -> SnapshotMetadata {
+        SnapshotMetadata {
+            last_included_index: 42,
+            last_included_term: 7,
+            config: Some(vec![(1, SocketAddr::from_str("127.0.0.1:8000").unwrap()),
+                              (2, SocketAddr::from_str("127.0.0.1:8001").unwrap())]),
+            joint_config: None,
+            learners: vec![]
+        }
+    }
+
+    #[test]
+    fn read_returns_none_without_snapshot() {
+        let snapshot_file = SnapshotFile::new_from_filename(&random_filename());
+        assert_eq!(snapshot_file.read().unwrap(), None);
+        assert_eq!(snapshot_file.get_metadata().unwrap(), None);
+    }
+
+    #[test]
+    fn saves_and_reads_snapshot() {
+        let filename = random_filename();
+        let snapshot_file = SnapshotFile::new_from_filename(&filename);
+        let metadata = metadata_with_config();
+        let data = vec![4, 8, 15, 16, 23, 42];
+        snapshot_file.save(&metadata, &data).unwrap();
+
+        assert_eq!(snapshot_file.read().unwrap(), Some((metadata.clone(), data)));
+        assert_eq!(snapshot_file.get_metadata().unwrap(), Some(metadata));
+        fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn saves_and_reads_joint_config() {
+        let filename = random_filename();
+        let snapshot_file = SnapshotFile::new_from_filename(&filename);
+        let metadata = SnapshotMetadata {
+            joint_config: Some(vec![(3, SocketAddr::from_str("127.0.0.1:8002").unwrap())]),
+            ..metadata_with_config()
+        };
+        snapshot_file.save(&metadata, &[1, 2, 3]).unwrap();
+
+        assert_eq!(snapshot_file.get_metadata().unwrap(), Some(metadata));
+        fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn saves_and_reads_learners() {
+        let filename = random_filename();
+        let snapshot_file = SnapshotFile::new_from_filename(&filename);
+        let metadata = SnapshotMetadata {
+            learners: vec![(3, SocketAddr::from_str("127.0.0.1:8002").unwrap())],
+            ..metadata_with_config()
+        };
+        snapshot_file.save(&metadata, &[1, 2, 3]).unwrap();
+
+        assert_eq!(snapshot_file.get_metadata().unwrap(), Some(metadata));
+        fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn save_replaces_old_snapshot() {
+        let filename = random_filename();
+        let snapshot_file = SnapshotFile::new_from_filename(&filename);
+        snapshot_file.save(&metadata_with_config(), &[1, 2, 3]).unwrap();
+
+        let metadata = SnapshotMetadata {
+            last_included_index: 100,
+            last_included_term: 9,
+            config: None,
+            joint_config: None,
+            learners: vec![]
+        };
+        snapshot_file.save(&metadata, &[]).unwrap();
+        assert_eq!(snapshot_file.read().unwrap(), Some((metadata, vec![])));
+        fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn incoming_snapshot_collects_chunks() {
+        let mut incoming = IncomingSnapshot::new(metadata_with_config());
+        assert!(incoming.write_chunk(0, &[4, 5]));
+        assert!(incoming.write_chunk(2, &[6]));
+        assert_eq!(incoming.finish(), (metadata_with_config(), vec![4, 5, 6]));
+    }
+
+    #[test]
+    fn incoming_snapshot_rejects_out_of_order_chunks() {
+        let mut incoming = IncomingSnapshot::new(metadata_with_config());
+        assert!(!incoming.write_chunk(3, &[4, 5]));
+        assert!(incoming.write_chunk(0, &[1, 2, 3]));
+        assert!(!incoming.write_chunk(0, &[1, 2, 3]));
+        assert_eq!(incoming.finish(), (metadata_with_config(), vec![1, 2, 3]));
+    }
+}
@@ -1,205 +1,233 @@
-use std::fs::File;
-use std::io::{Result, SeekFrom, Error, ErrorKind, Read, BufReader, Seek, BufRead, Write};
-use std::fs::OpenOptions;
-
-const VOTED_FOR_NONE: &'static str = "NONE";
-
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Error, ErrorKind, Read, Write};
+use std::path::Path;
+use super::checksum::crc32;
+use super::storage::HardState;
+
+// Every state file starts with these bytes, so we can tell it apart from any other file
+const MAGIC: &'static [u8; 4] = b"RRST";
+// Bumped whenever the layout of the file changes
+const VERSION: u32 = 1;
+// The magic bytes, the version, the term, whether we voted, who we voted for,
+// and a CRC-32 of all of those. Numbers are little endian.
+const FILE_LEN: usize = 4 + 4 + 8 + 1 + 8 + 4;
+
+///
+/// Keeps our term and vote in a file.
+/// Every save writes a whole new file and renames it over the old one, so a crash leaves us
+/// with either the old state or the new one, never a mix of the two.
+///
 #[derive(Debug)]
 pub struct StateFile {
-    f: File,
-    state: Option<State>
+    filename: String,
+    // The state in the file, which we keep in memory so reads never have to block
+    state: HardState
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub struct State {
-    pub term: u64,
-    pub voted_for: Option<u64>
-}
-
-impl State {
-    /// Serializes the state to a String
-    fn serialize (&self) -> String {
-        let mut state_str = self.term.to_string() + "\n";
+impl HardState {
+    /// Serializes the state, along with the header and checksum that make up a state file
+    fn serialize (&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FILE_LEN);
+        buf.extend_from_slice(MAGIC);
+        put_u32(&mut buf, VERSION);
+        put_u64(&mut buf, self.term);
+        buf.push(self.voted_for.is_some() as u8);
+        put_u64(&mut buf, self.voted_for.unwrap_or(0));
+        let checksum = crc32(&buf);
+        put_u32(&mut buf, checksum);
+        buf
+    }
 
-        if self.voted_for.is_none() {
-            state_str += VOTED_FOR_NONE;
-        } else {
-            state_str += &self.voted_for.unwrap().to_string();
+    /// Reads a state written by serialize
+    ///
+    /// #Errors
+    /// Returns a std::io::ErrorKind::InvalidData error saying what's wrong with |buf| if it
+    /// isn't a state file we can read
+    fn deserialize (buf: &[u8]) -> Result<HardState> {
+        if buf.len() < 8 || buf[0 .. 4] != MAGIC[..] {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  "Not a state file. It may have been written by an older version"));
+        }
+        let version = get_u32(&buf[4 .. 8]);
+        if version != VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("Unsupported state file version {}", version)));
+        }
+        if buf.len() != FILE_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "State file is the wrong length"));
+        }
+        if crc32(&buf[.. FILE_LEN - 4]) != get_u32(&buf[FILE_LEN - 4 ..]) {
+            return Err(Error::new(ErrorKind::InvalidData, "State file doesn't match its checksum"));
         }
-        
-        state_str += "\n";
 
-        return state_str;
+        let voted_for = match buf[16] {
+            0 => None,
+            1 => Some(get_u64(&buf[17 .. 25])),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Corrupt voted for"))
+        };
+        Ok(HardState {term: get_u64(&buf[8 .. 16]), voted_for: voted_for})
     }
 }
 
 impl StateFile {
-    /// Opens the file at filename creating it if it doesn't exist
+    /// Opens the file at filename, creating it with term 0 and no vote if it doesn't exist
+    ///
+    /// #Errors
+    /// * Returns an IO error if the file can't be read or created
+    /// * Returns a std::io::ErrorKind::InvalidData error if the file is corrupt, or was written
+    /// in a format we don't understand
     pub fn new_from_filename(filename: &str) -> Result<StateFile> {
-        OpenOptions::new().write(true).read(true).create(true).open(filename)
-            .map(|f| {
-                StateFile {f: f, state: None}
-            })
-    }
+        // a save we crashed in the middle of never made it into place, so we can drop it
+        let _ = fs::remove_file(temp_filename(filename));
+
+        let mut buf = vec![];
+        match File::open(filename) {
+            Ok(mut f) => { f.read_to_end(&mut buf)?; },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let mut state_file = StateFile {filename: String::from(filename), state: HardState::default()};
+                state_file.write_state(HardState::default())?;
+                return Ok(state_file);
+            },
+            Err(e) => return Err(e)
+        }
 
-    /// Reads the state in from the file or returns a cached version
-    /// May block if the state is not cached in memory
-    pub fn get_state(&mut self) -> Result<State> {
-        match self.state {
-            Some(s) => return Ok(s),
-            None => {}
-        };
+        HardState::deserialize(&buf)
+            .map_err(|e| Error::new(e.kind(), format!("Unable to read state file {}: {}", filename, e)))
+            .map(|state| StateFile {filename: String::from(filename), state: state})
+    }
 
-        self.f
-        .metadata()
-        .and_then(|m| {
-            if m.len() == 0 {
-                // there is no persisted state, so return defaults
-                return Ok(State {term: 0, voted_for: None})
-            }
-            self.f.seek(SeekFrom::Start(0))
-                .and_then(|_| {
-                    // clone f so we can create a buffered reader from it and drop the reader
-                    // when we're done
-                    self.f.try_clone()
-                    .and_then(|f| {
-                        let mut reader = BufReader::new(f);
-                        // the file exists and has bytes
-                        // it is considered corrupt if we can't read the term and voted_for from it
-                        let term = StateFile::read_term(&mut reader)?;
-                        let voted_for = StateFile::read_voted_for(&mut reader)?;
-
-                        Ok(State {term: term, voted_for: voted_for})
-                    })
-                })
-        })
-        .map(|s| {
-            self.state = Some(s);
-            s
-        })
+    /// Returns the state in the file
+    pub fn get_state(&mut self) -> Result<HardState> {
+        Ok(self.state)
     }
 
     /// Saves the state to disk. Caching it in memory as well.
     /// Will block until the state has been written. You may assume that if this function
     /// returns an Ok value then the state has been sucesfully written to disk
-    pub fn save_state(&mut self, state: State) -> Result<()> {
-        if self.state.is_some() && state == self.state.unwrap() {
+    pub fn save_state(&mut self, state: HardState) -> Result<()> {
+        if state == self.state {
             // Nothing to write. State is the same
             return Ok(());
         }
+        self.write_state(state)
+    }
 
-        let state_str = state.serialize();
-
-        self.f
-        .seek(SeekFrom::Start(0))
-        .and_then(|_| {
-            self.f.write_all(state_str.as_bytes())
-        })
-        .and_then(|_| {
-            self.f.flush()
-        })
-        .and_then(|_| {
-            self.f.set_len(state_str.len() as u64)
-        })
-        .and_then(|_| {
-            self.f.sync_all()
-        })
-        .map(|_| {
-            self.state = Some(state);
-        })
+    /// Replaces the file with one holding |state|
+    fn write_state(&mut self, state: HardState) -> Result<()> {
+        let temp_filename = temp_filename(&self.filename);
+        {
+            let mut f = OpenOptions::new().write(true).create(true).truncate(true).open(&temp_filename)?;
+            f.write_all(&state.serialize())?;
+            f.sync_all()?;
+        }
+        fs::rename(&temp_filename, &self.filename)?;
+        // the rename isn't durable until the directory entry is
+        let dir = match Path::new(&self.filename).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new(".")
+        };
+        File::open(dir)?.sync_all()?;
+        self.state = state;
+        Ok(())
     }
+}
+
+/// Where we write a new state before renaming it over the state file at |filename|
+fn temp_filename(filename: &str) -> String {
+    String::from(filename) + ".tmp"
+}
 
-    fn read_term<R: Read> (reader: &mut BufReader<R>) -> Result<u64> {
-        let mut buf = String::new();
-        reader.read_line(&mut buf)
-        .and_then(|bytes_read| {
-            if bytes_read > 0 {
-                // (buf - the new line) should now be the term
-                buf[0..buf.len() - 1].parse::<u64>()
-                .map_err(|_| {
-                    Error::new(ErrorKind::InvalidData, "Corrupt term")
-                })
-            } else {
-                Err(Error::new(ErrorKind::InvalidData, "Unable to read term from comfig file"))
-            }
-        })
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    for i in 0 .. 4 {
+        buf.push((n >> (8 * i)) as u8);
     }
+}
 
-    fn read_voted_for<R> (reader: &mut BufReader<R>) -> Result<Option<u64>> 
-    where R: Read {
-        let mut buf = String::new();
-        reader.read_line(&mut buf)
-        .and_then(|bytes_read| {
-            if bytes_read > 0 {
-                if buf[0..buf.len() - 1] == *VOTED_FOR_NONE {
-                    Ok(None)
-                } else {
-                    // (buf - the new line) should now be VotedFor 
-                    buf[0..buf.len() - 1].parse::<u64>()
-                    .map_err(|_| {
-                        Error::new(ErrorKind::InvalidData, "Corrupt term")
-                    })
-                    .map(|id| Some(id))
-                }
-            } else {
-                Err(Error::new(ErrorKind::InvalidData, "Unable to read term from comfig file"))
-            }
-        })
+fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    for i in 0 .. 8 {
+        buf.push((n >> (8 * i)) as u8);
     }
 }
 
+fn get_u32(bytes: &[u8]) -> u32 {
+    bytes[.. 4].iter().rev().fold(0, |n, &b| (n << 8) | b as u32)
+}
+
+fn get_u64(bytes: &[u8]) -> u64 {
+    bytes[.. 8].iter().rev().fold(0, |n, &b| (n << 8) | b as u64)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::io::{Cursor};
-    #[test]
-    fn read_term_reads_term() {
-        let term_str = Cursor::new("64\n");
-        let mut reader = BufReader::new(term_str);
-        let term = StateFile::read_term(&mut reader).unwrap();
+    use super::super::storage::HardState;
+    use rand::{thread_rng, Rng};
 
-        assert_eq!(term, 64);
+    /// Returns a filename in /tmp that nothing else is using
+    fn random_filename() -> String {
+        let name: String = thread_rng().gen_ascii_chars().take(20).collect();
+        String::from("/tmp/state_") + &name
     }
 
     #[test]
-    fn read_voted_for_reads_voted_for() {
-        let voted_for_str  = Cursor::new("8\n");
-        let mut reader = BufReader::new(voted_for_str);
-        let voted_for = StateFile::read_voted_for(&mut reader).unwrap();
-
-        assert_eq!(voted_for.unwrap(), 8);
+    fn state_serializes_and_deserializes() {
+        let state = HardState {term: 8, voted_for: Some(12)};
+        assert_eq!(HardState::deserialize(&state.serialize()).unwrap(), state);
     }
 
     #[test]
-    fn read_voted_for_reads_none() {
-        let voted_for_str  = Cursor::new(String::from(VOTED_FOR_NONE) + "\n");
-        let mut reader = BufReader::new(voted_for_str);
-        let voted_for = StateFile::read_voted_for(&mut reader).unwrap();
-
-        assert!(voted_for.is_none());
+    fn state_serializes_and_deserializes_none_voted_for() {
+        let state = HardState {term: 8, voted_for: None};
+        assert_eq!(HardState::deserialize(&state.serialize()).unwrap(), state);
     }
 
     #[test]
-    fn state_serializes_and_deserializes() {
-        let state = State {term: 8, voted_for: Some(12)};
-        let state_cursor = Cursor::new(state.serialize());
-        let mut reader = BufReader::new(state_cursor);
+    fn deserialize_rejects_corrupt_state() {
+        let mut buf = HardState {term: 8, voted_for: Some(12)}.serialize();
+        buf[10] ^= 1;
+        assert_eq!(HardState::deserialize(&buf).unwrap_err().kind(), ErrorKind::InvalidData);
 
-        let deserialized_term = StateFile::read_term(&mut reader).unwrap();
-        let deserialized_voted_for = StateFile::read_voted_for(&mut reader).unwrap();
-
-        assert_eq!(State {term: deserialized_term, voted_for: deserialized_voted_for}, state);
+        let buf = HardState {term: 8, voted_for: Some(12)}.serialize();
+        assert!(HardState::deserialize(&buf[.. FILE_LEN - 1]).is_err());
     }
 
     #[test]
-    fn state_serializes_and_deserializes_none_voted_for() {
-        let state = State {term: 8, voted_for: None};
-        let state_cursor = Cursor::new(state.serialize());
-        let mut reader = BufReader::new(state_cursor);
+    fn deserialize_rejects_other_formats() {
+        // how versions before this one laid out the file
+        assert!(HardState::deserialize(b"8\nNONE\n").is_err());
+
+        let mut buf = HardState {term: 8, voted_for: None}.serialize();
+        buf[4] = VERSION as u8 + 1;
+        let err = HardState::deserialize(&buf).unwrap_err();
+        assert!(err.to_string().contains("version"));
+    }
 
-        let deserialized_term = StateFile::read_term(&mut reader).unwrap();
-        let deserialized_voted_for = StateFile::read_voted_for(&mut reader).unwrap();
+    #[test]
+    fn state_file_saves_and_reopens() {
+        let filename = random_filename();
+        assert_eq!(StateFile::new_from_filename(&filename).unwrap().get_state().unwrap(),
+                   HardState {term: 0, voted_for: None});
+
+        let state = HardState {term: 3, voted_for: Some(1)};
+        StateFile::new_from_filename(&filename).unwrap().save_state(state).unwrap();
+        assert_eq!(StateFile::new_from_filename(&filename).unwrap().get_state().unwrap(), state);
+        assert!(fs::metadata(temp_filename(&filename)).is_err());
+        fs::remove_file(&filename).unwrap();
+    }
 
-        assert_eq!(State {term: deserialized_term, voted_for: deserialized_voted_for}, state);
+    #[test]
+    fn state_file_reports_corruption_on_open() {
+        let filename = random_filename();
+        StateFile::new_from_filename(&filename).unwrap().save_state(HardState {term: 3, voted_for: None}).unwrap();
+        let mut buf = vec![];
+        File::open(&filename).unwrap().read_to_end(&mut buf).unwrap();
+        buf[9] ^= 1;
+        File::create(&filename).unwrap().write_all(&buf).unwrap();
+
+        let err = StateFile::new_from_filename(&filename).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&filename));
+        fs::remove_file(&filename).unwrap();
     }
 }
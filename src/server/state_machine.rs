@@ -1,28 +1,45 @@
 use std::mem;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Sender};
-use std::thread;
-use std::thread::JoinHandle;
+use std::cmp::min;
+use std::sync::Arc;
+use simulation::sync::Mutex;
+use simulation::sync::mpsc::{channel, Sender};
+use simulation::thread;
+use simulation::thread::JoinHandle;
 
 use super::{MainThreadMessage, ServerState};
 use super::super::client::state_machine::RaftStateMachine;
 use super::super::common::{RaftError, raft_command, raft_query};
 use super::log::{Log};
+use super::snapshot::SnapshotMetadata;
+use super::storage::SharedStorage;
+
+// Most committed entries we read from the log at once while applying them
+const APPLY_BATCH_SIZE: usize = 1024;
 
 ///
 /// Messages to be sent to the state machine thread.
 ///
 #[derive(Clone)]
 pub enum StateMachineMessage {
+    // Commands reply with the index they were commited at
     Command {
         command: raft_command::Request,
-        response_channel: Sender<Result<raft_command::Reply, RaftError>>
+        response_channel: Sender<Result<(raft_command::Reply, usize), RaftError>>
     },
+    // Queries wait until the state machine has applied |read_index|
     Query { 
         query: raft_query::Request, 
+        read_index: usize,
         response_channel: Sender<Result<raft_query::Reply, RaftError>>
     },
     Commit (usize),
+    // Replaces the state machine with the snapshot the leader sent us, whose state machine
+    // image is |data|. Replies with the index of the last entry the state machine has applied.
+    InstallSnapshot {
+        metadata: SnapshotMetadata,
+        data: Vec<u8>,
+        response_channel: Sender<Result<usize, RaftError>>
+    },
     Flush,
     Shutdown
 }
@@ -43,8 +60,11 @@ pub struct StateMachineHandle {
 /// StateMachine::write on the log entry's command buffer at that index if it
 /// is a Write operation. This index is initialized at |start_index|.
 ///
-/// |Query| messages perform StateMachine::read on the |query_buffer|, and
-/// sends the result over |response_channel|.
+/// |Query| messages perform StateMachine::read on the |query_buffer| once the
+/// entry at |read_index| has been applied, and sends the result over |response_channel|.
+///
+/// Once |snapshot_threshold| entries have been applied since the last snapshot
+/// the state machine is snapshotted to |storage| and the log is compacted.
 ///
 /// Returns a handle to this thread.
 ///
@@ -53,11 +73,15 @@ pub fn state_machine_thread (log: Arc<Mutex<Log>>,
                              mut state_machine: Box<RaftStateMachine>,
                              state: Arc<Mutex<ServerState>>,
                              to_main: Sender<MainThreadMessage>,
+                             storage: SharedStorage,
+                             snapshot_threshold: usize
                             ) -> StateMachineHandle {
     let mut outstanding_commands = Vec::new();
+    let mut outstanding_queries = Vec::new();
     let(to_state_machine, from_main) = channel();
     let t = thread::spawn(move || {
         let mut next_index = start_index + 1;
+        let mut last_snapshot_index = start_index;
         loop {
             let message: StateMachineMessage = from_main.recv().unwrap();
             let message_copy = message.clone();
@@ -69,11 +93,36 @@ pub fn state_machine_thread (log: Arc<Mutex<Log>>,
                     next_index = apply_commands(next_index, commit_index,
                                                 log.clone(), &mut state_machine,
                                                 &mut outstanding_commands);
+
+                    let last_applied = next_index - 1;
+                    answer_queries(last_applied, &state_machine, &mut outstanding_queries);
+                    if snapshot_threshold > 0 && last_applied >= last_snapshot_index + snapshot_threshold {
+                        match take_snapshot(last_applied, log.clone(), &state_machine, &storage) {
+                            Ok(_) => last_snapshot_index = last_applied,
+                            // We'll try again after applying the next batch of entries
+                            Err(e) => warn!("Unable to snapshot the state machine at index {}: {:?}", last_applied, e)
+                        }
+                    }
+                },
+                StateMachineMessage::InstallSnapshot { metadata, data, response_channel } => {
+                    let result = install_snapshot(metadata, &data, next_index - 1, log.clone(),
+                                                  &mut state_machine, &storage);
+                    if let Ok(last_applied) = result {
+                        next_index = last_applied + 1;
+                        last_snapshot_index = last_applied;
+                        answer_queries(last_applied, &state_machine, &mut outstanding_queries);
+                    }
+                    response_channel.send(result).unwrap();
                 },
-                StateMachineMessage::Query { query, response_channel } => {
+                StateMachineMessage::Query { query, read_index, response_channel } => {
                     // Since this thread "linearizes" commit index updates wrt queries,
-                    // it's safe just to perform the query here and return it.
-                    response_channel.send(state_machine.query(&query)).unwrap();
+                    // it's safe just to perform the query here and return it once
+                    // we've caught up to the read index.
+                    if read_index < next_index {
+                        response_channel.send(state_machine.query(&query)).unwrap();
+                    } else {
+                        outstanding_queries.push((read_index, query, response_channel));
+                    }
                 },
                 StateMachineMessage::Command { command, .. } => {
                     // Inform main thread of client append request.
@@ -95,6 +144,14 @@ pub fn state_machine_thread (log: Arc<Mutex<Log>>,
                             .unwrap();
                         }
                     }
+                    // Queries waiting on entries that haven't been applied yet can retry
+                    // with the new leader as well
+                    for (_, _, response_channel) in outstanding_queries.drain(..) {
+                        // the client may have timed out waiting for us
+                        let _ = response_channel.send(
+                            Err(RaftError::NotLeader(
+                            { state.lock().unwrap().last_leader_contact.1 })));
+                    }
                 },
                 // TODO: Allow state machines to provide custom shutdown logic?
                 StateMachineMessage::Shutdown => break
@@ -112,23 +169,104 @@ pub fn apply_commands(next_index: usize, to_commit: usize,
                       state_machine: &mut Box<RaftStateMachine>,
                       outstanding_messages: &mut Vec<StateMachineMessage>)
         -> usize {
-    if to_commit < next_index { return next_index; }
-    let to_apply = { log.lock().unwrap().get_entries_from(next_index - 1)
-        [.. (to_commit - next_index + 1) ].to_vec() };
-    for entry in to_apply.into_iter() {
-        let response = state_machine.command(&entry.op);
-        let peek = { outstanding_messages.first().cloned() };
-        if let Some(message) = peek {
-            if let StateMachineMessage::Command
-                {ref command, ref response_channel} = message {
-                if *command == entry.op {
-                    response_channel.send(response).unwrap();
-                    outstanding_messages.remove(0);
+    let mut next_index = next_index;
+    while next_index <= to_commit {
+        // Entries that have left the log's cache come from disk, so only read a batch at a time
+        let to_apply = { log.lock().unwrap().get_entries_batch(next_index - 1,
+                                                               min(APPLY_BATCH_SIZE, to_commit - next_index + 1),
+                                                               usize::max_value()) };
+        next_index = to_apply.last().expect("Committed entries are missing from the log").index + 1;
+        for entry in to_apply.into_iter() {
+            let response = state_machine.command(&entry.op);
+            let peek = { outstanding_messages.first().cloned() };
+            if let Some(message) = peek {
+                if let StateMachineMessage::Command
+                    {ref command, ref response_channel} = message {
+                    if *command == entry.op {
+                        response_channel.send(response.map(|reply| (reply, entry.index))).unwrap();
+                        outstanding_messages.remove(0);
+                    }
                 }
             }
         }
     }
-    to_commit + 1
+    next_index
+}
+
+///
+/// Answers every outstanding query whose read index is at or before |last_applied|.
+///
+fn answer_queries(last_applied: usize, state_machine: &Box<RaftStateMachine>,
+                  outstanding_queries: &mut Vec<(usize, raft_query::Request,
+                                                 Sender<Result<raft_query::Reply, RaftError>>)>) {
+    let (ready, waiting): (Vec<_>, Vec<_>) = outstanding_queries.drain(..)
+        .partition(|&(read_index, _, _)| read_index <= last_applied);
+    *outstanding_queries = waiting;
+    for (_, query, response_channel) in ready {
+        // the client may have timed out waiting for us
+        let _ = response_channel.send(state_machine.query(&query));
+    }
+}
+
+///
+/// Snapshots |state_machine|, which has applied every entry up to and including |index|,
+/// to |storage| and then discards those entries from |log|.
+///
+/// # Errors
+/// Returns a RaftError::IoError if the snapshot could not be written or the log
+/// could not be compacted, or forwards any error from the state machine as-is.
+///
+fn take_snapshot(index: usize, log: Arc<Mutex<Log>>,
+                 state_machine: &Box<RaftStateMachine>,
+                 storage: &SharedStorage) -> Result<(), RaftError> {
+    let data = state_machine.snapshot()?;
+    let metadata = {
+        let log = log.lock().unwrap();
+        // a joint config has a second set of servers
+        let mut quorums = log.get_cluster_quorums_at(index).unwrap_or(vec![]).into_iter();
+        SnapshotMetadata {
+            last_included_index: index,
+            last_included_term: log.get_term(index).ok_or(
+                RaftError::IoError(format!("Entry {} is no longer in the log", index)))?,
+            config: quorums.next(),
+            joint_config: quorums.next(),
+            learners: log.get_cluster_learners_at(index)
+        }
+    };
+
+    storage.lock().unwrap().save_snapshot(&metadata, &data)
+        .map_err(|e| RaftError::IoError(e.to_string()))?;
+    // The snapshot is safely on disk, so we no longer need these entries
+    log.lock().unwrap().compact(metadata)
+        .map(|_| ())
+        .map_err(|e| RaftError::IoError(e.to_string()))
+}
+
+///
+/// Restores |state_machine| from |data|, the state machine image of the snapshot described by
+/// |metadata|, and then saves the snapshot to |storage|, unless it is no newer than
+/// |last_applied|. Any entries in |log| that are covered by the snapshot are discarded.
+/// Returns the index of the last entry the state machine has applied.
+///
+/// # Errors
+/// Returns a RaftError::IoError if the snapshot could not be saved or the log could not be
+/// compacted, or forwards any error from the state machine as-is.
+///
+fn install_snapshot(metadata: SnapshotMetadata, data: &[u8], last_applied: usize, log: Arc<Mutex<Log>>,
+                    state_machine: &mut Box<RaftStateMachine>,
+                    storage: &SharedStorage) -> Result<usize, RaftError> {
+    if metadata.last_included_index <= last_applied {
+        // We've already applied everything in this snapshot
+        return Ok(last_applied);
+    }
+
+    // a snapshot the state machine can't restore from must not replace the one we have
+    state_machine.restore_snapshot(data)?;
+    storage.lock().unwrap().save_snapshot(&metadata, data)
+        .map_err(|e| RaftError::IoError(e.to_string()))?;
+    log.lock().unwrap().compact(metadata.clone())
+        .map_err(|e| RaftError::IoError(e.to_string()))?;
+    Ok(metadata.last_included_index)
 }
 
 impl Drop for StateMachineHandle {
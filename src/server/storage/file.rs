@@ -0,0 +1,759 @@
+use std::io::{Result, BufReader, Read, Seek, SeekFrom, Write, Error, ErrorKind};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::cmp::{min, max};
+use raft_capnp::{entry};
+use capnp::serialize_packed;
+use capnp::message;
+use capnp::message::ReaderOptions;
+use super::{Storage, HardState};
+use super::super::log::Entry;
+use super::super::snapshot::{SnapshotFile, SnapshotMetadata, snapshot_filename};
+use super::super::state_file::StateFile;
+use super::super::checksum::crc32;
+
+// Extension of the segment files in a log directory. Each one is named by the index of its
+// first entry, zero padded so that they sort in order.
+const SEGMENT_EXTENSION: &'static str = "log";
+// Every entry in a segment is framed by a header holding the length of its packed capnp
+// message and a CRC-32 of it, both little endian u32s
+const RECORD_HEADER_LEN: usize = 8;
+
+///
+/// One file of a segmented log, holding a run of consecutive entries.
+///
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    file: File,
+    // Index of the first entry in the file
+    first_index: usize,
+    // Byte offset of each entry in the file
+    offsets: Vec<u64>,
+    // Number of bytes of entries in the file
+    len: u64
+}
+
+impl Segment {
+    fn path(log_dirname: &str, first_index: usize) -> PathBuf {
+        Path::new(log_dirname).join(format!("{:020}.{}", first_index, SEGMENT_EXTENSION))
+    }
+
+    /// Creates an empty segment in |log_dirname| for entries starting at |first_index|
+    fn create(log_dirname: &str, first_index: usize) -> Result<Segment> {
+        let path = Segment::path(log_dirname, first_index);
+        let file = OpenOptions::new().write(true).read(true).create(true).truncate(true).open(&path)?;
+        // the new file isn't durable until its directory entry is
+        sync_dir(log_dirname)?;
+        Ok(Segment {path: path, file: file, first_index: first_index, offsets: vec![], len: 0})
+    }
+
+    /// Opens the segment at |path|, and reads the offset of each of its entries.
+    /// If |repair_tail| is set, a torn or corrupt entry with nothing we can parse after it
+    /// is taken to be an append we crashed in the middle of, and it is cut off. Unless the
+    /// log's Durability says otherwise, appends are synced before we acknowledge them, so that
+    /// only loses entries nobody was told about.
+    ///
+    /// #Errors
+    /// * Returns an IO error if the file can't be read
+    /// * Returns a std::io::ErrorKind::InvalidData error if the segment is corrupt anywhere but
+    /// its last entry, or at all if |repair_tail| isn't set, or if its entries don't count up
+    /// from |first_index|
+    fn open(path: PathBuf, first_index: usize, repair_tail: bool) -> Result<Segment> {
+        let mut file = OpenOptions::new().write(true).read(true).open(&path)?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        let mut offsets = vec![];
+        let mut len = 0;
+        while len < data.len() {
+            let (entry, record_len) = match Segment::parse_record(&data[len ..]) {
+                Some(record) => record,
+                // an intact entry after a bad one means the bad one isn't a torn append
+                None if repair_tail &&
+                        !(len + 1 .. data.len()).any(|start| Segment::parse_record(&data[start ..]).is_some()) => {
+                    warn!("Discarding a torn entry at byte {} of log segment {:?}", len, path);
+                    file.set_len(len as u64)?;
+                    file.sync_all()?;
+                    break;
+                },
+                None => return Err(Error::new(ErrorKind::InvalidData,
+                                              format!("Log segment {:?} is corrupt", path)))
+            };
+            if entry.index != first_index + offsets.len() {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("Log segment {:?} is out of order", path)));
+            }
+            offsets.push(len as u64);
+            len += record_len;
+        }
+        Ok(Segment {path: path, file: file, first_index: first_index, offsets: offsets, len: len as u64})
+    }
+
+    /// Index that the entry after this segment's last one will have
+    fn next_index(&self) -> usize {
+        self.first_index + self.offsets.len()
+    }
+
+    /// Reads the entries in this segment from |from| up to but not including |to|
+    fn read_range(&mut self, from: usize, to: usize) -> Result<Vec<Entry>> {
+        let from = max(from, self.first_index) - self.first_index;
+        let to = min(to.saturating_sub(self.first_index), self.offsets.len());
+        if from >= to {
+            return Ok(vec![]);
+        }
+        self.file.seek(SeekFrom::Start(self.offsets[from]))?;
+        let mut reader = BufReader::new(&self.file);
+        (from .. to).map(|_| Segment::read_record(&mut reader)).collect()
+    }
+
+    /// Reads the entry at |index|, which must be in this segment
+    fn read_at(&mut self, index: usize) -> Result<Entry> {
+        self.file.seek(SeekFrom::Start(self.offsets[index - self.first_index]))?;
+        Segment::read_record(&mut BufReader::new(&self.file))
+    }
+
+    /// Reads the next entry from |reader|
+    ///
+    /// #Errors
+    /// Returns a std::io::ErrorKind::InvalidData error if the entry is corrupt
+    fn read_record(reader: &mut BufReader<&File>) -> Result<Entry> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let (len, checksum) = decode_header(&header);
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        Segment::decode_payload(&payload, checksum)
+    }
+
+    /// Parses the entry framed at the start of |data|, returning it along with the number of
+    /// bytes it takes up. Returns None if the entry is cut off or corrupt.
+    fn parse_record(data: &[u8]) -> Option<(Entry, usize)> {
+        if data.len() < RECORD_HEADER_LEN {
+            return None;
+        }
+        let (len, checksum) = decode_header(&data[.. RECORD_HEADER_LEN]);
+        let end = RECORD_HEADER_LEN + len;
+        if len == 0 || data.len() < end {
+            return None;
+        }
+        Segment::decode_payload(&data[RECORD_HEADER_LEN .. end], checksum).ok().map(|e| (e, end))
+    }
+
+    /// Checks |payload| against |checksum|, and decodes the entry in it
+    ///
+    /// #Errors
+    /// Returns a std::io::ErrorKind::InvalidData error if the entry is corrupt
+    fn decode_payload(mut payload: &[u8], checksum: u32) -> Result<Entry> {
+        if crc32(payload) != checksum {
+            return Err(Error::new(ErrorKind::InvalidData, "Log entry doesn't match its checksum"));
+        }
+        serialize_packed::read_message(&mut payload, ReaderOptions::new())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Corrupt captain proto entry while reading"))
+            .and_then(|buf| {
+                buf.get_root::<entry::Reader>()
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Corrupt captain proto entry while converting"))
+                    .map(|e| Entry::from_proto(e))
+            })
+    }
+
+    /// Writes |entry| to the end of this segment. Doesn't sync it to disk.
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let result = self.write_at_end(entry);
+        if result.is_err() {
+            // cut off whatever we got through, so that a retry doesn't leave it in the middle
+            // of the segment
+            let _ = self.file.set_len(self.len);
+        }
+        result
+    }
+
+    fn write_at_end(&mut self, entry: &Entry) -> Result<()> {
+        let mut payload = vec![];
+        {
+            let mut builder = message::Builder::new_default();
+            entry.into_proto(&mut builder.init_root::<entry::Builder>());
+            serialize_packed::write_message(&mut payload, &builder)?;
+        }
+        let mut record = encode_header(payload.len(), crc32(&payload)).to_vec();
+        record.extend_from_slice(&payload);
+
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(&record)?;
+        self.offsets.push(self.len);
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    /// Discards all but the first |num_entries| entries in this segment, and syncs the file
+    fn truncate(&mut self, num_entries: usize) -> Result<()> {
+        if num_entries < self.offsets.len() {
+            self.file.set_len(self.offsets[num_entries])?;
+            self.file.sync_all()?;
+            self.len = self.offsets[num_entries];
+            self.offsets.truncate(num_entries);
+        }
+        Ok(())
+    }
+}
+
+/// Syncs the directory |dirname|, so that files created in it or removed from it stay that way
+fn sync_dir(dirname: &str) -> Result<()> {
+    File::open(dirname)?.sync_all()
+}
+
+/// Frames an entry whose packed message is |len| bytes long and has a CRC-32 of |checksum|
+fn encode_header(len: usize, checksum: u32) -> [u8; RECORD_HEADER_LEN] {
+    let len = len as u32;
+    [len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8,
+     checksum as u8, (checksum >> 8) as u8, (checksum >> 16) as u8, (checksum >> 24) as u8]
+}
+
+/// Returns the length and checksum in an entry's header
+fn decode_header(header: &[u8]) -> (usize, u32) {
+    let read_u32 = |bytes: &[u8]| {
+        bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+    };
+    (read_u32(&header[0 .. 4]) as usize, read_u32(&header[4 .. 8]))
+}
+
+///
+/// The default Storage. Keeps the term and vote in a state file, and the log in a directory of
+/// segment files. Once a segment grows past |segment_size| bytes we start a new one, and
+/// segments are deleted once a snapshot covers every entry in them.
+/// Snapshots are kept in the snapshot file that accompanies the log.
+///
+#[derive(Debug)]
+pub struct FileStorage {
+    log_dirname: String,
+    segment_size: u64,
+    // In order of their first index. Only the last one is ever written to
+    segments: Vec<Segment>,
+    state_file: StateFile,
+    snapshot_file: SnapshotFile,
+    snapshot: Option<SnapshotMetadata>,
+    // Index of the first entry that isn't covered by our snapshot. Segments may still hold
+    // entries before it, which we ignore
+    start_index: usize
+}
+
+impl FileStorage {
+    ///
+    /// Opens the state file at |state_filename| and the log in the directory |log_dirname|,
+    /// creating them if they don't exist. New segments are started once the last one grows
+    /// past |segment_size| bytes. A torn entry at the end of the log, left by a crash in the
+    /// middle of an append, is discarded.
+    ///
+    /// #Errors
+    /// * Returns an IO error if the files can't be read
+    /// * Returns a std::io::ErrorKind::InvalidData error if the state file is corrupt, or if
+    /// the log is corrupt anywhere but at its end, has a gap, or doesn't pick up where our
+    /// snapshot leaves off
+    ///
+    /// Entries after our snapshot are discarded if the log holds a different entry than the
+    /// last one the snapshot covers.
+    ///
+    pub fn new (state_filename: &str, log_dirname: &str, segment_size: u64) -> Result<FileStorage> {
+        let state_file = StateFile::new_from_filename(state_filename)?;
+        let snapshot_file = SnapshotFile::new_from_filename(&snapshot_filename(log_dirname));
+        let snapshot = snapshot_file.get_metadata()?;
+        let start_index = snapshot.as_ref().map_or(1, |s| s.last_included_index + 1);
+
+        fs::create_dir_all(log_dirname)?;
+        let mut segment_paths = vec![];
+        for dir_entry in fs::read_dir(log_dirname)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            match path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<usize>().ok()) {
+                Some(first_index) => segment_paths.push((first_index, path)),
+                None => warn!("Ignoring {:?} in the log directory", path)
+            }
+        }
+        segment_paths.sort();
+
+        let mut segments: Vec<Segment> = vec![];
+        let num_segments = segment_paths.len();
+        for (i, (first_index, path)) in segment_paths.into_iter().enumerate() {
+            // We only ever write to the last segment, so that's the only place a crash can
+            // leave a torn entry. We sync a segment before starting the next one
+            let segment = Segment::open(path, first_index, i + 1 == num_segments)?;
+            // We may have crashed right after starting this segment, or after saving a snapshot
+            // that covers it but before deleting it
+            if segment.offsets.is_empty() || segment.next_index() <= start_index {
+                fs::remove_file(&segment.path)?;
+                continue;
+            }
+            if segments.last().map_or(false, |last| last.next_index() != first_index) {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("Log is missing the entries before {}", first_index)));
+            }
+            segments.push(segment);
+        }
+        if segments.first().map_or(false, |s| s.first_index > start_index) {
+            return Err(Error::new(ErrorKind::InvalidData, "Log does not continue from the snapshot"));
+        }
+
+        let mut storage = FileStorage {
+            log_dirname: String::from(log_dirname),
+            segment_size: segment_size,
+            segments: segments,
+            state_file: state_file,
+            snapshot_file: snapshot_file,
+            snapshot: snapshot.clone(),
+            start_index: start_index
+        };
+        if let Some(snapshot) = snapshot {
+            // We may have crashed after installing a snapshot from the leader but before
+            // compacting the log. Entries after it only belong with it if our logs agree on it
+            let index = snapshot.last_included_index;
+            let term = match storage.segment_for(index) {
+                Some(i) => Some(storage.segments[i].read_at(index)?.term),
+                None => None
+            };
+            if term.map_or(false, |term| term != snapshot.last_included_term) {
+                warn!("Discarding the log after our snapshot at {}, which doesn't match it", index);
+                storage.truncate(index)?;
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Returns the position of the segment holding |index| in |segments|
+    fn segment_for(&self, index: usize) -> Option<usize> {
+        match self.segments.iter().rposition(|s| s.first_index <= index) {
+            Some(i) if index < self.segments[i].next_index() => Some(i),
+            _ => None
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn hard_state (&mut self) -> Result<HardState> {
+        self.state_file.get_state()
+    }
+
+    fn save_hard_state (&mut self, state: HardState) -> Result<()> {
+        self.state_file.save_state(state)
+    }
+
+    fn snapshot_metadata (&self) -> Option<SnapshotMetadata> {
+        self.snapshot.clone()
+    }
+
+    fn snapshot (&self) -> Result<Option<(SnapshotMetadata, Box<Read>)>> {
+        self.snapshot_file.open()
+            .map(|snapshot| snapshot.map(|(metadata, reader)| (metadata, Box::new(reader) as Box<Read>)))
+    }
+
+    fn save_snapshot (&mut self, metadata: &SnapshotMetadata, data: &[u8]) -> Result<()> {
+        self.snapshot_file.save(metadata, data)
+    }
+
+    fn last_index (&self) -> usize {
+        let last_in_segments = self.segments.last().map_or(0, |s| s.next_index() - 1);
+        max(self.start_index - 1, last_in_segments)
+    }
+
+    fn entry (&mut self, index: usize) -> Result<Option<Entry>> {
+        if index < self.start_index {
+            return Ok(None);
+        }
+        match self.segment_for(index) {
+            Some(i) => self.segments[i].read_at(index).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    fn entries_between (&mut self, from: usize, to: usize) -> Result<Vec<Entry>> {
+        let from = max(from, self.start_index);
+        let mut entries = vec![];
+        for segment in self.segments.iter_mut() {
+            entries.extend(segment.read_range(from, to)?);
+        }
+        Ok(entries)
+    }
+
+    fn append (&mut self, entries: &[Entry]) -> Result<()> {
+        debug_assert!(entries.first().map_or(true, |e| e.index == self.last_index() + 1));
+        for entry in entries {
+            if self.segments.last().map_or(true, |s| s.len >= self.segment_size) {
+                // Only the last segment may be torn by a crash, so this one has to be whole
+                // before we start the next
+                if let Some(last) = self.segments.last() {
+                    last.file.sync_all()?;
+                }
+                let segment = Segment::create(&self.log_dirname, entry.index)?;
+                self.segments.push(segment);
+            }
+            self.segments.last_mut().unwrap().append(entry)?;
+        }
+
+        Ok(())
+    }
+
+    fn sync (&mut self) -> Result<()> {
+        // earlier segments were synced when we moved on from them
+        match self.segments.last() {
+            Some(last) => last.file.sync_all(),
+            None => Ok(())
+        }
+    }
+
+    fn truncate (&mut self, last_index: usize) -> Result<()> {
+        let mut removed = false;
+        while self.segments.last().map_or(false, |s| s.first_index > last_index) {
+            fs::remove_file(&self.segments.last().unwrap().path)?;
+            self.segments.pop();
+            removed = true;
+        }
+        if removed {
+            // the removed entries would come back if we crashed before the directory is synced
+            sync_dir(&self.log_dirname)?;
+        }
+
+        match self.segments.last_mut() {
+            Some(segment) => {
+                let num_entries = last_index + 1 - segment.first_index;
+                segment.truncate(num_entries)
+            },
+            None => Ok(())
+        }
+    }
+
+    fn compact (&mut self, snapshot: &SnapshotMetadata) -> Result<()> {
+        if snapshot.last_included_index < self.start_index {
+            return Ok(()); // already compacted
+        }
+
+        // Whole segments can go once the snapshot covers them. The rest of the entries it
+        // covers stay on disk until the segment they're in is covered too
+        let mut removed = false;
+        while self.segments.first().map_or(false, |s| s.next_index() <= snapshot.last_included_index + 1) {
+            fs::remove_file(&self.segments[0].path)?;
+            self.segments.remove(0);
+            removed = true;
+        }
+        if removed {
+            sync_dir(&self.log_dirname)?;
+        }
+        self.start_index = snapshot.last_included_index + 1;
+        self.snapshot = Some(snapshot.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileStorage, Segment, SEGMENT_EXTENSION};
+    use super::super::{Storage, HardState};
+    use super::super::super::super::common::constants;
+    use super::super::super::log::{Entry, random_entries_with_term};
+    use super::super::super::snapshot::{SnapshotMetadata, snapshot_filename};
+    use rand::{thread_rng, Rng};
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+
+    // Small enough that every few entries start a new segment
+    const SMALL_SEGMENT_SIZE: u64 = 64;
+
+    /// Deletes every file a FileStorage may leave behind when dropped
+    struct Files {
+        state: String,
+        log: String
+    }
+
+    impl Files {
+        fn new() -> Files {
+            let name: String = thread_rng().gen_ascii_chars().take(20).collect();
+            Files {state: format!("/tmp/state_{}", name), log: format!("/tmp/log_{}", name)}
+        }
+
+        fn open(&self) -> FileStorage {
+            FileStorage::new(&self.state, &self.log, constants::DEFAULT_LOG_SEGMENT_SIZE).unwrap()
+        }
+
+        fn open_with_small_segments(&self) -> FileStorage {
+            FileStorage::new(&self.state, &self.log, SMALL_SEGMENT_SIZE).unwrap()
+        }
+
+        /// Returns the names of the segment files in the log directory, in order
+        fn segments(&self) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(&self.log).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.ends_with(SEGMENT_EXTENSION))
+                .collect();
+            names.sort();
+            names
+        }
+
+        /// Returns the paths of the segment files in the log directory, in order
+        fn segment_paths(&self) -> Vec<PathBuf> {
+            self.segments().iter().map(|name| Path::new(&self.log).join(name)).collect()
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.state);
+            let _ = fs::remove_dir_all(&self.log);
+            let _ = fs::remove_file(snapshot_filename(&self.log));
+        }
+    }
+
+    fn indexed(entries: Vec<Entry>, start: usize) -> Vec<Entry> {
+        entries.into_iter().enumerate().map(|(i, mut e)| { e.index = start + i; e }).collect()
+    }
+
+    /// Flips the bits of the byte |from_end| bytes before the end of the file at |path|
+    fn corrupt(path: &Path, from_end: u64) {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let offset = file.metadata().unwrap().len() - from_end;
+        let mut byte = [0];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[!byte[0]]).unwrap();
+    }
+
+    #[test]
+    fn file_storage_reopens_hard_state() {
+        let files = Files::new();
+        files.open().save_hard_state(HardState {term: 4, voted_for: Some(1)}).unwrap();
+        assert_eq!(files.open().hard_state().unwrap(), HardState {term: 4, voted_for: Some(1)});
+    }
+
+    #[test]
+    fn file_storage_reads_entries_by_index() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(6, 1), 1);
+        let mut storage = files.open();
+        storage.append(&entries[.. 3]).unwrap();
+        storage.append(&entries[3 ..]).unwrap();
+
+        for entry in &entries {
+            assert_eq!(storage.entry(entry.index).unwrap().unwrap(), *entry);
+        }
+        assert!(storage.entry(0).unwrap().is_none());
+        assert!(storage.entry(7).unwrap().is_none());
+    }
+
+    #[test]
+    fn file_storage_reopens_truncated_log() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(6, 1), 1);
+        {
+            let mut storage = files.open();
+            storage.append(&entries).unwrap();
+            storage.truncate(3).unwrap();
+            // appending after a truncate mustn't leave a gap in the file
+            storage.append(&entries[3 .. 4]).unwrap();
+        }
+
+        let mut storage = files.open();
+        assert_eq!(storage.last_index(), 4);
+        assert_eq!(storage.entries().unwrap(), &entries[.. 4]);
+    }
+
+    fn snapshot_at(index: usize) -> SnapshotMetadata {
+        SnapshotMetadata {
+            last_included_index: index,
+            last_included_term: 1,
+            config: None,
+            joint_config: None,
+            learners: vec![]
+        }
+    }
+
+    #[test]
+    fn file_storage_skips_entries_covered_by_snapshot() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(6, 1), 1);
+        let metadata = snapshot_at(2);
+        let mut storage = files.open();
+        storage.append(&entries).unwrap();
+        // we crash after saving the snapshot but before compacting the log
+        storage.save_snapshot(&metadata, &[]).unwrap();
+
+        let mut storage = files.open();
+        assert_eq!(storage.snapshot_metadata(), Some(metadata));
+        assert!(storage.entry(2).unwrap().is_none());
+        assert_eq!(storage.entries().unwrap(), &entries[2 ..]);
+        assert_eq!(storage.last_index(), 6);
+    }
+
+    #[test]
+    fn file_storage_discards_entries_that_conflict_with_snapshot() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(6, 1), 1);
+        let mut storage = files.open();
+        storage.append(&entries).unwrap();
+        // we crash after installing a snapshot from a leader in a later term, but before
+        // compacting the log
+        let metadata = SnapshotMetadata {last_included_term: 2, ..snapshot_at(3)};
+        storage.save_snapshot(&metadata, &[]).unwrap();
+
+        let mut storage = files.open();
+        assert_eq!(storage.snapshot_metadata(), Some(metadata));
+        assert_eq!(storage.last_index(), 3);
+        assert!(storage.entries().unwrap().is_empty());
+        assert_eq!(files.open().last_index(), 3);
+    }
+
+    #[test]
+    fn file_storage_saves_snapshot() {
+        let files = Files::new();
+        let mut storage = files.open();
+        assert!(storage.snapshot().unwrap().is_none());
+        storage.save_snapshot(&snapshot_at(2), &[1, 2, 3]).unwrap();
+
+        let (metadata, mut reader) = files.open().snapshot().unwrap().unwrap();
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!((metadata, data), (snapshot_at(2), vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn file_storage_rolls_over_to_new_segments() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(20, 1), 1);
+        {
+            let mut storage = files.open_with_small_segments();
+            storage.append(&entries[.. 10]).unwrap();
+            for entry in &entries[10 ..] {
+                storage.append(&[entry.clone()]).unwrap();
+            }
+            assert_eq!(storage.entry(15).unwrap().unwrap(), entries[14]);
+            // reads that span segments
+            assert_eq!(storage.entries_between(3, 17).unwrap(), &entries[2 .. 16]);
+        }
+
+        let segments = files.segments();
+        assert!(segments.len() > 1);
+        assert_eq!(segments[0], format!("{:020}.{}", 1, SEGMENT_EXTENSION));
+        let mut storage = files.open_with_small_segments();
+        assert_eq!(storage.last_index(), 20);
+        assert_eq!(storage.entries().unwrap(), entries);
+    }
+
+    #[test]
+    fn file_storage_truncates_across_segments() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(20, 1), 1);
+        let mut storage = files.open_with_small_segments();
+        storage.append(&entries).unwrap();
+        let num_segments = files.segments().len();
+
+        storage.truncate(2).unwrap();
+        assert!(files.segments().len() < num_segments);
+        assert_eq!(storage.last_index(), 2);
+        storage.append(&entries[2 .. 4]).unwrap();
+
+        assert_eq!(files.open_with_small_segments().entries().unwrap(), &entries[.. 4]);
+        // truncating everything leaves no segments behind
+        storage.truncate(0).unwrap();
+        assert_eq!(files.segments().len(), 0);
+        assert_eq!(storage.last_index(), 0);
+    }
+
+    #[test]
+    fn file_storage_deletes_segments_covered_by_snapshot() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(20, 1), 1);
+        let mut storage = files.open_with_small_segments();
+        storage.append(&entries).unwrap();
+        let num_segments = files.segments().len();
+
+        let metadata = snapshot_at(12);
+        storage.save_snapshot(&metadata, &[]).unwrap();
+        storage.compact(&metadata).unwrap();
+        assert!(files.segments().len() < num_segments);
+        assert!(storage.entry(12).unwrap().is_none());
+        assert_eq!(storage.entries().unwrap(), &entries[12 ..]);
+
+        let mut storage = files.open_with_small_segments();
+        assert_eq!(storage.entries().unwrap(), &entries[12 ..]);
+        // a snapshot past the end of the log covers every segment
+        let metadata = snapshot_at(25);
+        storage.save_snapshot(&metadata, &[]).unwrap();
+        storage.compact(&metadata).unwrap();
+        assert_eq!(files.segments().len(), 0);
+        assert_eq!(storage.last_index(), 25);
+    }
+
+    #[test]
+    fn file_storage_discards_torn_tail() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(5, 1), 1);
+        files.open().append(&entries).unwrap();
+        // we crash partway through writing the last entry
+        let path = files.segment_paths().pop().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let mut storage = files.open();
+        assert_eq!(storage.last_index(), 4);
+        assert_eq!(storage.entries().unwrap(), &entries[.. 4]);
+        // appends pick up right after the last whole entry
+        storage.append(&entries[4 ..]).unwrap();
+        assert_eq!(files.open().entries().unwrap(), entries);
+    }
+
+    #[test]
+    fn file_storage_discards_corrupt_tail() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(5, 1), 1);
+        files.open().append(&entries).unwrap();
+        corrupt(&files.segment_paths().pop().unwrap(), 1);
+
+        let mut storage = files.open();
+        assert_eq!(storage.last_index(), 4);
+        assert_eq!(storage.entries().unwrap(), &entries[.. 4]);
+    }
+
+    #[test]
+    fn file_storage_rejects_corruption_in_the_middle_of_the_last_segment() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(5, 1), 1);
+        files.open().append(&entries).unwrap();
+        let path = files.segment_paths().pop().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        let offset = Segment::open(path.clone(), 1, false).unwrap().offsets[2] + RECORD_HEADER_LEN as u64;
+        corrupt(&path, len - offset);
+
+        let err = FileStorage::new(&files.state, &files.log, SMALL_SEGMENT_SIZE).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // the entries after the corrupt one are left alone
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn file_storage_rejects_corruption_before_the_last_segment() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(20, 1), 1);
+        files.open_with_small_segments().append(&entries).unwrap();
+        corrupt(&files.segment_paths()[0], 1);
+
+        let err = FileStorage::new(&files.state, &files.log, SMALL_SEGMENT_SIZE).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn file_storage_rejects_holes_in_a_segment() {
+        let files = Files::new();
+        let entries = indexed(random_entries_with_term(3, 1), 1);
+        fs::create_dir_all(&files.log).unwrap();
+        {
+            let mut segment = Segment::create(&files.log, 1).unwrap();
+            segment.append(&entries[0]).unwrap();
+            segment.append(&entries[2]).unwrap();
+        }
+
+        let err = FileStorage::new(&files.state, &files.log, SMALL_SEGMENT_SIZE).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,327 @@
+mod file;
+
+use std::fmt;
+use std::cmp::{min, max};
+use std::io::{Result, Read, Cursor};
+use std::sync::Arc;
+use simulation::sync::Mutex;
+use std::time::Duration;
+use super::log::Entry;
+use super::snapshot::SnapshotMetadata;
+
+pub use self::file::FileStorage;
+
+///
+/// The term and vote that a server must remember across restarts.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<u64>
+}
+
+///
+/// Where a server keeps everything that has to survive a crash: its log, its current term and
+/// vote, and the snapshot its log has been compacted into.
+///
+/// Every write except append must be durable by the time it returns. Appended entries only
+/// have to be durable once sync returns, so that several appends can share one sync.
+///
+pub trait Storage: Send + fmt::Debug {
+    ///
+    /// Returns the term and vote we last saved, or term 0 with no vote if we've never saved any.
+    ///
+    fn hard_state (&mut self) -> Result<HardState>;
+
+    ///
+    /// Saves our term and vote.
+    ///
+    fn save_hard_state (&mut self, state: HardState) -> Result<()>;
+
+    ///
+    /// Returns the metadata of the snapshot the log was last compacted into, if any.
+    ///
+    fn snapshot_metadata (&self) -> Option<SnapshotMetadata>;
+
+    ///
+    /// Opens the snapshot we last saved. Returns its metadata along with a reader pointed at
+    /// the first byte of its state machine image. The reader keeps seeing this snapshot even
+    /// if a newer one is saved while it's being read.
+    /// Returns None if we've never saved a snapshot.
+    ///
+    fn snapshot (&self) -> Result<Option<(SnapshotMetadata, Box<Read>)>>;
+
+    ///
+    /// Saves |data|, the state machine image described by |metadata|, as our snapshot in place
+    /// of any older one. The log isn't compacted until compact is called.
+    ///
+    fn save_snapshot (&mut self, metadata: &SnapshotMetadata, data: &[u8]) -> Result<()>;
+
+    ///
+    /// Returns the index of the last entry in the log, or of the last entry covered by our
+    /// snapshot if the log is empty.
+    ///
+    fn last_index (&self) -> usize;
+
+    ///
+    /// Returns the entry at |index|, or None if it isn't in the log.
+    ///
+    fn entry (&mut self, index: usize) -> Result<Option<Entry>>;
+
+    ///
+    /// Returns the entries in the log from |from| up to but not including |to|, in order.
+    /// Entries outside the log are left out.
+    ///
+    fn entries_between (&mut self, from: usize, to: usize) -> Result<Vec<Entry>>;
+
+    ///
+    /// Returns every entry in the log, in order. The first one comes right after our snapshot.
+    ///
+    fn entries (&mut self) -> Result<Vec<Entry>> {
+        let first = self.snapshot_metadata().map_or(1, |s| s.last_included_index + 1);
+        let last = self.last_index();
+        self.entries_between(first, last + 1)
+    }
+
+    ///
+    /// Appends |entries| to the log. Their indices must already be set, and must pick up right
+    /// after last_index. They can be read back right away, but may not survive a crash until
+    /// the next sync.
+    ///
+    fn append (&mut self, entries: &[Entry]) -> Result<()>;
+
+    ///
+    /// Makes every entry appended so far durable.
+    ///
+    fn sync (&mut self) -> Result<()>;
+
+    ///
+    /// Discards every entry after |last_index|.
+    ///
+    fn truncate (&mut self, last_index: usize) -> Result<()>;
+
+    ///
+    /// Discards every entry covered by |snapshot|, and remembers its metadata.
+    /// The snapshot itself must already be saved with save_snapshot.
+    ///
+    fn compact (&mut self, snapshot: &SnapshotMetadata) -> Result<()>;
+}
+
+///
+/// How hard the log works to make entries survive a crash before it counts them as persisted.
+/// Our term and vote are always synced before we act on them, whichever mode is used, so a
+/// server never votes twice in one term.
+///
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Durability {
+    /// Sync entries to disk before acknowledging them or counting them towards a commit.
+    /// Writes that arrive together share a sync. This is the only mode that keeps all of
+    /// Raft's guarantees: a committed entry survives any number of crashes.
+    SyncEveryWrite,
+    /// Sync at most once per interval. Leaders only count their own entries towards a commit
+    /// once they're synced, but followers acknowledge entries before syncing them, so if a
+    /// majority of the cluster loses power within the interval, committed entries can be lost
+    /// and servers can disagree about what was applied.
+    SyncInterval(Duration),
+    /// Never sync, and count entries as persisted as soon as the OS has them. Surviving the
+    /// server process crashing is up to the OS, and a machine crash can lose any entry the OS
+    /// hadn't written out yet, committed or not. Only for data that can be rebuilt.
+    OsBuffered
+}
+
+impl Default for Durability {
+    fn default () -> Durability {
+        Durability::SyncEveryWrite
+    }
+}
+
+///
+/// A Storage shared between the log and the rest of the server.
+/// NB: Nothing else may be locked while holding the storage lock.
+///
+pub type SharedStorage = Arc<Mutex<Box<Storage>>>;
+
+///
+/// A Storage that only lives as long as the server does. Useful for tests, and for caches that
+/// rebuild themselves from the rest of the cluster after a restart.
+///
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    hard_state: HardState,
+    snapshot: Option<SnapshotMetadata>,
+    // the snapshot we last saved and its state machine image. It can be newer than
+    // |snapshot| until the log is compacted into it
+    saved_snapshot: Option<(SnapshotMetadata, Vec<u8>)>,
+    entries: Vec<Entry>
+}
+
+impl MemoryStorage {
+    pub fn new () -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn start_index (&self) -> usize {
+        self.snapshot.as_ref().map_or(1, |s| s.last_included_index + 1)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn hard_state (&mut self) -> Result<HardState> {
+        Ok(self.hard_state)
+    }
+
+    fn save_hard_state (&mut self, state: HardState) -> Result<()> {
+        self.hard_state = state;
+        Ok(())
+    }
+
+    fn snapshot_metadata (&self) -> Option<SnapshotMetadata> {
+        self.snapshot.clone()
+    }
+
+    fn snapshot (&self) -> Result<Option<(SnapshotMetadata, Box<Read>)>> {
+        Ok(self.saved_snapshot.clone()
+               .map(|(metadata, data)| (metadata, Box::new(Cursor::new(data)) as Box<Read>)))
+    }
+
+    fn save_snapshot (&mut self, metadata: &SnapshotMetadata, data: &[u8]) -> Result<()> {
+        self.saved_snapshot = Some((metadata.clone(), data.to_vec()));
+        Ok(())
+    }
+
+    fn last_index (&self) -> usize {
+        self.start_index() + self.entries.len() - 1
+    }
+
+    fn entry (&mut self, index: usize) -> Result<Option<Entry>> {
+        if index < self.start_index() {
+            return Ok(None);
+        }
+        Ok(self.entries.get(index - self.start_index()).cloned())
+    }
+
+    fn entries_between (&mut self, from: usize, to: usize) -> Result<Vec<Entry>> {
+        let start = self.start_index();
+        let from = max(from, start) - start;
+        let to = min(to.saturating_sub(start), self.entries.len());
+        Ok(if from < to { self.entries[from .. to].to_vec() } else { vec![] })
+    }
+
+    fn append (&mut self, entries: &[Entry]) -> Result<()> {
+        debug_assert!(entries.first().map_or(true, |e| e.index == self.last_index() + 1));
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn sync (&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn truncate (&mut self, last_index: usize) -> Result<()> {
+        let len = (last_index + 1).saturating_sub(self.start_index());
+        self.entries.truncate(len);
+        Ok(())
+    }
+
+    fn compact (&mut self, snapshot: &SnapshotMetadata) -> Result<()> {
+        if snapshot.last_included_index < self.start_index() {
+            return Ok(()); // already compacted
+        }
+        let num_discarded = snapshot.last_included_index + 1 - self.start_index();
+        if num_discarded >= self.entries.len() {
+            self.entries.clear();
+        } else {
+            self.entries.drain(.. num_discarded);
+        }
+        self.snapshot = Some(snapshot.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use super::{Storage, MemoryStorage, HardState};
+    use super::super::log::{Entry, random_entries_with_term};
+    use super::super::snapshot::SnapshotMetadata;
+
+    /// Appends |entries| to |storage|, numbering them from its last index
+    fn append(storage: &mut Storage, entries: Vec<Entry>) {
+        let start = storage.last_index() + 1;
+        let indexed: Vec<Entry> = entries.into_iter().enumerate()
+            .map(|(i, mut e)| { e.index = start + i; e })
+            .collect();
+        storage.append(&indexed).unwrap();
+    }
+
+    fn snapshot_at(index: usize, term: u64) -> SnapshotMetadata {
+        SnapshotMetadata {
+            last_included_index: index,
+            last_included_term: term,
+            config: None,
+            joint_config: None,
+            learners: vec![]
+        }
+    }
+
+    #[test]
+    fn memory_storage_saves_hard_state() {
+        let mut storage = MemoryStorage::new();
+        assert_eq!(storage.hard_state().unwrap(), HardState {term: 0, voted_for: None});
+        storage.save_hard_state(HardState {term: 3, voted_for: Some(2)}).unwrap();
+        assert_eq!(storage.hard_state().unwrap(), HardState {term: 3, voted_for: Some(2)});
+    }
+
+    #[test]
+    fn memory_storage_appends_and_truncates() {
+        let mut storage = MemoryStorage::new();
+        let entries = random_entries_with_term(5, 1);
+        append(&mut storage, entries.clone());
+        assert_eq!(storage.last_index(), 5);
+        assert_eq!(storage.entry(3).unwrap().unwrap(), entries[2]);
+        assert!(storage.entry(6).unwrap().is_none());
+
+        assert_eq!(storage.entries_between(2, 4).unwrap(), &entries[1 .. 3]);
+        assert_eq!(storage.entries_between(4, 10).unwrap(), &entries[3 ..]);
+        assert_eq!(storage.entries_between(0, 2).unwrap(), &entries[.. 1]);
+
+        storage.truncate(2).unwrap();
+        assert_eq!(storage.last_index(), 2);
+        assert_eq!(storage.entries().unwrap(), &entries[.. 2]);
+    }
+
+    #[test]
+    fn memory_storage_compacts() {
+        let mut storage = MemoryStorage::new();
+        let entries = random_entries_with_term(5, 1);
+        append(&mut storage, entries.clone());
+
+        storage.compact(&snapshot_at(3, 1)).unwrap();
+        assert!(storage.entry(3).unwrap().is_none());
+        assert_eq!(storage.entry(4).unwrap().unwrap(), entries[3]);
+        assert_eq!(storage.entries().unwrap(), &entries[3 ..]);
+        assert_eq!(storage.last_index(), 5);
+        assert_eq!(storage.snapshot_metadata(), Some(snapshot_at(3, 1)));
+
+        // a snapshot past the end of the log leaves it empty
+        storage.compact(&snapshot_at(8, 2)).unwrap();
+        assert_eq!(storage.entries().unwrap().len(), 0);
+        assert_eq!(storage.last_index(), 8);
+    }
+
+    #[test]
+    fn memory_storage_saves_snapshot() {
+        let mut storage = MemoryStorage::new();
+        assert!(storage.snapshot().unwrap().is_none());
+
+        storage.save_snapshot(&snapshot_at(3, 1), &[1, 2, 3]).unwrap();
+        let (metadata, mut reader) = storage.snapshot().unwrap().unwrap();
+        storage.save_snapshot(&snapshot_at(5, 2), &[4, 5]).unwrap();
+        // the reader still sees the snapshot it opened
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!((metadata, data), (snapshot_at(3, 1), vec![1, 2, 3]));
+        // saving a snapshot doesn't compact the log
+        assert_eq!(storage.snapshot_metadata(), None);
+    }
+}
@@ -0,0 +1,383 @@
+use std::cell::RefCell;
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng, SeedableRng, StdRng};
+use server::clock::Clock;
+
+pub mod sync;
+pub mod thread;
+
+// The scheduler running this thread, and the thread's id in it
+thread_local!(static CURRENT: RefCell<Option<(Arc<Scheduler>, usize)>> = RefCell::new(None));
+
+// Something a blocked thread is waiting for
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Event {
+    // A message on the channel with this token, or its last sender going away
+    Channel(usize),
+    // The lock at this address being released
+    Unlock(usize),
+    // The thread with this id finishing
+    Exit(usize)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ThreadState {
+    // Ready to run once the scheduler gives it a turn
+    Runnable,
+    // Waiting for the event, or for the simulation to reach the given time, whichever
+    // comes first
+    Blocked(Option<Event>, Option<Duration>)
+}
+
+struct SchedulerState {
+    // Every thread that hasn't finished yet
+    threads: BTreeMap<usize, ThreadState>,
+    // Signalled when the thread with the given id is given its turn
+    turns: BTreeMap<usize, Arc<Condvar>>,
+    // The only thread that's allowed to run right now
+    running: Option<usize>,
+    // The thread that started the simulation
+    root: Option<usize>,
+    next_id: usize,
+    // How far the simulation's clock has moved since it started
+    elapsed: Duration,
+    rng: StdRng,
+    // (elapsed, thread) for every turn a thread was given
+    trace: Vec<(Duration, usize)>,
+    // Set once every thread is waiting on something that will never happen
+    deadlocked: bool
+}
+
+///
+/// Runs a simulation one thread at a time, so that a seed replays the entire run exactly.
+///
+/// Every thread that a simulation thread spawns with `simulation::thread::spawn` joins the
+/// simulation. Only one of them runs at once. A thread keeps running until it blocks on a
+/// `simulation::sync` channel or lock, a join, or a sleep, and then the scheduler's seeded rng
+/// picks the next thread to run from the ones that aren't blocked. Once every thread is waiting, the simulation's clock jumps
+/// ahead to the earliest timeout. No real time passes on that clock, so timeouts are never
+/// raced by the machine's load.
+///
+/// The scheduler is also the simulation's Clock, which every server in it should be given.
+///
+/// Outside of a simulation the `simulation` primitives behave exactly like the std ones.
+///
+pub struct Scheduler {
+    seed: u64,
+    start: Instant,
+    state: Mutex<SchedulerState>
+}
+
+impl Scheduler {
+    pub fn new (seed: u64) -> Arc<Scheduler> {
+        Arc::new(Scheduler {
+            seed: seed,
+            start: Instant::now(),
+            state: Mutex::new(SchedulerState {
+                threads: BTreeMap::new(),
+                turns: BTreeMap::new(),
+                running: None,
+                root: None,
+                next_id: 0,
+                elapsed: Duration::from_millis(0),
+                rng: StdRng::from_seed(&[seed as usize][..]),
+                trace: vec![],
+                deadlocked: false
+            })
+        })
+    }
+
+    ///
+    /// Starts the simulation on the calling thread, which runs until it first blocks.
+    ///
+    /// # Panics
+    /// Panics if the calling thread is already part of a simulation, or if the simulation
+    /// has already started.
+    ///
+    pub fn enter (scheduler: &Arc<Scheduler>) {
+        assert!(current().is_none(), "This thread is already part of a simulation");
+        let mut state = scheduler.state.lock().unwrap();
+        assert!(state.root.is_none(), "This simulation has already started");
+        let id = state.add_thread();
+        state.root = Some(id);
+        state.running = Some(id);
+        CURRENT.with(|current| *current.borrow_mut() = Some((scheduler.clone(), id)));
+    }
+
+    ///
+    /// Takes the calling thread out of its simulation. The rest of the simulation's threads
+    /// carry on without it. Noop if the thread isn't part of a simulation.
+    ///
+    pub fn exit () {
+        if let Some((scheduler, id)) = current() {
+            scheduler.finished(id);
+        }
+    }
+
+    pub fn seed (&self) -> u64 {
+        self.seed
+    }
+
+    ///
+    /// Returns how far the simulation's clock has moved since the simulation was created.
+    ///
+    pub fn elapsed (&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+
+    ///
+    /// Returns (elapsed, thread id) for every turn the scheduler has given a thread so far.
+    /// Two simulations with the same seed that run the same code have the same trace.
+    ///
+    pub fn trace (&self) -> Vec<(Duration, usize)> {
+        self.state.lock().unwrap().trace.clone()
+    }
+
+    // Adds a thread that's ready to run, and returns its id. The thread itself waits for its
+    // first turn in attach
+    fn spawned (&self) -> usize {
+        self.state.lock().unwrap().add_thread()
+    }
+
+    // Makes the calling thread the simulation thread |id|, and waits for its first turn
+    fn attach (scheduler: Arc<Scheduler>, id: usize) {
+        CURRENT.with(|current| *current.borrow_mut() = Some((scheduler.clone(), id)));
+        scheduler.wait_for_turn(scheduler.state.lock().unwrap(), id);
+    }
+
+    // Called once thread |id| has returned or panicked. Hands its turn to another thread
+    fn finished (&self, id: usize) {
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        let mut state = self.state.lock().unwrap();
+        state.threads.remove(&id);
+        state.turns.remove(&id);
+        Scheduler::wake_locked(&mut state, Event::Exit(id));
+        self.pick_next(&mut state);
+    }
+
+    fn is_finished (&self, id: usize) -> bool {
+        !self.state.lock().unwrap().threads.contains_key(&id)
+    }
+
+    // Puts thread |id| in |thread_state| and gives another thread a turn.
+    // Returns once it's |id|'s turn again
+    fn block (&self, id: usize, thread_state: ThreadState) {
+        let mut state = self.state.lock().unwrap();
+        state.threads.insert(id, thread_state);
+        self.pick_next(&mut state);
+        self.wait_for_turn(state, id);
+    }
+
+    // Lets every thread that's waiting for |event| run again
+    fn wake (&self, event: Event) {
+        Scheduler::wake_locked(&mut self.state.lock().unwrap(), event);
+    }
+
+    fn wake_locked (state: &mut SchedulerState, event: Event) {
+        for thread_state in state.threads.values_mut() {
+            if let ThreadState::Blocked(Some(waiting_for), _) = *thread_state {
+                if waiting_for == event {
+                    *thread_state = ThreadState::Runnable;
+                }
+            }
+        }
+    }
+
+    fn wait_for_turn (&self, mut state: MutexGuard<SchedulerState>, id: usize) {
+        let turn = state.turns[&id].clone();
+        while state.running != Some(id) {
+            if state.deadlocked && state.root == Some(id) {
+                mem::drop(state);
+                panic!("Simulation with seed {} deadlocked", self.seed);
+            }
+            state = turn.wait(state).unwrap();
+        }
+    }
+
+    // Gives the next turn to a runnable thread picked by our rng, moving the clock forward
+    // if every thread is waiting on a timeout
+    fn pick_next (&self, state: &mut SchedulerState) {
+        loop {
+            // the threads are in id order, so the same rng picks the same thread
+            let runnable: Vec<usize> = state.threads.iter()
+                .filter(|&(_, thread_state)| *thread_state == ThreadState::Runnable)
+                .map(|(&id, _)| id)
+                .collect();
+            if !runnable.is_empty() {
+                let next = runnable[state.rng.gen_range(0, runnable.len())];
+                let elapsed = state.elapsed;
+                state.trace.push((elapsed, next));
+                state.running = Some(next);
+                state.turns[&next].notify_one();
+                return;
+            }
+
+            let deadline = state.threads.values()
+                .filter_map(|thread_state| match *thread_state {
+                    ThreadState::Blocked(_, deadline) => deadline,
+                    _ => None
+                })
+                .min();
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => {
+                    // Nothing left can ever run. That's expected once the root thread is done
+                    state.running = None;
+                    state.deadlocked = true;
+                    if let Some(turn) = state.root.and_then(|root| state.turns.get(&root)) {
+                        turn.notify_one();
+                    }
+                    return;
+                }
+            };
+            let elapsed = max(state.elapsed, deadline);
+            state.elapsed = elapsed;
+            for thread_state in state.threads.values_mut() {
+                if let ThreadState::Blocked(_, Some(deadline)) = *thread_state {
+                    if deadline <= elapsed {
+                        *thread_state = ThreadState::Runnable;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl SchedulerState {
+    // Adds a thread that's ready to run, and returns its id
+    fn add_thread (&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.threads.insert(id, ThreadState::Runnable);
+        self.turns.insert(id, Arc::new(Condvar::new()));
+        id
+    }
+}
+
+impl Clock for Scheduler {
+    fn now (&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Scheduler {{ seed: {}, elapsed: {:?} }}", self.seed, self.elapsed())
+    }
+}
+
+// Returns the scheduler running the calling thread and the thread's id, if it's in a simulation
+fn current () -> Option<(Arc<Scheduler>, usize)> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+///
+/// Returns a random number. Threads in a simulation draw it from the simulation's seed,
+/// so their choices are replayed along with everything else.
+///
+pub fn random_u64 () -> u64 {
+    match current() {
+        Some((scheduler, _)) => scheduler.state.lock().unwrap().rng.next_u64(),
+        None => thread_rng().next_u64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use super::sync::Mutex;
+    use super::sync::mpsc::{channel, RecvTimeoutError};
+    use super::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Has a few threads race to push onto a list, with some timeouts thrown in, and returns
+    // the order they got there in along with the scheduler's trace
+    fn race (seed: u64) -> (Vec<u64>, Vec<(Duration, usize)>) {
+        let scheduler = Scheduler::new(seed);
+        Scheduler::enter(&scheduler);
+        let order = Arc::new(Mutex::new(vec![]));
+        let (tx, rx) = channel();
+        let threads: Vec<thread::JoinHandle<()>> = (0..4).map(|i| {
+            let (order, tx) = (order.clone(), tx.clone());
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10 * (i % 2)));
+                order.lock().unwrap().push(i);
+                tx.send(i).unwrap();
+            })
+        }).collect();
+        for _ in 0..4 {
+            rx.recv().unwrap();
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        let order = order.lock().unwrap().clone();
+        Scheduler::exit();
+        (order, scheduler.trace())
+    }
+
+    #[test]
+    fn same_seed_replays_the_same_schedule() {
+        assert_eq!(race(7), race(7));
+    }
+
+    #[test]
+    fn timeouts_use_simulated_time() {
+        let scheduler = Scheduler::new(3);
+        Scheduler::enter(&scheduler);
+        let (_tx, rx) = channel::<()>();
+        // nothing else can run, so the clock jumps straight to the timeout
+        assert_eq!(rx.recv_timeout(Duration::from_secs(60)), Err(RecvTimeoutError::Timeout));
+        assert_eq!(scheduler.elapsed(), Duration::from_secs(60));
+
+        thread::sleep(Duration::from_secs(1));
+        assert_eq!(scheduler.elapsed(), Duration::from_secs(61));
+        Scheduler::exit();
+    }
+
+    #[test]
+    fn locks_wait_for_their_holder() {
+        let scheduler = Scheduler::new(5);
+        Scheduler::enter(&scheduler);
+        let lock = Arc::new(Mutex::new(0));
+        let (tx, rx) = channel();
+        let holder = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let mut value = lock.lock().unwrap();
+                tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(10));
+                *value = 1;
+            })
+        };
+        rx.recv().unwrap();
+        assert_eq!(*lock.lock().unwrap(), 1);
+        assert_eq!(scheduler.elapsed(), Duration::from_millis(10));
+        holder.join().unwrap();
+        Scheduler::exit();
+    }
+
+    #[test]
+    #[should_panic(expected = "deadlocked")]
+    fn panics_when_the_simulation_deadlocks() {
+        let scheduler = Scheduler::new(3);
+        Scheduler::enter(&scheduler);
+        let (_tx, rx) = channel::<()>();
+        let _ = rx.recv();
+    }
+
+    #[test]
+    fn primitives_work_outside_a_simulation() {
+        let (tx, rx) = channel();
+        let t = thread::spawn(move || tx.send(5).unwrap());
+        assert_eq!(rx.recv(), Ok(5));
+        t.join().unwrap();
+    }
+}
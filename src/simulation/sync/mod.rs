@@ -0,0 +1,110 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{self, LockResult, PoisonError, TryLockError};
+
+use super::{current, Event, ThreadState};
+
+pub mod mpsc;
+
+///
+/// A std::sync::Mutex that gives up its thread's turn in a simulation while it waits for the
+/// lock, so that whoever holds it gets to run.
+///
+pub struct Mutex<T: ?Sized> {
+    inner: sync::Mutex<T>
+}
+
+impl<T> Mutex<T> {
+    pub fn new (t: T) -> Mutex<T> {
+        Mutex { inner: sync::Mutex::new(t) }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    ///
+    /// Blocks until the lock is free, then takes it.
+    ///
+    /// # Errors
+    /// Returns a PoisonError if a thread panicked while holding the lock.
+    ///
+    pub fn lock (&self) -> LockResult<MutexGuard<T>> {
+        let simulation = current();
+        loop {
+            let result = match simulation {
+                Some(_) => self.inner.try_lock(),
+                None => self.inner.lock().map_err(TryLockError::Poisoned)
+            };
+            match result {
+                Ok(guard) => return Ok(self.guard(guard)),
+                Err(TryLockError::Poisoned(e)) => {
+                    return Err(PoisonError::new(self.guard(e.into_inner())));
+                },
+                Err(TryLockError::WouldBlock) => {
+                    // safe to unwrap, since only simulations try the lock
+                    let (ref scheduler, me) = *simulation.as_ref().unwrap();
+                    scheduler.block(me, ThreadState::Blocked(Some(self.unlocked()), None));
+                }
+            }
+        }
+    }
+
+    fn guard<'a> (&'a self, guard: sync::MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        MutexGuard { inner: Some(guard), unlocked: self.unlocked() }
+    }
+
+    // Tells the scheduler which lock a thread is waiting on
+    fn unlocked (&self) -> Event {
+        Event::Unlock(self as *const Mutex<T> as *const () as usize)
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default () -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+///
+/// Holds a simulation::sync::Mutex until it's dropped, and then lets the simulation's threads
+/// that are waiting on the lock try for it again.
+///
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    // Only None while we're being dropped
+    inner: Option<sync::MutexGuard<'a, T>>,
+    unlocked: Event
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref (&self) -> &T {
+        self.inner.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut (&mut self) -> &mut T {
+        self.inner.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop (&mut self) {
+        self.inner = None;
+        if let Some((scheduler, _)) = current() {
+            scheduler.wake(self.unlocked);
+        }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'a, T> {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::time::Duration;
+
+pub use std::sync::mpsc::{SendError, RecvError, TryRecvError, RecvTimeoutError};
+use super::super::{current, Event, Scheduler, ThreadState};
+
+// The simulation whose thread is waiting on a channel, if there is one
+struct Waiter {
+    scheduler: Mutex<Option<Arc<Scheduler>>>
+}
+
+impl Waiter {
+    // Tells the scheduler which channel a thread is waiting on
+    fn event (&self) -> Event {
+        Event::Channel(self as *const Waiter as usize)
+    }
+
+    fn wake (&self) {
+        let scheduler = self.scheduler.lock().unwrap().take();
+        if let Some(scheduler) = scheduler {
+            scheduler.wake(self.event());
+        }
+    }
+}
+
+///
+/// Creates a std::sync::mpsc channel whose receiver gives up its thread's turn in a simulation
+/// while it waits, and whose timeouts are measured in the simulation's time.
+///
+pub fn channel<T> () -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::channel();
+    let waiter = Arc::new(Waiter { scheduler: Mutex::new(None) });
+    (Sender { inner: tx, waiter: waiter.clone() }, Receiver { inner: rx, waiter: waiter })
+}
+
+pub struct Sender<T> {
+    inner: mpsc::Sender<T>,
+    waiter: Arc<Waiter>
+}
+
+impl<T> Sender<T> {
+    ///
+    /// Sends |t| without blocking.
+    ///
+    /// # Errors
+    /// Returns |t| in a SendError if the receiver is gone.
+    ///
+    pub fn send (&self, t: T) -> Result<(), SendError<T>> {
+        let result = self.inner.send(t);
+        self.waiter.wake();
+        result
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone (&self) -> Sender<T> {
+        Sender { inner: self.inner.clone(), waiter: self.waiter.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    /// The receiver may be waiting to find out that the last sender is gone
+    fn drop (&mut self) {
+        self.waiter.wake();
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sender {{ .. }}")
+    }
+}
+
+pub struct Receiver<T> {
+    inner: mpsc::Receiver<T>,
+    waiter: Arc<Waiter>
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv (&self) -> Result<T, TryRecvError> {
+        self.inner.try_recv()
+    }
+
+    ///
+    /// Blocks until a message arrives.
+    ///
+    /// # Errors
+    /// Returns a RecvError once every sender is gone and there are no messages left.
+    ///
+    pub fn recv (&self) -> Result<T, RecvError> {
+        let (scheduler, me) = match current() {
+            Some(current) => current,
+            None => return self.inner.recv()
+        };
+        loop {
+            match self.inner.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => self.wait(&scheduler, me, None)
+            }
+        }
+    }
+
+    ///
+    /// Blocks until a message arrives, or until |timeout| has passed. In a simulation that's
+    /// |timeout| of the simulation's time.
+    ///
+    /// # Errors
+    /// Returns a RecvTimeoutError if we time out, or once every sender is gone and there
+    /// are no messages left.
+    ///
+    pub fn recv_timeout (&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let (scheduler, me) = match current() {
+            Some(current) => current,
+            None => return self.inner.recv_timeout(timeout)
+        };
+        let deadline = scheduler.elapsed() + timeout;
+        loop {
+            match self.inner.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {
+                    if scheduler.elapsed() >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.wait(&scheduler, me, Some(deadline));
+                }
+            }
+        }
+    }
+
+    pub fn iter (&self) -> Iter<T> {
+        Iter { rx: self }
+    }
+
+    pub fn try_iter (&self) -> TryIter<T> {
+        TryIter { rx: self }
+    }
+
+    // Gives up our turn until a sender wakes us up, or the simulation reaches |deadline|
+    fn wait (&self, scheduler: &Arc<Scheduler>, me: usize, deadline: Option<Duration>) {
+        *self.waiter.scheduler.lock().unwrap() = Some(scheduler.clone());
+        scheduler.block(me, ThreadState::Blocked(Some(self.waiter.event()), deadline));
+        *self.waiter.scheduler.lock().unwrap() = None;
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Receiver {{ .. }}")
+    }
+}
+
+/// Blocks for each message, until every sender is gone
+pub struct Iter<'a, T: 'a> {
+    rx: &'a Receiver<T>
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = T;
+
+    fn next (&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+/// Returns the messages that have already arrived
+pub struct TryIter<'a, T: 'a> {
+    rx: &'a Receiver<T>
+}
+
+impl<'a, T> Iterator for TryIter<'a, T> {
+    type Item = T;
+
+    fn next (&mut self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
+
+pub struct IntoIter<T> {
+    rx: Receiver<T>
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next (&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter (self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter (self) -> IntoIter<T> {
+        IntoIter { rx: self }
+    }
+}
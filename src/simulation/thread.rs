@@ -0,0 +1,84 @@
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::{current, Event, Scheduler, ThreadState};
+
+///
+/// Handle to a thread started with simulation::thread::spawn.
+///
+pub struct JoinHandle<T> {
+    thread: thread::JoinHandle<T>,
+    // The simulation the thread runs in, and its id there
+    simulated: Option<(Arc<Scheduler>, usize)>
+}
+
+impl<T> JoinHandle<T> {
+    ///
+    /// Blocks until the thread exits.
+    ///
+    /// # Errors
+    /// Returns what the thread panicked with, if it panicked.
+    ///
+    pub fn join (self) -> thread::Result<T> {
+        if let (Some((scheduler, id)), Some((_, me))) = (self.simulated, current()) {
+            while !scheduler.is_finished(id) {
+                scheduler.block(me, ThreadState::Blocked(Some(Event::Exit(id)), None));
+            }
+        }
+        self.thread.join()
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.thread.fmt(f)
+    }
+}
+
+// Tells the scheduler a simulation thread is done once it's dropped, even if the thread panics
+struct Finished(Arc<Scheduler>, usize);
+
+impl Drop for Finished {
+    fn drop (&mut self) {
+        self.0.finished(self.1);
+    }
+}
+
+///
+/// Spawns a new OS thread. If the calling thread is part of a simulation, so is the new one,
+/// and it waits for the scheduler to give it a turn before it starts.
+///
+/// # Panics
+/// Panics if the OS fails to create a thread.
+///
+pub fn spawn<F, T> (f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let simulated = current().map(|(scheduler, _)| {
+        let id = scheduler.spawned();
+        (scheduler, id)
+    });
+    let on_thread = simulated.clone();
+    let thread = thread::spawn(move || {
+        let _finished = on_thread.map(|(scheduler, id)| {
+            Scheduler::attach(scheduler.clone(), id);
+            Finished(scheduler, id)
+        });
+        f()
+    });
+    JoinHandle { thread: thread, simulated: simulated }
+}
+
+///
+/// Blocks for |duration|. In a simulation that's |duration| of the simulation's time.
+///
+pub fn sleep (duration: Duration) {
+    match current() {
+        Some((scheduler, me)) => {
+            let deadline = scheduler.elapsed() + duration;
+            scheduler.block(me, ThreadState::Blocked(None, Some(deadline)));
+        },
+        None => thread::sleep(duration)
+    }
+}
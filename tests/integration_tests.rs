@@ -6,21 +6,30 @@ extern crate env_logger;
 extern crate log;
 mod relay_server;
 mod mock_state_machine;
+mod simulation;
 
 use mock_state_machine::*;
 use relay_server::*;
-use rusty_raft::server::{start_test_server, ServerHandle, start_server_with_config};
+use simulation::Simulation;
+use rusty_raft::server::{start_test_server, ServerHandle, start_server_with_config,
+                         start_test_server_with_transport, start_server_with_storage};
+use rusty_raft::server::storage::MemoryStorage;
 use rusty_raft::client::RaftConnection;
 use rusty_raft::common::Config;
+use rusty_raft::rpc::transport::{ChannelNetwork, Transport};
 
 use rand::{thread_rng, Rng};
 use rand::distributions::{IndependentSample, Range};
 
 use std::collections::HashMap;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::sync::mpsc::{Receiver, channel};
 use std::time::Duration;
 use std::fs;
+use std::mem;
+use std::thread;
 
 const HEARTBEAT_TIMEOUT: u64 = 75;
 
@@ -36,13 +45,13 @@ struct StateMachineHandle {
 }
 
 impl Drop for StateMachineHandle {
-    /// Deletes the state and log files
+    /// Deletes the state file and log directory
     fn drop (&mut self) {
         if self.state_filename != "" {
             fs::remove_file(&self.state_filename).unwrap();
         }
         if self.log_filename != "" {
-            fs::remove_file(&self.log_filename).unwrap();
+            fs::remove_dir_all(&self.log_filename).unwrap();
         }
     }
 }
@@ -81,6 +90,41 @@ fn start_raft_server(id: u64, addr: Option<SocketAddr>) -> StateMachineHandle
         server_handle: server_handle, state_filename: state_filename, log_filename: log_filename}
 }
 
+/// Starts up a new raft server that listens on the given address of an in-process network
+fn start_channel_raft_server(id: u64, addr: SocketAddr, first: bool, network: &ChannelNetwork)
+    -> StateMachineHandle {
+    const STATE_FILENAME_LEN: usize = 20;
+
+    let random_filename: String = thread_rng().gen_ascii_chars().take(STATE_FILENAME_LEN).collect();
+    let state_filename = String::from("/tmp/state_") + &random_filename;
+    let log_filename = String::from("/tmp/log_") + &random_filename;
+
+    let (tx, rx) = channel();
+    let state_machine = Box::new(MockStateMachine::new_with_sender(tx));
+    let server_handle = start_test_server_with_transport(id, move || state_machine, addr, first,
+                                                         &state_filename, &log_filename,
+                                                         Arc::new(network.transport(addr))).unwrap();
+
+    StateMachineHandle {rx: rx, id: id, addr: addr, server_handle: server_handle,
+        state_filename: state_filename, log_filename: log_filename}
+}
+
+/// Starts up a new raft server on an in-process network that keeps everything in memory
+fn start_memory_raft_server(id: u64, addr: SocketAddr, network: &ChannelNetwork) -> StateMachineHandle {
+    let (tx, rx) = channel();
+    let state_machine = Box::new(MockStateMachine::new_with_sender(tx));
+    let mut config = Config::new(id, addr, Duration::from_millis(HEARTBEAT_TIMEOUT), "", "");
+    config.transport = Arc::new(network.transport(addr));
+    // snapshot every few entries, so that snapshots are kept in memory too
+    config.snapshot_threshold = 2;
+    let server_handle = start_server_with_storage(config, Box::new(MemoryStorage::new()),
+                                                  move || state_machine).unwrap();
+
+    // there are no files to clean up
+    StateMachineHandle {rx: rx, id: id, addr: addr, server_handle: server_handle,
+        state_filename: String::new(), log_filename: String::new()}
+}
+
 fn start_raft_servers(num_servers: u64, bootstrap_addr: SocketAddr) -> Vec<StateMachineHandle> {
     (0..num_servers)
     .map(|i| {
@@ -111,6 +155,53 @@ fn bootstrap_raft_cluster (num_servers: u64) -> (RaftConnection, Vec<StateMachin
     (raft_db, state_machines, relay_server)
 }
 
+/// Like bootstrap_raft_cluster, but every server talks to the others through the relay server,
+/// so that a test can cut any of them off. Also returns the relay address of each server.
+fn bootstrap_relayed_raft_cluster (num_servers: u64)
+    -> (RaftConnection, Vec<StateMachineHandle>, RelayServer, HashMap<u64, SocketAddr>) {
+    let (mut relay_server, addrs) = start_relay_server(num_servers);
+    let state_machines = start_raft_servers(num_servers, addrs[&0].clone());
+    for server in state_machines.iter() {
+        relay_server.relay_address(addrs[&server.id], server.addr.clone());
+    }
+
+    {
+        // only the first server is part of the cluster until we add the others
+        let mut first_server = HashMap::new();
+        first_server.insert(0, addrs[&0]);
+        let mut raft_db = RaftConnection::new_with_session(&first_server).unwrap();
+        for server in state_machines.iter().skip(1) {
+            raft_db.add_server(server.id, addrs[&server.id]).unwrap();
+        }
+    }
+
+    let raft_db = RaftConnection::new_with_session(&addrs).unwrap();
+    (raft_db, state_machines, relay_server, addrs)
+}
+
+/// Shuts down a server without deleting its state and log files, and returns
+/// (id, address, state filename, log filename) so restart_raft_server can bring it back up.
+fn crash_raft_server(mut handle: StateMachineHandle) -> (u64, SocketAddr, String, String) {
+    let state_filename = mem::replace(&mut handle.state_filename, String::new());
+    let log_filename = mem::replace(&mut handle.log_filename, String::new());
+    (handle.id, handle.addr, state_filename, log_filename)
+}
+
+/// Restarts a server from the files that crash_raft_server left behind
+fn restart_raft_server(id: u64, addr: SocketAddr, state_filename: String, log_filename: String)
+    -> StateMachineHandle {
+    let server_handle = {
+        let config = Config::new(id, addr, Duration::from_millis(HEARTBEAT_TIMEOUT),
+                                 &state_filename, &log_filename);
+        let (tx, rx) = channel();
+        let state_machine = Box::new(MockStateMachine::new_with_sender(tx));
+        (start_server_with_config(config, move || state_machine).unwrap(), rx)
+    };
+
+    StateMachineHandle {rx: server_handle.1, server_handle: server_handle.0, id: id, addr: addr,
+        state_filename: state_filename, log_filename: log_filename}
+}
+
 #[test]
 /// This test is as much a sanity check on our testing code as on the raft code.
 /// It just makes sure we can start up a dynamic cluster of servers without crashing
@@ -230,6 +321,58 @@ fn new_server_stays_in_cluster() {
     // TODO
 }
 
+#[test]
+// Starts a 3 server cluster and removes one of the followers
+// ensures that the remaining servers still commit entries without it
+fn it_removes_a_follower() {
+    const NUM_SERVERS: u64 = 3;
+    const REPLICATE_TIMEOUT: u64 = 5000;
+    const REMOVED_TIMEOUT: u64 = 500;
+    const REMOVED_ID: u64 = 2;
+
+    let (mut raft_db, state_machines, _relay_server) = bootstrap_raft_cluster(NUM_SERVERS);
+    assert_eq!(state_machines.len(), NUM_SERVERS as usize);
+
+    // the bootstrapped server is the leader, so this removes a follower
+    raft_db.remove_server(REMOVED_ID, state_machines[REMOVED_ID as usize].addr).unwrap();
+
+    let data = issue_command_and_assert_ok(&mut raft_db);
+    assert!(state_machines
+                .iter()
+                .filter(|handle| handle.id != REMOVED_ID)
+                .map(|handle| handle.rx.recv_timeout(Duration::from_millis(REPLICATE_TIMEOUT)).unwrap())
+                .all(|vec| vec == data));
+    // the removed server shouldn't hear about new entries
+    assert!(state_machines[REMOVED_ID as usize].rx
+                .recv_timeout(Duration::from_millis(REMOVED_TIMEOUT)).is_err());
+}
+
+#[test]
+// Starts a 3 server cluster and removes the leader
+// ensures that the remaining servers elect a new leader and keep commiting entries
+fn it_removes_the_leader() {
+    const NUM_SERVERS: u64 = 3;
+    const REPLICATE_TIMEOUT: u64 = 5000;
+    const REMOVED_TIMEOUT: u64 = 500;
+    const LEADER_ID: u64 = 0;
+
+    let (mut raft_db, state_machines, relay_server) = bootstrap_raft_cluster(NUM_SERVERS);
+    assert_eq!(state_machines.len(), NUM_SERVERS as usize);
+
+    // the bootstrapped server is the leader, and the rest of the cluster knows it by its relay address
+    let leader_addr = relay_server.get_bound_addresses()[0];
+    raft_db.remove_server(LEADER_ID, leader_addr).unwrap();
+
+    let data = issue_command_and_assert_ok(&mut raft_db);
+    assert!(state_machines
+                .iter()
+                .filter(|handle| handle.id != LEADER_ID)
+                .map(|handle| handle.rx.recv_timeout(Duration::from_millis(REPLICATE_TIMEOUT)).unwrap())
+                .all(|vec| vec == data));
+    assert!(state_machines[LEADER_ID as usize].rx
+                .recv_timeout(Duration::from_millis(REMOVED_TIMEOUT)).is_err());
+}
+
 fn issue_command_and_assert_ok(db: &mut RaftConnection) -> Vec<u8> {
     const DATA_LENGTH: usize = 1;
     let data: String = thread_rng().gen_ascii_chars().take(DATA_LENGTH).collect();
@@ -306,3 +449,256 @@ fn it_catches_up_when_behind() {
         assert_data_replicated(&state_machines, &data, |_| true, None);
     }
 }
+
+#[test]
+// The leader of a 5 server cluster gets an entry to one follower before it's cut off, and the
+// servers that never saw that entry go on to elect a leader that commits a replacement for it.
+// Once the two servers that had the entry come back they have to replace it too, and it must
+// never be applied anywhere. The full Figure 8 sequence from the Raft paper needs a leader to
+// crash between two AppendEntries, so it's scripted in the server's unit tests instead.
+fn it_never_applies_overwritten_entries() {
+    const NUM_SERVERS: u64 = 5;
+    const REPLICATE_TIMEOUT: u64 = 5000;
+    const OVERWRITTEN_TIMEOUT: u64 = 500;
+    const LOST_MAJORITY_TIMEOUT: u64 = 1000;
+    const FIRST: &'static [u8] = b"first";
+    const OVERWRITTEN: &'static [u8] = b"overwritten";
+    const REPLACEMENT: &'static [u8] = b"replacement";
+    const LAST: &'static [u8] = b"last";
+    let replicate_timeout = Duration::from_millis(REPLICATE_TIMEOUT);
+
+    let (mut raft_db, mut state_machines, mut relay_server, addrs) =
+        bootstrap_relayed_raft_cluster(NUM_SERVERS);
+    raft_db.command(FIRST).unwrap();
+    for handle in state_machines.iter() {
+        assert_eq!(&handle.rx.recv_timeout(replicate_timeout).unwrap()[..], FIRST);
+    }
+
+    // The bootstrapped server is the leader. Give it a client of its own that we can cut off,
+    // so the client can't retry its command once the cluster moves on without it
+    let stale_client_addr = relay_server.bind_random_addresses(1)[0];
+    relay_server.relay_address(stale_client_addr, state_machines[0].addr);
+    let mut stale_cluster = HashMap::new();
+    stale_cluster.insert(0, stale_client_addr);
+    let mut stale_db = RaftConnection::new_with_session(&stale_cluster).unwrap();
+
+    // Only server 1 hears about the next entry
+    for id in 2..NUM_SERVERS {
+        relay_server.set_address_active(addrs[&id], false);
+    }
+    thread::spawn(move || stale_db.command(OVERWRITTEN));
+    thread::sleep(Duration::from_millis(HEARTBEAT_TIMEOUT * 2));
+    relay_server.set_address_active(stale_client_addr, false);
+    // give the leader time to notice it's lost its majority and step down
+    thread::sleep(Duration::from_millis(LOST_MAJORITY_TIMEOUT));
+
+    // The two servers with the entry go down, and the rest of the cluster replaces it
+    let crashed: Vec<(u64, SocketAddr, String, String)> = (0..2)
+        .map(|_| crash_raft_server(state_machines.remove(0)))
+        .collect();
+    for id in 2..NUM_SERVERS {
+        relay_server.set_address_active(addrs[&id], true);
+    }
+    raft_db.command(REPLACEMENT).unwrap();
+    for handle in state_machines.iter() {
+        assert_eq!(&handle.rx.recv_timeout(replicate_timeout).unwrap()[..], REPLACEMENT);
+    }
+
+    // Once they're back, the servers that had the entry have to replace it too
+    for (id, addr, state_filename, log_filename) in crashed {
+        state_machines.push(restart_raft_server(id, addr, state_filename, log_filename));
+    }
+    raft_db.command(LAST).unwrap();
+    for handle in state_machines.iter() {
+        let expected: Vec<&[u8]> = if handle.id < 2 { vec![FIRST, REPLACEMENT, LAST] } else { vec![LAST] };
+        for data in expected {
+            assert_eq!(&handle.rx.recv_timeout(replicate_timeout).unwrap()[..], data);
+        }
+        assert!(handle.rx.recv_timeout(Duration::from_millis(OVERWRITTEN_TIMEOUT)).is_err());
+    }
+}
+
+#[test]
+fn it_replicates_over_a_channel_network() {
+    const NUM_SERVERS: u64 = 3;
+    const REPLICATE_TIMEOUT: u64 = 5000;
+    const PARTITIONED_TIMEOUT: u64 = 500;
+    const FIRST: &'static [u8] = b"first";
+    const SECOND: &'static [u8] = b"second";
+    let replicate_timeout = Duration::from_millis(REPLICATE_TIMEOUT);
+
+    // Nothing binds to these addresses, so they can't conflict with other tests
+    let network = ChannelNetwork::new();
+    let addrs: HashMap<u64, SocketAddr> = (0..NUM_SERVERS)
+        .map(|id| (id, SocketAddr::from_str(&format!("127.0.0.1:{}", id + 1)).unwrap()))
+        .collect();
+    let state_machines: Vec<StateMachineHandle> = (0..NUM_SERVERS)
+        .map(|id| start_channel_raft_server(id, addrs[&id], id == 0, &network))
+        .collect();
+    let client_transport: Arc<Transport> =
+        Arc::new(network.transport(SocketAddr::from_str("127.0.0.1:100").unwrap()));
+    {
+        // only the first server is part of the cluster until we add the others
+        let mut first_server = HashMap::new();
+        first_server.insert(0, addrs[&0]);
+        let mut raft_db = RaftConnection::new_with_transport(&first_server, client_transport.clone())
+            .unwrap();
+        for server in state_machines.iter().skip(1) {
+            raft_db.add_server(server.id, server.addr).unwrap();
+        }
+    }
+
+    let mut raft_db = RaftConnection::new_with_transport(&addrs, client_transport).unwrap();
+    raft_db.command(FIRST).unwrap();
+    for handle in state_machines.iter() {
+        assert_eq!(&handle.rx.recv_timeout(replicate_timeout).unwrap()[..], FIRST);
+    }
+
+    // The other two servers are still a majority without the last one
+    for id in 0..2 {
+        network.disconnect(addrs[&id], addrs[&2]);
+    }
+    raft_db.command(SECOND).unwrap();
+    for handle in state_machines.iter().take(2) {
+        assert_eq!(&handle.rx.recv_timeout(replicate_timeout).unwrap()[..], SECOND);
+    }
+    assert!(state_machines[2].rx.recv_timeout(Duration::from_millis(PARTITIONED_TIMEOUT)).is_err());
+
+    // It catches up once it's back
+    for id in 0..2 {
+        network.reconnect(addrs[&id], addrs[&2]);
+    }
+    assert_eq!(&state_machines[2].rx.recv_timeout(replicate_timeout).unwrap()[..], SECOND);
+}
+
+#[test]
+fn it_serves_follower_reads_over_a_channel_network() {
+    const NUM_SERVERS: u64 = 3;
+    const REPLICATE_TIMEOUT: u64 = 5000;
+    const DATA: &'static [u8] = b"data";
+
+    let network = ChannelNetwork::new();
+    let addrs: HashMap<u64, SocketAddr> = (0..NUM_SERVERS)
+        .map(|id| (id, SocketAddr::from_str(&format!("127.0.0.1:{}", id + 1)).unwrap()))
+        .collect();
+    let state_machines: Vec<StateMachineHandle> = (0..NUM_SERVERS)
+        .map(|id| start_channel_raft_server(id, addrs[&id], id == 0, &network))
+        .collect();
+    let client_addr = SocketAddr::from_str("127.0.0.1:100").unwrap();
+    let client_transport: Arc<Transport> = Arc::new(network.transport(client_addr));
+    {
+        let mut first_server = HashMap::new();
+        first_server.insert(0, addrs[&0]);
+        let mut raft_db = RaftConnection::new_with_transport(&first_server, client_transport.clone())
+            .unwrap();
+        for server in state_machines.iter().skip(1) {
+            raft_db.add_server(server.id, server.addr).unwrap();
+        }
+    }
+
+    let mut raft_db = RaftConnection::new_with_transport(&addrs, client_transport).unwrap();
+    raft_db.command(DATA).unwrap();
+    for handle in state_machines.iter() {
+        let data = handle.rx.recv_timeout(Duration::from_millis(REPLICATE_TIMEOUT)).unwrap();
+        assert_eq!(&data[..], DATA);
+    }
+
+    // The client can only reach the followers now, which get their read index from the
+    // leader over the network
+    network.disconnect(client_addr, addrs[&0]);
+    assert_eq!(raft_db.linearizable_follower_query(b"query").unwrap(), Vec::<u8>::new());
+    assert_eq!(raft_db.follower_query(b"query").unwrap(), Vec::<u8>::new());
+}
+
+#[test]
+fn it_replicates_to_servers_with_memory_storage() {
+    const NUM_SERVERS: u64 = 3;
+    const REPLICATE_TIMEOUT: u64 = 5000;
+    const NUM_COMMANDS: usize = 5;
+    const DATA: &'static [u8] = b"in memory";
+
+    let network = ChannelNetwork::new();
+    let addrs: HashMap<u64, SocketAddr> = (0..NUM_SERVERS)
+        .map(|id| (id, SocketAddr::from_str(&format!("127.0.0.1:{}", id + 1)).unwrap()))
+        .collect();
+    // the first server bootstraps the cluster from its log file
+    let state_machines: Vec<StateMachineHandle> = (0..NUM_SERVERS)
+        .map(|id| if id == 0 {
+            start_channel_raft_server(id, addrs[&id], true, &network)
+        } else {
+            start_memory_raft_server(id, addrs[&id], &network)
+        })
+        .collect();
+    let client_transport: Arc<Transport> =
+        Arc::new(network.transport(SocketAddr::from_str("127.0.0.1:100").unwrap()));
+    {
+        let mut first_server = HashMap::new();
+        first_server.insert(0, addrs[&0]);
+        let mut raft_db = RaftConnection::new_with_transport(&first_server, client_transport.clone())
+            .unwrap();
+        for server in state_machines.iter().skip(1) {
+            raft_db.add_server(server.id, server.addr).unwrap();
+        }
+    }
+
+    let mut raft_db = RaftConnection::new_with_transport(&addrs, client_transport).unwrap();
+    // enough commands that the servers with memory storage snapshot their state machines
+    for _ in 0..NUM_COMMANDS {
+        raft_db.command(DATA).unwrap();
+        for handle in state_machines.iter() {
+            let data = handle.rx.recv_timeout(Duration::from_millis(REPLICATE_TIMEOUT)).unwrap();
+            assert_eq!(&data[..], DATA);
+        }
+    }
+}
+
+#[test]
+fn it_survives_random_crashes_in_simulation() {
+    const NUM_SERVERS: u64 = 3;
+    const NUM_COMMANDS: usize = 5;
+    // A failing seed replays the same run, so it can be rerun with logging turned on
+    const SEED: u64 = 2017;
+
+    let mut sim = Simulation::new(NUM_SERVERS, SEED);
+    let commands: Vec<Vec<u8>> = (0..NUM_COMMANDS)
+        .map(|i| format!("command {}", i).into_bytes())
+        .collect();
+    for command in commands.iter() {
+        sim.command(command.clone());
+        let id = sim.rng.gen_range(0, NUM_SERVERS);
+        sim.crash(id);
+        sim.restart(id);
+    }
+
+    // Restarted servers apply their whole log again, so every server's current
+    // state machine should see every command exactly once
+    for id in 0..NUM_SERVERS {
+        assert_eq!(sim.applied(id, NUM_COMMANDS), commands);
+    }
+}
+
+#[test]
+fn it_replays_a_simulation_from_its_seed() {
+    const NUM_SERVERS: u64 = 3;
+    const NUM_COMMANDS: usize = 3;
+    const SEED: u64 = 7;
+
+    // Crashes servers in between commands, so the replay has to elect the same leaders
+    fn run (seed: u64) -> (Vec<(Duration, String)>, Vec<(Duration, usize)>) {
+        let mut sim = Simulation::new(NUM_SERVERS, seed);
+        for i in 0..NUM_COMMANDS {
+            sim.command(format!("command {}", i).into_bytes());
+            let id = sim.rng.gen_range(0, NUM_SERVERS);
+            sim.crash(id);
+            sim.restart(id);
+        }
+        for id in 0..NUM_SERVERS {
+            sim.applied(id, NUM_COMMANDS);
+        }
+        (sim.events().to_vec(), sim.scheduler.trace())
+    }
+
+    let (events, trace) = run(SEED);
+    assert!(events.iter().any(|&(_, ref event)| event.starts_with("crashed")));
+    assert_eq!(run(SEED), (events, trace));
+}
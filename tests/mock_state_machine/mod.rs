@@ -33,4 +33,13 @@ impl StateMachine for MockStateMachine {
     fn query(&self, _: &[u8]) -> Result<Vec<u8>, RaftError> {
         Ok(Vec::new())
     }
+
+    /// the mock has no state of its own to snapshot
+    fn snapshot(&self) -> Result<Vec<u8>, RaftError> {
+        Ok(Vec::new())
+    }
+
+    fn restore_snapshot(&mut self, _: &[u8]) -> Result<(), RaftError> {
+        Ok(())
+    }
 }
@@ -100,6 +100,19 @@ impl RelayServer {
         socket_info.online = true;
     }
 
+    /// Sets whether messages sent to address are relayed. Connections to an inactive
+    /// address are dropped, as though the server behind it had gone down.
+    ///
+    /// #Panics
+    /// Panics if the address is not an existing mapping
+    /// Also panics if any of the server's background threads have panicked.
+    pub fn set_address_active(&mut self, address: SocketAddr, active: bool) {
+        let mut addrs = self.addrs.lock().unwrap();
+        let socket_info: &mut SocketInfo = addrs.get_mut(&address).unwrap();
+
+        socket_info.online = active;
+    }
+
     /// Runs in a background thread, and relays messages according to the hash map.
     fn relay_messages(listener: TcpListener, addrs: Arc<Mutex<HashMap<SocketAddr, SocketInfo>>>) {
         let local_addr = listener.local_addr().unwrap();
@@ -250,6 +263,30 @@ mod tests {
         assert_eq!(&v[..], msg.as_bytes());
     }
 
+    #[test]
+    fn it_drops_messages_to_inactive_address() {
+        let mut relay_server = RelayServer::new_with_random_addresses(1);
+        let addresses = relay_server.get_bound_addresses();
+
+        let (addr, rx) = start_tcp_listening_server();
+        relay_server.relay_address(addresses[0], addr);
+        relay_server.set_address_active(addresses[0], false);
+        {
+            let mut client = TcpStream::connect(addresses[0]).unwrap();
+            let _ = client.write_all(b"dropped");
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(TIMEOUT / 10)).is_err());
+
+        relay_server.set_address_active(addresses[0], true);
+        {
+            let mut client = TcpStream::connect(addresses[0]).unwrap();
+            client.write_all(b"relayed").unwrap();
+            client.flush().unwrap();
+        }
+        let result = rx.recv_timeout(Duration::from_millis(TIMEOUT)).unwrap();
+        assert_eq!(&result[..], b"relayed");
+    }
+
     /// Starts a simple TCP server on the returned address (OS assigned) in a background thread
     /// that sends every message it recieves down through the returned channel
     fn start_tcp_listening_server() -> (SocketAddr, Receiver<Vec<u8>>) {
@@ -0,0 +1,206 @@
+use rusty_raft::client::RaftConnection;
+use rusty_raft::common::Config;
+use rusty_raft::rpc::transport::{ChannelNetwork, Transport};
+use rusty_raft::server::{ServerHandle, start_test_server_with_config};
+use rusty_raft::server::clock::Clock;
+use rusty_raft::simulation::Scheduler;
+use rusty_raft::simulation::thread;
+use mock_state_machine::MockStateMachine;
+
+use rand::{thread_rng, Rng, SeedableRng, StdRng};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::mem;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, channel};
+use std::time::Duration;
+
+const HEARTBEAT_TIMEOUT: u64 = 75;
+// How often we check whether a server has applied the commands we're waiting for, in simulated m.s.
+const POLL_INTERVAL: u64 = 5;
+// Longest we wait for a server to apply them before failing the test, in simulated m.s.
+const MAX_WAIT: u64 = 60000;
+
+// A server in the simulation, along with the files it keeps across crashes
+struct SimulatedServer {
+    server_handle: Option<ServerHandle>,
+    rx: Receiver<Vec<u8>>,
+    state_filename: String,
+    log_filename: String
+}
+
+impl Drop for SimulatedServer {
+    /// Deletes the state file and log directory
+    fn drop (&mut self) {
+        let _ = fs::remove_file(&self.state_filename);
+        let _ = fs::remove_dir_all(&self.log_filename);
+    }
+}
+
+///
+/// Runs a cluster on a ChannelNetwork under a simulation::Scheduler, on the thread that
+/// creates the Simulation. Every server, peer, log and rpc thread joins the simulation, and
+/// only one of them runs at a time, in an order picked by the simulation's seed. The servers
+/// take their time from the scheduler, which only moves once every thread is waiting on a
+/// timeout, and their election timeouts come from seeds derived from the simulation's seed.
+/// The simulation's rng should drive any other random choices a test makes, like which
+/// server to crash.
+///
+/// So a seed replays the entire run exactly: the same elections, the same messages handled in
+/// the same order, and the same crashes at the same simulated times. A failing seed can be
+/// rerun with logging turned on to see what happened.
+///
+pub struct Simulation {
+    pub scheduler: Arc<Scheduler>,
+    pub network: ChannelNetwork,
+    pub rng: StdRng,
+    pub addrs: HashMap<u64, SocketAddr>,
+    seed: u64,
+    servers: BTreeMap<u64, SimulatedServer>,
+    client_transport: Arc<Transport>,
+    // What the test has done to the cluster, and the simulated time it finished at
+    events: Vec<(Duration, String)>
+}
+
+impl Simulation {
+    /// Starts up |num_servers| servers, and adds them all to the cluster
+    ///
+    /// # Panics
+    /// Panics if the calling thread is already running a simulation
+    pub fn new (num_servers: u64, seed: u64) -> Simulation {
+        let scheduler = Scheduler::new(seed);
+        Scheduler::enter(&scheduler);
+        let network = ChannelNetwork::new();
+        let client_addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+        let mut sim = Simulation {
+            scheduler: scheduler,
+            client_transport: Arc::new(network.transport(client_addr)),
+            network: network,
+            rng: StdRng::from_seed(&[seed as usize][..]),
+            addrs: (0..num_servers)
+                .map(|id| (id, SocketAddr::from_str(&format!("127.0.0.1:{}", id + 1)).unwrap()))
+                .collect(),
+            seed: seed,
+            servers: BTreeMap::new(),
+            events: vec![]
+        };
+
+        for id in 0..num_servers {
+            // Simulations with the same seed may run in parallel, so the files can't come from it
+            let random_filename: String = thread_rng().gen_ascii_chars().take(20).collect();
+            sim.servers.insert(id, SimulatedServer {
+                server_handle: None,
+                rx: channel().1,
+                state_filename: String::from("/tmp/state_") + &random_filename,
+                log_filename: String::from("/tmp/log_") + &random_filename
+            });
+            sim.start_server(id, id == 0);
+        }
+
+        // only the first server is part of the cluster until we add the others
+        let mut first_server = HashMap::new();
+        first_server.insert(0, sim.addrs[&0]);
+        let mut raft_db = RaftConnection::new_with_transport(&first_server, sim.client_transport.clone())
+            .unwrap();
+        for id in 1..num_servers {
+            raft_db.add_server(id, sim.addrs[&id]).unwrap();
+            sim.record(format!("added server {}", id));
+        }
+        sim
+    }
+
+    /// Commits |data| to the cluster
+    pub fn command (&mut self, data: Vec<u8>) {
+        let mut raft_db = RaftConnection::new_with_transport(&self.addrs, self.client_transport.clone())
+            .unwrap();
+        raft_db.command(&data).unwrap();
+        self.record(format!("committed {:?}", String::from_utf8_lossy(&data)));
+    }
+
+    /// Returns the next |count| commands the server's state machine applies
+    ///
+    /// # Panics
+    /// Panics if the server doesn't apply them within MAX_WAIT of simulated time
+    pub fn applied (&mut self, id: u64, count: usize) -> Vec<Vec<u8>> {
+        let deadline = self.scheduler.elapsed() + Duration::from_millis(MAX_WAIT);
+        let mut applied = vec![];
+        while self.scheduler.elapsed() < deadline {
+            while let Ok(data) = self.servers[&id].rx.try_recv() {
+                applied.push(data);
+            }
+            if applied.len() >= count {
+                self.record(format!("server {} applied {} commands", id, applied.len()));
+                return applied;
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL));
+        }
+        panic!("Server {} only applied {} commands with seed {}", id, applied.len(), self.seed);
+    }
+
+    /// Shuts down the server, keeping its state and log files around for restart
+    pub fn crash (&mut self, id: u64) {
+        // The server hands off leadership before it shuts down, which takes simulated time
+        let handle = self.servers.get_mut(&id).unwrap().server_handle.take();
+        mem::drop(handle);
+        self.record(format!("crashed server {}", id));
+    }
+
+    /// Brings a crashed server back up from its files
+    pub fn restart (&mut self, id: u64) {
+        self.start_server(id, false);
+        self.record(format!("restarted server {}", id));
+    }
+
+    /// Returns everything the test has done to the cluster so far, along with when it happened.
+    /// Two simulations with the same seed that run the same test return the same events, as
+    /// does the scheduler's trace of which thread ran when.
+    pub fn events (&self) -> &[(Duration, String)] {
+        &self.events
+    }
+
+    fn record (&mut self, event: String) {
+        self.events.push((self.scheduler.elapsed(), event));
+    }
+
+    fn start_server (&mut self, id: u64, first: bool) {
+        let addr = self.addrs[&id];
+        let transport = Arc::new(self.network.transport(addr));
+        let clock: Arc<Clock> = self.scheduler.clone();
+        let seed = self.seed + id;
+        let server = self.servers.get_mut(&id).unwrap();
+        let (tx, rx) = channel();
+        let state_machine = Box::new(MockStateMachine::new_with_sender(tx));
+        let handle = {
+            let mut config = Config::new(id, addr, Duration::from_millis(HEARTBEAT_TIMEOUT),
+                                         &server.state_filename, &server.log_filename);
+            config.transport = transport;
+            config.clock = clock;
+            config.seed = Some(seed);
+            start_test_server_with_config(config, first, move || state_machine).unwrap()
+        };
+        server.server_handle = Some(handle);
+        server.rx = rx;
+    }
+}
+
+impl Drop for Simulation {
+    /// Shuts down every server, and leaves the simulation
+    fn drop (&mut self) {
+        if ::std::thread::panicking() {
+            // None of the other threads get another turn after a failed test, so shutting the
+            // servers down would block forever. Leave them parked instead
+            for server in self.servers.values_mut() {
+                mem::forget(server.server_handle.take());
+            }
+            return;
+        }
+        let ids: Vec<u64> = self.servers.keys().cloned().collect();
+        for id in ids {
+            self.crash(id);
+        }
+        Scheduler::exit();
+    }
+}